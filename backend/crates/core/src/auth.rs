@@ -0,0 +1,934 @@
+//! Authorization rules every local and federated event must pass before it is persisted.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use thiserror::Error;
+
+use crate::{
+    messaging::{EDIT_EVENT, MESSAGE_EVENT, REACTION_EVENT},
+    redaction::REDACTION_EVENT,
+    state::{
+        JoinRule, Membership, PowerLevelsContent, StateContent, StateError, StateMap,
+        CHANNEL_ENCRYPTION_EVENT, CHANNEL_JOIN_RULES_EVENT, CHANNEL_MEMBER_EVENT,
+        CHANNEL_POWER_LEVELS_EVENT, DEFAULT_STATE_POWER_LEVEL, DEFAULT_USER_POWER_LEVEL,
+    },
+    CanonicalEvent, EventId,
+};
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("sender '{sender}' does not belong to origin '{origin}'")]
+    SenderOriginMismatch { sender: String, origin: String },
+    #[error("auth event '{0}' is unknown")]
+    UnknownAuthEvent(EventId),
+    #[error("auth event '{event_id}' is not valid here: {reason}")]
+    InvalidAuthEvent { event_id: EventId, reason: String },
+    #[error("'{user}' is banned from the channel")]
+    Banned { user: String },
+    #[error("'{user}' has not joined the channel")]
+    NotJoined { user: String },
+    #[error("'{user}' needs power level {required} but has {actual}")]
+    InsufficientPower {
+        user: String,
+        required: i64,
+        actual: i64,
+    },
+    #[error("membership change not allowed: {0}")]
+    Membership(String),
+    #[error("redaction not allowed: {0}")]
+    Redaction(String),
+    #[error("edit not allowed: {0}")]
+    Edit(String),
+    #[error("reaction not allowed: {0}")]
+    Reaction(String),
+    #[error("power levels change not allowed: {0}")]
+    PowerLevels(String),
+    #[error("channel is encrypted: {0}")]
+    Encrypted(String),
+    #[error(transparent)]
+    InvalidState(#[from] StateError),
+}
+
+/// Fully qualified `@localpart:server` form of a user id; bare ids are scoped to `server`.
+pub fn qualify_user_id(user_id: &str, server: &str) -> String {
+    if user_id.contains(':') {
+        user_id.to_string()
+    } else {
+        format!("@{}:{server}", user_id.trim_start_matches('@'))
+    }
+}
+
+/// Qualified senders (`@user:server`) must name the server that created the event.
+pub fn check_sender_origin(event: &CanonicalEvent) -> Result<(), AuthError> {
+    match event.sender.split_once(':') {
+        Some((_, server)) if server != event.origin_server => {
+            Err(AuthError::SenderOriginMismatch {
+                sender: event.sender.clone(),
+                origin: event.origin_server.clone(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The parts of channel state the auth rules consult, keyed by qualified user id.
+#[derive(Debug, Clone, Default)]
+pub struct AuthState {
+    power_levels: Option<PowerLevelsContent>,
+    members: HashMap<String, Membership>,
+    managed: bool,
+    encrypted: bool,
+    join_rule: JoinRule,
+    creator: Option<String>,
+}
+
+impl AuthState {
+    /// Build from power-levels, membership, encryption and join rules events; other events
+    /// are ignored.
+    ///
+    /// `managed` marks channels whose state holds membership events. Channels without any
+    /// (created before state events existed) admit every sender that is not banned; servers
+    /// only apply that to their own users.
+    pub fn from_events<'a>(
+        events: impl IntoIterator<Item = &'a CanonicalEvent>,
+        managed: bool,
+    ) -> Self {
+        let mut state = Self {
+            managed,
+            ..Self::default()
+        };
+        for event in events {
+            let Some(state_key) = event.state_key.as_deref() else {
+                continue;
+            };
+            match StateContent::parse(&event.event_type, &event.content) {
+                Ok(Some(StateContent::PowerLevels(mut content))) => {
+                    content.users = content
+                        .users
+                        .into_iter()
+                        .map(|(user, level)| (qualify_user_id(&user, &event.origin_server), level))
+                        .collect();
+                    state.power_levels = Some(content);
+                }
+                Ok(Some(StateContent::Member(content))) => {
+                    state.members.insert(
+                        qualify_user_id(state_key, &event.origin_server),
+                        content.membership,
+                    );
+                }
+                Ok(Some(StateContent::Encryption(_))) if state_key.is_empty() => {
+                    state.encrypted = true;
+                }
+                Ok(Some(StateContent::JoinRules(content))) if state_key.is_empty() => {
+                    state.join_rule = content.join_rule;
+                }
+                _ => {}
+            }
+        }
+        state
+    }
+
+    /// Record the qualified id of the channel's creator, the only sender who may set the
+    /// first power levels.
+    pub fn with_creator(mut self, creator: Option<String>) -> Self {
+        self.creator = creator;
+        self
+    }
+
+    /// Count `user_id` as joined unless the state already records a membership for them.
+    /// Servers use this for local members enrolled outside of membership events.
+    pub fn admit(&mut self, user_id: String) {
+        self.members.entry(user_id).or_insert(Membership::Join);
+    }
+
+    pub fn membership(&self, user_id: &str) -> Option<Membership> {
+        self.members.get(user_id).copied()
+    }
+
+    /// Servers named by the qualified ids of joined members.
+    pub fn joined_servers(&self) -> BTreeSet<String> {
+        self.members
+            .iter()
+            .filter(|(_, membership)| **membership == Membership::Join)
+            .filter_map(|(user, _)| user.split_once(':').map(|(_, server)| server.to_string()))
+            .collect()
+    }
+
+    pub fn join_rule(&self) -> JoinRule {
+        self.join_rule
+    }
+
+    /// Whether the channel has turned on end-to-end encryption.
+    pub fn encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    pub fn user_level(&self, user_id: &str) -> i64 {
+        self.power_levels
+            .as_ref()
+            .map(|levels| levels.user_level(user_id))
+            .unwrap_or(DEFAULT_USER_POWER_LEVEL)
+    }
+
+    fn message_level(&self, event_type: &str) -> i64 {
+        self.power_levels
+            .as_ref()
+            .map(|levels| {
+                levels
+                    .events
+                    .get(event_type)
+                    .copied()
+                    .unwrap_or(levels.events_default)
+            })
+            .unwrap_or(DEFAULT_USER_POWER_LEVEL)
+    }
+
+    /// Without a power-levels event every member may send state.
+    fn state_level(&self, event_type: &str) -> i64 {
+        self.power_levels
+            .as_ref()
+            .map(|levels| {
+                levels
+                    .events
+                    .get(event_type)
+                    .copied()
+                    .unwrap_or(levels.state_default)
+            })
+            .unwrap_or(DEFAULT_USER_POWER_LEVEL)
+    }
+
+    /// Encryption cannot be turned off again, so without power levels it still needs the
+    /// default state level rather than being open to every member.
+    fn encryption_level(&self) -> i64 {
+        self.power_levels
+            .as_ref()
+            .map(|_| self.state_level(CHANNEL_ENCRYPTION_EVENT))
+            .unwrap_or(DEFAULT_STATE_POWER_LEVEL)
+    }
+
+    fn require_joined(&self, user: &str) -> Result<(), AuthError> {
+        if self.managed && self.membership(user) != Some(Membership::Join) {
+            return Err(AuthError::NotJoined {
+                user: user.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn require_level(&self, user: &str, required: i64) -> Result<(), AuthError> {
+        let actual = self.user_level(user);
+        if actual < required {
+            return Err(AuthError::InsufficientPower {
+                user: user.to_string(),
+                required,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    fn require_outranks(&self, user: &str, target: &str) -> Result<(), AuthError> {
+        if self.user_level(target) >= self.user_level(user) {
+            return Err(AuthError::Membership(format!(
+                "'{user}' does not outrank '{target}'"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// State events an event's authorization depends on: the power levels, the encryption
+/// setting, the sender's membership and, for membership events, the join rules and the
+/// target's membership.
+pub fn auth_event_ids(event: &CanonicalEvent, state: &StateMap) -> Vec<EventId> {
+    let mut slots = vec![
+        (CHANNEL_POWER_LEVELS_EVENT, ""),
+        (CHANNEL_ENCRYPTION_EVENT, ""),
+        (CHANNEL_MEMBER_EVENT, event.sender.as_str()),
+    ];
+    if event.event_type == CHANNEL_MEMBER_EVENT {
+        slots.push((CHANNEL_JOIN_RULES_EVENT, ""));
+        if let Some(state_key) = event.state_key.as_deref() {
+            slots.push((CHANNEL_MEMBER_EVENT, state_key));
+        }
+    }
+
+    let mut ids: Vec<EventId> = Vec::new();
+    for (event_type, state_key) in slots {
+        if let Some(id) = state.get(event_type, state_key) {
+            if !ids.contains(id) {
+                ids.push(id.clone());
+            }
+        }
+    }
+    ids
+}
+
+/// Check `event` against channel state.
+pub fn check_event(event: &CanonicalEvent, state: &AuthState) -> Result<(), AuthError> {
+    check_sender_origin(event)?;
+    let sender = qualify_user_id(&event.sender, &event.origin_server);
+    if state.membership(&sender) == Some(Membership::Ban) {
+        return Err(AuthError::Banned { user: sender });
+    }
+
+    let Some(state_key) = event.state_key.as_deref() else {
+        state.require_joined(&sender)?;
+        if state.encrypted && [MESSAGE_EVENT, EDIT_EVENT].contains(&event.event_type.as_str()) {
+            return Err(AuthError::Encrypted(format!(
+                "plaintext '{}' events are not allowed",
+                event.event_type
+            )));
+        }
+        return state.require_level(&sender, state.message_level(&event.event_type));
+    };
+
+    match StateContent::from_event(event)? {
+        Some(StateContent::Member(content)) => {
+            let target = qualify_user_id(state_key, &event.origin_server);
+            check_membership(&sender, &target, content.membership, state)
+        }
+        Some(StateContent::PowerLevels(content)) => {
+            state.require_joined(&sender)?;
+            check_power_levels(&sender, &event.origin_server, content, state)
+        }
+        Some(StateContent::Encryption(_)) => {
+            state.require_joined(&sender)?;
+            if state.encrypted {
+                return Err(AuthError::Encrypted(
+                    "encryption cannot be changed once enabled".into(),
+                ));
+            }
+            state.require_level(&sender, state.encryption_level())
+        }
+        _ => {
+            state.require_joined(&sender)?;
+            state.require_level(&sender, state.state_level(&event.event_type))
+        }
+    }
+}
+
+fn check_membership(
+    sender: &str,
+    target: &str,
+    membership: Membership,
+    state: &AuthState,
+) -> Result<(), AuthError> {
+    let target_membership = state.membership(target);
+    match membership {
+        Membership::Join => {
+            if target != sender {
+                return Err(AuthError::Membership(
+                    "users can only join themselves".into(),
+                ));
+            }
+            // Invited users accept and joined users may repeat their join; anyone else
+            // needs a public channel.
+            let admitted = matches!(
+                target_membership,
+                Some(Membership::Invite | Membership::Join)
+            );
+            if state.managed && !admitted && state.join_rule != JoinRule::Public {
+                return Err(AuthError::Membership(format!(
+                    "'{target}' is not invited to an invite-only channel"
+                )));
+            }
+            Ok(())
+        }
+        Membership::Leave if target == sender => Ok(()),
+        Membership::Leave => {
+            state.require_joined(sender)?;
+            state.require_level(sender, kick_level(state))?;
+            state.require_outranks(sender, target)
+        }
+        Membership::Invite => {
+            state.require_joined(sender)?;
+            state.require_level(sender, invite_level(state))?;
+            if matches!(target_membership, Some(Membership::Join | Membership::Ban)) {
+                return Err(AuthError::Membership(format!(
+                    "'{target}' is already joined or banned"
+                )));
+            }
+            Ok(())
+        }
+        Membership::Ban => {
+            state.require_joined(sender)?;
+            state.require_level(sender, ban_level(state))?;
+            state.require_outranks(sender, target)
+        }
+    }
+}
+
+/// Senders may redact their own events; redacting anyone else's needs the `redact` level.
+/// The redaction itself must already have passed `check_event`.
+pub fn check_redaction(
+    redaction: &CanonicalEvent,
+    target: &CanonicalEvent,
+    state: &AuthState,
+) -> Result<(), AuthError> {
+    if target.room_id != redaction.room_id {
+        return Err(AuthError::Redaction(format!(
+            "'{}' belongs to another channel",
+            target.event_id
+        )));
+    }
+    let sender = qualify_user_id(&redaction.sender, &redaction.origin_server);
+    if qualify_user_id(&target.sender, &target.origin_server) == sender {
+        return Ok(());
+    }
+    let required = state
+        .power_levels
+        .as_ref()
+        .map(|levels| levels.redact)
+        .unwrap_or(DEFAULT_STATE_POWER_LEVEL);
+    state.require_level(&sender, required)
+}
+
+/// Only the original sender may edit a message, and only `message` events can be edited.
+/// The edit itself must already have passed `check_event`.
+pub fn check_edit(edit: &CanonicalEvent, target: &CanonicalEvent) -> Result<(), AuthError> {
+    if target.room_id != edit.room_id {
+        return Err(AuthError::Edit(format!(
+            "'{}' belongs to another channel",
+            target.event_id
+        )));
+    }
+    if target.event_type != MESSAGE_EVENT {
+        return Err(AuthError::Edit(format!(
+            "'{}' is a '{}' event, not a message",
+            target.event_id, target.event_type
+        )));
+    }
+    let sender = qualify_user_id(&edit.sender, &edit.origin_server);
+    if qualify_user_id(&target.sender, &target.origin_server) != sender {
+        return Err(AuthError::Edit(format!(
+            "'{sender}' did not send '{}'",
+            target.event_id
+        )));
+    }
+    Ok(())
+}
+
+/// Reactions annotate events in the same channel. Annotations themselves (reactions, edits
+/// and redactions) cannot be reacted to. The reaction must already have passed `check_event`.
+pub fn check_reaction(reaction: &CanonicalEvent, target: &CanonicalEvent) -> Result<(), AuthError> {
+    if target.room_id != reaction.room_id {
+        return Err(AuthError::Reaction(format!(
+            "'{}' belongs to another channel",
+            target.event_id
+        )));
+    }
+    if [REACTION_EVENT, EDIT_EVENT, REDACTION_EVENT].contains(&target.event_type.as_str()) {
+        return Err(AuthError::Reaction(format!(
+            "'{}' is a '{}' event",
+            target.event_id, target.event_type
+        )));
+    }
+    Ok(())
+}
+
+fn kick_level(state: &AuthState) -> i64 {
+    state
+        .power_levels
+        .as_ref()
+        .map(|levels| levels.kick)
+        .unwrap_or(DEFAULT_USER_POWER_LEVEL)
+}
+
+fn ban_level(state: &AuthState) -> i64 {
+    state
+        .power_levels
+        .as_ref()
+        .map(|levels| levels.ban)
+        .unwrap_or(DEFAULT_USER_POWER_LEVEL)
+}
+
+fn invite_level(state: &AuthState) -> i64 {
+    state
+        .power_levels
+        .as_ref()
+        .map(|levels| levels.invite)
+        .unwrap_or(DEFAULT_USER_POWER_LEVEL)
+}
+
+/// The first power-levels event bootstraps the channel and must come from its creator.
+/// Later ones may only change levels and thresholds that stay within the sender's own
+/// level, and may not touch users who rank at or above the sender.
+fn check_power_levels(
+    sender: &str,
+    origin: &str,
+    proposed: PowerLevelsContent,
+    state: &AuthState,
+) -> Result<(), AuthError> {
+    let Some(current) = state.power_levels.as_ref() else {
+        if state.creator.as_deref() != Some(sender) {
+            return Err(AuthError::PowerLevels(format!(
+                "'{sender}' did not create the channel"
+            )));
+        }
+        return Ok(());
+    };
+    state.require_level(sender, state.state_level(CHANNEL_POWER_LEVELS_EVENT))?;
+    let sender_level = state.user_level(sender);
+    let exceeds = |required: i64| AuthError::InsufficientPower {
+        user: sender.to_string(),
+        required,
+        actual: sender_level,
+    };
+
+    let thresholds = [
+        (current.users_default, proposed.users_default),
+        (current.events_default, proposed.events_default),
+        (current.state_default, proposed.state_default),
+        (current.ban, proposed.ban),
+        (current.kick, proposed.kick),
+        (current.invite, proposed.invite),
+        (current.redact, proposed.redact),
+    ];
+    for (old, new) in thresholds {
+        if old != new && old.max(new) > sender_level {
+            return Err(exceeds(old.max(new)));
+        }
+    }
+
+    let event_types: BTreeSet<&String> = current
+        .events
+        .keys()
+        .chain(proposed.events.keys())
+        .collect();
+    for event_type in event_types {
+        let old = current.events.get(event_type);
+        let new = proposed.events.get(event_type);
+        if old != new {
+            let highest = old
+                .into_iter()
+                .chain(new)
+                .copied()
+                .max()
+                .unwrap_or_default();
+            if highest > sender_level {
+                return Err(exceeds(highest));
+            }
+        }
+    }
+
+    let proposed_users: HashMap<String, i64> = proposed
+        .users
+        .iter()
+        .map(|(user, level)| (qualify_user_id(user, origin), *level))
+        .collect();
+    let users: BTreeSet<&String> = current.users.keys().chain(proposed_users.keys()).collect();
+    for user in users {
+        let old = current.users.get(user);
+        let new = proposed_users.get(user);
+        if old == new {
+            continue;
+        }
+        if let Some(new) = new {
+            if *new > sender_level {
+                return Err(exceeds(*new));
+            }
+        }
+        let old_level = old.copied().unwrap_or(current.users_default);
+        if user != sender && old_level >= sender_level {
+            return Err(AuthError::Membership(format!(
+                "'{sender}' cannot change the power level of '{user}'"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Check `event` against the state its `auth_events` cite. Each cited event must be the
+/// power levels, the encryption setting, the join rules or a membership of the sender (or
+/// target) in the same channel, at most one per slot. `creator` is the channel's creator
+/// as passed to [`AuthState::with_creator`].
+pub fn check_auth_events(
+    event: &CanonicalEvent,
+    auth_events: &[CanonicalEvent],
+    creator: Option<&str>,
+) -> Result<(), AuthError> {
+    let sender = qualify_user_id(&event.sender, &event.origin_server);
+    let target = match (event.event_type.as_str(), event.state_key.as_deref()) {
+        (CHANNEL_MEMBER_EVENT, Some(state_key)) => {
+            Some(qualify_user_id(state_key, &event.origin_server))
+        }
+        _ => None,
+    };
+
+    let mut slots = HashSet::new();
+    for auth in auth_events {
+        let invalid = |reason: &str| AuthError::InvalidAuthEvent {
+            event_id: auth.event_id.clone(),
+            reason: reason.to_string(),
+        };
+        if auth.room_id != event.room_id {
+            return Err(invalid("belongs to another channel"));
+        }
+        let Some(state_key) = auth.state_key.as_deref() else {
+            return Err(invalid("not a state event"));
+        };
+        let slot = match auth.event_type.as_str() {
+            CHANNEL_POWER_LEVELS_EVENT | CHANNEL_ENCRYPTION_EVENT | CHANNEL_JOIN_RULES_EVENT
+                if state_key.is_empty() =>
+            {
+                String::new()
+            }
+            CHANNEL_MEMBER_EVENT => {
+                let member = qualify_user_id(state_key, &auth.origin_server);
+                if member != sender && Some(&member) != target.as_ref() {
+                    return Err(invalid("membership of an unrelated user"));
+                }
+                member
+            }
+            _ => return Err(invalid("does not authorize events")),
+        };
+        if !slots.insert((auth.event_type.as_str(), slot)) {
+            return Err(invalid("duplicates another auth event"));
+        }
+    }
+
+    let managed = auth_events
+        .iter()
+        .any(|auth| auth.event_type == CHANNEL_MEMBER_EVENT);
+    check_event(
+        event,
+        &AuthState::from_events(auth_events, managed).with_creator(creator.map(str::to_string)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state::{ChannelNameContent, MembershipContent},
+        EventBuilder,
+    };
+    use std::collections::BTreeMap;
+
+    const ORIGIN: &str = "example.org";
+
+    fn member(user: &str, membership: Membership) -> CanonicalEvent {
+        StateContent::Member(MembershipContent { membership }).to_event(
+            ORIGIN,
+            "room",
+            user,
+            user,
+            Vec::new(),
+        )
+    }
+
+    fn power_levels(users: &[(&str, i64)]) -> CanonicalEvent {
+        StateContent::PowerLevels(PowerLevelsContent {
+            users: users
+                .iter()
+                .map(|(user, level)| (user.to_string(), *level))
+                .collect::<BTreeMap<_, _>>(),
+            ..PowerLevelsContent::default()
+        })
+        .to_event(ORIGIN, "room", "@admin", "", Vec::new())
+    }
+
+    fn message(sender: &str) -> CanonicalEvent {
+        EventBuilder::new(ORIGIN, "room", "message")
+            .sender(sender)
+            .build()
+    }
+
+    #[test]
+    fn qualified_senders_must_match_origin() {
+        let event = EventBuilder::new(ORIGIN, "room", "message")
+            .sender("@mallory:evil.example")
+            .build();
+        assert!(matches!(
+            check_event(&event, &AuthState::default()),
+            Err(AuthError::SenderOriginMismatch { .. })
+        ));
+        assert!(check_event(&message("@alice:example.org"), &AuthState::default()).is_ok());
+    }
+
+    #[test]
+    fn managed_channels_require_join_and_reject_bans() {
+        let alice = member("@alice", Membership::Join);
+        let mallory = member("@mallory", Membership::Ban);
+        let state = AuthState::from_events([&alice, &mallory], true);
+
+        assert!(check_event(&message("@alice"), &state).is_ok());
+        assert!(matches!(
+            check_event(&message("@bob"), &state),
+            Err(AuthError::NotJoined { .. })
+        ));
+        assert!(matches!(
+            check_event(&message("@mallory"), &state),
+            Err(AuthError::Banned { .. })
+        ));
+        // Banned users cannot rejoin either.
+        assert!(matches!(
+            check_event(&member("@mallory", Membership::Join), &state),
+            Err(AuthError::Banned { .. })
+        ));
+    }
+
+    #[test]
+    fn joining_a_managed_channel_needs_an_invite_or_public_join_rule() {
+        use crate::state::JoinRulesContent;
+
+        let alice = member("@alice", Membership::Join);
+        let invited = member("@carol", Membership::Invite);
+        let invite_only = AuthState::from_events([&alice, &invited], true);
+
+        assert!(check_event(&member("@carol", Membership::Join), &invite_only).is_ok());
+        assert!(check_event(&member("@alice", Membership::Join), &invite_only).is_ok());
+        assert!(matches!(
+            check_event(&member("@bob", Membership::Join), &invite_only),
+            Err(AuthError::Membership(_))
+        ));
+
+        let public = StateContent::JoinRules(JoinRulesContent {
+            join_rule: JoinRule::Public,
+        })
+        .to_event(ORIGIN, "room", "@alice", "", Vec::new());
+        let state = AuthState::from_events([&alice, &public], true);
+        assert_eq!(state.join_rule(), JoinRule::Public);
+        assert!(check_event(&member("@bob", Membership::Join), &state).is_ok());
+
+        // Joins cite the join rules, and federated joins may do so.
+        let mut current = StateMap::new();
+        current.apply(&public);
+        let join = member("@bob", Membership::Join);
+        assert_eq!(
+            auth_event_ids(&join, &current),
+            vec![public.event_id.clone()]
+        );
+        assert!(check_auth_events(&join, &[public], None).is_ok());
+    }
+
+    #[test]
+    fn state_changes_respect_power_levels() {
+        let levels = power_levels(&[("@admin", 100), ("@mod", 50)]);
+        let admin = member("@admin", Membership::Join);
+        let moderator = member("@mod", Membership::Join);
+        let user = member("@user", Membership::Join);
+        let state = AuthState::from_events([&levels, &admin, &moderator, &user], true);
+
+        let rename = |sender: &str| {
+            StateContent::Name(ChannelNameContent {
+                name: "renamed".into(),
+            })
+            .to_event(ORIGIN, "room", sender, "", Vec::new())
+        };
+        assert!(check_event(&rename("@mod"), &state).is_ok());
+        assert!(matches!(
+            check_event(&rename("@user"), &state),
+            Err(AuthError::InsufficientPower { required: 50, .. })
+        ));
+
+        let ban = |sender: &str, target: &str| {
+            StateContent::Member(MembershipContent {
+                membership: Membership::Ban,
+            })
+            .to_event(ORIGIN, "room", sender, target, Vec::new())
+        };
+        assert!(check_event(&ban("@mod", "@user"), &state).is_ok());
+        assert!(check_event(&ban("@mod", "@admin"), &state).is_err());
+
+        let mut promote_self = power_levels(&[("@admin", 100), ("@mod", 100)]);
+        promote_self.sender = "@mod".into();
+        assert!(matches!(
+            check_event(&promote_self, &state),
+            Err(AuthError::InsufficientPower { required: 100, .. })
+        ));
+    }
+
+    #[test]
+    fn only_the_creator_bootstraps_power_levels() {
+        let admin = member("@admin", Membership::Join);
+        let levels = power_levels(&[("@admin", 100)]);
+        let state = AuthState::from_events([&admin], true);
+
+        assert!(matches!(
+            check_event(&levels, &state),
+            Err(AuthError::PowerLevels(_))
+        ));
+        let mallory = state
+            .clone()
+            .with_creator(Some("@mallory:example.org".into()));
+        assert!(matches!(
+            check_event(&levels, &mallory),
+            Err(AuthError::PowerLevels(_))
+        ));
+        let created = state.with_creator(Some("@admin:example.org".into()));
+        assert!(check_event(&levels, &created).is_ok());
+    }
+
+    #[test]
+    fn redacting_others_needs_the_redact_level() {
+        use crate::redaction::RedactionContent;
+
+        let levels = power_levels(&[("@mod", 50)]);
+        let state = AuthState::from_events([&levels], false);
+        let target = message("@alice");
+        let redaction = |sender: &str| {
+            RedactionContent {
+                redacts: target.event_id.clone(),
+                reason: None,
+            }
+            .to_event(ORIGIN, "room", sender, Vec::new())
+        };
+
+        assert!(check_redaction(&redaction("@alice"), &target, &state).is_ok());
+        assert!(check_redaction(&redaction("@mod"), &target, &state).is_ok());
+        assert!(matches!(
+            check_redaction(&redaction("@bob"), &target, &state),
+            Err(AuthError::InsufficientPower { required: 50, .. })
+        ));
+    }
+
+    #[test]
+    fn only_the_sender_may_edit_a_message() {
+        use crate::messaging::EditContent;
+
+        let target = message("@alice");
+        let edit = |sender: &str, target: &CanonicalEvent| {
+            EditContent {
+                edits: target.event_id.clone(),
+                content: "fixed".into(),
+            }
+            .to_event(ORIGIN, "room", sender, Vec::new())
+        };
+
+        assert!(check_edit(&edit("@alice", &target), &target).is_ok());
+        assert!(matches!(
+            check_edit(&edit("@bob", &target), &target),
+            Err(AuthError::Edit(_))
+        ));
+        let state_event = member("@alice", Membership::Join);
+        assert!(matches!(
+            check_edit(&edit("@alice", &state_event), &state_event),
+            Err(AuthError::Edit(_))
+        ));
+    }
+
+    #[test]
+    fn reactions_cannot_target_annotations() {
+        use crate::messaging::ReactionContent;
+
+        let target = message("@alice");
+        let react = |target: &CanonicalEvent| {
+            ReactionContent {
+                reacts_to: target.event_id.clone(),
+                key: "👍".into(),
+            }
+            .to_event(ORIGIN, "room", "@bob", Vec::new())
+        };
+
+        let reaction = react(&target);
+        assert!(check_reaction(&reaction, &target).is_ok());
+        assert!(matches!(
+            check_reaction(&react(&reaction), &reaction),
+            Err(AuthError::Reaction(_))
+        ));
+    }
+
+    #[test]
+    fn encrypted_channels_reject_plaintext_for_good() {
+        use crate::{
+            messaging::{EncryptedContent, EDIT_EVENT},
+            state::EncryptionContent,
+        };
+
+        let alice = member("@alice", Membership::Join);
+        let encryption = |algorithm: &str| {
+            StateContent::Encryption(EncryptionContent {
+                algorithm: algorithm.into(),
+            })
+            .to_event(ORIGIN, "room", "@alice", "", Vec::new())
+        };
+        let mls = encryption("mls");
+        let levels = power_levels(&[("@alice", 100)]);
+        let plain = AuthState::from_events([&alice, &levels], true);
+        assert!(check_event(&mls, &plain).is_ok());
+
+        let state = AuthState::from_events([&alice, &levels, &mls], true);
+        assert!(state.encrypted());
+        for event_type in [MESSAGE_EVENT, EDIT_EVENT] {
+            let event = EventBuilder::new(ORIGIN, "room", event_type)
+                .sender("@alice")
+                .build();
+            assert!(matches!(
+                check_event(&event, &state),
+                Err(AuthError::Encrypted(_))
+            ));
+        }
+        let ciphertext = EncryptedContent {
+            ciphertext: "AAE".into(),
+            epoch: 3,
+            device_id: "laptop".into(),
+        }
+        .to_event(ORIGIN, "room", "@alice", Vec::new());
+        assert!(check_event(&ciphertext, &state).is_ok());
+        assert!(matches!(
+            check_event(&encryption("none"), &state),
+            Err(AuthError::Encrypted(_))
+        ));
+
+        // Federated events citing the encryption event are held to the same rule.
+        assert!(check_auth_events(&message("@alice"), &[alice, mls], None).is_err());
+    }
+
+    #[test]
+    fn plain_members_cannot_enable_encryption() {
+        use crate::state::EncryptionContent;
+
+        let alice = member("@alice", Membership::Join);
+        let mls = StateContent::Encryption(EncryptionContent {
+            algorithm: "mls".into(),
+        })
+        .to_event(ORIGIN, "room", "@alice", "", Vec::new());
+
+        // Without power levels the default state level still applies.
+        let unset = AuthState::from_events([&alice], true);
+        assert!(matches!(
+            check_event(&mls, &unset),
+            Err(AuthError::InsufficientPower { required: 50, .. })
+        ));
+        let levels = power_levels(&[("@admin", 100)]);
+        let state = AuthState::from_events([&alice, &levels], true);
+        assert!(matches!(
+            check_event(&mls, &state),
+            Err(AuthError::InsufficientPower { required: 50, .. })
+        ));
+    }
+
+    #[test]
+    fn auth_events_must_be_relevant_state() {
+        let alice = member("@alice", Membership::Join);
+        let bob = member("@bob", Membership::Join);
+        let event = message("@alice");
+
+        assert!(check_auth_events(&event, std::slice::from_ref(&alice), None).is_ok());
+        assert!(matches!(
+            check_auth_events(&event, &[bob], None),
+            Err(AuthError::InvalidAuthEvent { .. })
+        ));
+        assert!(matches!(
+            check_auth_events(&event, &[message("@alice")], None),
+            Err(AuthError::InvalidAuthEvent { .. })
+        ));
+    }
+
+    #[test]
+    fn auth_event_ids_pick_power_levels_and_memberships() {
+        let levels = power_levels(&[]);
+        let alice = member("@alice", Membership::Join);
+        let mut state = StateMap::new();
+        state.apply(&levels);
+        state.apply(&alice);
+        state.apply(&member("@bob", Membership::Join));
+
+        assert_eq!(
+            auth_event_ids(&message("@alice"), &state),
+            vec![levels.event_id.clone(), alice.event_id.clone()]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Canonical JSON: the byte encoding every OpenGuild hash and signature is computed over.
+//!
+//! The encoding is independent of struct field order and map implementation so other
+//! implementations can reproduce event ids. See `docs/PROTOCOL.md#canonical-json` for the
+//! rules and `testdata/canonical_json.json` for the published test vectors.
+
+use std::fmt::Write;
+
+use serde::Serialize;
+use serde_json::{Number, Value};
+
+/// Encode `value` as canonical JSON.
+pub fn encode(value: &Value) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value);
+    out
+}
+
+/// Serialize `value` through `serde_json::Value` and encode the result canonically.
+pub fn to_canonical_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, serde_json::Error> {
+    let value = serde_json::to_value(value)?;
+    Ok(encode(&value).into_bytes())
+}
+
+fn write_value(out: &mut String, value: &Value) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(true) => out.push_str("true"),
+        Value::Bool(false) => out.push_str("false"),
+        Value::Number(number) => write_number(out, number),
+        Value::String(string) => write_string(out, string),
+        Value::Array(items) => {
+            out.push('[');
+            for (index, item) in items.iter().enumerate() {
+                if index > 0 {
+                    out.push(',');
+                }
+                write_value(out, item);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            // Keys sort by their UTF-8 bytes, which is the same as Unicode code point order.
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|(left, _), (right, _)| left.as_bytes().cmp(right.as_bytes()));
+            out.push('{');
+            for (index, (key, item)) in entries.into_iter().enumerate() {
+                if index > 0 {
+                    out.push(',');
+                }
+                write_string(out, key);
+                out.push(':');
+                write_value(out, item);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if ch < '\u{20}' => {
+                let _ = write!(out, "\\u{:04x}", ch as u32);
+            }
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+}
+
+/// Integers are written exactly. Other numbers follow ECMAScript `Number.prototype.toString`
+/// (the RFC 8785 rule): the shortest digits that round-trip, in plain notation for decimal
+/// exponents in `[-7, 21)` and `d.ddde±x` otherwise.
+fn write_number(out: &mut String, number: &Number) {
+    if let Some(value) = number.as_i64() {
+        let _ = write!(out, "{value}");
+    } else if let Some(value) = number.as_u64() {
+        let _ = write!(out, "{value}");
+    } else if let Some(value) = number.as_f64() {
+        write_float(out, value);
+    }
+}
+
+fn write_float(out: &mut String, value: f64) {
+    if value == 0.0 {
+        out.push('0');
+        return;
+    }
+    if value < 0.0 {
+        out.push('-');
+    }
+
+    // `{:e}` renders the shortest round-tripping digits, e.g. `1.2345e-7`.
+    let formatted = format!("{:e}", value.abs());
+    let (mantissa, exponent) = formatted
+        .split_once('e')
+        .expect("exponent notation always contains 'e'");
+    let exponent: i32 = exponent.parse().expect("exponent is an integer");
+    let digits: String = mantissa.chars().filter(|ch| *ch != '.').collect();
+    let len = digits.len() as i32;
+    // Position of the decimal point relative to the start of `digits`.
+    let point = exponent + 1;
+
+    if len <= point && point <= 21 {
+        out.push_str(&digits);
+        out.extend(std::iter::repeat_n('0', (point - len) as usize));
+    } else if 0 < point && point <= 21 {
+        let (whole, fraction) = digits.split_at(point as usize);
+        out.push_str(whole);
+        out.push('.');
+        out.push_str(fraction);
+    } else if -6 < point && point <= 0 {
+        out.push_str("0.");
+        out.extend(std::iter::repeat_n('0', (-point) as usize));
+        out.push_str(&digits);
+    } else {
+        let (first, rest) = digits.split_at(1);
+        out.push_str(first);
+        if !rest.is_empty() {
+            out.push('.');
+            out.push_str(rest);
+        }
+        let sign = if point > 0 { '+' } else { '-' };
+        let _ = write!(out, "e{sign}{}", (point - 1).abs());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CanonicalEvent;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Vectors {
+        values: Vec<ValueVector>,
+        events: Vec<EventVector>,
+    }
+
+    #[derive(Deserialize)]
+    struct ValueVector {
+        name: String,
+        input: String,
+        canonical: String,
+    }
+
+    #[derive(Deserialize)]
+    struct EventVector {
+        name: String,
+        event: CanonicalEvent,
+        /// Input of the content hash stored in `hashes`.
+        content_canonical: String,
+        /// Input of the reference hash: the redacted event.
+        canonical: String,
+    }
+
+    fn vectors() -> Vectors {
+        serde_json::from_str(include_str!("../testdata/canonical_json.json"))
+            .expect("test vectors parse")
+    }
+
+    #[test]
+    fn value_vectors_encode_canonically() {
+        for vector in vectors().values {
+            let value: Value = serde_json::from_str(&vector.input).expect("vector input parses");
+            assert_eq!(encode(&value), vector.canonical, "vector '{}'", vector.name);
+
+            // Canonical output is itself valid JSON that encodes to the same bytes.
+            let reparsed: Value =
+                serde_json::from_str(&vector.canonical).expect("canonical output parses");
+            assert_eq!(
+                encode(&reparsed),
+                vector.canonical,
+                "vector '{}'",
+                vector.name
+            );
+        }
+    }
+
+    #[test]
+    fn event_vectors_match_hash_input_and_id() {
+        for vector in vectors().events {
+            let event = vector.event;
+            let mut unhashed = serde_json::to_value(&event).unwrap();
+            for key in ["event_id", "hashes", "signatures"] {
+                unhashed.as_object_mut().unwrap().remove(key);
+            }
+            assert_eq!(
+                encode(&unhashed),
+                vector.content_canonical,
+                "vector '{}'",
+                vector.name
+            );
+            assert!(event.has_valid_content_hash(), "vector '{}'", vector.name);
+            assert_eq!(
+                String::from_utf8(event.canonical_bytes()).unwrap(),
+                vector.canonical,
+                "vector '{}'",
+                vector.name
+            );
+            assert_eq!(
+                CanonicalEvent::event_id_from_hash(&event.canonical_hash()),
+                event.event_id,
+                "vector '{}'",
+                vector.name
+            );
+        }
+    }
+
+    #[test]
+    fn floats_use_ecmascript_formatting() {
+        let cases = [
+            (1.0, "1"),
+            (-0.0, "0"),
+            (0.1, "0.1"),
+            (-1.5, "-1.5"),
+            (1e21, "1e+21"),
+            (1e20, "100000000000000000000"),
+            (1.5e-7, "1.5e-7"),
+            (0.000001, "0.000001"),
+            (123456.789, "123456.789"),
+            (f64::MAX, "1.7976931348623157e+308"),
+            (5e-324, "5e-324"),
+        ];
+        for (value, expected) in cases {
+            let mut out = String::new();
+            write_float(&mut out, value);
+            assert_eq!(out, expected, "{value:?}");
+        }
+    }
+}
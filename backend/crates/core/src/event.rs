@@ -6,10 +6,17 @@ use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use thiserror::Error;
+
+use crate::{canonical_json, redaction};
+
 /// Public alias representing canonical event identifiers.
 pub type EventId = String;
 /// Current schema version for canonical events.
 pub const EVENT_SCHEMA_VERSION: u8 = 1;
+/// Upper bound on how many forward extremities a new event references as `prev_events`.
+pub const MAX_PREV_EVENTS: usize = 20;
+/// Key in `CanonicalEvent::hashes` holding the content hash.
+pub const CONTENT_HASH_ALGORITHM: &str = "blake3";
 
 fn default_event_version() -> u8 {
     EVENT_SCHEMA_VERSION
@@ -34,10 +41,17 @@ pub struct CanonicalEvent {
     pub sender: String,
     pub origin_ts: i64,
     pub content: Value,
+    /// Present on state events; identifies which slot of the channel state they replace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_key: Option<String>,
     #[serde(default)]
     pub prev_events: Vec<EventId>,
     #[serde(default)]
     pub auth_events: Vec<EventId>,
+    /// Hashes of the full event (see `content_hash`), so redacted copies still commit to
+    /// the original content.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hashes: BTreeMap<String, String>,
     #[serde(default)]
     pub signatures: BTreeMap<String, BTreeMap<String, String>>,
 }
@@ -47,6 +61,10 @@ impl CanonicalEvent {
         EVENT_SCHEMA_VERSION
     }
 
+    pub fn is_state(&self) -> bool {
+        self.state_key.is_some()
+    }
+
     pub fn sign_with(&mut self, server_name: &str, key_id: &str, signing_key: &SigningKey) {
         let hash = self.canonical_hash();
         let signature = signing_key.sign(&hash);
@@ -82,6 +100,8 @@ impl CanonicalEvent {
             .map_err(|_| EventError::SignatureVerification)
     }
 
+    /// Reference hash: covers the redacted form of the event, so event ids and signatures
+    /// stay valid after redaction. The content is covered through `hashes`.
     pub fn canonical_hash(&self) -> Vec<u8> {
         let mut hasher = Hasher::new();
         let canonical = self.canonical_bytes();
@@ -89,11 +109,37 @@ impl CanonicalEvent {
         hasher.finalize().as_bytes().to_vec()
     }
 
-    fn canonical_bytes(&self) -> Vec<u8> {
-        let mut cloned = self.clone();
-        cloned.event_id.clear();
-        cloned.signatures.clear();
-        serde_json::to_vec(&cloned).expect("serialization must succeed")
+    /// Canonical JSON of the redacted event without `event_id` and `signatures`; the bytes
+    /// that are hashed into the event id and signed.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        encode_without(&self.redacted(), &["event_id", "signatures"])
+    }
+
+    /// Hash of the full event without `event_id`, `hashes` and `signatures`.
+    pub fn content_hash(&self) -> Vec<u8> {
+        let canonical = encode_without(self, &["event_id", "hashes", "signatures"]);
+        blake3::hash(&canonical).as_bytes().to_vec()
+    }
+
+    /// Whether `hashes` matches the event's current content. Fails for redacted copies.
+    pub fn has_valid_content_hash(&self) -> bool {
+        let expected = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.content_hash());
+        self.hashes.get(CONTENT_HASH_ALGORITHM) == Some(&expected)
+    }
+
+    /// The event with `content` stripped to the keys its type preserves.
+    pub fn redacted(&self) -> CanonicalEvent {
+        redaction::redact(self)
+    }
+
+    /// Recompute the content hash and `event_id` after changing any hashed field.
+    pub fn refresh_event_id(&mut self) {
+        let content_hash =
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.content_hash());
+        self.hashes
+            .insert(CONTENT_HASH_ALGORITHM.to_string(), content_hash);
+        let hash = self.canonical_hash();
+        self.event_id = Self::event_id_from_hash(&hash);
     }
 
     pub fn event_id_from_hash(hash: &[u8]) -> EventId {
@@ -102,6 +148,16 @@ impl CanonicalEvent {
     }
 }
 
+fn encode_without(event: &CanonicalEvent, keys: &[&str]) -> Vec<u8> {
+    let mut value = serde_json::to_value(event).expect("serialization must succeed");
+    if let Value::Object(map) = &mut value {
+        for key in keys {
+            map.remove(*key);
+        }
+    }
+    canonical_json::encode(&value).into_bytes()
+}
+
 pub struct EventBuilder {
     event: CanonicalEvent,
 }
@@ -121,8 +177,10 @@ impl EventBuilder {
             sender: String::new(),
             origin_ts: chrono::Utc::now().timestamp_millis(),
             content: Value::Null,
+            state_key: None,
             prev_events: Vec::new(),
             auth_events: Vec::new(),
+            hashes: BTreeMap::new(),
             signatures: BTreeMap::new(),
         };
 
@@ -139,6 +197,11 @@ impl EventBuilder {
         self
     }
 
+    pub fn state_key(mut self, state_key: impl Into<String>) -> Self {
+        self.event.state_key = Some(state_key.into());
+        self
+    }
+
     pub fn prev_events(mut self, prev_events: Vec<EventId>) -> Self {
         self.event.prev_events = prev_events;
         self
@@ -155,8 +218,7 @@ impl EventBuilder {
     }
 
     pub fn build(mut self) -> CanonicalEvent {
-        let hash = self.event.canonical_hash();
-        self.event.event_id = CanonicalEvent::event_id_from_hash(&hash);
+        self.event.refresh_event_id();
         self.event
     }
 }
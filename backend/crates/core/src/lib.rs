@@ -1,6 +1,10 @@
 //! Core domain types, canonical JSON handling, and signatures.
 
+pub mod auth;
+pub mod canonical_json;
 pub mod event;
 pub mod messaging;
+pub mod redaction;
+pub mod state;
 
 pub use event::{CanonicalEvent, EventBuilder, EventId};
@@ -1,7 +1,17 @@
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
 
-use crate::{CanonicalEvent, EventBuilder};
+use crate::{CanonicalEvent, EventBuilder, EventId};
+
+/// Event type of a plain chat message.
+pub const MESSAGE_EVENT: &str = "message";
+/// Event type that replaces the body of an earlier `message` from the same sender.
+pub const EDIT_EVENT: &str = "edit";
+/// Event type that annotates an earlier event with a reaction key, usually an emoji.
+pub const REACTION_EVENT: &str = "reaction";
+/// Event type of an end-to-end encrypted message. The server stores and relays it without
+/// reading the ciphertext.
+pub const ENCRYPTED_EVENT: &str = "encrypted";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageAuthorSnapshot {
@@ -11,14 +21,121 @@ pub struct MessageAuthorSnapshot {
     pub display_name: Option<String>,
 }
 
+/// Markup language of a message's formatted body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    Markdown,
+    Html,
+}
+
+/// Rich rendering of a message. Clients that cannot render `format` fall back to the
+/// plain-text `content`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormattedBody {
+    pub format: MessageFormat,
+    pub body: String,
+}
+
+/// Who and what a message mentions explicitly. Clients must not infer mentions from the
+/// body text.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mentions {
+    /// User ids.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+    /// Channel role names, such as `moderator`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// Channel ids.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<String>,
+}
+
+impl Mentions {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.roles.is_empty() && self.channels.is_empty()
+    }
+}
+
+/// Reference to an uploaded media object.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub media_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Size in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Image dimensions and placeholder, as returned when the media was uploaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessagePayload {
+    /// Plain-text body; the fallback when `formatted` is set.
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formatted: Option<FormattedBody>,
+    #[serde(default, skip_serializing_if = "Mentions::is_empty")]
+    pub mentions: Mentions,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub author: Option<MessageAuthorSnapshot>,
+    /// Message this one replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<EventId>,
+    /// Root message of the thread this message belongs to. Roots are never in a thread
+    /// themselves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_root: Option<EventId>,
 }
 
 impl MessagePayload {
+    /// Plain-text message without author snapshot, relations or rich content.
+    pub fn new(content: impl Into<String>) -> Self {
+        Self {
+            content: content.into(),
+            formatted: None,
+            mentions: Mentions::default(),
+            attachments: Vec::new(),
+            author: None,
+            reply_to: None,
+            thread_root: None,
+        }
+    }
+
+    /// Parse the content of a `message` event; `None` for other types or malformed content.
+    /// Redacted messages parse with empty `content`.
+    pub fn from_event(event: &CanonicalEvent) -> Option<Self> {
+        if event.event_type != MESSAGE_EVENT {
+            return None;
+        }
+        let content = event.content.as_object()?;
+        let relation = |key: &str| content.get(key).and_then(Value::as_str).map(str::to_string);
+        Some(Self {
+            content: content
+                .get("content")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            formatted: content_field(content, "formatted"),
+            mentions: content_field(content, "mentions").unwrap_or_default(),
+            attachments: content_field(content, "attachments").unwrap_or_default(),
+            author: content_field(content, "author"),
+            reply_to: relation("reply_to"),
+            thread_root: relation("thread_root"),
+        })
+    }
+
     pub fn to_event(
         &self,
         origin_server: &str,
@@ -26,15 +143,39 @@ impl MessagePayload {
         sender: &str,
         prev_events: Vec<String>,
     ) -> CanonicalEvent {
-        let mut content = serde_json::Map::new();
+        let mut content = Map::new();
         content.insert("content".into(), Value::String(self.content.clone()));
+        if let Some(formatted) = &self.formatted {
+            content.insert(
+                "formatted".into(),
+                serde_json::to_value(formatted).expect("formatted body serializes"),
+            );
+        }
+        if !self.mentions.is_empty() {
+            content.insert(
+                "mentions".into(),
+                serde_json::to_value(&self.mentions).expect("mentions serialize"),
+            );
+        }
+        if !self.attachments.is_empty() {
+            content.insert(
+                "attachments".into(),
+                serde_json::to_value(&self.attachments).expect("attachments serialize"),
+            );
+        }
         if let Some(author) = &self.author {
             if let Ok(author_value) = serde_json::to_value(author) {
                 content.insert("author".into(), author_value);
             }
         }
+        if let Some(reply_to) = &self.reply_to {
+            content.insert("reply_to".into(), Value::String(reply_to.clone()));
+        }
+        if let Some(thread_root) = &self.thread_root {
+            content.insert("thread_root".into(), Value::String(thread_root.clone()));
+        }
 
-        EventBuilder::new(origin_server.to_owned(), room_id.to_owned(), "message")
+        EventBuilder::new(origin_server.to_owned(), room_id.to_owned(), MESSAGE_EVENT)
             .sender(sender.to_owned())
             .content(Value::Object(content))
             .prev_events(prev_events)
@@ -42,6 +183,117 @@ impl MessagePayload {
     }
 }
 
+/// Parse `content[key]`; `None` when it is missing or malformed, so one bad field does not
+/// hide the rest of a message.
+fn content_field<T: DeserializeOwned>(content: &Map<String, Value>, key: &str) -> Option<T> {
+    content
+        .get(key)
+        .and_then(|value| serde_json::from_value(value.clone()).ok())
+}
+
+/// Content of an `edit` event. The original message stays in the DAG; the newest edit
+/// supplies the body clients display.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EditContent {
+    pub edits: EventId,
+    /// Empty once the edit is redacted.
+    #[serde(default)]
+    pub content: String,
+}
+
+impl EditContent {
+    /// Parse the content of an `edit` event; `None` for other types or malformed content.
+    pub fn from_event(event: &CanonicalEvent) -> Option<Self> {
+        if event.event_type != EDIT_EVENT {
+            return None;
+        }
+        serde_json::from_value(event.content.clone()).ok()
+    }
+
+    pub fn to_event(
+        &self,
+        origin_server: &str,
+        room_id: &str,
+        sender: &str,
+        prev_events: Vec<EventId>,
+    ) -> CanonicalEvent {
+        EventBuilder::new(origin_server, room_id, EDIT_EVENT)
+            .sender(sender)
+            .content(serde_json::to_value(self).expect("edit content serializes"))
+            .prev_events(prev_events)
+            .build()
+    }
+}
+
+/// Content of a `reaction` event. Reactions are removed by redacting them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReactionContent {
+    pub reacts_to: EventId,
+    /// Empty once the reaction is redacted.
+    #[serde(default)]
+    pub key: String,
+}
+
+impl ReactionContent {
+    /// Parse the content of a `reaction` event; `None` for other types or malformed content.
+    pub fn from_event(event: &CanonicalEvent) -> Option<Self> {
+        if event.event_type != REACTION_EVENT {
+            return None;
+        }
+        serde_json::from_value(event.content.clone()).ok()
+    }
+
+    pub fn to_event(
+        &self,
+        origin_server: &str,
+        room_id: &str,
+        sender: &str,
+        prev_events: Vec<EventId>,
+    ) -> CanonicalEvent {
+        EventBuilder::new(origin_server, room_id, REACTION_EVENT)
+            .sender(sender)
+            .content(serde_json::to_value(self).expect("reaction content serializes"))
+            .prev_events(prev_events)
+            .build()
+    }
+}
+
+/// Content of an `encrypted` event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedContent {
+    /// URL-safe base64 of an MLS `PrivateMessage`, framed as an `MLSMessage`.
+    pub ciphertext: String,
+    /// MLS epoch the message was encrypted in.
+    pub epoch: u64,
+    /// Device of the sender that encrypted the message.
+    pub device_id: String,
+}
+
+impl EncryptedContent {
+    /// Parse the content of an `encrypted` event; `None` for other types, malformed or
+    /// redacted content.
+    pub fn from_event(event: &CanonicalEvent) -> Option<Self> {
+        if event.event_type != ENCRYPTED_EVENT {
+            return None;
+        }
+        serde_json::from_value(event.content.clone()).ok()
+    }
+
+    pub fn to_event(
+        &self,
+        origin_server: &str,
+        room_id: &str,
+        sender: &str,
+        prev_events: Vec<EventId>,
+    ) -> CanonicalEvent {
+        EventBuilder::new(origin_server, room_id, ENCRYPTED_EVENT)
+            .sender(sender)
+            .content(serde_json::to_value(self).expect("encrypted content serializes"))
+            .prev_events(prev_events)
+            .build()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -49,13 +301,48 @@ mod tests {
     use std::collections::HashSet;
     use uuid::Uuid;
 
+    #[test]
+    fn rich_content_round_trips_through_events() {
+        let payload = MessagePayload {
+            formatted: Some(FormattedBody {
+                format: MessageFormat::Markdown,
+                body: "ping **@alice**, see _report.pdf_".into(),
+            }),
+            mentions: Mentions {
+                users: vec!["alice".into()],
+                roles: vec!["moderator".into()],
+                channels: Vec::new(),
+            },
+            attachments: vec![Attachment {
+                media_id: "media-1".into(),
+                filename: Some("report.pdf".into()),
+                content_type: Some("application/pdf".into()),
+                size: Some(1024),
+                ..Attachment::default()
+            }],
+            ..MessagePayload::new("ping @alice, see report.pdf")
+        };
+        let event = payload.to_event("example.org", "room", "@bob:example.org", Vec::new());
+        assert_eq!(event.content["formatted"]["format"], "markdown");
+        assert_eq!(
+            event.content["mentions"],
+            serde_json::json!({ "users": ["alice"], "roles": ["moderator"] })
+        );
+
+        let parsed = MessagePayload::from_event(&event).expect("message parses");
+        assert_eq!(parsed.formatted, payload.formatted);
+        assert_eq!(parsed.mentions, payload.mentions);
+        assert_eq!(parsed.attachments, payload.attachments);
+
+        // Plain messages carry none of the rich keys.
+        let plain = MessagePayload::new("hi").to_event("example.org", "room", "@bob", Vec::new());
+        assert_eq!(plain.content, serde_json::json!({ "content": "hi" }));
+    }
+
     proptest! {
         #[test]
         fn event_builder_produces_unique_event_ids(count in 1usize..64) {
-            let payload = MessagePayload {
-                content: "hello world".into(),
-                author: None,
-            };
+            let payload = MessagePayload::new("hello world");
             let mut seen = HashSet::new();
 
             for _ in 0..count {
@@ -0,0 +1,166 @@
+//! Redaction: stripping an event's content down to what its type needs to stay meaningful.
+//!
+//! Event ids and signatures cover the redacted form of an event (see
+//! `CanonicalEvent::canonical_hash`), so a redacted copy verifies exactly like the original.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    messaging::{EDIT_EVENT, MESSAGE_EVENT, REACTION_EVENT},
+    state::{
+        CHANNEL_ENCRYPTION_EVENT, CHANNEL_JOIN_RULES_EVENT, CHANNEL_MEMBER_EVENT,
+        CHANNEL_POWER_LEVELS_EVENT,
+    },
+    CanonicalEvent, EventBuilder, EventId,
+};
+
+/// Event type that removes the content of an earlier event in the same channel.
+pub const REDACTION_EVENT: &str = "redaction";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedactionContent {
+    pub redacts: EventId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl RedactionContent {
+    /// Parse the content of a `redaction` event; `None` for other types or malformed content.
+    pub fn from_event(event: &CanonicalEvent) -> Option<Self> {
+        if event.event_type != REDACTION_EVENT {
+            return None;
+        }
+        serde_json::from_value(event.content.clone()).ok()
+    }
+
+    pub fn to_event(
+        &self,
+        origin_server: &str,
+        room_id: &str,
+        sender: &str,
+        prev_events: Vec<EventId>,
+    ) -> CanonicalEvent {
+        EventBuilder::new(origin_server, room_id, REDACTION_EVENT)
+            .sender(sender)
+            .content(serde_json::to_value(self).expect("redaction content serializes"))
+            .prev_events(prev_events)
+            .build()
+    }
+}
+
+/// Content keys that survive redaction for `event_type`. Everything else is removed.
+pub fn preserved_content_keys(event_type: &str) -> &'static [&'static str] {
+    match event_type {
+        CHANNEL_MEMBER_EVENT => &["membership"],
+        CHANNEL_POWER_LEVELS_EVENT => &[
+            "users",
+            "users_default",
+            "events",
+            "events_default",
+            "state_default",
+            "ban",
+            "kick",
+            "invite",
+            "redact",
+        ],
+        // Redacting the encryption event must not turn encryption off.
+        CHANNEL_ENCRYPTION_EVENT => &["algorithm"],
+        CHANNEL_JOIN_RULES_EVENT => &["join_rule"],
+        REDACTION_EVENT => &["redacts"],
+        EDIT_EVENT => &["edits"],
+        MESSAGE_EVENT => &["reply_to", "thread_root"],
+        REACTION_EVENT => &["reacts_to"],
+        _ => &[],
+    }
+}
+
+/// Copy of `event` whose `content` only holds the preserved keys. Every other field,
+/// including `hashes` and `signatures`, is kept.
+pub fn redact(event: &CanonicalEvent) -> CanonicalEvent {
+    let preserved = preserved_content_keys(&event.event_type);
+    let content = match &event.content {
+        Value::Object(map) => map
+            .iter()
+            .filter(|(key, _)| preserved.contains(&key.as_str()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect(),
+        _ => Map::new(),
+    };
+    let mut redacted = event.clone();
+    redacted.content = Value::Object(content);
+    redacted
+}
+
+/// Whether `event` is already in its redacted form.
+pub fn is_redacted(event: &CanonicalEvent) -> bool {
+    redact(event).content == event.content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Membership, MembershipContent, StateContent};
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn redacted_copies_keep_id_and_signature() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let mut event = EventBuilder::new("example.org", "room", "message")
+            .sender("@alice:example.org")
+            .content(serde_json::json!({ "content": "secret", "author": { "id": "@alice" } }))
+            .build();
+        event.sign_with("example.org", "1", &key);
+        assert!(event.has_valid_content_hash());
+
+        let redacted = event.redacted();
+        assert_eq!(redacted.content, serde_json::json!({}));
+        assert!(is_redacted(&redacted));
+        assert!(!is_redacted(&event));
+        assert_eq!(
+            CanonicalEvent::event_id_from_hash(&redacted.canonical_hash()),
+            event.event_id
+        );
+        assert!(redacted
+            .verify_with("example.org", "1", &key.verifying_key())
+            .is_ok());
+        assert!(!redacted.has_valid_content_hash());
+
+        // Tampering with content is caught by the content hash, not the event id.
+        let mut tampered = event.clone();
+        tampered.content = serde_json::json!({ "content": "forged" });
+        assert!(!tampered.has_valid_content_hash());
+        assert!(!is_redacted(&tampered));
+    }
+
+    #[test]
+    fn membership_survives_redaction() {
+        let mut member = StateContent::Member(MembershipContent {
+            membership: Membership::Join,
+        })
+        .to_event("example.org", "room", "@alice", "@alice", Vec::new());
+        member.content["displayname"] = Value::String("Alice".into());
+
+        assert_eq!(
+            redact(&member).content,
+            serde_json::json!({ "membership": "join" })
+        );
+    }
+
+    #[test]
+    fn replies_stay_in_their_thread_after_redaction() {
+        let reply = EventBuilder::new("example.org", "room", "message")
+            .sender("@alice:example.org")
+            .content(serde_json::json!({
+                "content": "+1",
+                "reply_to": "$parent",
+                "thread_root": "$root",
+            }))
+            .build();
+
+        assert_eq!(
+            redact(&reply).content,
+            serde_json::json!({ "reply_to": "$parent", "thread_root": "$root" })
+        );
+    }
+}
@@ -0,0 +1,724 @@
+//! Typed channel state events and deterministic state resolution.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    auth::{self, AuthState},
+    CanonicalEvent, EventBuilder, EventId,
+};
+
+pub const CHANNEL_NAME_EVENT: &str = "channel.name";
+pub const CHANNEL_TOPIC_EVENT: &str = "channel.topic";
+pub const CHANNEL_MEMBER_EVENT: &str = "channel.member";
+pub const CHANNEL_POWER_LEVELS_EVENT: &str = "channel.power_levels";
+pub const CHANNEL_ENCRYPTION_EVENT: &str = "channel.encryption";
+pub const CHANNEL_JOIN_RULES_EVENT: &str = "channel.join_rules";
+
+/// Power level granted to users missing from `PowerLevelsContent::users`.
+pub const DEFAULT_USER_POWER_LEVEL: i64 = 0;
+/// Power level required to send state events, ban, kick or redact when no override applies.
+pub const DEFAULT_STATE_POWER_LEVEL: i64 = 50;
+
+#[derive(Debug, Error)]
+pub enum StateError {
+    #[error("event '{0}' is not a state event")]
+    NotStateEvent(EventId),
+    #[error("invalid content for '{event_type}': {reason}")]
+    InvalidContent { event_type: String, reason: String },
+    #[error("'{event_type}' requires {expected}")]
+    InvalidStateKey {
+        event_type: String,
+        expected: &'static str,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelNameContent {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelTopicContent {
+    pub topic: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Membership {
+    Join,
+    Leave,
+    Invite,
+    Ban,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MembershipContent {
+    pub membership: Membership,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PowerLevelsContent {
+    #[serde(default)]
+    pub users: BTreeMap<String, i64>,
+    #[serde(default = "default_user_level")]
+    pub users_default: i64,
+    #[serde(default)]
+    pub events: BTreeMap<String, i64>,
+    #[serde(default = "default_user_level")]
+    pub events_default: i64,
+    #[serde(default = "default_state_level")]
+    pub state_default: i64,
+    #[serde(default = "default_state_level")]
+    pub ban: i64,
+    #[serde(default = "default_state_level")]
+    pub kick: i64,
+    #[serde(default = "default_user_level")]
+    pub invite: i64,
+    /// Level needed to redact other users' events; anyone may redact their own.
+    #[serde(default = "default_state_level")]
+    pub redact: i64,
+}
+
+fn default_user_level() -> i64 {
+    DEFAULT_USER_POWER_LEVEL
+}
+
+fn default_state_level() -> i64 {
+    DEFAULT_STATE_POWER_LEVEL
+}
+
+impl Default for PowerLevelsContent {
+    fn default() -> Self {
+        Self {
+            users: BTreeMap::new(),
+            users_default: DEFAULT_USER_POWER_LEVEL,
+            events: BTreeMap::new(),
+            events_default: DEFAULT_USER_POWER_LEVEL,
+            state_default: DEFAULT_STATE_POWER_LEVEL,
+            ban: DEFAULT_STATE_POWER_LEVEL,
+            kick: DEFAULT_STATE_POWER_LEVEL,
+            invite: DEFAULT_USER_POWER_LEVEL,
+            redact: DEFAULT_STATE_POWER_LEVEL,
+        }
+    }
+}
+
+impl PowerLevelsContent {
+    pub fn user_level(&self, user_id: &str) -> i64 {
+        self.users
+            .get(user_id)
+            .copied()
+            .unwrap_or(self.users_default)
+    }
+}
+
+/// Turns on end-to-end encryption for a channel. Once set it cannot be changed or removed,
+/// and plaintext messages and edits are rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionContent {
+    /// Group messaging protocol clients use, currently always `mls`.
+    pub algorithm: String,
+}
+
+/// Who may join a channel without an invite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JoinRule {
+    /// Anyone who is not banned.
+    Public,
+    /// Only invited users. Channels without a join rules event use this.
+    #[default]
+    Invite,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JoinRulesContent {
+    pub join_rule: JoinRule,
+}
+
+/// Content of the state event types the server understands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateContent {
+    Name(ChannelNameContent),
+    Topic(ChannelTopicContent),
+    Member(MembershipContent),
+    PowerLevels(PowerLevelsContent),
+    Encryption(EncryptionContent),
+    JoinRules(JoinRulesContent),
+}
+
+impl StateContent {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            StateContent::Name(_) => CHANNEL_NAME_EVENT,
+            StateContent::Topic(_) => CHANNEL_TOPIC_EVENT,
+            StateContent::Member(_) => CHANNEL_MEMBER_EVENT,
+            StateContent::PowerLevels(_) => CHANNEL_POWER_LEVELS_EVENT,
+            StateContent::Encryption(_) => CHANNEL_ENCRYPTION_EVENT,
+            StateContent::JoinRules(_) => CHANNEL_JOIN_RULES_EVENT,
+        }
+    }
+
+    /// Parse typed content; returns `Ok(None)` for event types this server does not model.
+    pub fn parse(event_type: &str, content: &Value) -> Result<Option<Self>, StateError> {
+        fn decode<T: serde::de::DeserializeOwned>(
+            event_type: &str,
+            content: &Value,
+        ) -> Result<T, StateError> {
+            serde_json::from_value(content.clone()).map_err(|err| StateError::InvalidContent {
+                event_type: event_type.to_string(),
+                reason: err.to_string(),
+            })
+        }
+
+        let parsed = match event_type {
+            CHANNEL_NAME_EVENT => StateContent::Name(decode(event_type, content)?),
+            CHANNEL_TOPIC_EVENT => StateContent::Topic(decode(event_type, content)?),
+            CHANNEL_MEMBER_EVENT => StateContent::Member(decode(event_type, content)?),
+            CHANNEL_POWER_LEVELS_EVENT => StateContent::PowerLevels(decode(event_type, content)?),
+            CHANNEL_ENCRYPTION_EVENT => StateContent::Encryption(decode(event_type, content)?),
+            CHANNEL_JOIN_RULES_EVENT => StateContent::JoinRules(decode(event_type, content)?),
+            _ => return Ok(None),
+        };
+        Ok(Some(parsed))
+    }
+
+    /// Parse the typed content of a state event and check its `state_key` shape.
+    pub fn from_event(event: &CanonicalEvent) -> Result<Option<Self>, StateError> {
+        let state_key = event
+            .state_key
+            .as_deref()
+            .ok_or_else(|| StateError::NotStateEvent(event.event_id.clone()))?;
+        let Some(content) = Self::parse(&event.event_type, &event.content)? else {
+            return Ok(None);
+        };
+        content.validate_state_key(state_key)?;
+        Ok(Some(content))
+    }
+
+    /// Membership events are keyed by user id; every other type uses the empty key.
+    pub fn validate_state_key(&self, state_key: &str) -> Result<(), StateError> {
+        let (valid, expected) = match self {
+            StateContent::Member(_) => (!state_key.is_empty(), "the member's user id as state_key"),
+            _ => (state_key.is_empty(), "an empty state_key"),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(StateError::InvalidStateKey {
+                event_type: self.event_type().to_string(),
+                expected,
+            })
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let value = match self {
+            StateContent::Name(content) => serde_json::to_value(content),
+            StateContent::Topic(content) => serde_json::to_value(content),
+            StateContent::Member(content) => serde_json::to_value(content),
+            StateContent::PowerLevels(content) => serde_json::to_value(content),
+            StateContent::Encryption(content) => serde_json::to_value(content),
+            StateContent::JoinRules(content) => serde_json::to_value(content),
+        };
+        value.expect("serialization must succeed")
+    }
+
+    pub fn to_event(
+        &self,
+        origin_server: &str,
+        room_id: &str,
+        sender: &str,
+        state_key: &str,
+        prev_events: Vec<EventId>,
+    ) -> CanonicalEvent {
+        EventBuilder::new(
+            origin_server.to_owned(),
+            room_id.to_owned(),
+            self.event_type(),
+        )
+        .sender(sender.to_owned())
+        .content(self.to_value())
+        .state_key(state_key.to_owned())
+        .prev_events(prev_events)
+        .build()
+    }
+}
+
+/// `(event_type, state_key)` slot within a channel's state.
+pub type StateSlot = (String, String);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateEntry {
+    pub event_type: String,
+    pub state_key: String,
+    pub event_id: EventId,
+}
+
+/// Channel state at a point in the event graph: the event currently filling each slot.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<StateEntry>", into = "Vec<StateEntry>")]
+pub struct StateMap(BTreeMap<StateSlot, EventId>);
+
+impl StateMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, event_type: &str, state_key: &str) -> Option<&EventId> {
+        self.0.get(&(event_type.to_string(), state_key.to_string()))
+    }
+
+    pub fn insert(&mut self, event_type: &str, state_key: &str, event_id: EventId) {
+        self.0
+            .insert((event_type.to_string(), state_key.to_string()), event_id);
+    }
+
+    /// Apply `event` on top of this state if it is a state event.
+    pub fn apply(&mut self, event: &CanonicalEvent) {
+        if let Some(state_key) = &event.state_key {
+            self.insert(&event.event_type, state_key, event.event_id.clone());
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&StateSlot, &EventId)> {
+        self.0.iter()
+    }
+
+    pub fn event_ids(&self) -> impl Iterator<Item = &EventId> {
+        self.0.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<Vec<StateEntry>> for StateMap {
+    fn from(entries: Vec<StateEntry>) -> Self {
+        Self(
+            entries
+                .into_iter()
+                .map(|entry| ((entry.event_type, entry.state_key), entry.event_id))
+                .collect(),
+        )
+    }
+}
+
+impl From<StateMap> for Vec<StateEntry> {
+    fn from(map: StateMap) -> Self {
+        map.0
+            .into_iter()
+            .map(|((event_type, state_key), event_id)| StateEntry {
+                event_type,
+                state_key,
+                event_id,
+            })
+            .collect()
+    }
+}
+
+/// Merge the state of several forks into one.
+///
+/// Slots every fork agrees on (or that only one candidate fills) are kept as-is. The
+/// power-levels slot is resolved next (see [`resolve_power_levels`]). Remaining conflicts
+/// pick the candidate whose sender holds the highest power under the resolved power levels,
+/// then the newest `origin_ts`, then the greatest event id. The result does not depend on
+/// the order of `forks`. Candidates missing from `events` rank below every known event.
+pub fn resolve_state(forks: &[StateMap], events: &HashMap<EventId, CanonicalEvent>) -> StateMap {
+    let candidates = slot_candidates(forks);
+    let managed = candidates
+        .keys()
+        .any(|(event_type, _)| event_type == CHANNEL_MEMBER_EVENT);
+
+    let mut resolved = StateMap::new();
+    let mut conflicted = Vec::new();
+    for (slot, ids) in candidates {
+        if ids.len() == 1 {
+            let event_id = ids.into_iter().next().expect("one candidate");
+            resolved.0.insert(slot.clone(), event_id.clone());
+        } else {
+            conflicted.push((slot, ids));
+        }
+    }
+
+    let power_slot = (CHANNEL_POWER_LEVELS_EVENT.to_string(), String::new());
+    if let Some((_, ids)) = conflicted.iter().find(|(slot, _)| **slot == power_slot) {
+        if let Some(winner) = resolve_power_levels(ids, &resolved, events, managed) {
+            resolved.0.insert(power_slot.clone(), winner);
+        }
+    }
+
+    let power_levels = AuthState::from_events(
+        resolved
+            .get(CHANNEL_POWER_LEVELS_EVENT, "")
+            .and_then(|id| events.get(id)),
+        managed,
+    );
+
+    for (slot, ids) in conflicted {
+        if *slot == power_slot {
+            continue;
+        }
+        let winner = ids
+            .iter()
+            .max_by_key(|id| {
+                let sender_level = events
+                    .get(**id)
+                    .map(|event| power_levels.user_level(&sender_id(event)))
+                    .unwrap_or(i64::MIN);
+                (sender_level, origin_ts(events, id), **id)
+            })
+            .expect("conflicts have candidates");
+        resolved.0.insert(slot.clone(), (*winner).clone());
+    }
+
+    resolved
+}
+
+/// Settle conflicting power levels. Candidates rank by their sender's level under the power
+/// levels they replaced (the ones they cite in `auth_events`; first power levels rank
+/// last), then the newest `origin_ts`, then the greatest event id. The first candidate that
+/// still passes the auth rules against the unconflicted state wins. When none does, the
+/// power levels the top candidate replaced stay in place.
+fn resolve_power_levels(
+    ids: &BTreeSet<&EventId>,
+    unconflicted: &StateMap,
+    events: &HashMap<EventId, CanonicalEvent>,
+    managed: bool,
+) -> Option<EventId> {
+    let previous = |event: &CanonicalEvent| {
+        event
+            .auth_events
+            .iter()
+            .filter_map(|id| events.get(id))
+            .find(|auth| auth.event_type == CHANNEL_POWER_LEVELS_EVENT)
+    };
+    let mut ranked: Vec<&CanonicalEvent> = ids.iter().filter_map(|id| events.get(*id)).collect();
+    ranked.sort_by_cached_key(|event| {
+        let level = previous(event)
+            .map(|levels| AuthState::from_events([levels], managed).user_level(&sender_id(event)))
+            .unwrap_or(i64::MIN);
+        std::cmp::Reverse((level, event.origin_ts, event.event_id.clone()))
+    });
+
+    let passes = |event: &CanonicalEvent| {
+        let cited = event.auth_events.iter().filter_map(|id| events.get(id));
+        let current = unconflicted.iter().filter_map(|(_, id)| events.get(id));
+        auth::check_event(
+            event,
+            &AuthState::from_events(cited.chain(current), managed),
+        )
+        .is_ok()
+    };
+    match ranked.iter().find(|event| passes(event)) {
+        Some(winner) => Some(winner.event_id.clone()),
+        None => ranked
+            .first()
+            .and_then(|top| previous(top))
+            .map(|levels| levels.event_id.clone()),
+    }
+}
+
+/// Events `resolve_state` needs to settle `forks`: every conflicted candidate plus each
+/// fork's power-levels event. When the power levels conflict, the unconflicted membership,
+/// encryption and join rules events are included too; callers must also supply the
+/// `auth_events` of each conflicted power-levels candidate.
+pub fn resolution_inputs(forks: &[StateMap]) -> BTreeSet<EventId> {
+    let candidates = slot_candidates(forks);
+    let power_conflict = candidates
+        .iter()
+        .any(|((event_type, _), ids)| event_type == CHANNEL_POWER_LEVELS_EVENT && ids.len() > 1);
+
+    let mut inputs: BTreeSet<EventId> = BTreeSet::new();
+    for ((event_type, _), ids) in &candidates {
+        let auth_slot = [
+            CHANNEL_MEMBER_EVENT,
+            CHANNEL_ENCRYPTION_EVENT,
+            CHANNEL_JOIN_RULES_EVENT,
+        ]
+        .contains(&event_type.as_str());
+        if ids.len() > 1 || (power_conflict && auth_slot) {
+            inputs.extend(ids.iter().map(|id| (*id).clone()));
+        }
+    }
+    inputs.extend(
+        forks
+            .iter()
+            .filter_map(|fork| fork.get(CHANNEL_POWER_LEVELS_EVENT, ""))
+            .cloned(),
+    );
+    inputs
+}
+
+fn sender_id(event: &CanonicalEvent) -> String {
+    auth::qualify_user_id(&event.sender, &event.origin_server)
+}
+
+fn slot_candidates(forks: &[StateMap]) -> BTreeMap<&StateSlot, BTreeSet<&EventId>> {
+    let mut candidates: BTreeMap<&StateSlot, BTreeSet<&EventId>> = BTreeMap::new();
+    for fork in forks {
+        for (slot, event_id) in fork.iter() {
+            candidates.entry(slot).or_default().insert(event_id);
+        }
+    }
+    candidates
+}
+
+fn origin_ts(events: &HashMap<EventId, CanonicalEvent>, event_id: &str) -> i64 {
+    events
+        .get(event_id)
+        .map(|event| event.origin_ts)
+        .unwrap_or(i64::MIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn state_event(
+        content: StateContent,
+        sender: &str,
+        state_key: &str,
+        origin_ts: i64,
+    ) -> CanonicalEvent {
+        let mut event = content.to_event("example.org", "room", sender, state_key, Vec::new());
+        event.origin_ts = origin_ts;
+        event.refresh_event_id();
+        event
+    }
+
+    fn name(value: &str) -> StateContent {
+        StateContent::Name(ChannelNameContent { name: value.into() })
+    }
+
+    #[test]
+    fn state_content_round_trips_through_events() {
+        let event = StateContent::Member(MembershipContent {
+            membership: Membership::Join,
+        })
+        .to_event("example.org", "room", "@alice", "@alice", Vec::new());
+
+        assert_eq!(event.event_type, CHANNEL_MEMBER_EVENT);
+        assert_eq!(event.content, json!({ "membership": "join" }));
+        assert!(matches!(
+            StateContent::from_event(&event).unwrap(),
+            Some(StateContent::Member(MembershipContent {
+                membership: Membership::Join
+            }))
+        ));
+    }
+
+    #[test]
+    fn state_keys_are_validated_per_type() {
+        let wrong = name("general").to_event("example.org", "room", "@alice", "@alice", Vec::new());
+        assert!(matches!(
+            StateContent::from_event(&wrong),
+            Err(StateError::InvalidStateKey { .. })
+        ));
+
+        let message = EventBuilder::new("example.org", "room", "message").build();
+        assert!(matches!(
+            StateContent::from_event(&message),
+            Err(StateError::NotStateEvent(_))
+        ));
+    }
+
+    #[test]
+    fn resolution_keeps_unconflicted_state_and_is_order_independent() {
+        let topic = state_event(
+            StateContent::Topic(ChannelTopicContent {
+                topic: "shared".into(),
+            }),
+            "@alice",
+            "",
+            1,
+        );
+        let older = state_event(name("old"), "@alice", "", 2);
+        let newer = state_event(name("new"), "@bob", "", 3);
+        let events: HashMap<_, _> = [&topic, &older, &newer]
+            .into_iter()
+            .map(|event| (event.event_id.clone(), event.clone()))
+            .collect();
+
+        let mut left = StateMap::new();
+        left.apply(&topic);
+        left.apply(&older);
+        let mut right = StateMap::new();
+        right.apply(&topic);
+        right.apply(&newer);
+
+        let resolved = resolve_state(&[left.clone(), right.clone()], &events);
+        assert_eq!(resolved, resolve_state(&[right, left], &events));
+        assert_eq!(resolved.get(CHANNEL_TOPIC_EVENT, ""), Some(&topic.event_id));
+        assert_eq!(resolved.get(CHANNEL_NAME_EVENT, ""), Some(&newer.event_id));
+    }
+
+    #[test]
+    fn resolution_prefers_senders_with_more_power() {
+        let power = state_event(
+            StateContent::PowerLevels(PowerLevelsContent {
+                users: BTreeMap::from([("@admin".to_string(), 100)]),
+                ..PowerLevelsContent::default()
+            }),
+            "@admin",
+            "",
+            1,
+        );
+        let by_admin = state_event(name("admin's pick"), "@admin", "", 2);
+        let by_member = state_event(name("member's pick"), "@member", "", 5);
+        let events: HashMap<_, _> = [&power, &by_admin, &by_member]
+            .into_iter()
+            .map(|event| (event.event_id.clone(), event.clone()))
+            .collect();
+
+        let mut left = StateMap::new();
+        left.apply(&power);
+        left.apply(&by_admin);
+        let mut right = StateMap::new();
+        right.apply(&power);
+        right.apply(&by_member);
+
+        let resolved = resolve_state(&[left, right], &events);
+        assert_eq!(
+            resolved.get(CHANNEL_NAME_EVENT, ""),
+            Some(&by_admin.event_id)
+        );
+    }
+
+    #[test]
+    fn resolution_qualifies_senders_before_looking_up_levels() {
+        let power = state_event(
+            StateContent::PowerLevels(PowerLevelsContent {
+                users: BTreeMap::from([("@admin:example.org".to_string(), 100)]),
+                ..PowerLevelsContent::default()
+            }),
+            "@admin",
+            "",
+            1,
+        );
+        let by_admin = state_event(name("admin's pick"), "@admin", "", 2);
+        let by_member = state_event(name("member's pick"), "@member", "", 5);
+        let events: HashMap<_, _> = [&power, &by_admin, &by_member]
+            .into_iter()
+            .map(|event| (event.event_id.clone(), event.clone()))
+            .collect();
+
+        let mut left = StateMap::new();
+        left.apply(&power);
+        left.apply(&by_admin);
+        let mut right = StateMap::new();
+        right.apply(&power);
+        right.apply(&by_member);
+
+        let resolved = resolve_state(&[left, right], &events);
+        assert_eq!(
+            resolved.get(CHANNEL_NAME_EVENT, ""),
+            Some(&by_admin.event_id)
+        );
+    }
+
+    #[test]
+    fn power_levels_conflicts_rank_by_the_replaced_levels_and_recheck_auth() {
+        let levels = |users: &[(&str, i64)], invite: i64| {
+            StateContent::PowerLevels(PowerLevelsContent {
+                users: users
+                    .iter()
+                    .map(|(user, level)| (user.to_string(), *level))
+                    .collect(),
+                invite,
+                ..PowerLevelsContent::default()
+            })
+        };
+        let base = state_event(levels(&[("@admin", 100), ("@mod", 50)], 0), "@admin", "", 1);
+        let citing = |content: StateContent, sender: &str, origin_ts: i64| {
+            let mut event = state_event(content, sender, "", origin_ts);
+            event.auth_events = vec![base.event_id.clone()];
+            event.refresh_event_id();
+            event
+        };
+        let by_admin = citing(levels(&[("@admin", 100), ("@mod", 60)], 0), "@admin", 2);
+        // Newer, but sent by a lower-ranked user.
+        let by_mod = citing(levels(&[("@admin", 100), ("@mod", 50)], 50), "@mod", 9);
+        // Newest of all, and not allowed under the levels it replaced.
+        let forged = citing(levels(&[("@mallory", 100)], 0), "@mallory", 20);
+        let mut events: HashMap<_, _> = [&base, &by_admin, &by_mod, &forged]
+            .into_iter()
+            .map(|event| (event.event_id.clone(), event.clone()))
+            .collect();
+        let fork = |event: &CanonicalEvent, extra: &[&CanonicalEvent]| {
+            let mut state = StateMap::new();
+            for event in extra {
+                state.apply(event);
+            }
+            state.apply(event);
+            state
+        };
+
+        let forks = [fork(&by_admin, &[]), fork(&by_mod, &[]), fork(&forged, &[])];
+        let resolved = resolve_state(&forks, &events);
+        assert_eq!(
+            resolved.get(CHANNEL_POWER_LEVELS_EVENT, ""),
+            Some(&by_admin.event_id)
+        );
+
+        // A candidate whose sender is banned in the resolved state fails the auth rules.
+        let mod_join = state_event(
+            StateContent::Member(MembershipContent {
+                membership: Membership::Join,
+            }),
+            "@mod",
+            "@mod",
+            3,
+        );
+        let admin_ban = state_event(
+            StateContent::Member(MembershipContent {
+                membership: Membership::Ban,
+            }),
+            "@mod",
+            "@admin",
+            4,
+        );
+        for event in [&mod_join, &admin_ban] {
+            events.insert(event.event_id.clone(), event.clone());
+        }
+        let members = [&mod_join, &admin_ban];
+        let forks = [fork(&by_admin, &members), fork(&by_mod, &members)];
+        let resolved = resolve_state(&forks, &events);
+        assert_eq!(
+            resolved.get(CHANNEL_POWER_LEVELS_EVENT, ""),
+            Some(&by_mod.event_id)
+        );
+
+        // With no acceptable candidate, the replaced power levels stay.
+        let also_forged = citing(levels(&[("@eve", 100)], 0), "@eve", 30);
+        events.insert(also_forged.event_id.clone(), also_forged.clone());
+        let forks = [fork(&forged, &[]), fork(&also_forged, &[])];
+        let resolved = resolve_state(&forks, &events);
+        assert_eq!(
+            resolved.get(CHANNEL_POWER_LEVELS_EVENT, ""),
+            Some(&base.event_id)
+        );
+    }
+
+    #[test]
+    fn state_map_serializes_as_entry_list() {
+        let mut state = StateMap::new();
+        state.insert(CHANNEL_MEMBER_EVENT, "@alice", "$a".into());
+        let value = serde_json::to_value(&state).unwrap();
+        assert_eq!(
+            value,
+            json!([{ "event_type": CHANNEL_MEMBER_EVENT, "state_key": "@alice", "event_id": "$a" }])
+        );
+        assert_eq!(serde_json::from_value::<StateMap>(value).unwrap(), state);
+    }
+}
@@ -0,0 +1,470 @@
+//! Image decoding, thumbnails, placeholders and metadata stripping. Everything here is
+//! synchronous CPU work; async callers should run it on a blocking thread.
+
+use std::io::Cursor;
+
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    imageops::FilterType,
+    DynamicImage, ImageDecoder, ImageReader, Limits,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Content types the pipeline decodes.
+pub const PREVIEWABLE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// Sizes thumbnails are rendered at. Requests are rounded to one of these so the number of
+/// cached derivatives per object stays bounded.
+pub const THUMBNAIL_SIZES: &[ThumbnailSpec] = &[
+    ThumbnailSpec::new(32, 32, ThumbnailMethod::Crop),
+    ThumbnailSpec::new(96, 96, ThumbnailMethod::Crop),
+    ThumbnailSpec::new(320, 240, ThumbnailMethod::Scale),
+    ThumbnailSpec::new(640, 480, ThumbnailMethod::Scale),
+    ThumbnailSpec::new(800, 600, ThumbnailMethod::Scale),
+];
+
+const JPEG_QUALITY: u8 = 80;
+/// Longest edge of the image the placeholder is computed from.
+const BLURHASH_SAMPLE_EDGE: u32 = 64;
+
+#[derive(Debug, Error)]
+pub enum ImageError {
+    #[error("unsupported image format")]
+    Unsupported,
+    #[error("image exceeds the decoding limits")]
+    TooLarge,
+    #[error("failed to decode image: {0}")]
+    Decode(String),
+    #[error("failed to encode image: {0}")]
+    Encode(String),
+}
+
+impl From<image::ImageError> for ImageError {
+    fn from(err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::Limits(_) => ImageError::TooLarge,
+            image::ImageError::Unsupported(_) => ImageError::Unsupported,
+            image::ImageError::Encoding(err) => ImageError::Encode(err.to_string()),
+            other => ImageError::Decode(other.to_string()),
+        }
+    }
+}
+
+/// Bounds applied before and while decoding, so hostile images cannot exhaust memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Upper bound on decoder allocations, including the decoded pixels.
+    pub max_alloc: u64,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_width: 8192,
+            max_height: 8192,
+            max_alloc: 256 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailMethod {
+    /// Fill the box exactly, cropping the overflowing edges.
+    Crop,
+    /// Fit inside the box, keeping the aspect ratio.
+    Scale,
+}
+
+impl ThumbnailMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThumbnailMethod::Crop => "crop",
+            ThumbnailMethod::Scale => "scale",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ThumbnailSpec {
+    pub width: u32,
+    pub height: u32,
+    pub method: ThumbnailMethod,
+}
+
+impl ThumbnailSpec {
+    pub const fn new(width: u32, height: u32, method: ThumbnailMethod) -> Self {
+        Self {
+            width,
+            height,
+            method,
+        }
+    }
+
+    /// The smallest configured size of `method` covering `width`x`height`, or the largest
+    /// one when none does.
+    pub fn select(width: u32, height: u32, method: ThumbnailMethod) -> ThumbnailSpec {
+        let candidates = THUMBNAIL_SIZES.iter().filter(|spec| spec.method == method);
+        candidates
+            .clone()
+            .find(|spec| spec.width >= width && spec.height >= height)
+            .or_else(|| candidates.clone().next_back())
+            .copied()
+            .expect("every method has at least one thumbnail size")
+    }
+}
+
+/// Facts about a decoded image, cached per content hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageInfo {
+    /// Dimensions after applying the EXIF orientation.
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: String,
+    /// Thumbnails of images with transparency are PNG, others JPEG.
+    pub has_alpha: bool,
+}
+
+impl ImageInfo {
+    pub fn thumbnail_content_type(&self) -> &'static str {
+        if self.has_alpha {
+            "image/png"
+        } else {
+            "image/jpeg"
+        }
+    }
+}
+
+/// Decode `bytes` within `limits`, rotated upright according to its EXIF orientation.
+pub fn decode_image(bytes: &[u8], limits: &ImageLimits) -> Result<DynamicImage, ImageError> {
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|err| ImageError::Decode(err.to_string()))?;
+    if reader.format().is_none() {
+        return Err(ImageError::Unsupported);
+    }
+    let mut decoder_limits = Limits::default();
+    decoder_limits.max_image_width = Some(limits.max_width);
+    decoder_limits.max_image_height = Some(limits.max_height);
+    decoder_limits.max_alloc = Some(limits.max_alloc);
+    reader.limits(decoder_limits);
+
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Dimensions, placeholder and alpha flag of a decoded image.
+pub fn image_info(image: &DynamicImage) -> Result<ImageInfo, ImageError> {
+    Ok(ImageInfo {
+        width: image.width(),
+        height: image.height(),
+        blurhash: placeholder(image)?,
+        has_alpha: image.color().has_alpha(),
+    })
+}
+
+/// Blurhash of `image`, with more components along its longer edge.
+pub fn placeholder(image: &DynamicImage) -> Result<String, ImageError> {
+    let sample = image
+        .thumbnail(BLURHASH_SAMPLE_EDGE, BLURHASH_SAMPLE_EDGE)
+        .to_rgba8();
+    let (components_x, components_y) = if sample.width() >= sample.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+    blurhash::encode(
+        components_x,
+        components_y,
+        sample.width(),
+        sample.height(),
+        sample.as_raw(),
+    )
+    .map_err(|err| ImageError::Encode(err.to_string()))
+}
+
+/// Render `image` at `spec` and encode it (PNG with transparency, JPEG otherwise). Images
+/// smaller than a scale box are not enlarged.
+pub fn render_thumbnail(image: &DynamicImage, spec: ThumbnailSpec) -> Result<Vec<u8>, ImageError> {
+    let resized = match spec.method {
+        ThumbnailMethod::Crop => {
+            image.resize_to_fill(spec.width, spec.height, FilterType::Triangle)
+        }
+        ThumbnailMethod::Scale if image.width() <= spec.width && image.height() <= spec.height => {
+            image.clone()
+        }
+        ThumbnailMethod::Scale => image.resize(spec.width, spec.height, FilterType::Triangle),
+    };
+
+    let mut out = Vec::new();
+    if image.color().has_alpha() {
+        resized
+            .to_rgba8()
+            .write_with_encoder(PngEncoder::new(&mut out))?;
+    } else {
+        resized
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?;
+    }
+    Ok(out)
+}
+
+/// Copy of a JPEG or PNG without EXIF, XMP, IPTC and text metadata (which can carry GPS
+/// coordinates and device details). A JPEG's EXIF orientation is kept so the image still
+/// displays upright. `None` when the format is not handled or nothing was removed.
+pub fn strip_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.starts_with(&[0xff, 0xd8]) {
+        strip_jpeg(bytes)
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        strip_png(bytes)
+    } else {
+        None
+    }
+}
+
+const JPEG_APP1: u8 = 0xe1;
+const JPEG_APP13: u8 = 0xed;
+const JPEG_COMMENT: u8 = 0xfe;
+const JPEG_START_OF_SCAN: u8 = 0xda;
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+fn strip_jpeg(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..2]);
+    let mut orientation = None;
+    let mut removed = false;
+    let mut pos = 2;
+
+    loop {
+        if bytes.get(pos) != Some(&0xff) {
+            return None;
+        }
+        let marker = *bytes.get(pos + 1)?;
+        if marker == 0xff {
+            // Fill byte before a marker.
+            pos += 1;
+            continue;
+        }
+        if marker == JPEG_START_OF_SCAN {
+            if let Some(orientation) = orientation.filter(|value| *value != 1) {
+                out.extend_from_slice(&orientation_segment(orientation));
+            }
+            out.extend_from_slice(&bytes[pos..]);
+            break;
+        }
+        let length = u16::from_be_bytes([*bytes.get(pos + 2)?, *bytes.get(pos + 3)?]) as usize;
+        let end = pos + 2 + length;
+        let segment = bytes.get(pos..end)?;
+        let payload = &segment[4..];
+        match marker {
+            JPEG_APP1 | JPEG_APP13 | JPEG_COMMENT => {
+                if marker == JPEG_APP1 && payload.starts_with(EXIF_HEADER) {
+                    orientation = orientation.or_else(|| exif_orientation(payload));
+                }
+                removed = true;
+            }
+            _ => out.extend_from_slice(segment),
+        }
+        pos = end;
+    }
+
+    removed.then_some(out)
+}
+
+fn exif_orientation(app1_payload: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new()
+        .read_raw(app1_payload[EXIF_HEADER.len()..].to_vec())
+        .ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+        .filter(|value| (1..=8).contains(value))
+}
+
+/// APP1 segment holding a big-endian TIFF block whose only entry is the orientation tag.
+fn orientation_segment(orientation: u32) -> Vec<u8> {
+    let mut tiff = Vec::with_capacity(26);
+    tiff.extend_from_slice(b"MM\0\x2a\0\0\0\x08");
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&0x0112u16.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&(orientation as u16).to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+
+    let length = (2 + EXIF_HEADER.len() + tiff.len()) as u16;
+    let mut segment = vec![0xff, JPEG_APP1];
+    segment.extend_from_slice(&length.to_be_bytes());
+    segment.extend_from_slice(EXIF_HEADER);
+    segment.extend_from_slice(&tiff);
+    segment
+}
+
+/// Ancillary PNG chunks that carry metadata rather than pixels or colour information.
+const PNG_METADATA_CHUNKS: &[&[u8; 4]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+fn strip_png(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..8]);
+    let mut removed = false;
+    let mut pos = 8;
+
+    while pos < bytes.len() {
+        let length = u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let end = pos.checked_add(12)?.checked_add(length)?;
+        let chunk = bytes.get(pos..end)?;
+        if PNG_METADATA_CHUNKS.iter().any(|kind| &chunk[4..8] == *kind) {
+            removed = true;
+        } else {
+            out.extend_from_slice(chunk);
+        }
+        pos = end;
+    }
+
+    removed.then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, ImageFormat, Rgb, Rgba};
+
+    fn encoded(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        image.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |x, y| {
+            Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        }))
+    }
+
+    /// APP1 EXIF segment with an orientation entry and a fake GPS marker.
+    fn exif_segment(orientation: u16) -> Vec<u8> {
+        let mut segment = orientation_segment(orientation as u32);
+        // Smuggle recognisable bytes into the segment after the TIFF block.
+        segment.extend_from_slice(b"GPSLatitude=52.37");
+        let length = (segment.len() - 2) as u16;
+        segment[2..4].copy_from_slice(&length.to_be_bytes());
+        segment
+    }
+
+    #[test]
+    fn thumbnails_crop_exactly_and_scale_within_the_box() {
+        let source = encoded(gradient(400, 200), ImageFormat::Png);
+        let image = decode_image(&source, &ImageLimits::default()).unwrap();
+
+        let crop = ThumbnailSpec::select(90, 90, ThumbnailMethod::Crop);
+        assert_eq!(crop, ThumbnailSpec::new(96, 96, ThumbnailMethod::Crop));
+        let thumbnail = decode_image(
+            &render_thumbnail(&image, crop).unwrap(),
+            &ImageLimits::default(),
+        )
+        .unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (96, 96));
+
+        let scale = ThumbnailSpec::select(320, 240, ThumbnailMethod::Scale);
+        let thumbnail = decode_image(
+            &render_thumbnail(&image, scale).unwrap(),
+            &ImageLimits::default(),
+        )
+        .unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (320, 160));
+
+        // Small images are not enlarged, and oversized requests use the largest size.
+        let large = ThumbnailSpec::select(4000, 4000, ThumbnailMethod::Scale);
+        assert_eq!(large, ThumbnailSpec::new(800, 600, ThumbnailMethod::Scale));
+        let thumbnail = decode_image(
+            &render_thumbnail(&image, large).unwrap(),
+            &ImageLimits::default(),
+        )
+        .unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (400, 200));
+    }
+
+    #[test]
+    fn info_reports_placeholder_and_alpha() {
+        let opaque = decode_image(
+            &encoded(gradient(64, 32), ImageFormat::Png),
+            &ImageLimits::default(),
+        )
+        .unwrap();
+        let info = image_info(&opaque).unwrap();
+        assert_eq!((info.width, info.height), (64, 32));
+        assert!(!info.has_alpha);
+        assert_eq!(info.thumbnail_content_type(), "image/jpeg");
+        // 4x3 components encode to 1 + 1 + 4 + 2 * (4 * 3 - 1) characters.
+        assert_eq!(info.blurhash.len(), 28);
+        assert_eq!(info.blurhash, image_info(&opaque).unwrap().blurhash);
+
+        let transparent = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(8, 8, Rgba([0; 4])));
+        let info = image_info(&transparent).unwrap();
+        assert!(info.has_alpha);
+        assert_eq!(info.thumbnail_content_type(), "image/png");
+    }
+
+    #[test]
+    fn decoding_enforces_limits_and_rejects_non_images() {
+        let source = encoded(gradient(300, 100), ImageFormat::Png);
+        let limits = ImageLimits {
+            max_width: 200,
+            ..ImageLimits::default()
+        };
+        assert!(matches!(
+            decode_image(&source, &limits),
+            Err(ImageError::TooLarge)
+        ));
+        assert!(matches!(
+            decode_image(b"plain text", &ImageLimits::default()),
+            Err(ImageError::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn jpeg_metadata_is_stripped_but_orientation_kept() {
+        let jpeg = encoded(gradient(40, 20), ImageFormat::Jpeg);
+        let mut tagged = jpeg[..2].to_vec();
+        tagged.extend_from_slice(&exif_segment(6));
+        tagged.extend_from_slice(&[0xff, JPEG_COMMENT, 0, 7, b'h', b'e', b'l', b'l', b'o']);
+        tagged.extend_from_slice(&jpeg[2..]);
+
+        let stripped = strip_metadata(&tagged).expect("metadata removed");
+        assert!(!stripped
+            .windows(b"GPSLatitude".len())
+            .any(|window| window == b"GPSLatitude"));
+        assert!(!stripped.windows(5).any(|window| window == b"hello"));
+        let image = decode_image(&stripped, &ImageLimits::default()).unwrap();
+        // Orientation 6 rotates by 90 degrees.
+        assert_eq!((image.width(), image.height()), (20, 40));
+
+        assert!(strip_metadata(&jpeg).is_none());
+        assert!(strip_metadata(b"not an image").is_none());
+    }
+
+    #[test]
+    fn png_text_chunks_are_stripped() {
+        let png = encoded(gradient(4, 4), ImageFormat::Png);
+        // Insert a tEXt chunk right after IHDR (8 byte signature + 25 byte chunk).
+        let text = b"Comment\0secret";
+        let mut chunk = (text.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"tEXt");
+        chunk.extend_from_slice(text);
+        chunk.extend_from_slice(&[0; 4]);
+        let mut tagged = png[..33].to_vec();
+        tagged.extend_from_slice(&chunk);
+        tagged.extend_from_slice(&png[33..]);
+
+        let stripped = strip_metadata(&tagged).expect("metadata removed");
+        assert_eq!(stripped, png);
+        assert!(strip_metadata(&png).is_none());
+    }
+}
@@ -1,14 +1,51 @@
 //! Media storage adapters.
+//!
+//! Uploads are content-addressed: an object's key is derived from the BLAKE3 hash of its
+//! bytes (see `content_key`), so identical uploads share one stored object. Objects live in
+//! an S3-compatible bucket (`S3Store`) or, for tests and single-node installs, in a local
+//! directory (`FilesystemStore`). Image previews (thumbnails and blurhash placeholders) are
+//! derived on the CPU and cached in the same store under the source's hash. Media hosted
+//! by federated servers is fetched on first access, verified against its hash and kept in
+//! a size-bounded LRU cache (`RemoteMediaCache`).
 
 use anyhow::Result;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Clone)]
+mod imaging;
+mod preview;
+mod range;
+mod remote;
+mod s3;
+mod store;
+mod upload;
+
+pub use imaging::{
+    decode_image, image_info, placeholder, render_thumbnail, strip_metadata, ImageError, ImageInfo,
+    ImageLimits, ThumbnailMethod, ThumbnailSpec, PREVIEWABLE_TYPES, THUMBNAIL_SIZES,
+};
+pub use preview::{PreviewCache, PreviewError};
+pub use range::{parse_range, ByteRange, RangeNotSatisfiable};
+pub use remote::{RemoteFetcher, RemoteMedia, RemoteMediaCache, RemoteMediaError, RemoteObject};
+pub use s3::S3Store;
+pub use store::{content_key, read_object, ByteStream, FilesystemStore, MediaStore};
+pub use upload::{
+    resolve_content_type, sniff_content_type, stage_upload, StageError, StagedUpload, SNIFF_LENGTH,
+};
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// Connection settings for an S3-compatible bucket.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct MediaConfig {
     pub endpoint: String,
     pub bucket: String,
     pub access_key: String,
     pub secret_key: String,
+    /// Region used in request signatures; most self-hosted stores accept the default.
+    #[serde(default = "default_region")]
+    pub region: String,
 }
 
 pub fn validate_config(cfg: &MediaConfig) -> Result<()> {
@@ -30,6 +67,7 @@ mod tests {
             bucket: "uploads".into(),
             access_key: "minio".into(),
             secret_key: "secret".into(),
+            region: default_region(),
         };
 
         assert!(validate_config(&cfg).is_ok());
@@ -42,6 +80,7 @@ mod tests {
             bucket: "".into(),
             access_key: "minio".into(),
             secret_key: "secret".into(),
+            region: default_region(),
         };
 
         let err = validate_config(&cfg).expect_err("validation should fail");
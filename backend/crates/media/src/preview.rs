@@ -0,0 +1,227 @@
+//! Image previews cached in the media store, keyed by the content hash of their source.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use crate::{
+    imaging::{self, ImageError, ImageInfo, ImageLimits, ThumbnailSpec, THUMBNAIL_SIZES},
+    store::{content_key, read_object, MediaStore},
+};
+
+#[derive(Debug, Error)]
+pub enum PreviewError {
+    #[error("source object not found")]
+    SourceMissing,
+    #[error(transparent)]
+    Image(#[from] ImageError),
+    #[error("preview storage failed: {0}")]
+    Storage(#[from] anyhow::Error),
+}
+
+/// Computes image info and thumbnails once per content hash and stores them next to the
+/// source object under `previews/`.
+#[derive(Clone)]
+pub struct PreviewCache {
+    store: Arc<dyn MediaStore>,
+    limits: ImageLimits,
+    max_source_bytes: u64,
+}
+
+impl PreviewCache {
+    /// `max_source_bytes` bounds how much of a source object is read for decoding.
+    pub fn new(store: Arc<dyn MediaStore>, limits: ImageLimits, max_source_bytes: u64) -> Self {
+        Self {
+            store,
+            limits,
+            max_source_bytes,
+        }
+    }
+
+    fn info_key(content_hash: &str) -> String {
+        format!("previews/{}/info.json", content_key(content_hash))
+    }
+
+    fn thumbnail_key(content_hash: &str, spec: ThumbnailSpec, info: &ImageInfo) -> String {
+        let extension = if info.has_alpha { "png" } else { "jpg" };
+        format!(
+            "previews/{}/{}-{}x{}.{extension}",
+            content_key(content_hash),
+            spec.method.as_str(),
+            spec.width,
+            spec.height
+        )
+    }
+
+    async fn cached(&self, key: &str) -> Result<Option<Vec<u8>>, PreviewError> {
+        match self.store.get(key, None).await? {
+            Some(stream) => Ok(Some(read_object(stream, self.max_source_bytes).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn cached_info(&self, content_hash: &str) -> Result<Option<ImageInfo>, PreviewError> {
+        let Some(bytes) = self.cached(&Self::info_key(content_hash)).await? else {
+            return Ok(None);
+        };
+        // A corrupt entry is recomputed rather than reported.
+        Ok(serde_json::from_slice(&bytes).ok())
+    }
+
+    async fn source(&self, content_hash: &str) -> Result<Vec<u8>, PreviewError> {
+        let stream = self
+            .store
+            .get(&content_key(content_hash), None)
+            .await?
+            .ok_or(PreviewError::SourceMissing)?;
+        Ok(read_object(stream, self.max_source_bytes).await?)
+    }
+
+    async fn store_info(&self, content_hash: &str, info: &ImageInfo) -> Result<(), PreviewError> {
+        let bytes = serde_json::to_vec(info).map_err(anyhow::Error::from)?;
+        self.store
+            .put_bytes(&Self::info_key(content_hash), Bytes::from(bytes))
+            .await?;
+        Ok(())
+    }
+
+    /// Info for the image with `content_hash`, computed from `source` unless cached.
+    pub async fn prime(
+        &self,
+        content_hash: &str,
+        source: Vec<u8>,
+    ) -> Result<ImageInfo, PreviewError> {
+        if let Some(info) = self.cached_info(content_hash).await? {
+            return Ok(info);
+        }
+        let limits = self.limits;
+        let info = tokio::task::spawn_blocking(move || {
+            imaging::decode_image(&source, &limits).and_then(|image| imaging::image_info(&image))
+        })
+        .await
+        .map_err(anyhow::Error::from)??;
+        self.store_info(content_hash, &info).await?;
+        Ok(info)
+    }
+
+    /// Info for the stored image with `content_hash`.
+    pub async fn info(&self, content_hash: &str) -> Result<ImageInfo, PreviewError> {
+        if let Some(info) = self.cached_info(content_hash).await? {
+            return Ok(info);
+        }
+        let source = self.source(content_hash).await?;
+        self.prime(content_hash, source).await
+    }
+
+    /// Encoded thumbnail of the stored image with `content_hash`; its content type is
+    /// `ImageInfo::thumbnail_content_type`.
+    pub async fn thumbnail(
+        &self,
+        content_hash: &str,
+        spec: ThumbnailSpec,
+    ) -> Result<(ImageInfo, Bytes), PreviewError> {
+        let cached_info = self.cached_info(content_hash).await?;
+        if let Some(info) = &cached_info {
+            let key = Self::thumbnail_key(content_hash, spec, info);
+            if let Some(bytes) = self.cached(&key).await? {
+                return Ok((info.clone(), Bytes::from(bytes)));
+            }
+        }
+
+        let source = self.source(content_hash).await?;
+        let limits = self.limits;
+        let (info, rendered) = tokio::task::spawn_blocking(move || {
+            let image = imaging::decode_image(&source, &limits)?;
+            let info = match cached_info {
+                Some(info) => info,
+                None => imaging::image_info(&image)?,
+            };
+            let rendered = imaging::render_thumbnail(&image, spec)?;
+            Ok::<_, ImageError>((info, rendered))
+        })
+        .await
+        .map_err(anyhow::Error::from)??;
+
+        let rendered = Bytes::from(rendered);
+        self.store_info(content_hash, &info).await?;
+        self.store
+            .put_bytes(
+                &Self::thumbnail_key(content_hash, spec, &info),
+                rendered.clone(),
+            )
+            .await?;
+        Ok((info, rendered))
+    }
+
+    /// Delete every preview cached for `content_hash`, once its source has been removed.
+    pub async fn purge(&self, content_hash: &str) -> Result<(), PreviewError> {
+        if let Some(info) = self.cached_info(content_hash).await? {
+            for spec in THUMBNAIL_SIZES {
+                self.store
+                    .delete(&Self::thumbnail_key(content_hash, *spec, &info))
+                    .await?;
+            }
+        }
+        self.store.delete(&Self::info_key(content_hash)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{imaging::ThumbnailMethod, FilesystemStore};
+    use image::{DynamicImage, ImageBuffer, ImageFormat, Rgb};
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn previews_are_computed_once_and_cached_by_hash() {
+        let root = tempfile::tempdir().unwrap();
+        let store: Arc<dyn MediaStore> = Arc::new(FilesystemStore::new(root.path()));
+        let cache = PreviewCache::new(store.clone(), ImageLimits::default(), 1 << 20);
+
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_fn(200, 100, |x, y| {
+            Rgb([x as u8, y as u8, 0])
+        }));
+        let mut source = Cursor::new(Vec::new());
+        image.write_to(&mut source, ImageFormat::Png).unwrap();
+        let source = source.into_inner();
+        let hash = blake3::hash(&source).to_hex().to_string();
+
+        assert!(matches!(
+            cache.info(&hash).await,
+            Err(PreviewError::SourceMissing)
+        ));
+        store
+            .put_bytes(&content_key(&hash), Bytes::from(source))
+            .await
+            .unwrap();
+
+        let spec = ThumbnailSpec::new(96, 96, ThumbnailMethod::Crop);
+        let (info, thumbnail) = cache.thumbnail(&hash, spec).await.unwrap();
+        assert_eq!((info.width, info.height), (200, 100));
+        assert_eq!(cache.info(&hash).await.unwrap(), info);
+
+        // Once cached, previews are served without the source.
+        store.delete(&content_key(&hash)).await.unwrap();
+        let (_, cached) = cache.thumbnail(&hash, spec).await.unwrap();
+        assert_eq!(cached, thumbnail);
+        assert!(matches!(
+            cache
+                .thumbnail(&hash, ThumbnailSpec::new(32, 32, ThumbnailMethod::Crop))
+                .await,
+            Err(PreviewError::SourceMissing)
+        ));
+
+        cache.purge(&hash).await.unwrap();
+        assert!(matches!(
+            cache.thumbnail(&hash, spec).await,
+            Err(PreviewError::SourceMissing)
+        ));
+        assert!(matches!(
+            cache.info(&hash).await,
+            Err(PreviewError::SourceMissing)
+        ));
+    }
+}
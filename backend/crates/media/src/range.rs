@@ -0,0 +1,120 @@
+//! Single-range `Range: bytes=` requests.
+
+use thiserror::Error;
+
+/// Inclusive byte range within an object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// Number of bytes covered by the range.
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// `Content-Range` value for an object of `size` bytes.
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("requested range is outside the object")]
+pub struct RangeNotSatisfiable;
+
+/// Parse a `Range` header for an object of `size` bytes.
+///
+/// Headers this server does not handle (other units, multiple ranges, malformed values)
+/// yield `Ok(None)` so the caller serves the whole object, as RFC 9110 allows.
+pub fn parse_range(header: &str, size: u64) -> Result<Option<ByteRange>, RangeNotSatisfiable> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // Suffix range: the last `end` bytes.
+        let Ok(suffix) = end.parse::<u64>() else {
+            return Ok(None);
+        };
+        if suffix == 0 || size == 0 {
+            return Err(RangeNotSatisfiable);
+        }
+        return Ok(Some(ByteRange {
+            start: size.saturating_sub(suffix),
+            end: size - 1,
+        }));
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return Ok(None);
+    };
+    let end = if end.is_empty() {
+        None
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => Some(end),
+            _ => return Ok(None),
+        }
+    };
+    if start >= size {
+        return Err(RangeNotSatisfiable);
+    }
+    Ok(Some(ByteRange {
+        start,
+        end: end.map_or(size - 1, |end| end.min(size - 1)),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bounded_open_and_suffix_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-9", 100),
+            Ok(Some(ByteRange { start: 0, end: 9 }))
+        );
+        assert_eq!(
+            parse_range("bytes=90-", 100),
+            Ok(Some(ByteRange { start: 90, end: 99 }))
+        );
+        assert_eq!(
+            parse_range("bytes=50-500", 100),
+            Ok(Some(ByteRange { start: 50, end: 99 }))
+        );
+        assert_eq!(
+            parse_range("bytes=-10", 100),
+            Ok(Some(ByteRange { start: 90, end: 99 }))
+        );
+        assert_eq!(
+            parse_range("bytes=-500", 100),
+            Ok(Some(ByteRange { start: 0, end: 99 }))
+        );
+        assert_eq!(
+            ByteRange { start: 90, end: 99 }.content_range(100),
+            "bytes 90-99/100"
+        );
+    }
+
+    #[test]
+    fn unsupported_headers_are_ignored_and_out_of_bounds_rejected() {
+        assert_eq!(parse_range("items=0-1", 100), Ok(None));
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), Ok(None));
+        assert_eq!(parse_range("bytes=9-1", 100), Ok(None));
+        assert_eq!(parse_range("bytes=abc", 100), Ok(None));
+        assert_eq!(parse_range("bytes=100-", 100), Err(RangeNotSatisfiable));
+        assert_eq!(parse_range("bytes=-0", 100), Err(RangeNotSatisfiable));
+        assert_eq!(parse_range("bytes=0-", 0), Err(RangeNotSatisfiable));
+    }
+}
@@ -0,0 +1,353 @@
+//! Local cache of media fetched from federated servers.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    range::ByteRange,
+    store::{content_key, ByteStream, MediaStore},
+    upload::{resolve_content_type, stage_upload, StageError},
+};
+
+/// Longest server name or media id accepted in a remote reference.
+const MAX_REFERENCE_LENGTH: usize = 255;
+
+/// An object as served by a peer, before it has been verified.
+pub struct RemoteObject {
+    pub content_type: Option<String>,
+    /// Hex BLAKE3 digest the peer claims for the body.
+    pub content_hash: String,
+    pub body: ByteStream,
+}
+
+/// Downloads media from the server that hosts it.
+#[async_trait]
+pub trait RemoteFetcher: Send + Sync {
+    /// Fetch `media_id` from `server`; `None` when the peer does not know it.
+    async fn fetch(&self, server: &str, media_id: &str) -> anyhow::Result<Option<RemoteObject>>;
+}
+
+/// A verified remote object held in the local store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteMedia {
+    pub server: String,
+    pub media_id: String,
+    pub content_hash: String,
+    pub content_type: String,
+    pub size: u64,
+}
+
+impl RemoteMedia {
+    /// Store key of the cached object. Remote objects live apart from local uploads so
+    /// evicting one never removes a file a local upload shares.
+    pub fn key(&self) -> String {
+        format!("remote/{}/{}", self.server, content_key(&self.content_hash))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RemoteMediaError {
+    #[error("invalid remote media reference")]
+    InvalidReference,
+    #[error("remote media not found")]
+    NotFound,
+    #[error("remote media exceeds the {0} byte limit")]
+    TooLarge(u64),
+    #[error("remote media hash mismatch (expected {expected}, got {actual})")]
+    HashMismatch { expected: String, actual: String },
+    #[error("fetching remote media failed: {0}")]
+    Fetch(String),
+    #[error("remote media storage failed: {0}")]
+    Storage(#[from] anyhow::Error),
+}
+
+struct CacheState {
+    entries: LruCache<(String, String), RemoteMedia>,
+    total_bytes: u64,
+}
+
+/// Fetches remote media on first access and keeps it in the local store, evicting the
+/// least recently used objects once `max_total_bytes` is exceeded.
+///
+/// The index lives in memory; objects left behind by a previous run are picked up again
+/// when the same media is next requested.
+pub struct RemoteMediaCache {
+    store: Arc<dyn MediaStore>,
+    fetcher: Arc<dyn RemoteFetcher>,
+    staging_dir: PathBuf,
+    max_object_bytes: u64,
+    max_total_bytes: u64,
+    state: Mutex<CacheState>,
+}
+
+impl RemoteMediaCache {
+    pub fn new(
+        store: Arc<dyn MediaStore>,
+        fetcher: Arc<dyn RemoteFetcher>,
+        staging_dir: PathBuf,
+        max_object_bytes: u64,
+        max_total_bytes: u64,
+    ) -> Self {
+        Self {
+            store,
+            fetcher,
+            staging_dir,
+            max_object_bytes,
+            max_total_bytes,
+            state: Mutex::new(CacheState {
+                entries: LruCache::unbounded(),
+                total_bytes: 0,
+            }),
+        }
+    }
+
+    /// Bytes currently held by the cache.
+    pub fn total_bytes(&self) -> u64 {
+        self.state
+            .lock()
+            .expect("remote media cache poisoned")
+            .total_bytes
+    }
+
+    /// Metadata for `media_id` hosted by `server`, fetching and verifying the object on
+    /// first access.
+    pub async fn get(&self, server: &str, media_id: &str) -> Result<RemoteMedia, RemoteMediaError> {
+        if !valid_reference(server) || !valid_reference(media_id) {
+            return Err(RemoteMediaError::InvalidReference);
+        }
+        let cache_key = (server.to_string(), media_id.to_string());
+        if let Some(entry) = self
+            .state
+            .lock()
+            .expect("remote media cache poisoned")
+            .entries
+            .get(&cache_key)
+        {
+            return Ok(entry.clone());
+        }
+
+        let object = self
+            .fetcher
+            .fetch(server, media_id)
+            .await
+            .map_err(|err| RemoteMediaError::Fetch(err.to_string()))?
+            .ok_or(RemoteMediaError::NotFound)?;
+        let staged = stage_upload(&self.staging_dir, object.body, self.max_object_bytes)
+            .await
+            .map_err(|err| match err {
+                StageError::TooLarge(limit) => RemoteMediaError::TooLarge(limit),
+                StageError::Body(reason) => RemoteMediaError::Fetch(reason),
+                StageError::Io(err) => RemoteMediaError::Storage(err.into()),
+            })?;
+        if !staged
+            .content_hash
+            .eq_ignore_ascii_case(object.content_hash.trim())
+        {
+            return Err(RemoteMediaError::HashMismatch {
+                expected: object.content_hash,
+                actual: staged.content_hash.clone(),
+            });
+        }
+
+        let entry = RemoteMedia {
+            server: server.to_string(),
+            media_id: media_id.to_string(),
+            content_hash: staged.content_hash.clone(),
+            content_type: resolve_content_type(object.content_type.as_deref(), staged.head()),
+            size: staged.size,
+        };
+        self.store
+            .put_file(&entry.key(), staged.path(), staged.size)
+            .await?;
+        self.insert(cache_key, entry.clone()).await;
+        Ok(entry)
+    }
+
+    /// Stream the cached object, or only `range` of it. An object removed from the store
+    /// behind the cache's back is forgotten and reported as not found.
+    pub async fn open(
+        &self,
+        entry: &RemoteMedia,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream, RemoteMediaError> {
+        match self.store.get(&entry.key(), range).await? {
+            Some(stream) => Ok(stream),
+            None => {
+                let mut state = self.state.lock().expect("remote media cache poisoned");
+                let cache_key = (entry.server.clone(), entry.media_id.clone());
+                if let Some(removed) = state.entries.pop(&cache_key) {
+                    state.total_bytes -= removed.size;
+                }
+                Err(RemoteMediaError::NotFound)
+            }
+        }
+    }
+
+    async fn insert(&self, cache_key: (String, String), entry: RemoteMedia) {
+        let evicted = {
+            let mut state = self.state.lock().expect("remote media cache poisoned");
+            state.total_bytes += entry.size;
+            if let Some(previous) = state.entries.put(cache_key.clone(), entry) {
+                state.total_bytes -= previous.size;
+            }
+            let mut evicted = Vec::new();
+            while state.total_bytes > self.max_total_bytes {
+                // Never evict the entry that was just inserted.
+                if state
+                    .entries
+                    .peek_lru()
+                    .is_some_and(|(key, _)| *key == cache_key)
+                {
+                    break;
+                }
+                let Some((_, removed)) = state.entries.pop_lru() else {
+                    break;
+                };
+                state.total_bytes -= removed.size;
+                let object_key = removed.key();
+                // Identical content from one server shares an object; keep it while any
+                // other entry still points at it.
+                if !state
+                    .entries
+                    .iter()
+                    .any(|(_, other)| other.key() == object_key)
+                {
+                    evicted.push(object_key);
+                }
+            }
+            evicted
+        };
+
+        for key in evicted {
+            if let Err(err) = self.store.delete(&key).await {
+                tracing::warn!(%err, key, "failed to delete evicted remote media");
+            }
+        }
+    }
+}
+
+fn valid_reference(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REFERENCE_LENGTH
+        && value != "."
+        && value != ".."
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::store::FilesystemStore;
+
+    /// Serves fixed bodies; `lie` makes it claim the wrong hash.
+    struct StaticPeer {
+        objects: HashMap<String, &'static [u8]>,
+        lie: bool,
+    }
+
+    #[async_trait]
+    impl RemoteFetcher for StaticPeer {
+        async fn fetch(
+            &self,
+            _server: &str,
+            media_id: &str,
+        ) -> anyhow::Result<Option<RemoteObject>> {
+            let Some(body) = self.objects.get(media_id).copied() else {
+                return Ok(None);
+            };
+            let content_hash = if self.lie {
+                blake3::hash(b"something else").to_hex().to_string()
+            } else {
+                blake3::hash(body).to_hex().to_string()
+            };
+            Ok(Some(RemoteObject {
+                content_type: Some("text/plain".into()),
+                content_hash,
+                body: Box::pin(futures::stream::iter([Ok(Bytes::from_static(body))])),
+            }))
+        }
+    }
+
+    fn cache(root: &std::path::Path, lie: bool, max_total_bytes: u64) -> RemoteMediaCache {
+        let objects = HashMap::from([
+            ("a".to_string(), &b"0123456789"[..]),
+            ("b".to_string(), &b"abcdefghij"[..]),
+            ("big".to_string(), &[b'x'; 64][..]),
+        ]);
+        RemoteMediaCache::new(
+            Arc::new(FilesystemStore::new(root.join("objects"))),
+            Arc::new(StaticPeer { objects, lie }),
+            root.join("staging"),
+            32,
+            max_total_bytes,
+        )
+    }
+
+    #[tokio::test]
+    async fn fetches_verify_hashes_and_limits() {
+        let root = tempfile::tempdir().unwrap();
+        let honest = cache(root.path(), false, 1024);
+        let entry = honest.get("peer.example", "a").await.unwrap();
+        assert_eq!(
+            (entry.size, entry.content_type.as_str()),
+            (10, "text/plain")
+        );
+        let stream = honest
+            .open(&entry, Some(ByteRange { start: 2, end: 4 }))
+            .await
+            .unwrap();
+        let body = crate::store::read_object(stream, 1024).await.unwrap();
+        assert_eq!(body, b"234");
+
+        assert!(matches!(
+            honest.get("peer.example", "big").await,
+            Err(RemoteMediaError::TooLarge(32))
+        ));
+        assert!(matches!(
+            honest.get("peer.example", "missing").await,
+            Err(RemoteMediaError::NotFound)
+        ));
+        assert!(matches!(
+            honest.get("peer.example", "../a").await,
+            Err(RemoteMediaError::InvalidReference)
+        ));
+
+        let lying = cache(root.path(), true, 1024);
+        assert!(matches!(
+            lying.get("peer.example", "b").await,
+            Err(RemoteMediaError::HashMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_objects_are_evicted() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = cache(root.path(), false, 20);
+        let a = cache.get("peer.example", "a").await.unwrap();
+        let b = cache.get("peer.example", "b").await.unwrap();
+        assert_eq!(cache.total_bytes(), 20);
+
+        // Touch `a` so `b` becomes the eviction candidate, then overflow the cache.
+        cache.get("peer.example", "a").await.unwrap();
+        cache.get("other.example", "b").await.unwrap();
+        assert_eq!(cache.total_bytes(), 20);
+        assert!(cache.open(&a, None).await.is_ok());
+        assert!(matches!(
+            cache.open(&b, None).await,
+            Err(RemoteMediaError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+//! Object storage backends.
+
+use std::{
+    io::{self, SeekFrom},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::range::ByteRange;
+
+/// Streamed object body.
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// Object key for content with the given hex BLAKE3 digest. The two-level fan-out keeps
+/// directory listings small on the filesystem backend.
+pub fn content_key(hash_hex: &str) -> String {
+    match (hash_hex.get(..2), hash_hex.get(2..4)) {
+        (Some(first), Some(second)) => format!("{first}/{second}/{hash_hex}"),
+        _ => hash_hex.to_string(),
+    }
+}
+
+/// Blob storage for media objects, addressed by `content_key`.
+#[async_trait]
+pub trait MediaStore: Send + Sync {
+    /// Store the file at `path` (`size` bytes) under `key`. Keys are content hashes, so an
+    /// existing object is left untouched.
+    async fn put_file(&self, key: &str, path: &Path, size: u64) -> Result<()>;
+
+    /// Store `bytes` under `key`, replacing any existing object. Used for small derived
+    /// objects such as thumbnails.
+    async fn put_bytes(&self, key: &str, bytes: Bytes) -> Result<()>;
+
+    /// Stream the object, or only `range` of it. `None` when no object has that key.
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<Option<ByteStream>>;
+
+    /// Remove the object; removing a missing object succeeds.
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Read a whole object into memory, failing once it exceeds `max_bytes`.
+pub async fn read_object(mut stream: ByteStream, max_bytes: u64) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if (out.len() + chunk.len()) as u64 > max_bytes {
+            anyhow::bail!("object exceeds {max_bytes} bytes");
+        }
+        out.extend_from_slice(&chunk);
+    }
+    Ok(out)
+}
+
+/// Stores objects as files below a root directory.
+#[derive(Debug, Clone)]
+pub struct FilesystemStore {
+    root: PathBuf,
+}
+
+impl FilesystemStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn object_path(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+        if key.is_empty()
+            || relative
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            anyhow::bail!("invalid media key '{key}'");
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl MediaStore for FilesystemStore {
+    async fn put_file(&self, key: &str, path: &Path, _size: u64) -> Result<()> {
+        let target = self.object_path(key)?;
+        let source = path.to_path_buf();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            if target.exists() {
+                return Ok(());
+            }
+            let parent = target.parent().context("media key has no parent")?;
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+            // Copy next to the target and rename so readers never see a partial object.
+            let mut staged = tempfile::NamedTempFile::new_in(parent)?;
+            std::io::copy(&mut std::fs::File::open(&source)?, staged.as_file_mut())?;
+            staged.persist(&target).map_err(|err| err.error)?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn put_bytes(&self, key: &str, bytes: Bytes) -> Result<()> {
+        let target = self.object_path(key)?;
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let parent = target.parent().context("media key has no parent")?;
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+            let mut staged = tempfile::NamedTempFile::new_in(parent)?;
+            std::io::Write::write_all(staged.as_file_mut(), &bytes)?;
+            staged.persist(&target).map_err(|err| err.error)?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<Option<ByteStream>> {
+        let path = self.object_path(key)?;
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("failed to open {key}")),
+        };
+        let stream: ByteStream = match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                Box::pin(ReaderStream::new(file.take(range.length())))
+            }
+            None => Box::pin(ReaderStream::new(file)),
+        };
+        Ok(Some(stream))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.object_path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("failed to remove {key}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    async fn collect(stream: ByteStream) -> Vec<u8> {
+        stream
+            .try_fold(Vec::new(), |mut acc, chunk| async move {
+                acc.extend_from_slice(&chunk);
+                Ok(acc)
+            })
+            .await
+            .expect("stream reads")
+    }
+
+    #[tokio::test]
+    async fn filesystem_store_round_trips_objects_and_ranges() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FilesystemStore::new(root.path().join("objects"));
+        let source = root.path().join("upload");
+        std::fs::write(&source, b"hello media world").unwrap();
+
+        let key = content_key(&blake3::hash(b"hello media world").to_hex());
+        store.put_file(&key, &source, 17).await.unwrap();
+        // Content addressing makes a second put a no-op.
+        store.put_file(&key, &source, 17).await.unwrap();
+
+        let whole = store.get(&key, None).await.unwrap().expect("object exists");
+        assert_eq!(collect(whole).await, b"hello media world");
+        let part = store
+            .get(&key, Some(ByteRange { start: 6, end: 10 }))
+            .await
+            .unwrap()
+            .expect("object exists");
+        assert_eq!(collect(part).await, b"media");
+
+        store.delete(&key).await.unwrap();
+        store.delete(&key).await.unwrap();
+        assert!(store.get(&key, None).await.unwrap().is_none());
+        assert!(store.get("../escape", None).await.is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Staging streamed uploads and detecting their content type.
+
+use std::{fmt::Display, path::Path};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use tempfile::TempPath;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+use crate::store::content_key;
+
+/// Leading bytes of an upload kept for content sniffing.
+pub const SNIFF_LENGTH: usize = 512;
+
+/// Declared types that browsers execute or render as documents. Uploads claiming them are
+/// stored as opaque bytes unless sniffing identifies something safer.
+const ACTIVE_CONTENT_TYPES: &[&str] = &[
+    "text/html",
+    "text/javascript",
+    "text/xml",
+    "application/javascript",
+    "application/xhtml+xml",
+    "application/xml",
+    "image/svg+xml",
+];
+
+#[derive(Debug, Error)]
+pub enum StageError {
+    #[error("upload exceeds the {0} byte limit")]
+    TooLarge(u64),
+    #[error("upload body failed: {0}")]
+    Body(String),
+    #[error("failed to stage upload: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// An upload written to a temporary file. The file is removed when this value is dropped.
+#[derive(Debug)]
+pub struct StagedUpload {
+    path: TempPath,
+    /// Hex BLAKE3 digest of the upload.
+    pub content_hash: String,
+    pub size: u64,
+    head: Vec<u8>,
+}
+
+impl StagedUpload {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The first `SNIFF_LENGTH` bytes of the upload.
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+
+    /// Object key the upload is stored under.
+    pub fn key(&self) -> String {
+        content_key(&self.content_hash)
+    }
+}
+
+/// Write `body` to a temporary file in `dir`, hashing it on the way. Fails with
+/// `StageError::TooLarge` as soon as more than `max_bytes` arrive.
+pub async fn stage_upload<S, E>(
+    dir: &Path,
+    body: S,
+    max_bytes: u64,
+) -> Result<StagedUpload, StageError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Display,
+{
+    tokio::fs::create_dir_all(dir).await?;
+    let (file, path) = tempfile::NamedTempFile::new_in(dir)?.into_parts();
+    let mut file = tokio::fs::File::from_std(file);
+    let mut hasher = blake3::Hasher::new();
+    let mut head = Vec::with_capacity(SNIFF_LENGTH);
+    let mut size = 0u64;
+
+    let mut body = std::pin::pin!(body);
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|err| StageError::Body(err.to_string()))?;
+        size += chunk.len() as u64;
+        if size > max_bytes {
+            return Err(StageError::TooLarge(max_bytes));
+        }
+        hasher.update(&chunk);
+        if head.len() < SNIFF_LENGTH {
+            let take = (SNIFF_LENGTH - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..take]);
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    Ok(StagedUpload {
+        path,
+        content_hash: hasher.finalize().to_hex().to_string(),
+        size,
+        head,
+    })
+}
+
+/// Detect the content type from the leading bytes of an object.
+pub fn sniff_content_type(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"ID3", "audio/mpeg"),
+    ];
+
+    if let Some((_, content_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| head.starts_with(signature))
+    {
+        return Some(content_type);
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") {
+        match &head[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            _ => {}
+        }
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return Some(match &head[8..12] {
+            b"avif" => "image/avif",
+            b"heic" | b"heix" => "image/heic",
+            b"qt  " => "video/quicktime",
+            b"M4A " => "audio/mp4",
+            _ => "video/mp4",
+        });
+    }
+    if head.len() >= 2 && head[0] == 0xff && head[1] & 0xe0 == 0xe0 {
+        return Some("audio/mpeg");
+    }
+    if !head.is_empty() && looks_like_text(head) {
+        return Some("text/plain");
+    }
+    None
+}
+
+fn looks_like_text(head: &[u8]) -> bool {
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // The head may end inside a multi-byte character.
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&head[..err.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+    text.chars()
+        .all(|ch| !ch.is_control() || matches!(ch, '\n' | '\r' | '\t' | '\u{0c}'))
+}
+
+/// Content type an upload is stored and served with. Recognised binary formats win over
+/// the declared type; text and unknown data keep the declared type unless it is one
+/// browsers would execute.
+pub fn resolve_content_type(declared: Option<&str>, head: &[u8]) -> String {
+    let sniffed = sniff_content_type(head);
+    if let Some(sniffed) = sniffed.filter(|sniffed| *sniffed != "text/plain") {
+        return sniffed.to_string();
+    }
+
+    let declared = declared
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .filter(|value| {
+            value.split_once('/').is_some_and(|(kind, subtype)| {
+                !kind.is_empty()
+                    && !subtype.is_empty()
+                    && value.chars().all(|ch| {
+                        ch.is_ascii_alphanumeric() || matches!(ch, '/' | '.' | '+' | '-' | '_')
+                    })
+            })
+        })
+        .filter(|value| !ACTIVE_CONTENT_TYPES.contains(&value.as_str()));
+
+    match (declared, sniffed) {
+        (Some(declared), Some(_)) if declared.starts_with("text/") => declared,
+        (Some(declared), None) => declared,
+        (_, Some(sniffed)) => sniffed.to_string(),
+        (_, None) => "application/octet-stream".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffing_prefers_magic_bytes_over_declared_types() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(sniff_content_type(png), Some("image/png"));
+        assert_eq!(resolve_content_type(Some("text/html"), png), "image/png");
+        assert_eq!(
+            sniff_content_type(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(
+            sniff_content_type(b"\0\0\0\x18ftypisom\0\0\x02\0"),
+            Some("video/mp4")
+        );
+
+        assert_eq!(
+            resolve_content_type(Some("text/markdown; charset=utf-8"), b"# notes"),
+            "text/markdown"
+        );
+        assert_eq!(
+            resolve_content_type(Some("text/html"), b"<script>alert(1)</script>"),
+            "text/plain"
+        );
+        assert_eq!(
+            resolve_content_type(Some("image/png"), b"plain words"),
+            "text/plain"
+        );
+        assert_eq!(
+            resolve_content_type(None, b"\0\x01\x02binary"),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            resolve_content_type(Some("application/x-custom"), b"\0\x01\x02binary"),
+            "application/x-custom"
+        );
+    }
+
+    #[tokio::test]
+    async fn staging_hashes_uploads_and_enforces_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunks = || {
+            futures::stream::iter([
+                Ok::<_, std::io::Error>(Bytes::from_static(b"hello ")),
+                Ok(Bytes::from_static(b"world")),
+            ])
+        };
+
+        let staged = stage_upload(dir.path(), chunks(), 64).await.unwrap();
+        assert_eq!(staged.size, 11);
+        assert_eq!(
+            staged.content_hash,
+            blake3::hash(b"hello world").to_hex().to_string()
+        );
+        assert_eq!(staged.head(), b"hello world");
+        assert_eq!(std::fs::read(staged.path()).unwrap(), b"hello world");
+        let path = staged.path().to_path_buf();
+        drop(staged);
+        assert!(!path.exists());
+
+        let err = stage_upload(dir.path(), chunks(), 8).await.unwrap_err();
+        assert!(matches!(err, StageError::TooLarge(8)));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
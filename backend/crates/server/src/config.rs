@@ -19,6 +19,10 @@ pub enum ConfigError {
     InvalidFederationConfig(String),
     #[error("invalid MLS configuration: {0}")]
     InvalidMlsConfig(String),
+    #[error("invalid media configuration: {0}")]
+    InvalidMediaConfig(String),
+    #[error("invalid voice configuration: {0}")]
+    InvalidVoiceConfig(String),
 }
 
 #[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, Default)]
@@ -47,6 +51,8 @@ pub struct MessagingConfig {
     pub max_messages_per_user_per_window: usize,
     pub max_messages_per_ip_per_window: usize,
     pub rate_limit_window_secs: u64,
+    /// How often guild retention policies are enforced; zero disables the reaper.
+    pub retention_sweep_interval_secs: u64,
 }
 
 impl Default for MessagingConfig {
@@ -55,21 +61,63 @@ impl Default for MessagingConfig {
             max_messages_per_user_per_window: 60,
             max_messages_per_ip_per_window: 200,
             rate_limit_window_secs: 60,
+            retention_sweep_interval_secs: 3600,
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(default)]
 pub struct FederationConfig {
+    /// Base64-encoded ed25519 signing key used to sign outbound events and requests.
+    /// An ephemeral key is generated at startup when omitted.
+    pub signing_key: Option<String>,
+    /// Identifier published alongside the signing key (`ed25519:{key_id}`).
+    pub key_id: String,
+    /// Retired verifying keys still published so peers can verify older events.
+    pub old_keys: Vec<FederationOldKeyConfig>,
     pub trusted_servers: Vec<FederatedServerConfig>,
 }
 
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            signing_key: None,
+            key_id: "1".to_string(),
+            old_keys: Vec::new(),
+            trusted_servers: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FederationOldKeyConfig {
+    pub key_id: String,
+    pub verifying_key: String,
+    /// Milliseconds since the epoch after which the key stopped signing events.
+    pub expired_ts: i64,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct FederatedServerConfig {
     pub server_name: String,
+    /// Pinned key id; leave unset (with `verifying_key`) to discover keys via `/federation/keys`.
+    #[serde(default)]
     pub key_id: String,
-    pub verifying_key: String,
+    #[serde(default)]
+    pub verifying_key: Option<String>,
+    /// Base URL for outbound federation requests; defaults to `https://{server_name}`.
+    #[serde(default)]
+    pub base_url: Option<String>,
+}
+
+impl FederatedServerConfig {
+    pub fn base_url(&self) -> String {
+        self.base_url
+            .as_deref()
+            .map(|url| url.trim_end_matches('/').to_string())
+            .unwrap_or_else(|| format!("https://{}", self.server_name))
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Default)]
@@ -95,6 +143,8 @@ pub struct ServerConfig {
     pub messaging: MessagingConfig,
     pub federation: FederationConfig,
     pub mls: MlsConfig,
+    pub media: MediaConfig,
+    pub voice: VoiceConfig,
     pub database_url: Option<String>,
     pub session: SessionConfig,
 }
@@ -112,6 +162,8 @@ impl Default for ServerConfig {
             messaging: MessagingConfig::default(),
             federation: FederationConfig::default(),
             mls: MlsConfig::default(),
+            media: MediaConfig::default(),
+            voice: VoiceConfig::default(),
             database_url: None,
             session: SessionConfig::default(),
         }
@@ -149,8 +201,21 @@ impl ServerConfig {
                 "messaging.rate_limit_window_secs",
                 defaults.messaging.rate_limit_window_secs as i64,
             )?
+            .set_default(
+                "messaging.retention_sweep_interval_secs",
+                defaults.messaging.retention_sweep_interval_secs as i64,
+            )?
             .set_default("mls.enabled", defaults.mls.enabled)?
-            .set_default("mls.ciphersuite", defaults.mls.ciphersuite.clone())?;
+            .set_default("mls.ciphersuite", defaults.mls.ciphersuite.clone())?
+            .set_default("media.backend", defaults.media.backend.as_str())?
+            .set_default(
+                "media.max_upload_bytes",
+                defaults.media.max_upload_bytes as i64,
+            )?
+            .set_default(
+                "media.remote_cache_max_bytes",
+                defaults.media.remote_cache_max_bytes as i64,
+            )?;
 
         let settings: ServerConfig = builder.build()?.try_deserialize()?;
         settings.validate()?;
@@ -207,30 +272,88 @@ impl ServerConfig {
             verifying_key_from_base64(key)
                 .map_err(|err| ConfigError::InvalidSessionKey(err.to_string()))?;
         }
-        if self.mls.enabled {
-            if self.mls.ciphersuite.trim().is_empty() {
-                return Err(ConfigError::InvalidMlsConfig(
-                    "mls.ciphersuite must be provided when MLS is enabled".into(),
-                ));
+        if self.mls.enabled && self.mls.ciphersuite.trim().is_empty() {
+            return Err(ConfigError::InvalidMlsConfig(
+                "mls.ciphersuite must be provided when MLS is enabled".into(),
+            ));
+        }
+        if self.media.backend != MediaBackend::Disabled && self.media.max_upload_bytes == 0 {
+            return Err(ConfigError::InvalidMediaConfig(
+                "media.max_upload_bytes must be greater than zero".into(),
+            ));
+        }
+        match self.media.backend {
+            MediaBackend::Disabled => {}
+            MediaBackend::Filesystem => {
+                if self
+                    .media
+                    .path
+                    .as_deref()
+                    .is_none_or(|path| path.trim().is_empty())
+                {
+                    return Err(ConfigError::InvalidMediaConfig(
+                        "media.path is required for the filesystem backend".into(),
+                    ));
+                }
+            }
+            MediaBackend::S3 => {
+                let s3 = self.media.s3.as_ref().ok_or_else(|| {
+                    ConfigError::InvalidMediaConfig(
+                        "media.s3 settings are required for the s3 backend".into(),
+                    )
+                })?;
+                openguild_media::validate_config(s3)
+                    .map_err(|err| ConfigError::InvalidMediaConfig(err.to_string()))?;
             }
-            if self.mls.identities.is_empty() {
-                return Err(ConfigError::InvalidMlsConfig(
-                    "mls.identities must include at least one identity when MLS is enabled".into(),
+        }
+        if let Some(url) = self
+            .voice
+            .sfu_url
+            .as_deref()
+            .filter(|url| !url.trim().is_empty())
+        {
+            let parsed = reqwest::Url::parse(url).map_err(|err| {
+                ConfigError::InvalidVoiceConfig(format!("invalid voice.sfu_url '{url}': {err}"))
+            })?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(ConfigError::InvalidVoiceConfig(
+                    "voice.sfu_url must use http or https".into(),
                 ));
             }
         }
+        if self.federation.key_id.trim().is_empty() {
+            return Err(ConfigError::InvalidFederationConfig(
+                "federation.key_id cannot be empty".into(),
+            ));
+        }
+        if let Some(key) = &self.federation.signing_key {
+            signing_key_from_base64(key).map_err(|err| {
+                ConfigError::InvalidFederationConfig(format!("invalid signing key: {err}"))
+            })?;
+        }
+        for old in &self.federation.old_keys {
+            verifying_key_from_base64(&old.verifying_key).map_err(|err| {
+                ConfigError::InvalidFederationConfig(format!(
+                    "invalid old verifying key '{}': {}",
+                    old.key_id, err
+                ))
+            })?;
+        }
         for peer in &self.federation.trusted_servers {
             if peer.server_name.trim().is_empty() {
                 return Err(ConfigError::InvalidFederationConfig(
                     "trusted server entries require a server_name".into(),
                 ));
             }
+            let Some(verifying_key) = &peer.verifying_key else {
+                continue;
+            };
             if peer.key_id.trim().is_empty() {
                 return Err(ConfigError::InvalidFederationConfig(
-                    "trusted server entries require a key_id".into(),
+                    "trusted server entries with a verifying_key require a key_id".into(),
                 ));
             }
-            verifying_key_from_base64(&peer.verifying_key).map_err(|err| {
+            verifying_key_from_base64(verifying_key).map_err(|err| {
                 ConfigError::InvalidFederationConfig(format!(
                     "invalid verifying key for '{}': {}",
                     peer.server_name, err
@@ -377,6 +500,7 @@ mod tests {
         assert_eq!(config.messaging.max_messages_per_user_per_window, 60);
         assert_eq!(config.messaging.max_messages_per_ip_per_window, 200);
         assert_eq!(config.messaging.rate_limit_window_secs, 60);
+        assert_eq!(config.messaging.retention_sweep_interval_secs, 3600);
         assert!(config.database_url.is_none());
         assert!(config.session.active_signing_key.is_none());
         assert!(config.session.fallback_verifying_keys.is_empty());
@@ -516,18 +640,67 @@ mod tests {
         cfg.federation.trusted_servers.push(FederatedServerConfig {
             server_name: "".into(),
             key_id: "".into(),
-            verifying_key: "invalid".into(),
+            verifying_key: Some("invalid".into()),
+            base_url: None,
         });
         let err = cfg.validate().unwrap_err();
         assert!(matches!(err, ConfigError::InvalidFederationConfig(_)));
     }
+
+    #[test]
+    fn federation_signing_key_must_decode() {
+        let mut cfg = ServerConfig::default();
+        cfg.federation.signing_key = Some("not-a-key".into());
+        let err = cfg.validate().unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidFederationConfig(_)));
+    }
+
+    #[test]
+    fn media_backends_require_their_settings() {
+        let mut cfg = ServerConfig::default();
+        assert_eq!(cfg.media.backend, MediaBackend::Disabled);
+        assert!(cfg.validate().is_ok());
+
+        cfg.media.backend = MediaBackend::Filesystem;
+        let err = cfg.validate().unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidMediaConfig(_)));
+        cfg.media.path = Some("/var/lib/openguild/media".into());
+        assert!(cfg.validate().is_ok());
+
+        cfg.media.backend = MediaBackend::S3;
+        let err = cfg.validate().unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidMediaConfig(_)));
+        cfg.media.s3 = Some(openguild_media::MediaConfig {
+            endpoint: "http://minio.local:9000".into(),
+            bucket: "media".into(),
+            access_key: "minio".into(),
+            secret_key: "secret".into(),
+            region: "us-east-1".into(),
+        });
+        assert!(cfg.validate().is_ok());
+
+        cfg.media.max_upload_bytes = 0;
+        let err = cfg.validate().unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidMediaConfig(_)));
+    }
+
+    #[test]
+    fn voice_sfu_url_must_be_http() {
+        let mut cfg = ServerConfig::default();
+        assert!(cfg.voice.sfu_url.is_none());
+
+        cfg.voice.sfu_url = Some("ftp://sfu.local".into());
+        let err = cfg.validate().unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidVoiceConfig(_)));
+        cfg.voice.sfu_url = Some("http://sfu.local:7880/control".into());
+        assert!(cfg.validate().is_ok());
+    }
 }
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(default)]
 pub struct MlsConfig {
     pub enabled: bool,
     pub ciphersuite: String,
-    pub identities: Vec<String>,
 }
 
 impl Default for MlsConfig {
@@ -535,7 +708,65 @@ impl Default for MlsConfig {
         Self {
             enabled: false,
             ciphersuite: "MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519".into(),
-            identities: Vec::new(),
         }
     }
 }
+
+/// Where uploaded media is stored.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaBackend {
+    #[default]
+    Disabled,
+    Filesystem,
+    S3,
+}
+
+impl MediaBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaBackend::Disabled => "disabled",
+            MediaBackend::Filesystem => "filesystem",
+            MediaBackend::S3 => "s3",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct MediaConfig {
+    pub backend: MediaBackend,
+    /// Object directory for the filesystem backend.
+    pub path: Option<String>,
+    /// Bucket settings for the s3 backend.
+    pub s3: Option<openguild_media::MediaConfig>,
+    /// Directory uploads are streamed into before they are stored; defaults to
+    /// `{path}/staging` for the filesystem backend and the system temp directory otherwise.
+    pub staging_path: Option<String>,
+    pub max_upload_bytes: u64,
+    /// Space the cache of media fetched from federation peers may use.
+    pub remote_cache_max_bytes: u64,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            backend: MediaBackend::Disabled,
+            path: None,
+            s3: None,
+            staging_path: None,
+            max_upload_bytes: 50 * 1024 * 1024,
+            remote_cache_max_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Voice signaling; voice endpoints return 501 until an SFU is configured.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct VoiceConfig {
+    /// Base URL of the SFU control API.
+    pub sfu_url: Option<String>,
+    /// Bearer token sent to the SFU control API.
+    pub sfu_token: Option<String>,
+}
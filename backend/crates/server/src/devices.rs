@@ -0,0 +1,1037 @@
+//! End-to-end encryption device registry. Each device publishes its ed25519 identity key,
+//! signed with that key; a user's master cross-signing key signs the devices they have
+//! verified. Anyone signed in
+//! may read a user's device list, and changes are pushed to everyone sharing a channel with
+//! them so they can update their MLS groups.
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{MatchedPath, Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use openguild_core::canonical_json;
+use openguild_crypto::{verify_signature, verifying_key_from_base64, Signature};
+use openguild_storage::{DeviceRecord, DeviceRepository, MasterKeyRecord, NewDevice, StoragePool};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    config::ServerConfig,
+    messaging::{MessagingService, NotificationEvent},
+    mls::MAX_DEVICE_ID_LENGTH,
+    session, AppState,
+};
+
+/// Longest display name a device may carry.
+pub const MAX_DEVICE_NAME_LENGTH: usize = 100;
+
+#[derive(Debug, Error)]
+pub enum DeviceError {
+    #[error("device not found")]
+    DeviceNotFound,
+    #[error("device is already registered with a different identity key")]
+    KeyMismatch,
+    #[error("user has no master cross-signing key")]
+    NoMasterKey,
+    #[error("{0}")]
+    Forbidden(&'static str),
+    #[error("{0}")]
+    Invalid(&'static str),
+    #[error(transparent)]
+    Storage(#[from] anyhow::Error),
+}
+
+/// Bytes a master key signs to vouch for a device: the canonical JSON of the user id,
+/// device id and identity key.
+pub fn device_signing_payload(user_id: Uuid, device_id: &str, identity_key: &str) -> Vec<u8> {
+    canonical_json::to_canonical_bytes(&json!({
+        "user_id": user_id.to_string(),
+        "device_id": device_id,
+        "identity_key": identity_key,
+    }))
+    .expect("device payload serializes")
+}
+
+/// Bytes the current master key signs to hand over to `master_key`.
+pub fn master_key_rotation_payload(user_id: Uuid, master_key: &str) -> Vec<u8> {
+    canonical_json::to_canonical_bytes(&json!({
+        "user_id": user_id.to_string(),
+        "master_key": master_key,
+    }))
+    .expect("master key payload serializes")
+}
+
+fn decode_signature(signature: &str) -> Result<Signature, DeviceError> {
+    URL_SAFE_NO_PAD
+        .decode(signature.trim())
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or(DeviceError::Invalid(
+            "signature must be a 64-byte ed25519 signature in URL-safe base64",
+        ))
+}
+
+/// Decode an ed25519 public key and return it in canonical URL-safe base64.
+fn normalize_key(raw: &str) -> Result<String, DeviceError> {
+    verifying_key_from_base64(raw)
+        .map(|key| URL_SAFE_NO_PAD.encode(key.as_bytes()))
+        .map_err(|_| DeviceError::Invalid("keys must be 32-byte ed25519 keys in URL-safe base64"))
+}
+
+fn validate_device_id(device_id: &str) -> Result<(), DeviceError> {
+    if device_id.is_empty() || device_id.len() > MAX_DEVICE_ID_LENGTH {
+        return Err(DeviceError::Invalid("device id must be 1-128 bytes"));
+    }
+    Ok(())
+}
+
+#[async_trait]
+pub trait DeviceStore: Send + Sync {
+    /// `None` when the device exists with a different identity key.
+    async fn publish(&self, device: NewDevice) -> Result<Option<DeviceRecord>, DeviceError>;
+    async fn device(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<DeviceRecord>, DeviceError>;
+    async fn devices(&self, user_id: Uuid) -> Result<Vec<DeviceRecord>, DeviceError>;
+    async fn remove(&self, user_id: Uuid, device_id: &str) -> Result<bool, DeviceError>;
+    async fn sign_device(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+        identity_key: &str,
+        signature: &str,
+    ) -> Result<Option<DeviceRecord>, DeviceError>;
+    async fn master_key(&self, user_id: Uuid) -> Result<Option<MasterKeyRecord>, DeviceError>;
+    /// Replacing the master key drops every device signature made with the old one.
+    async fn set_master_key(
+        &self,
+        user_id: Uuid,
+        master_key: &str,
+    ) -> Result<MasterKeyRecord, DeviceError>;
+}
+
+#[async_trait]
+impl DeviceStore for DeviceRepository {
+    async fn publish(&self, device: NewDevice) -> Result<Option<DeviceRecord>, DeviceError> {
+        Ok(DeviceRepository::publish(self, &device).await?)
+    }
+
+    async fn device(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<DeviceRecord>, DeviceError> {
+        Ok(DeviceRepository::device(self, user_id, device_id).await?)
+    }
+
+    async fn devices(&self, user_id: Uuid) -> Result<Vec<DeviceRecord>, DeviceError> {
+        Ok(DeviceRepository::devices(self, user_id).await?)
+    }
+
+    async fn remove(&self, user_id: Uuid, device_id: &str) -> Result<bool, DeviceError> {
+        Ok(DeviceRepository::remove(self, user_id, device_id).await?)
+    }
+
+    async fn sign_device(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+        identity_key: &str,
+        signature: &str,
+    ) -> Result<Option<DeviceRecord>, DeviceError> {
+        Ok(
+            DeviceRepository::sign_device(self, user_id, device_id, identity_key, signature)
+                .await?,
+        )
+    }
+
+    async fn master_key(&self, user_id: Uuid) -> Result<Option<MasterKeyRecord>, DeviceError> {
+        Ok(DeviceRepository::master_key(self, user_id).await?)
+    }
+
+    async fn set_master_key(
+        &self,
+        user_id: Uuid,
+        master_key: &str,
+    ) -> Result<MasterKeyRecord, DeviceError> {
+        Ok(DeviceRepository::set_master_key(self, user_id, master_key).await?)
+    }
+}
+
+#[derive(Default)]
+struct InMemoryDevicesState {
+    devices: HashMap<(Uuid, String), DeviceRecord>,
+    master_keys: HashMap<Uuid, MasterKeyRecord>,
+}
+
+/// Non-durable device registry used when Postgres is not configured.
+#[derive(Default)]
+pub struct InMemoryDevices {
+    state: Mutex<InMemoryDevicesState>,
+}
+
+#[async_trait]
+impl DeviceStore for InMemoryDevices {
+    async fn publish(&self, device: NewDevice) -> Result<Option<DeviceRecord>, DeviceError> {
+        let mut state = self.state.lock().await;
+        let now = Utc::now();
+        let key = (device.user_id, device.device_id.clone());
+        if let Some(existing) = state.devices.get_mut(&key) {
+            if existing.identity_key != device.identity_key {
+                return Ok(None);
+            }
+            existing.display_name = device.display_name;
+            existing.updated_at = now;
+            return Ok(Some(existing.clone()));
+        }
+        let record = DeviceRecord {
+            user_id: device.user_id,
+            device_id: device.device_id,
+            display_name: device.display_name,
+            identity_key: device.identity_key,
+            master_signature: None,
+            created_at: now,
+            updated_at: now,
+        };
+        state.devices.insert(key, record.clone());
+        Ok(Some(record))
+    }
+
+    async fn device(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<DeviceRecord>, DeviceError> {
+        let state = self.state.lock().await;
+        Ok(state
+            .devices
+            .get(&(user_id, device_id.to_string()))
+            .cloned())
+    }
+
+    async fn devices(&self, user_id: Uuid) -> Result<Vec<DeviceRecord>, DeviceError> {
+        let state = self.state.lock().await;
+        let mut devices: Vec<DeviceRecord> = state
+            .devices
+            .values()
+            .filter(|device| device.user_id == user_id)
+            .cloned()
+            .collect();
+        devices.sort_by(|a, b| (a.created_at, &a.device_id).cmp(&(b.created_at, &b.device_id)));
+        Ok(devices)
+    }
+
+    async fn remove(&self, user_id: Uuid, device_id: &str) -> Result<bool, DeviceError> {
+        let mut state = self.state.lock().await;
+        Ok(state
+            .devices
+            .remove(&(user_id, device_id.to_string()))
+            .is_some())
+    }
+
+    async fn sign_device(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+        identity_key: &str,
+        signature: &str,
+    ) -> Result<Option<DeviceRecord>, DeviceError> {
+        let mut state = self.state.lock().await;
+        let Some(device) = state
+            .devices
+            .get_mut(&(user_id, device_id.to_string()))
+            .filter(|device| device.identity_key == identity_key)
+        else {
+            return Ok(None);
+        };
+        device.master_signature = Some(signature.to_string());
+        device.updated_at = Utc::now();
+        Ok(Some(device.clone()))
+    }
+
+    async fn master_key(&self, user_id: Uuid) -> Result<Option<MasterKeyRecord>, DeviceError> {
+        let state = self.state.lock().await;
+        Ok(state.master_keys.get(&user_id).cloned())
+    }
+
+    async fn set_master_key(
+        &self,
+        user_id: Uuid,
+        master_key: &str,
+    ) -> Result<MasterKeyRecord, DeviceError> {
+        let mut state = self.state.lock().await;
+        if let Some(existing) = state.master_keys.get(&user_id) {
+            if existing.master_key == master_key {
+                return Ok(existing.clone());
+            }
+        }
+        let now = Utc::now();
+        let record = MasterKeyRecord {
+            user_id,
+            master_key: master_key.to_string(),
+            updated_at: now,
+        };
+        state.master_keys.insert(user_id, record.clone());
+        for device in state.devices.values_mut() {
+            if device.user_id == user_id && device.master_signature.take().is_some() {
+                device.updated_at = now;
+            }
+        }
+        Ok(record)
+    }
+}
+
+pub struct DeviceRegistry {
+    store: Arc<dyn DeviceStore>,
+    messaging: Arc<MessagingService>,
+}
+
+impl DeviceRegistry {
+    pub fn new(store: Arc<dyn DeviceStore>, messaging: Arc<MessagingService>) -> Self {
+        Self { store, messaging }
+    }
+
+    /// Register a device or rename it. Only the session's own device may be published, and
+    /// `signature` must be the identity key's signature over `device_signing_payload` to
+    /// prove the device holds it. A device's identity key never changes; a new key needs a
+    /// new device id.
+    pub async fn publish(
+        &self,
+        user_id: Uuid,
+        session_device: Option<&str>,
+        device_id: &str,
+        display_name: Option<String>,
+        identity_key: &str,
+        signature: &str,
+    ) -> Result<DeviceResponse, DeviceError> {
+        validate_device_id(device_id)?;
+        if session_device != Some(device_id) {
+            return Err(DeviceError::Forbidden(
+                "devices can only be published from their own session",
+            ));
+        }
+        let display_name = display_name
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
+        if display_name
+            .as_ref()
+            .is_some_and(|name| name.chars().count() > MAX_DEVICE_NAME_LENGTH)
+        {
+            return Err(DeviceError::Invalid("display name exceeds 100 characters"));
+        }
+        let identity_key = normalize_key(identity_key)?;
+        let signature = decode_signature(signature)?;
+        let verifying_key =
+            verifying_key_from_base64(&identity_key).map_err(DeviceError::Storage)?;
+        let payload = device_signing_payload(user_id, device_id, &identity_key);
+        verify_signature(&verifying_key, &payload, &signature).map_err(|_| {
+            DeviceError::Invalid("signature does not verify against the identity key")
+        })?;
+        let previous = self.store.device(user_id, device_id).await?;
+        let device = self
+            .store
+            .publish(NewDevice {
+                user_id,
+                device_id: device_id.to_string(),
+                display_name,
+                identity_key,
+            })
+            .await?
+            .ok_or(DeviceError::KeyMismatch)?;
+        if previous.is_none_or(|previous| previous.display_name != device.display_name) {
+            self.notify_change(user_id, Some(device_id)).await;
+        }
+        Ok(DeviceResponse::from(device))
+    }
+
+    pub async fn remove(&self, user_id: Uuid, device_id: &str) -> Result<(), DeviceError> {
+        if !self.store.remove(user_id, device_id).await? {
+            return Err(DeviceError::DeviceNotFound);
+        }
+        self.notify_change(user_id, Some(device_id)).await;
+        Ok(())
+    }
+
+    /// Set the caller's master cross-signing key. Replacing an existing key needs the old
+    /// key's `signature` over `master_key_rotation_payload`. A new key unverifies every
+    /// device.
+    pub async fn set_master_key(
+        &self,
+        user_id: Uuid,
+        master_key: &str,
+        signature: Option<&str>,
+    ) -> Result<MasterKeyResponse, DeviceError> {
+        let master_key = normalize_key(master_key)?;
+        let previous = self.store.master_key(user_id).await?;
+        if let Some(previous) = previous
+            .as_ref()
+            .filter(|previous| previous.master_key != master_key)
+        {
+            let signature = signature.ok_or(DeviceError::Forbidden(
+                "replacing the master key needs a signature by the current one",
+            ))?;
+            let signature = decode_signature(signature)?;
+            let verifying_key =
+                verifying_key_from_base64(&previous.master_key).map_err(DeviceError::Storage)?;
+            let payload = master_key_rotation_payload(user_id, &master_key);
+            verify_signature(&verifying_key, &payload, &signature).map_err(|_| {
+                DeviceError::Forbidden("signature does not verify against the current master key")
+            })?;
+        }
+        let record = self.store.set_master_key(user_id, &master_key).await?;
+        if previous.is_none_or(|previous| previous.master_key != record.master_key) {
+            self.notify_change(user_id, None).await;
+        }
+        Ok(MasterKeyResponse::from(record))
+    }
+
+    /// Record the master key's signature over `device_signing_payload` for the device.
+    pub async fn sign_device(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+        signature: &str,
+    ) -> Result<DeviceResponse, DeviceError> {
+        let master_key = self
+            .store
+            .master_key(user_id)
+            .await?
+            .ok_or(DeviceError::NoMasterKey)?;
+        let device = self
+            .store
+            .device(user_id, device_id)
+            .await?
+            .ok_or(DeviceError::DeviceNotFound)?;
+        let signature_bytes = decode_signature(signature)?;
+        let verifying_key =
+            verifying_key_from_base64(&master_key.master_key).map_err(DeviceError::Storage)?;
+        let payload = device_signing_payload(user_id, device_id, &device.identity_key);
+        verify_signature(&verifying_key, &payload, &signature_bytes).map_err(|_| {
+            DeviceError::Invalid("signature does not verify against the master key")
+        })?;
+
+        let signed = self
+            .store
+            .sign_device(
+                user_id,
+                device_id,
+                &device.identity_key,
+                &URL_SAFE_NO_PAD.encode(signature_bytes.to_bytes()),
+            )
+            .await?
+            .ok_or(DeviceError::DeviceNotFound)?;
+        self.notify_change(user_id, Some(device_id)).await;
+        Ok(DeviceResponse::from(signed))
+    }
+
+    /// A user's master key and devices with their verification status.
+    pub async fn device_list(&self, user_id: Uuid) -> Result<DeviceListResponse, DeviceError> {
+        let master_key = self.store.master_key(user_id).await?;
+        let devices = self.store.devices(user_id).await?;
+        Ok(DeviceListResponse {
+            user_id,
+            master_key: master_key.map(|key| key.master_key),
+            devices: devices.into_iter().map(DeviceResponse::from).collect(),
+        })
+    }
+
+    async fn notify_change(&self, user_id: Uuid, device_id: Option<&str>) {
+        let notification = Arc::new(NotificationEvent {
+            kind: "device_list_changed".to_string(),
+            channel_id: None,
+            guild_id: None,
+            sequence: None,
+            event: json!({ "user_id": user_id, "device_id": device_id }),
+        });
+        if let Err(err) = self
+            .messaging
+            .notify_channel_peers(user_id, notification)
+            .await
+        {
+            tracing::warn!(?err, user_id = %user_id, "failed to notify peers of device change");
+        }
+    }
+}
+
+/// Enabled alongside MLS; devices persist when a database is configured.
+pub fn init_device_registry(
+    config: &ServerConfig,
+    pool: Option<StoragePool>,
+    messaging: Arc<MessagingService>,
+) -> Option<DeviceRegistry> {
+    if !config.mls.enabled {
+        return None;
+    }
+    let store: Arc<dyn DeviceStore> = match pool {
+        Some(pool) => Arc::new(DeviceRepository::new(pool.cloned())),
+        None => {
+            tracing::info!("device registry persistence unavailable; using in-memory registry");
+            Arc::new(InMemoryDevices::default())
+        }
+    };
+    Some(DeviceRegistry::new(store, messaging))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceResponse {
+    pub device_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    pub identity_key: String,
+    /// Master key signature over `device_signing_payload`, so peers can check it themselves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub master_signature: Option<String>,
+    /// Whether the device is signed by the user's current master key.
+    pub verified: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<DeviceRecord> for DeviceResponse {
+    fn from(record: DeviceRecord) -> Self {
+        Self {
+            device_id: record.device_id,
+            display_name: record.display_name,
+            identity_key: record.identity_key,
+            verified: record.master_signature.is_some(),
+            master_signature: record.master_signature,
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceListResponse {
+    pub user_id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub master_key: Option<String>,
+    pub devices: Vec<DeviceResponse>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MasterKeyResponse {
+    pub user_id: Uuid,
+    pub master_key: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<MasterKeyRecord> for MasterKeyResponse {
+    fn from(record: MasterKeyRecord) -> Self {
+        Self {
+            user_id: record.user_id,
+            master_key: record.master_key,
+            updated_at: record.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublishDeviceRequest {
+    pub identity_key: String,
+    /// The identity key's signature over `device_signing_payload`.
+    pub signature: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MasterKeyRequest {
+    pub master_key: String,
+    /// The current master key's signature over `master_key_rotation_payload`; required
+    /// when replacing a key.
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignDeviceRequest {
+    pub signature: String,
+}
+
+fn status_for(state: &AppState, err: &DeviceError) -> StatusCode {
+    match err {
+        DeviceError::DeviceNotFound => StatusCode::NOT_FOUND,
+        DeviceError::KeyMismatch | DeviceError::NoMasterKey => {
+            state.record_messaging_rejection("device_key_conflict");
+            StatusCode::CONFLICT
+        }
+        DeviceError::Invalid(reason) => {
+            tracing::debug!(reason, "rejected device registry request");
+            state.record_messaging_rejection("device_invalid");
+            StatusCode::BAD_REQUEST
+        }
+        DeviceError::Forbidden(reason) => {
+            tracing::debug!(reason, "refused device registry request");
+            state.record_messaging_rejection("device_forbidden");
+            StatusCode::FORBIDDEN
+        }
+        DeviceError::Storage(err) => {
+            tracing::error!(?err, "device registry storage failure");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+pub async fn list_user_devices(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<DeviceListResponse>, StatusCode> {
+    let Some(devices) = state.devices() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match devices.device_list(user_id).await {
+        Ok(list) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(list));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn publish_device(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(device_id): Path<String>,
+    Json(body): Json<PublishDeviceRequest>,
+) -> Result<Json<DeviceResponse>, StatusCode> {
+    let Some(devices) = state.devices() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match devices
+        .publish(
+            claims.user_id,
+            claims.device_id.as_deref(),
+            &device_id,
+            body.display_name,
+            &body.identity_key,
+            &body.signature,
+        )
+        .await
+    {
+        Ok(device) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(device));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn remove_device(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(device_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let Some(devices) = state.devices() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match devices.remove(claims.user_id, &device_id).await {
+        Ok(()) => {
+            let status = StatusCode::NO_CONTENT;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Ok(status);
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn sign_device(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(device_id): Path<String>,
+    Json(body): Json<SignDeviceRequest>,
+) -> Result<Json<DeviceResponse>, StatusCode> {
+    let Some(devices) = state.devices() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match devices
+        .sign_device(claims.user_id, &device_id, &body.signature)
+        .await
+    {
+        Ok(device) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(device));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn set_master_key(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<MasterKeyRequest>,
+) -> Result<Json<MasterKeyResponse>, StatusCode> {
+    let Some(devices) = state.devices() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match devices
+        .set_master_key(claims.user_id, &body.master_key, body.signature.as_deref())
+        .await
+    {
+        Ok(key) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(key));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openguild_crypto::{generate_signing_key, sign_message, verifying_key_from, SigningKey};
+
+    fn encode_key(key: &SigningKey) -> String {
+        URL_SAFE_NO_PAD.encode(verifying_key_from(key).as_bytes())
+    }
+
+    fn sign(key: &SigningKey, payload: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(sign_message(key, payload).to_bytes())
+    }
+
+    fn self_signature(user_id: Uuid, device_id: &str, key: &SigningKey) -> String {
+        sign(
+            key,
+            &device_signing_payload(user_id, device_id, &encode_key(key)),
+        )
+    }
+
+    fn registry() -> (DeviceRegistry, Arc<MessagingService>) {
+        let messaging = Arc::new(MessagingService::new_in_memory("local.test".to_string()));
+        (
+            DeviceRegistry::new(Arc::new(InMemoryDevices::default()), messaging.clone()),
+            messaging,
+        )
+    }
+
+    #[tokio::test]
+    async fn master_key_signatures_verify_devices_until_rotation() {
+        let (registry, _) = registry();
+        let alice = Uuid::new_v4();
+        let laptop = generate_signing_key();
+        let master = generate_signing_key();
+        let other = generate_signing_key();
+
+        registry
+            .publish(
+                alice,
+                Some("laptop"),
+                "laptop",
+                Some("Laptop".into()),
+                &encode_key(&laptop),
+                &self_signature(alice, "laptop", &laptop),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            registry
+                .publish(
+                    alice,
+                    Some("laptop"),
+                    "laptop",
+                    None,
+                    &encode_key(&other),
+                    &self_signature(alice, "laptop", &other),
+                )
+                .await,
+            Err(DeviceError::KeyMismatch)
+        ));
+        assert!(matches!(
+            registry.sign_device(alice, "laptop", "AAAA").await,
+            Err(DeviceError::NoMasterKey)
+        ));
+
+        registry
+            .set_master_key(alice, &encode_key(&master), None)
+            .await
+            .unwrap();
+        let payload = device_signing_payload(alice, "laptop", &encode_key(&laptop));
+        let forged = sign_message(&laptop, &payload);
+        assert!(matches!(
+            registry
+                .sign_device(alice, "laptop", &URL_SAFE_NO_PAD.encode(forged.to_bytes()))
+                .await,
+            Err(DeviceError::Invalid(_))
+        ));
+        let signature = URL_SAFE_NO_PAD.encode(sign_message(&master, &payload).to_bytes());
+        let signed = registry
+            .sign_device(alice, "laptop", &signature)
+            .await
+            .unwrap();
+        assert!(signed.verified);
+
+        let list = registry.device_list(alice).await.unwrap();
+        assert_eq!(list.master_key, Some(encode_key(&master)));
+        assert!(list.devices[0].verified);
+
+        let replacement = encode_key(&generate_signing_key());
+        let rotation = master_key_rotation_payload(alice, &replacement);
+        registry
+            .set_master_key(alice, &replacement, Some(&sign(&master, &rotation)))
+            .await
+            .unwrap();
+        let list = registry.device_list(alice).await.unwrap();
+        assert!(!list.devices[0].verified);
+        assert!(list.devices[0].master_signature.is_none());
+    }
+
+    #[tokio::test]
+    async fn device_keys_must_be_self_signed() {
+        let (registry, _) = registry();
+        let alice = Uuid::new_v4();
+        let laptop = generate_signing_key();
+        let other = generate_signing_key();
+
+        for signature in [
+            self_signature(alice, "laptop", &other),
+            self_signature(alice, "phone", &laptop),
+            self_signature(Uuid::new_v4(), "laptop", &laptop),
+            "not base64!".to_string(),
+        ] {
+            assert!(matches!(
+                registry
+                    .publish(
+                        alice,
+                        Some("laptop"),
+                        "laptop",
+                        None,
+                        &encode_key(&laptop),
+                        &signature,
+                    )
+                    .await,
+                Err(DeviceError::Invalid(_))
+            ));
+        }
+        assert!(registry
+            .device_list(alice)
+            .await
+            .unwrap()
+            .devices
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn devices_are_published_only_from_their_own_session() {
+        let (registry, _) = registry();
+        let alice = Uuid::new_v4();
+        let laptop = generate_signing_key();
+        let (key, signature) = (
+            encode_key(&laptop),
+            self_signature(alice, "laptop", &laptop),
+        );
+
+        for session_device in [Some("phone"), None] {
+            assert!(matches!(
+                registry
+                    .publish(alice, session_device, "laptop", None, &key, &signature)
+                    .await,
+                Err(DeviceError::Forbidden(_))
+            ));
+        }
+        assert!(registry
+            .device_list(alice)
+            .await
+            .unwrap()
+            .devices
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn master_key_rotation_needs_the_old_key_signature() {
+        let (registry, _) = registry();
+        let alice = Uuid::new_v4();
+        let laptop = generate_signing_key();
+        let master = generate_signing_key();
+        registry
+            .publish(
+                alice,
+                Some("laptop"),
+                "laptop",
+                None,
+                &encode_key(&laptop),
+                &self_signature(alice, "laptop", &laptop),
+            )
+            .await
+            .unwrap();
+        registry
+            .set_master_key(alice, &encode_key(&master), None)
+            .await
+            .unwrap();
+        let payload = device_signing_payload(alice, "laptop", &encode_key(&laptop));
+        registry
+            .sign_device(alice, "laptop", &sign(&master, &payload))
+            .await
+            .unwrap();
+
+        let replacement = generate_signing_key();
+        let rotation = master_key_rotation_payload(alice, &encode_key(&replacement));
+        for signature in [
+            None,
+            Some(sign(&laptop, &rotation)),
+            Some(sign(&replacement, &rotation)),
+            Some(sign(
+                &master,
+                &master_key_rotation_payload(Uuid::new_v4(), &encode_key(&replacement)),
+            )),
+        ] {
+            assert!(matches!(
+                registry
+                    .set_master_key(alice, &encode_key(&replacement), signature.as_deref())
+                    .await,
+                Err(DeviceError::Forbidden(_))
+            ));
+        }
+        let list = registry.device_list(alice).await.unwrap();
+        assert_eq!(list.master_key, Some(encode_key(&master)));
+        assert!(list.devices[0].verified);
+    }
+
+    #[tokio::test]
+    async fn device_changes_reach_channel_peers() {
+        let (registry, messaging) = registry();
+        let guild = messaging.create_guild("E2EE").await.unwrap();
+        let channel = messaging
+            .create_channel(guild.guild_id, "secrets")
+            .await
+            .unwrap();
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        let stranger = Uuid::new_v4();
+        for user in [alice, bob] {
+            messaging
+                .upsert_channel_membership(channel.channel_id, user, "member")
+                .await
+                .unwrap();
+        }
+        let mut bob_rx = messaging.notification_sender(bob).await.subscribe();
+        let mut stranger_rx = messaging.notification_sender(stranger).await.subscribe();
+
+        let phone = generate_signing_key();
+        let (key, signature) = (encode_key(&phone), self_signature(alice, "phone", &phone));
+        registry
+            .publish(alice, Some("phone"), "phone", None, &key, &signature)
+            .await
+            .unwrap();
+        let notification = bob_rx.recv().await.unwrap();
+        assert_eq!(notification.kind, "device_list_changed");
+        assert_eq!(notification.event["user_id"], alice.to_string());
+        assert_eq!(notification.event["device_id"], "phone");
+
+        // Publishing the same keys again changes nothing.
+        registry
+            .publish(alice, Some("phone"), "phone", None, &key, &signature)
+            .await
+            .unwrap();
+        registry.remove(alice, "phone").await.unwrap();
+        let removal = bob_rx.recv().await.unwrap();
+        assert_eq!(removal.event["device_id"], "phone");
+        assert!(bob_rx.try_recv().is_err());
+        assert!(stranger_rx.try_recv().is_err());
+        assert!(matches!(
+            registry.remove(alice, "phone").await,
+            Err(DeviceError::DeviceNotFound)
+        ));
+    }
+}
@@ -1,19 +1,57 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use base64::Engine;
-use openguild_core::{CanonicalEvent, EventId};
-use openguild_crypto::{verify_signature, verifying_key_from_base64, Signature, VerifyingKey};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures::TryStreamExt;
+use openguild_core::{
+    auth::{self, AuthError},
+    canonical_json, redaction, CanonicalEvent, EventId,
+};
+use openguild_crypto::{
+    generate_signing_key, signing_key_from_base64, verify_signature, verifying_key_from_base64,
+    Signature, SigningKeyRing, VerifyingKey,
+};
+use openguild_media::{RemoteFetcher, RemoteObject};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::warn;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::{config::FederationConfig, messaging};
+use crate::{
+    config::FederationConfig,
+    messaging::{self, MessagingError, MessagingService},
+};
+
+/// `Authorization` scheme for server-to-server requests.
+pub const FEDERATION_AUTH_SCHEME: &str = "X-OpenGuild";
+
+/// Upper bound on ancestors requested from a peer while filling a gap in the DAG.
+pub const MAX_BACKFILL_EVENTS: usize = 100;
+/// Upper bound on ancestors fetched across all rounds of a single backfill.
+pub const MAX_BACKFILL_TOTAL: usize = 1_000;
+const FEDERATION_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Media downloads stream whole files, so they get longer than other requests.
+const MEDIA_FETCH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How long peers may cache the key set served by `/federation/keys`.
+pub const KEY_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+/// How far a request's signed `origin_ts` may be from local time. Nonces are remembered
+/// for this long, since older requests are refused on their timestamp alone.
+pub const REQUEST_TIMESTAMP_SKEW: Duration = Duration::from_secs(5 * 60);
+/// Minimum spacing between key fetches for the same server, so unknown key ids
+/// in a flood of events don't turn into a flood of requests.
+const KEY_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Error)]
 pub enum FederationError {
     #[error("trusted server '{server}' has invalid verifying key: {reason}")]
     InvalidTrustedServer { server: String, reason: String },
+    #[error("invalid federation signing key: {0}")]
+    InvalidSigningKey(String),
     #[error("received event from untrusted origin '{origin}'")]
     UntrustedOrigin { origin: String },
     #[error("event origin '{event_origin}' mismatched transaction origin '{origin}'")]
@@ -25,21 +63,469 @@ pub enum FederationError {
     MissingSignature { origin: String, key_id: String },
     #[error("event id mismatch (expected {expected}, got {actual})")]
     EventIdMismatch { expected: EventId, actual: EventId },
+    #[error("content hash mismatch for unredacted event")]
+    ContentHashMismatch,
     #[error("signature encoding invalid")]
     InvalidSignatureEncoding,
     #[error("signature verification failed")]
     SignatureVerificationFailed,
+    #[error("federation request to '{destination}' failed: {reason}")]
+    Transport { destination: String, reason: String },
+    #[error("messaging error: {0}")]
+    Messaging(#[from] MessagingError),
+    #[error("outbound queue error: {0}")]
+    Queue(#[from] anyhow::Error),
+    #[error("key set for '{server}' is invalid: {reason}")]
+    InvalidServerKeys { server: String, reason: String },
+    #[error("missing federation authorization")]
+    MissingAuthorization,
+    #[error("malformed federation authorization: {0}")]
+    InvalidAuthorization(String),
+    #[error("request addressed to '{actual}' instead of '{expected}'")]
+    WrongDestination { expected: String, actual: String },
+    #[error("request timestamp {origin_ts} is outside the accepted window")]
+    StaleRequest { origin_ts: i64 },
+    #[error("request nonce '{nonce}' from '{origin}' was already used")]
+    ReplayedRequest { origin: String, nonce: String },
+    #[error("auth rules: {0}")]
+    Auth(#[from] AuthError),
 }
 
+/// Signs events this server originates so peers can verify them.
 #[derive(Clone)]
-struct TrustedPeer {
+pub struct FederationSigner {
     key_id: String,
+    key_ring: SigningKeyRing,
+    old_key_ids: Vec<(String, i64)>,
+}
+
+impl FederationSigner {
+    pub fn from_config(config: &FederationConfig) -> Result<Self, FederationError> {
+        let signing_key = match config.signing_key.as_deref() {
+            Some(raw) => signing_key_from_base64(raw)
+                .map_err(|err| FederationError::InvalidSigningKey(err.to_string()))?,
+            None => {
+                warn!("federation.signing_key not configured; generated an ephemeral key");
+                generate_signing_key()
+            }
+        };
+        let mut fallbacks = Vec::with_capacity(config.old_keys.len());
+        let mut old_key_ids = Vec::with_capacity(config.old_keys.len());
+        for old in &config.old_keys {
+            let verifying_key = verifying_key_from_base64(&old.verifying_key)
+                .map_err(|err| FederationError::InvalidSigningKey(err.to_string()))?;
+            fallbacks.push(verifying_key);
+            old_key_ids.push((old.key_id.clone(), old.expired_ts));
+        }
+        Ok(Self {
+            key_id: config.key_id.clone(),
+            key_ring: SigningKeyRing::new(signing_key, fallbacks),
+            old_key_ids,
+        })
+    }
+
+    pub fn sign_event(&self, origin: &str, event: &mut CanonicalEvent) {
+        event.sign_with(origin, &self.key_id, self.key_ring.primary());
+    }
+
+    /// `Authorization` header value for a request from `origin` to `destination`, stamped
+    /// with the current time and a fresh nonce.
+    pub fn sign_request(
+        &self,
+        origin: &str,
+        destination: &str,
+        method: &str,
+        uri: &str,
+        body: &[u8],
+    ) -> String {
+        let mut auth = FederationRequestAuth {
+            origin: origin.to_string(),
+            destination: destination.to_string(),
+            key: format!("ed25519:{}", self.key_id),
+            origin_ts: chrono::Utc::now().timestamp_millis(),
+            nonce: Uuid::new_v4().simple().to_string(),
+            signature: String::new(),
+        };
+        let signature = self
+            .key_ring
+            .sign(&request_signing_bytes(method, uri, &auth, body));
+        auth.signature = base64::engine::general_purpose::STANDARD.encode(signature.to_bytes());
+        auth.to_header()
+    }
+
+    /// Self-signed key set published at `/federation/keys`.
+    pub fn server_keys(&self, server_name: &str, now_ms: i64) -> ServerKeysResponse {
+        let mut verify_keys = BTreeMap::new();
+        verify_keys.insert(
+            format!("ed25519:{}", self.key_id),
+            VerifyKey {
+                key: URL_SAFE_NO_PAD.encode(self.key_ring.active_verifying_key().as_bytes()),
+            },
+        );
+        let old_verify_keys = self
+            .old_key_ids
+            .iter()
+            .zip(self.key_ring.fallback_verifying_keys())
+            .map(|((key_id, expired_ts), key)| {
+                (
+                    format!("ed25519:{key_id}"),
+                    OldVerifyKey {
+                        key: URL_SAFE_NO_PAD.encode(key.as_bytes()),
+                        expired_ts: *expired_ts,
+                    },
+                )
+            })
+            .collect();
+
+        let mut response = ServerKeysResponse {
+            server_name: server_name.to_string(),
+            valid_until_ts: now_ms + KEY_VALIDITY.as_millis() as i64,
+            verify_keys,
+            old_verify_keys,
+            signatures: BTreeMap::new(),
+        };
+        let signature = self.key_ring.sign(&response.signing_bytes());
+        response
+            .signatures
+            .entry(server_name.to_string())
+            .or_default()
+            .insert(
+                format!("ed25519:{}", self.key_id),
+                base64::engine::general_purpose::STANDARD.encode(signature.to_bytes()),
+            );
+        response
+    }
+}
+
+/// Parsed `Authorization: X-OpenGuild origin="..",destination="..",key="..",ts="..",nonce="..",sig=".."`
+/// header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederationRequestAuth {
+    pub origin: String,
+    pub destination: String,
+    pub key: String,
+    /// Milliseconds since the epoch when the request was signed.
+    pub origin_ts: i64,
+    /// Single-use value distinguishing otherwise identical requests.
+    pub nonce: String,
+    pub signature: String,
+}
+
+impl FederationRequestAuth {
+    pub fn parse(header: &str) -> Result<Self, FederationError> {
+        let invalid = |reason: &str| FederationError::InvalidAuthorization(reason.to_string());
+        let params = header
+            .strip_prefix(FEDERATION_AUTH_SCHEME)
+            .and_then(|rest| rest.strip_prefix(' '))
+            .ok_or_else(|| invalid("unsupported scheme"))?;
+
+        let (mut origin, mut destination, mut key, mut signature) = (None, None, None, None);
+        let (mut origin_ts, mut nonce) = (None, None);
+        for param in params.split(',') {
+            let (name, value) = param
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| invalid("expected name=value pairs"))?;
+            let value = value.trim_matches('"').to_string();
+            match name {
+                "origin" => origin = Some(value),
+                "destination" => destination = Some(value),
+                "key" => key = Some(value),
+                "ts" => {
+                    origin_ts = Some(value.parse().map_err(|_| invalid("malformed ts"))?);
+                }
+                "nonce" => nonce = Some(value),
+                "sig" => signature = Some(value),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            origin: origin.ok_or_else(|| invalid("missing origin"))?,
+            destination: destination.ok_or_else(|| invalid("missing destination"))?,
+            key: key.ok_or_else(|| invalid("missing key"))?,
+            origin_ts: origin_ts.ok_or_else(|| invalid("missing ts"))?,
+            nonce: nonce
+                .filter(|nonce| !nonce.is_empty())
+                .ok_or_else(|| invalid("missing nonce"))?,
+            signature: signature.ok_or_else(|| invalid("missing sig"))?,
+        })
+    }
+
+    pub fn to_header(&self) -> String {
+        format!(
+            "{FEDERATION_AUTH_SCHEME} origin=\"{}\",destination=\"{}\",key=\"{}\",ts=\"{}\",nonce=\"{}\",sig=\"{}\"",
+            self.origin, self.destination, self.key, self.origin_ts, self.nonce, self.signature
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct RequestSigningPayload<'a> {
+    method: &'a str,
+    uri: &'a str,
+    origin: &'a str,
+    destination: &'a str,
+    origin_ts: i64,
+    nonce: &'a str,
+    content_hash: String,
+}
+
+/// Bytes covered by a request signature: method, path and query, both server names, the
+/// timestamp and nonce, and a blake3 hash of the body.
+fn request_signing_bytes(
+    method: &str,
+    uri: &str,
+    auth: &FederationRequestAuth,
+    body: &[u8],
+) -> Vec<u8> {
+    let payload = RequestSigningPayload {
+        method,
+        uri,
+        origin: &auth.origin,
+        destination: &auth.destination,
+        origin_ts: auth.origin_ts,
+        nonce: &auth.nonce,
+        content_hash: URL_SAFE_NO_PAD.encode(blake3::hash(body).as_bytes()),
+    };
+    canonical_json::to_canonical_bytes(&payload).expect("serialization must succeed")
+}
+
+/// Status code plus parsed body returned by a peer for a transaction.
+#[derive(Debug)]
+pub struct TransactionOutcome {
+    pub status: u16,
+    pub response: Option<TransactionResponse>,
+}
+
+#[derive(Clone)]
+struct TrustedPeer {
+    pinned_key: Option<(String, VerifyingKey)>,
+    base_url: String,
+}
+
+/// Verifying key learned from a peer's `/federation/keys` response.
+#[derive(Clone)]
+struct CachedKey {
     verifying_key: VerifyingKey,
+    /// Current keys are trusted until the response's `valid_until_ts`.
+    valid_until_ts: i64,
+    /// Retired keys only verify events created before they expired.
+    expired_ts: Option<i64>,
+}
+
+#[derive(Default)]
+struct KeyCache {
+    keys: HashMap<(String, String), CachedKey>,
+    last_fetch: HashMap<String, Instant>,
 }
 
 #[derive(Clone)]
 pub struct FederationService {
     peers: HashMap<String, TrustedPeer>,
+    client: Option<Arc<dyn FederationClient>>,
+    key_cache: Arc<RwLock<KeyCache>>,
+    /// `(origin, nonce)` of authenticated requests, with their `origin_ts`.
+    request_nonces: Arc<RwLock<HashMap<(String, String), i64>>>,
+}
+
+/// Outbound calls this server makes to its federation peers.
+#[async_trait]
+pub trait FederationClient: Send + Sync {
+    async fn missing_events(
+        &self,
+        destination: &str,
+        base_url: &str,
+        channel_id: Uuid,
+        request: &MissingEventsRequest,
+    ) -> Result<Vec<CanonicalEvent>, FederationError>;
+
+    async fn send_transaction(
+        &self,
+        destination: &str,
+        base_url: &str,
+        transaction: &TransactionRequest,
+    ) -> Result<TransactionOutcome, FederationError>;
+
+    async fn server_keys(
+        &self,
+        destination: &str,
+        base_url: &str,
+    ) -> Result<ServerKeysResponse, FederationError>;
+
+    /// Download media hosted by `destination`; `None` when the peer does not know it.
+    async fn media(
+        &self,
+        destination: &str,
+        base_url: &str,
+        media_id: &str,
+    ) -> Result<Option<RemoteObject>, FederationError>;
+}
+
+pub struct HttpFederationClient {
+    http: reqwest::Client,
+    origin: String,
+    signer: Arc<FederationSigner>,
+}
+
+impl HttpFederationClient {
+    pub fn new(origin: String, signer: Arc<FederationSigner>) -> Result<Self, FederationError> {
+        let http = reqwest::Client::builder()
+            .timeout(FEDERATION_REQUEST_TIMEOUT)
+            .build()
+            .map_err(|err| FederationError::Transport {
+                destination: origin.clone(),
+                reason: err.to_string(),
+            })?;
+        Ok(Self {
+            http,
+            origin,
+            signer,
+        })
+    }
+
+    /// Build a JSON POST carrying a request signature for `destination`.
+    fn signed_post<T: Serialize>(
+        &self,
+        destination: &str,
+        base_url: &str,
+        path: &str,
+        body: &T,
+    ) -> Result<reqwest::RequestBuilder, FederationError> {
+        let body = serde_json::to_vec(body).map_err(|err| FederationError::Transport {
+            destination: destination.to_string(),
+            reason: err.to_string(),
+        })?;
+        let authorization =
+            self.signer
+                .sign_request(&self.origin, destination, "POST", path, &body);
+        Ok(self
+            .http
+            .post(format!("{base_url}{path}"))
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body))
+    }
+}
+
+#[async_trait]
+impl FederationClient for HttpFederationClient {
+    async fn missing_events(
+        &self,
+        destination: &str,
+        base_url: &str,
+        channel_id: Uuid,
+        request: &MissingEventsRequest,
+    ) -> Result<Vec<CanonicalEvent>, FederationError> {
+        let transport = |err: reqwest::Error| FederationError::Transport {
+            destination: destination.to_string(),
+            reason: err.to_string(),
+        };
+        let path = format!("/federation/channels/{channel_id}/missing_events");
+        let response = self
+            .signed_post(destination, base_url, &path, request)?
+            .send()
+            .await
+            .map_err(transport)?
+            .error_for_status()
+            .map_err(transport)?;
+        let body: MissingEventsResponse = response.json().await.map_err(transport)?;
+        Ok(body.events)
+    }
+
+    async fn send_transaction(
+        &self,
+        destination: &str,
+        base_url: &str,
+        transaction: &TransactionRequest,
+    ) -> Result<TransactionOutcome, FederationError> {
+        let response = self
+            .signed_post(
+                destination,
+                base_url,
+                "/federation/transactions",
+                transaction,
+            )?
+            .send()
+            .await
+            .map_err(|err| FederationError::Transport {
+                destination: destination.to_string(),
+                reason: err.to_string(),
+            })?;
+        let status = response.status().as_u16();
+        // Error statuses may carry no (or a non-JSON) body; treat that as "no verdict".
+        let response = response.json::<TransactionResponse>().await.ok();
+        Ok(TransactionOutcome { status, response })
+    }
+
+    async fn server_keys(
+        &self,
+        destination: &str,
+        base_url: &str,
+    ) -> Result<ServerKeysResponse, FederationError> {
+        let transport = |err: reqwest::Error| FederationError::Transport {
+            destination: destination.to_string(),
+            reason: err.to_string(),
+        };
+        self.http
+            .get(format!("{base_url}/federation/keys"))
+            .send()
+            .await
+            .map_err(transport)?
+            .error_for_status()
+            .map_err(transport)?
+            .json()
+            .await
+            .map_err(transport)
+    }
+
+    async fn media(
+        &self,
+        destination: &str,
+        base_url: &str,
+        media_id: &str,
+    ) -> Result<Option<RemoteObject>, FederationError> {
+        let transport = |reason: String| FederationError::Transport {
+            destination: destination.to_string(),
+            reason,
+        };
+        let path = format!("/federation/media/{destination}/{media_id}");
+        let authorization = self
+            .signer
+            .sign_request(&self.origin, destination, "GET", &path, &[]);
+        let response = self
+            .http
+            .get(format!("{base_url}{path}"))
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .timeout(MEDIA_FETCH_TIMEOUT)
+            .send()
+            .await
+            .map_err(|err| transport(err.to_string()))?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response
+            .error_for_status()
+            .map_err(|err| transport(err.to_string()))?;
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        // The ETag of a media download is its quoted content hash.
+        let content_hash = header(reqwest::header::ETAG)
+            .map(|etag| etag.trim_matches('"').to_string())
+            .ok_or_else(|| transport("media response carries no ETag".into()))?;
+        let content_type = header(reqwest::header::CONTENT_TYPE);
+        Ok(Some(RemoteObject {
+            content_type,
+            content_hash,
+            body: Box::pin(response.bytes_stream().map_err(std::io::Error::other)),
+        }))
+    }
 }
 
 impl FederationService {
@@ -50,30 +536,46 @@ impl FederationService {
 
         let mut peers = HashMap::new();
         for peer in &config.trusted_servers {
-            let verifying_key = verifying_key_from_base64(&peer.verifying_key).map_err(|err| {
-                FederationError::InvalidTrustedServer {
-                    server: peer.server_name.clone(),
-                    reason: err.to_string(),
+            let pinned_key = match &peer.verifying_key {
+                Some(raw) => {
+                    let verifying_key = verifying_key_from_base64(raw).map_err(|err| {
+                        FederationError::InvalidTrustedServer {
+                            server: peer.server_name.clone(),
+                            reason: err.to_string(),
+                        }
+                    })?;
+                    Some((format!("ed25519:{}", peer.key_id), verifying_key))
                 }
-            })?;
+                None => None,
+            };
 
             peers.insert(
                 peer.server_name.clone(),
                 TrustedPeer {
-                    key_id: peer.key_id.clone(),
-                    verifying_key,
+                    pinned_key,
+                    base_url: peer.base_url(),
                 },
             );
         }
 
-        Ok(Some(Self { peers }))
+        Ok(Some(Self {
+            peers,
+            client: None,
+            key_cache: Arc::new(RwLock::new(KeyCache::default())),
+            request_nonces: Arc::new(RwLock::new(HashMap::new())),
+        }))
+    }
+
+    pub fn with_client(mut self, client: Arc<dyn FederationClient>) -> Self {
+        self.client = Some(client);
+        self
     }
 
     pub fn is_trusted(&self, origin: &str) -> bool {
         self.peers.contains_key(origin)
     }
 
-    pub fn evaluate_transaction(
+    pub async fn evaluate_transaction(
         &self,
         origin: &str,
         events: Vec<CanonicalEvent>,
@@ -81,7 +583,7 @@ impl FederationService {
         let mut evaluation = FederationEvaluation::new(origin.to_string());
 
         for event in events {
-            match self.verify_event(origin, &event) {
+            match self.verify_event(origin, &event).await {
                 Ok(()) => evaluation.accepted_events.push(event),
                 Err(err) => {
                     warn!(
@@ -101,13 +603,125 @@ impl FederationService {
         evaluation
     }
 
-    fn verify_event(&self, origin: &str, event: &CanonicalEvent) -> Result<(), FederationError> {
-        let peer = self
-            .peers
-            .get(origin)
-            .ok_or_else(|| FederationError::UntrustedOrigin {
+    /// Fetches ancestors of `event` that are missing locally from `origin`, verifies them, and
+    /// returns them in an order that can be ingested (parents before children).
+    pub async fn backfill_missing_events(
+        &self,
+        origin: &str,
+        messaging: &MessagingService,
+        event: &CanonicalEvent,
+    ) -> Result<Vec<CanonicalEvent>, FederationError> {
+        let Ok(channel_id) = Uuid::parse_str(&event.room_id) else {
+            return Ok(Vec::new());
+        };
+        let unknown = messaging
+            .unknown_events(channel_id, &event.prev_events)
+            .await?;
+        if unknown.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let (Some(client), Some(peer)) = (&self.client, self.peers.get(origin)) else {
+            warn!(
+                %origin,
+                event_id = %event.event_id,
+                missing = unknown.len(),
+                "cannot backfill missing prev_events; no federation client configured"
+            );
+            return Ok(Vec::new());
+        };
+
+        // Each round asks for the ancestors just below what has been fetched so far, until
+        // every gap reaches history this server already has.
+        let earliest_events = messaging.forward_extremities(channel_id).await?;
+        let mut latest_events = vec![event.event_id.clone()];
+        let mut candidates: Vec<CanonicalEvent> = Vec::new();
+        let mut seen = HashSet::new();
+        while candidates.len() < MAX_BACKFILL_TOTAL {
+            let request = MissingEventsRequest {
+                earliest_events: earliest_events.clone(),
+                latest_events,
+                limit: Some(MAX_BACKFILL_EVENTS),
+            };
+            let fetched = client
+                .missing_events(origin, &peer.base_url, channel_id, &request)
+                .await?;
+
+            let before = candidates.len();
+            for candidate in fetched.into_iter().take(MAX_BACKFILL_EVENTS) {
+                if candidate.room_id != event.room_id || !seen.insert(candidate.event_id.clone()) {
+                    continue;
+                }
+                if let Err(err) = self
+                    .verify_event(&candidate.origin_server, &candidate)
+                    .await
+                {
+                    warn!(
+                        %origin,
+                        event_id = %candidate.event_id,
+                        error = %err,
+                        "discarding backfilled event"
+                    );
+                    continue;
+                }
+                let known = messaging
+                    .unknown_events(channel_id, std::slice::from_ref(&candidate.event_id))
+                    .await?
+                    .is_empty();
+                if !known {
+                    candidates.push(candidate);
+                }
+            }
+            if candidates.len() == before {
+                break;
+            }
+
+            let fetched_ids: HashSet<&EventId> =
+                candidates.iter().map(|event| &event.event_id).collect();
+            let mut frontier: Vec<EventId> = candidates
+                .iter()
+                .flat_map(|event| event.prev_events.iter())
+                .filter(|prev| !fetched_ids.contains(prev))
+                .cloned()
+                .collect();
+            frontier.sort();
+            frontier.dedup();
+            let missing: HashSet<EventId> = messaging
+                .unknown_events(channel_id, &frontier)
+                .await?
+                .into_iter()
+                .collect();
+            if missing.is_empty() {
+                break;
+            }
+            latest_events = candidates
+                .iter()
+                .filter(|event| event.prev_events.iter().any(|prev| missing.contains(prev)))
+                .map(|event| event.event_id.clone())
+                .collect();
+        }
+        if candidates.len() >= MAX_BACKFILL_TOTAL {
+            warn!(
+                %origin,
+                event_id = %event.event_id,
+                fetched = candidates.len(),
+                "stopped backfilling at the total limit; the gap may not be closed"
+            );
+        }
+
+        Ok(order_by_ancestry(candidates))
+    }
+
+    async fn verify_event(
+        &self,
+        origin: &str,
+        event: &CanonicalEvent,
+    ) -> Result<(), FederationError> {
+        if !self.is_trusted(origin) {
+            return Err(FederationError::UntrustedOrigin {
                 origin: origin.to_string(),
-            })?;
+            });
+        }
 
         if event.origin_server != origin {
             return Err(FederationError::OriginMismatch {
@@ -115,6 +729,7 @@ impl FederationService {
                 event_origin: event.origin_server.clone(),
             });
         }
+        auth::check_sender_origin(event)?;
 
         let hash = event.canonical_hash();
         let expected_id = CanonicalEvent::event_id_from_hash(&hash);
@@ -124,29 +739,247 @@ impl FederationService {
                 actual: event.event_id.clone(),
             });
         }
+        // Redacted copies cannot match their content hash; anything else must.
+        if !event.has_valid_content_hash() && !redaction::is_redacted(event) {
+            return Err(FederationError::ContentHashMismatch);
+        }
 
-        let signature_key = format!("ed25519:{}", peer.key_id);
-        let signature_b64 = event
-            .signatures
-            .get(origin)
-            .and_then(|map| map.get(&signature_key))
-            .ok_or_else(|| FederationError::MissingSignature {
+        let signatures = event.signatures.get(origin);
+        let mut last_error = None;
+        for (key_name, signature_b64) in signatures.into_iter().flatten() {
+            let Some(verifying_key) = self.resolve_key(origin, key_name, event.origin_ts).await
+            else {
+                continue;
+            };
+            match verify_encoded_signature(&verifying_key, &hash, signature_b64) {
+                Ok(()) => return Ok(()),
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        Err(
+            last_error.unwrap_or_else(|| FederationError::MissingSignature {
                 origin: origin.to_string(),
-                key_id: signature_key.clone(),
+                key_id: self.expected_key_name(origin),
+            }),
+        )
+    }
+
+    /// Verify a request's `Authorization` header and return the authenticated origin. Each
+    /// signed request is accepted once, and only within [`REQUEST_TIMESTAMP_SKEW`] of its
+    /// `origin_ts`.
+    pub async fn authenticate_request(
+        &self,
+        authorization: Option<&str>,
+        local_server: &str,
+        method: &str,
+        uri: &str,
+        body: &[u8],
+    ) -> Result<String, FederationError> {
+        let auth = FederationRequestAuth::parse(
+            authorization.ok_or(FederationError::MissingAuthorization)?,
+        )?;
+        if auth.destination != local_server {
+            return Err(FederationError::WrongDestination {
+                expected: local_server.to_string(),
+                actual: auth.destination,
+            });
+        }
+        if !self.is_trusted(&auth.origin) {
+            return Err(FederationError::UntrustedOrigin {
+                origin: auth.origin,
+            });
+        }
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let skew = REQUEST_TIMESTAMP_SKEW.as_millis() as i64;
+        if (now - auth.origin_ts).abs() > skew {
+            return Err(FederationError::StaleRequest {
+                origin_ts: auth.origin_ts,
+            });
+        }
+        let verifying_key = self
+            .resolve_key(&auth.origin, &auth.key, now)
+            .await
+            .ok_or_else(|| FederationError::MissingSignature {
+                origin: auth.origin.clone(),
+                key_id: auth.key.clone(),
             })?;
+        let payload = request_signing_bytes(method, uri, &auth, body);
+        verify_encoded_signature(&verifying_key, &payload, &auth.signature)?;
+
+        // Only authenticated nonces are recorded, so forged headers cannot fill the cache.
+        let mut nonces = self.request_nonces.write().await;
+        nonces.retain(|_, origin_ts| now - *origin_ts <= skew);
+        if nonces
+            .insert((auth.origin.clone(), auth.nonce.clone()), auth.origin_ts)
+            .is_some()
+        {
+            return Err(FederationError::ReplayedRequest {
+                origin: auth.origin,
+                nonce: auth.nonce,
+            });
+        }
+        Ok(auth.origin)
+    }
+
+    /// Download `media_id` from the trusted `server` that hosts it.
+    pub async fn fetch_media(
+        &self,
+        server: &str,
+        media_id: &str,
+    ) -> Result<Option<RemoteObject>, FederationError> {
+        let peer = self
+            .peers
+            .get(server)
+            .ok_or_else(|| FederationError::UntrustedOrigin {
+                origin: server.to_string(),
+            })?;
+        let client = self
+            .client
+            .as_ref()
+            .ok_or_else(|| FederationError::Transport {
+                destination: server.to_string(),
+                reason: "no federation client configured".into(),
+            })?;
+        client.media(server, &peer.base_url, media_id).await
+    }
+
+    fn expected_key_name(&self, origin: &str) -> String {
+        self.peers
+            .get(origin)
+            .and_then(|peer| peer.pinned_key.as_ref())
+            .map(|(key_name, _)| key_name.clone())
+            .unwrap_or_else(|| "ed25519:*".to_string())
+    }
+
+    /// Look up `key_name` for `server`: pinned config first, then the cache, then the
+    /// server's `/federation/keys` endpoint.
+    async fn resolve_key(
+        &self,
+        server: &str,
+        key_name: &str,
+        origin_ts: i64,
+    ) -> Option<VerifyingKey> {
+        let peer = self.peers.get(server)?;
+        if let Some((pinned_name, key)) = &peer.pinned_key {
+            if pinned_name == key_name {
+                return Some(*key);
+            }
+        }
+
+        if let Some(key) = self.cached_key(server, key_name, origin_ts).await {
+            return Some(key);
+        }
+
+        if let Err(err) = self.refresh_server_keys(server).await {
+            warn!(%server, error = %err, "failed to fetch federation keys");
+            return None;
+        }
+        self.cached_key(server, key_name, origin_ts).await
+    }
+
+    async fn cached_key(
+        &self,
+        server: &str,
+        key_name: &str,
+        origin_ts: i64,
+    ) -> Option<VerifyingKey> {
+        let cache = self.key_cache.read().await;
+        let cached = cache
+            .keys
+            .get(&(server.to_string(), key_name.to_string()))?;
+        let usable = match cached.expired_ts {
+            Some(expired_ts) => origin_ts < expired_ts,
+            None => chrono::Utc::now().timestamp_millis() < cached.valid_until_ts,
+        };
+        usable.then_some(cached.verifying_key)
+    }
+
+    /// Fetch and cache `server`'s published keys, at most once per refetch interval.
+    pub async fn refresh_server_keys(&self, server: &str) -> Result<(), FederationError> {
+        let (Some(client), Some(peer)) = (&self.client, self.peers.get(server)) else {
+            return Ok(());
+        };
+        {
+            let mut cache = self.key_cache.write().await;
+            if let Some(last) = cache.last_fetch.get(server) {
+                if last.elapsed() < KEY_REFETCH_INTERVAL {
+                    return Ok(());
+                }
+            }
+            cache.last_fetch.insert(server.to_string(), Instant::now());
+        }
 
-        let bytes = base64::engine::general_purpose::STANDARD
-            .decode(signature_b64)
-            .map_err(|_| FederationError::InvalidSignatureEncoding)?;
-        let signature =
-            Signature::from_slice(&bytes).map_err(|_| FederationError::InvalidSignatureEncoding)?;
+        let response = client.server_keys(server, &peer.base_url).await?;
+        if response.server_name != server {
+            return Err(FederationError::InvalidServerKeys {
+                server: server.to_string(),
+                reason: format!("response names '{}'", response.server_name),
+            });
+        }
+        let keys = response.verified_keys()?;
 
-        verify_signature(&peer.verifying_key, &hash, &signature)
-            .map_err(|_| FederationError::SignatureVerificationFailed)
+        let mut cache = self.key_cache.write().await;
+        cache
+            .keys
+            .retain(|(cached_server, _), _| cached_server != server);
+        for (key_name, key) in keys {
+            cache.keys.insert((server.to_string(), key_name), key);
+        }
+        info!(%server, "refreshed federation keys");
+        Ok(())
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[async_trait]
+impl RemoteFetcher for FederationService {
+    async fn fetch(&self, server: &str, media_id: &str) -> anyhow::Result<Option<RemoteObject>> {
+        Ok(self.fetch_media(server, media_id).await?)
+    }
+}
+
+fn verify_encoded_signature(
+    verifying_key: &VerifyingKey,
+    message: &[u8],
+    signature_b64: &str,
+) -> Result<(), FederationError> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(signature_b64)
+        .map_err(|_| FederationError::InvalidSignatureEncoding)?;
+    let signature =
+        Signature::from_slice(&bytes).map_err(|_| FederationError::InvalidSignatureEncoding)?;
+    verify_signature(verifying_key, message, &signature)
+        .map_err(|_| FederationError::SignatureVerificationFailed)
+}
+
+/// Orders events so that each appears after any of its `prev_events` within the same batch.
+fn order_by_ancestry(mut pending: Vec<CanonicalEvent>) -> Vec<CanonicalEvent> {
+    let mut ordered = Vec::with_capacity(pending.len());
+    let mut placed = HashSet::new();
+    while !pending.is_empty() {
+        let batch_ids: HashSet<EventId> = pending.iter().map(|e| e.event_id.clone()).collect();
+        let (ready, blocked): (Vec<_>, Vec<_>) = pending.into_iter().partition(|event| {
+            event
+                .prev_events
+                .iter()
+                .all(|prev| placed.contains(prev) || !batch_ids.contains(prev))
+        });
+        if ready.is_empty() {
+            // A cycle cannot be resolved; fall back to the order the peer sent.
+            ordered.extend(blocked);
+            break;
+        }
+        for event in ready {
+            placed.insert(event.event_id.clone());
+            ordered.push(event);
+        }
+        pending = blocked;
+    }
+    ordered
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TransactionRequest {
     pub origin: String,
     #[serde(default)]
@@ -160,6 +993,107 @@ pub struct FederationEventsResponse {
     pub events: Vec<messaging::TimelineEvent>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyKey {
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OldVerifyKey {
+    pub key: String,
+    pub expired_ts: i64,
+}
+
+/// Key set a server publishes at `/federation/keys`, signed by one of its current keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerKeysResponse {
+    pub server_name: String,
+    pub valid_until_ts: i64,
+    pub verify_keys: BTreeMap<String, VerifyKey>,
+    #[serde(default)]
+    pub old_verify_keys: BTreeMap<String, OldVerifyKey>,
+    #[serde(default)]
+    pub signatures: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl ServerKeysResponse {
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        unsigned.signatures.clear();
+        canonical_json::to_canonical_bytes(&unsigned).expect("serialization must succeed")
+    }
+
+    /// Decode every published key after checking the response is signed by a current key.
+    fn verified_keys(&self) -> Result<Vec<(String, CachedKey)>, FederationError> {
+        let invalid = |reason: &str| FederationError::InvalidServerKeys {
+            server: self.server_name.clone(),
+            reason: reason.to_string(),
+        };
+        let decode = |raw: &str| verifying_key_from_base64(raw).map_err(|_| invalid("bad key"));
+
+        let mut current = Vec::new();
+        for (key_name, key) in &self.verify_keys {
+            current.push((key_name.clone(), decode(&key.key)?));
+        }
+
+        let message = self.signing_bytes();
+        let self_signed = self
+            .signatures
+            .get(&self.server_name)
+            .into_iter()
+            .flatten()
+            .any(|(key_name, signature)| {
+                current.iter().any(|(name, key)| {
+                    name == key_name && verify_encoded_signature(key, &message, signature).is_ok()
+                })
+            });
+        if !self_signed {
+            return Err(invalid("missing valid self-signature"));
+        }
+
+        let mut keys: Vec<_> = current
+            .into_iter()
+            .map(|(key_name, verifying_key)| {
+                (
+                    key_name,
+                    CachedKey {
+                        verifying_key,
+                        valid_until_ts: self.valid_until_ts,
+                        expired_ts: None,
+                    },
+                )
+            })
+            .collect();
+        for (key_name, old) in &self.old_verify_keys {
+            keys.push((
+                key_name.clone(),
+                CachedKey {
+                    verifying_key: decode(&old.key)?,
+                    valid_until_ts: self.valid_until_ts,
+                    expired_ts: Some(old.expired_ts),
+                },
+            ));
+        }
+        Ok(keys)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingEventsRequest {
+    #[serde(default)]
+    pub earliest_events: Vec<EventId>,
+    pub latest_events: Vec<EventId>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingEventsResponse {
+    pub origin: String,
+    pub channel_id: Uuid,
+    pub events: Vec<CanonicalEvent>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TransactionResponse {
     pub origin: String,
@@ -213,3 +1147,367 @@ impl FederationEvaluation {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FederatedServerConfig, FederationOldKeyConfig};
+    use openguild_core::EventBuilder;
+    use openguild_crypto::{generate_signing_key, verifying_key_from};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Stand-in peer that serves `signer`'s key set and counts fetches.
+    struct KeyServingPeer {
+        server_name: String,
+        signer: FederationSigner,
+        fetches: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl FederationClient for KeyServingPeer {
+        async fn missing_events(
+            &self,
+            _destination: &str,
+            _base_url: &str,
+            _channel_id: Uuid,
+            _request: &MissingEventsRequest,
+        ) -> Result<Vec<CanonicalEvent>, FederationError> {
+            Ok(Vec::new())
+        }
+
+        async fn send_transaction(
+            &self,
+            destination: &str,
+            _base_url: &str,
+            _transaction: &TransactionRequest,
+        ) -> Result<TransactionOutcome, FederationError> {
+            Err(FederationError::Transport {
+                destination: destination.to_string(),
+                reason: "not supported".into(),
+            })
+        }
+
+        async fn server_keys(
+            &self,
+            _destination: &str,
+            _base_url: &str,
+        ) -> Result<ServerKeysResponse, FederationError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(self
+                .signer
+                .server_keys(&self.server_name, chrono::Utc::now().timestamp_millis()))
+        }
+
+        async fn media(
+            &self,
+            _destination: &str,
+            _base_url: &str,
+            _media_id: &str,
+        ) -> Result<Option<RemoteObject>, FederationError> {
+            Ok(None)
+        }
+    }
+
+    /// Stand-in peer holding a channel's history that answers `missing_events` the way
+    /// `MessagingService::missing_events` does, counting requests.
+    struct HistoryPeer {
+        events: HashMap<EventId, CanonicalEvent>,
+        requests: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl FederationClient for HistoryPeer {
+        async fn missing_events(
+            &self,
+            _destination: &str,
+            _base_url: &str,
+            _channel_id: Uuid,
+            request: &MissingEventsRequest,
+        ) -> Result<Vec<CanonicalEvent>, FederationError> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            let limit = request.limit.unwrap_or(MAX_BACKFILL_EVENTS);
+            let mut seen: HashSet<EventId> = request.earliest_events.iter().cloned().collect();
+            seen.extend(request.latest_events.iter().cloned());
+            let mut queue: std::collections::VecDeque<EventId> = request
+                .latest_events
+                .iter()
+                .filter_map(|id| self.events.get(id))
+                .flat_map(|event| event.prev_events.iter().cloned())
+                .collect();
+            let mut missing = Vec::new();
+            while let Some(event_id) = queue.pop_front() {
+                if missing.len() >= limit {
+                    break;
+                }
+                if !seen.insert(event_id.clone()) {
+                    continue;
+                }
+                let Some(event) = self.events.get(&event_id) else {
+                    continue;
+                };
+                queue.extend(event.prev_events.iter().cloned());
+                missing.push(event.clone());
+            }
+            missing.reverse();
+            Ok(missing)
+        }
+
+        async fn send_transaction(
+            &self,
+            destination: &str,
+            _base_url: &str,
+            _transaction: &TransactionRequest,
+        ) -> Result<TransactionOutcome, FederationError> {
+            Err(FederationError::Transport {
+                destination: destination.to_string(),
+                reason: "not supported".into(),
+            })
+        }
+
+        async fn server_keys(
+            &self,
+            destination: &str,
+            _base_url: &str,
+        ) -> Result<ServerKeysResponse, FederationError> {
+            Err(FederationError::Transport {
+                destination: destination.to_string(),
+                reason: "not supported".into(),
+            })
+        }
+
+        async fn media(
+            &self,
+            _destination: &str,
+            _base_url: &str,
+            _media_id: &str,
+        ) -> Result<Option<RemoteObject>, FederationError> {
+            Ok(None)
+        }
+    }
+
+    fn encode_signing(key: &openguild_crypto::SigningKey) -> String {
+        URL_SAFE_NO_PAD.encode(key.to_bytes())
+    }
+
+    fn encode_verifying(key: &openguild_crypto::SigningKey) -> String {
+        URL_SAFE_NO_PAD.encode(verifying_key_from(key).to_bytes())
+    }
+
+    fn discovering_service(peer: Arc<KeyServingPeer>) -> FederationService {
+        let config = FederationConfig {
+            trusted_servers: vec![FederatedServerConfig {
+                server_name: peer.server_name.clone(),
+                key_id: String::new(),
+                verifying_key: None,
+                base_url: None,
+            }],
+            ..FederationConfig::default()
+        };
+        FederationService::from_config(&config)
+            .unwrap()
+            .unwrap()
+            .with_client(peer)
+    }
+
+    fn remote_event(origin_ts: i64) -> CanonicalEvent {
+        let mut event =
+            EventBuilder::new("remote.example.org", Uuid::new_v4().to_string(), "message")
+                .content(json!({ "content": "hi" }))
+                .build();
+        event.origin_ts = origin_ts;
+        event.refresh_event_id();
+        event
+    }
+
+    #[test]
+    fn server_keys_are_self_signed_and_list_old_keys() {
+        let retired = generate_signing_key();
+        let config = FederationConfig {
+            signing_key: Some(encode_signing(&generate_signing_key())),
+            key_id: "2".into(),
+            old_keys: vec![FederationOldKeyConfig {
+                key_id: "1".into(),
+                verifying_key: encode_verifying(&retired),
+                expired_ts: 1_000,
+            }],
+            ..FederationConfig::default()
+        };
+        let signer = FederationSigner::from_config(&config).unwrap();
+        let keys = signer.server_keys("local.test", 5_000);
+
+        assert_eq!(keys.valid_until_ts, 5_000 + KEY_VALIDITY.as_millis() as i64);
+        assert!(keys.verify_keys.contains_key("ed25519:2"));
+        assert_eq!(keys.old_verify_keys["ed25519:1"].expired_ts, 1_000);
+        assert_eq!(keys.verified_keys().unwrap().len(), 2);
+
+        let mut tampered = keys.clone();
+        tampered.valid_until_ts += 1;
+        assert!(tampered.verified_keys().is_err());
+    }
+
+    #[tokio::test]
+    async fn signed_requests_are_accepted_once_and_only_while_fresh() {
+        let signing = generate_signing_key();
+        let config = FederationConfig {
+            signing_key: Some(encode_signing(&signing)),
+            key_id: "1".into(),
+            trusted_servers: vec![FederatedServerConfig {
+                server_name: "remote.example.org".into(),
+                key_id: "1".into(),
+                verifying_key: Some(encode_verifying(&signing)),
+                base_url: None,
+            }],
+            ..FederationConfig::default()
+        };
+        let signer = FederationSigner::from_config(&config).unwrap();
+        let service = FederationService::from_config(&config).unwrap().unwrap();
+        let uri = "/federation/channels/c/events";
+        let authenticate = |header: String| {
+            let service = &service;
+            async move {
+                service
+                    .authenticate_request(Some(&header), "localhost", "GET", uri, &[])
+                    .await
+            }
+        };
+
+        let header = signer.sign_request("remote.example.org", "localhost", "GET", uri, &[]);
+        assert_eq!(
+            authenticate(header.clone()).await.unwrap(),
+            "remote.example.org"
+        );
+        assert!(matches!(
+            authenticate(header).await,
+            Err(FederationError::ReplayedRequest { .. })
+        ));
+        // A fresh nonce makes an otherwise identical request acceptable.
+        let again = signer.sign_request("remote.example.org", "localhost", "GET", uri, &[]);
+        assert!(authenticate(again).await.is_ok());
+
+        let mut stale = FederationRequestAuth {
+            origin: "remote.example.org".into(),
+            destination: "localhost".into(),
+            key: "ed25519:1".into(),
+            origin_ts: chrono::Utc::now().timestamp_millis()
+                - REQUEST_TIMESTAMP_SKEW.as_millis() as i64
+                - 1_000,
+            nonce: "stale".into(),
+            signature: String::new(),
+        };
+        let signature = signer
+            .key_ring
+            .sign(&request_signing_bytes("GET", uri, &stale, &[]));
+        stale.signature = base64::engine::general_purpose::STANDARD.encode(signature.to_bytes());
+        assert!(matches!(
+            authenticate(stale.to_header()).await,
+            Err(FederationError::StaleRequest { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_keys_are_fetched_and_cached() {
+        let signing = generate_signing_key();
+        let retired = generate_signing_key();
+        let config = FederationConfig {
+            signing_key: Some(encode_signing(&signing)),
+            key_id: "new".into(),
+            old_keys: vec![FederationOldKeyConfig {
+                key_id: "old".into(),
+                verifying_key: encode_verifying(&retired),
+                expired_ts: 10_000,
+            }],
+            ..FederationConfig::default()
+        };
+        let peer = Arc::new(KeyServingPeer {
+            server_name: "remote.example.org".into(),
+            signer: FederationSigner::from_config(&config).unwrap(),
+            fetches: AtomicUsize::new(0),
+        });
+        let service = discovering_service(peer.clone());
+
+        let mut current = remote_event(chrono::Utc::now().timestamp_millis());
+        current.sign_with("remote.example.org", "new", &signing);
+        let mut historical = remote_event(5_000);
+        historical.sign_with("remote.example.org", "old", &retired);
+        let mut forged = remote_event(20_000);
+        forged.sign_with("remote.example.org", "old", &retired);
+
+        let evaluation = service
+            .evaluate_transaction(
+                "remote.example.org",
+                vec![current.clone(), historical.clone(), forged.clone()],
+            )
+            .await;
+
+        let accepted: Vec<_> = evaluation
+            .accepted_events
+            .iter()
+            .map(|event| event.event_id.clone())
+            .collect();
+        assert_eq!(accepted, vec![current.event_id, historical.event_id]);
+        assert_eq!(evaluation.rejected.len(), 1);
+        assert_eq!(evaluation.rejected[0].event_id, forged.event_id);
+        assert_eq!(peer.fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn backfill_keeps_fetching_until_the_gap_is_closed() {
+        let signing = generate_signing_key();
+        let messaging = MessagingService::new_in_memory("local.test".to_string());
+        let guild = messaging.create_guild("Backfill").await.unwrap();
+        let channel = messaging
+            .create_channel(guild.guild_id, "history")
+            .await
+            .unwrap();
+
+        let mut chain: Vec<CanonicalEvent> = Vec::new();
+        for index in 0..250 {
+            let prev_events = chain
+                .last()
+                .map(|event| vec![event.event_id.clone()])
+                .unwrap_or_default();
+            let mut event = EventBuilder::new(
+                "remote.example.org",
+                channel.channel_id.to_string(),
+                "message",
+            )
+            .sender("@remote:remote.example.org")
+            .content(json!({ "content": format!("message {index}") }))
+            .prev_events(prev_events)
+            .build();
+            event.sign_with("remote.example.org", "1", &signing);
+            chain.push(event);
+        }
+        let peer = Arc::new(HistoryPeer {
+            events: chain
+                .iter()
+                .map(|event| (event.event_id.clone(), event.clone()))
+                .collect(),
+            requests: AtomicUsize::new(0),
+        });
+        let latest = chain.pop().unwrap();
+        let config = FederationConfig {
+            trusted_servers: vec![FederatedServerConfig {
+                server_name: "remote.example.org".into(),
+                key_id: "1".into(),
+                verifying_key: Some(encode_verifying(&signing)),
+                base_url: None,
+            }],
+            ..FederationConfig::default()
+        };
+        let service = FederationService::from_config(&config)
+            .unwrap()
+            .unwrap()
+            .with_client(peer.clone());
+
+        let backfilled = service
+            .backfill_missing_events("remote.example.org", &messaging, &latest)
+            .await
+            .unwrap();
+        let ids: Vec<_> = backfilled.iter().map(|event| &event.event_id).collect();
+        let expected: Vec<_> = chain.iter().map(|event| &event.event_id).collect();
+        assert_eq!(ids, expected);
+        assert_eq!(peer.requests.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,638 @@
+//! Background delivery of locally created events to trusted federation peers.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use openguild_core::{CanonicalEvent, EventId};
+use openguild_storage::FederationOutboxStore;
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    config::FederationConfig,
+    federation::{FederationClient, FederationError, TransactionOutcome, TransactionRequest},
+};
+
+/// Upper bound on PDUs batched into a single transaction.
+pub const MAX_PDUS_PER_TRANSACTION: usize = 50;
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[async_trait]
+pub trait OutboundQueue: Send + Sync {
+    async fn enqueue(
+        &self,
+        destination: &str,
+        event: &CanonicalEvent,
+    ) -> Result<(), FederationError>;
+    async fn pending(
+        &self,
+        destination: &str,
+        limit: usize,
+    ) -> Result<Vec<CanonicalEvent>, FederationError>;
+    async fn remove(&self, destination: &str, event_ids: &[EventId])
+        -> Result<(), FederationError>;
+}
+
+#[async_trait]
+impl OutboundQueue for FederationOutboxStore {
+    async fn enqueue(
+        &self,
+        destination: &str,
+        event: &CanonicalEvent,
+    ) -> Result<(), FederationError> {
+        let body = serde_json::to_value(event).map_err(anyhow::Error::from)?;
+        FederationOutboxStore::enqueue(self, destination, &event.event_id, &body).await?;
+        Ok(())
+    }
+
+    async fn pending(
+        &self,
+        destination: &str,
+        limit: usize,
+    ) -> Result<Vec<CanonicalEvent>, FederationError> {
+        let entries = FederationOutboxStore::pending(self, destination, limit as i64).await?;
+        let mut events = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match serde_json::from_value(entry.body) {
+                Ok(event) => events.push(event),
+                Err(err) => {
+                    warn!(
+                        %destination,
+                        event_id = %entry.event_id,
+                        ?err,
+                        "dropping undecodable outbox entry"
+                    );
+                    FederationOutboxStore::remove(self, destination, &[entry.event_id]).await?;
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    async fn remove(
+        &self,
+        destination: &str,
+        event_ids: &[EventId],
+    ) -> Result<(), FederationError> {
+        FederationOutboxStore::remove(self, destination, event_ids).await?;
+        Ok(())
+    }
+}
+
+/// Non-durable queue used when Postgres is not configured.
+#[derive(Default)]
+pub struct InMemoryOutboundQueue {
+    entries: Mutex<HashMap<String, Vec<CanonicalEvent>>>,
+}
+
+#[async_trait]
+impl OutboundQueue for InMemoryOutboundQueue {
+    async fn enqueue(
+        &self,
+        destination: &str,
+        event: &CanonicalEvent,
+    ) -> Result<(), FederationError> {
+        let mut entries = self.entries.lock().await;
+        let queue = entries.entry(destination.to_string()).or_default();
+        if !queue.iter().any(|queued| queued.event_id == event.event_id) {
+            queue.push(event.clone());
+        }
+        Ok(())
+    }
+
+    async fn pending(
+        &self,
+        destination: &str,
+        limit: usize,
+    ) -> Result<Vec<CanonicalEvent>, FederationError> {
+        let entries = self.entries.lock().await;
+        Ok(entries
+            .get(destination)
+            .map(|queue| queue.iter().take(limit).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn remove(
+        &self,
+        destination: &str,
+        event_ids: &[EventId],
+    ) -> Result<(), FederationError> {
+        if let Some(queue) = self.entries.lock().await.get_mut(destination) {
+            queue.retain(|event| !event_ids.contains(&event.event_id));
+        }
+        Ok(())
+    }
+}
+
+/// Exponential backoff applied per destination after failed deliveries.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(600),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(16);
+        self.initial.saturating_mul(1u32 << exponent).min(self.max)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlushOutcome {
+    /// Nothing was queued for the destination.
+    Idle,
+    /// The destination is backing off after an earlier failure.
+    BackingOff,
+    /// The peer returned a verdict; accepted and rejected events left the queue.
+    Delivered {
+        accepted: Vec<EventId>,
+        rejected: Vec<EventId>,
+    },
+    /// Delivery failed; the batch stays queued until `retry_in` elapses.
+    RetryScheduled { retry_in: Duration },
+}
+
+struct Destination {
+    server_name: String,
+    base_url: String,
+}
+
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+pub struct FederationSender {
+    origin: String,
+    destinations: Vec<Destination>,
+    queue: Arc<dyn OutboundQueue>,
+    client: Arc<dyn FederationClient>,
+    retry: RetryPolicy,
+    backoff: Mutex<HashMap<String, Backoff>>,
+    wake: Notify,
+}
+
+impl FederationSender {
+    pub fn new(
+        origin: String,
+        config: &FederationConfig,
+        queue: Arc<dyn OutboundQueue>,
+        client: Arc<dyn FederationClient>,
+    ) -> Self {
+        let destinations = config
+            .trusted_servers
+            .iter()
+            .filter(|peer| peer.server_name != origin)
+            .map(|peer| Destination {
+                server_name: peer.server_name.clone(),
+                base_url: peer.base_url(),
+            })
+            .collect();
+        Self {
+            origin,
+            destinations,
+            queue,
+            client,
+            retry: RetryPolicy::default(),
+            backoff: Mutex::new(HashMap::new()),
+            wake: Notify::new(),
+        }
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Trusted destinations among `servers`, normally those with members in a channel.
+    pub fn destinations_for(&self, servers: &BTreeSet<String>) -> Vec<String> {
+        self.destinations
+            .iter()
+            .filter(|destination| servers.contains(&destination.server_name))
+            .map(|destination| destination.server_name.clone())
+            .collect()
+    }
+
+    /// Queue a signed local event for `destinations` and wake the delivery loop.
+    pub async fn enqueue(&self, event: &CanonicalEvent, destinations: &[String]) {
+        for destination in destinations {
+            if let Err(err) = self.queue.enqueue(destination, event).await {
+                warn!(
+                    %destination,
+                    event_id = %event.event_id,
+                    ?err,
+                    "failed to queue event for federation"
+                );
+            }
+        }
+        self.notify_queued();
+    }
+
+    /// Wake the delivery loop for events written to the outbox together with the event.
+    pub fn notify_queued(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Attempt one transaction to `destination` with the oldest queued events.
+    ///
+    /// Rejected events are dropped rather than retried: the peer evaluated them and
+    /// resending the same bytes cannot change the verdict. Events the peer neither
+    /// accepted nor rejected stay queued and trigger backoff.
+    pub async fn flush(&self, destination: &str) -> Result<FlushOutcome, FederationError> {
+        let Some(target) = self
+            .destinations
+            .iter()
+            .find(|candidate| candidate.server_name == destination)
+        else {
+            return Ok(FlushOutcome::Idle);
+        };
+
+        if let Some(retry_at) = self
+            .backoff
+            .lock()
+            .await
+            .get(destination)
+            .and_then(|state| state.retry_at)
+        {
+            if retry_at > Instant::now() {
+                return Ok(FlushOutcome::BackingOff);
+            }
+        }
+
+        let pdus = self
+            .queue
+            .pending(destination, MAX_PDUS_PER_TRANSACTION)
+            .await?;
+        if pdus.is_empty() {
+            return Ok(FlushOutcome::Idle);
+        }
+        let sent: Vec<EventId> = pdus.iter().map(|event| event.event_id.clone()).collect();
+        let transaction = TransactionRequest {
+            origin: self.origin.clone(),
+            pdus,
+        };
+
+        let result = self
+            .client
+            .send_transaction(destination, &target.base_url, &transaction)
+            .await;
+
+        let verdict = match result {
+            Ok(TransactionOutcome {
+                status,
+                response: Some(response),
+            }) if (!response.disabled && (200..300).contains(&status)) || status == 400 => {
+                Some(response)
+            }
+            Ok(TransactionOutcome { status, .. }) => {
+                warn!(%destination, status, "federation transaction not processed");
+                None
+            }
+            Err(err) => {
+                warn!(%destination, error = %err, "federation transaction failed");
+                None
+            }
+        };
+
+        let Some(response) = verdict else {
+            return Ok(self.schedule_retry(destination).await);
+        };
+
+        let accepted: Vec<EventId> = response
+            .accepted
+            .into_iter()
+            .filter(|id| sent.contains(id))
+            .collect();
+        let rejected: Vec<EventId> = response
+            .rejected
+            .into_iter()
+            .filter(|rejection| sent.contains(&rejection.event_id))
+            .map(|rejection| {
+                warn!(
+                    %destination,
+                    event_id = %rejection.event_id,
+                    reason = %rejection.reason,
+                    "peer rejected federated event"
+                );
+                rejection.event_id
+            })
+            .collect();
+
+        let mut settled = accepted.clone();
+        settled.extend(rejected.iter().cloned());
+        self.queue.remove(destination, &settled).await?;
+
+        if settled.len() < sent.len() {
+            return Ok(self.schedule_retry(destination).await);
+        }
+
+        self.backoff.lock().await.remove(destination);
+        debug!(
+            %destination,
+            accepted = accepted.len(),
+            rejected = rejected.len(),
+            "federation transaction delivered"
+        );
+        Ok(FlushOutcome::Delivered { accepted, rejected })
+    }
+
+    async fn schedule_retry(&self, destination: &str) -> FlushOutcome {
+        let mut backoff = self.backoff.lock().await;
+        let state = backoff.entry(destination.to_string()).or_default();
+        state.failures = state.failures.saturating_add(1);
+        let retry_in = self.retry.delay(state.failures);
+        state.retry_at = Some(Instant::now() + retry_in);
+        FlushOutcome::RetryScheduled { retry_in }
+    }
+
+    async fn next_wakeup(&self) -> Duration {
+        let now = Instant::now();
+        self.backoff
+            .lock()
+            .await
+            .values()
+            .filter_map(|state| state.retry_at)
+            .map(|retry_at| retry_at.saturating_duration_since(now))
+            .min()
+            .unwrap_or(IDLE_POLL_INTERVAL)
+            .min(IDLE_POLL_INTERVAL)
+    }
+
+    /// Drive delivery until the task is aborted; queued rows from a previous run are sent first.
+    pub fn spawn(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                for destination in &self.destinations {
+                    loop {
+                        match self.flush(&destination.server_name).await {
+                            Ok(FlushOutcome::Delivered { .. }) => continue,
+                            Ok(_) => break,
+                            Err(err) => {
+                                warn!(
+                                    destination = %destination.server_name,
+                                    error = %err,
+                                    "federation outbox unavailable"
+                                );
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                let wait = self.next_wakeup().await;
+                tokio::select! {
+                    _ = self.wake.notified() => {}
+                    _ = tokio::time::sleep(wait) => {}
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::FederatedServerConfig,
+        federation::{
+            MissingEventsRequest, RejectedEvent, ServerKeysResponse, TransactionResponse,
+        },
+    };
+    use openguild_core::EventBuilder;
+    use serde_json::json;
+    use std::sync::Mutex as StdMutex;
+    use uuid::Uuid;
+
+    /// Peer stand-in that replays scripted verdicts and records what it received.
+    #[derive(Default)]
+    struct ScriptedPeer {
+        verdicts: StdMutex<Vec<Result<TransactionOutcome, FederationError>>>,
+        received: StdMutex<Vec<Vec<EventId>>>,
+    }
+
+    impl ScriptedPeer {
+        fn push(&self, verdict: Result<TransactionOutcome, FederationError>) {
+            self.verdicts.lock().unwrap().push(verdict);
+        }
+    }
+
+    #[async_trait]
+    impl FederationClient for ScriptedPeer {
+        async fn missing_events(
+            &self,
+            _destination: &str,
+            _base_url: &str,
+            _channel_id: Uuid,
+            _request: &MissingEventsRequest,
+        ) -> Result<Vec<CanonicalEvent>, FederationError> {
+            Ok(Vec::new())
+        }
+
+        async fn send_transaction(
+            &self,
+            _destination: &str,
+            _base_url: &str,
+            transaction: &TransactionRequest,
+        ) -> Result<TransactionOutcome, FederationError> {
+            self.received.lock().unwrap().push(
+                transaction
+                    .pdus
+                    .iter()
+                    .map(|event| event.event_id.clone())
+                    .collect(),
+            );
+            self.verdicts.lock().unwrap().remove(0)
+        }
+
+        async fn server_keys(
+            &self,
+            destination: &str,
+            _base_url: &str,
+        ) -> Result<ServerKeysResponse, FederationError> {
+            Err(FederationError::Transport {
+                destination: destination.to_string(),
+                reason: "not scripted".into(),
+            })
+        }
+
+        async fn media(
+            &self,
+            _destination: &str,
+            _base_url: &str,
+            _media_id: &str,
+        ) -> Result<Option<openguild_media::RemoteObject>, FederationError> {
+            Ok(None)
+        }
+    }
+
+    fn sender(peer: Arc<ScriptedPeer>, queue: Arc<InMemoryOutboundQueue>) -> FederationSender {
+        let config = FederationConfig {
+            trusted_servers: vec![FederatedServerConfig {
+                server_name: "remote.example.org".into(),
+                key_id: "1".into(),
+                verifying_key: None,
+                base_url: None,
+            }],
+            ..FederationConfig::default()
+        };
+        FederationSender::new("local.test".into(), &config, queue, peer).with_retry_policy(
+            RetryPolicy {
+                initial: Duration::ZERO,
+                max: Duration::ZERO,
+            },
+        )
+    }
+
+    fn remote() -> Vec<String> {
+        vec!["remote.example.org".into()]
+    }
+
+    fn event(body: &str) -> CanonicalEvent {
+        EventBuilder::new("local.test", Uuid::new_v4().to_string(), "message")
+            .content(json!({ "content": body }))
+            .build()
+    }
+
+    fn verdict(
+        status: u16,
+        accepted: &[&CanonicalEvent],
+        rejected: &[&CanonicalEvent],
+    ) -> TransactionOutcome {
+        TransactionOutcome {
+            status,
+            response: Some(TransactionResponse {
+                origin: "remote.example.org".into(),
+                accepted: accepted.iter().map(|e| e.event_id.clone()).collect(),
+                rejected: rejected
+                    .iter()
+                    .map(|e| RejectedEvent {
+                        event_id: e.event_id.clone(),
+                        reason: "signature verification failed".into(),
+                    })
+                    .collect(),
+                disabled: false,
+            }),
+        }
+    }
+
+    #[test]
+    fn retry_policy_doubles_until_capped() {
+        let policy = RetryPolicy {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+        };
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(4), Duration::from_secs(8));
+        assert_eq!(policy.delay(5), Duration::from_secs(10));
+        assert_eq!(policy.delay(64), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn destinations_are_trusted_servers_with_members() {
+        let sender = sender(
+            Arc::new(ScriptedPeer::default()),
+            Arc::new(InMemoryOutboundQueue::default()),
+        );
+        let servers = BTreeSet::from([
+            "local.test".to_string(),
+            "remote.example.org".to_string(),
+            "untrusted.example.org".to_string(),
+        ]);
+        assert_eq!(sender.destinations_for(&servers), remote());
+        assert!(sender
+            .destinations_for(&BTreeSet::from(["local.test".to_string()]))
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn multi_status_drops_settled_events_only() {
+        let peer = Arc::new(ScriptedPeer::default());
+        let queue = Arc::new(InMemoryOutboundQueue::default());
+        let sender = sender(peer.clone(), queue.clone());
+        let (first, second, third) = (event("one"), event("two"), event("three"));
+        for event in [&first, &second, &third] {
+            sender.enqueue(event, &remote()).await;
+        }
+
+        peer.push(Ok(verdict(207, &[&first], &[&second])));
+        let outcome = sender.flush("remote.example.org").await.unwrap();
+        assert!(matches!(outcome, FlushOutcome::RetryScheduled { .. }));
+
+        let remaining = queue.pending("remote.example.org", 10).await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].event_id, third.event_id);
+
+        peer.push(Ok(verdict(202, &[&third], &[])));
+        let outcome = sender.flush("remote.example.org").await.unwrap();
+        assert_eq!(
+            outcome,
+            FlushOutcome::Delivered {
+                accepted: vec![third.event_id.clone()],
+                rejected: Vec::new(),
+            }
+        );
+        assert_eq!(
+            peer.received.lock().unwrap().clone(),
+            vec![
+                vec![
+                    first.event_id.clone(),
+                    second.event_id.clone(),
+                    third.event_id.clone()
+                ],
+                vec![third.event_id.clone()],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failures_back_off_and_keep_the_queue() {
+        let peer = Arc::new(ScriptedPeer::default());
+        let queue = Arc::new(InMemoryOutboundQueue::default());
+        let sender = sender(peer.clone(), queue.clone()).with_retry_policy(RetryPolicy {
+            initial: Duration::from_secs(60),
+            max: Duration::from_secs(600),
+        });
+        let event = event("hello");
+        sender.enqueue(&event, &remote()).await;
+
+        peer.push(Err(FederationError::Transport {
+            destination: "remote.example.org".into(),
+            reason: "connection refused".into(),
+        }));
+        assert_eq!(
+            sender.flush("remote.example.org").await.unwrap(),
+            FlushOutcome::RetryScheduled {
+                retry_in: Duration::from_secs(60)
+            }
+        );
+        assert_eq!(
+            sender.flush("remote.example.org").await.unwrap(),
+            FlushOutcome::BackingOff
+        );
+        assert_eq!(peer.received.lock().unwrap().len(), 1);
+        assert_eq!(
+            queue.pending("remote.example.org", 10).await.unwrap().len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,863 @@
+//! Server-side key backup. Clients encrypt MLS epoch secrets and message keys with a recovery
+//! key that never leaves their devices and upload the ciphertext here, so a new device can
+//! restore encrypted history. Backups are versioned per user: creating a version (for example
+//! after rotating the recovery key) retires the previous one for uploads, while older live
+//! versions stay readable until deleted.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{MatchedPath, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use openguild_storage::{
+    BackupKeyRecord, KeyBackupRepository, KeyBackupVersion, NewBackupKey, StoragePool,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{config::ServerConfig, mls_groups::MAX_GROUP_ID_LENGTH, session, AppState};
+
+/// Longest backup algorithm name.
+pub const MAX_ALGORITHM_LENGTH: usize = 128;
+/// Upper bound on a version's serialized `auth_data`.
+pub const MAX_AUTH_DATA_LENGTH: usize = 4 * 1024;
+/// Upper bound on one decoded backup entry.
+pub const MAX_BACKUP_KEY_LENGTH: usize = 64 * 1024;
+/// Entries a single upload may carry.
+pub const MAX_BACKUP_KEYS_PER_UPLOAD: usize = 500;
+
+#[derive(Debug, Error)]
+pub enum KeyBackupError {
+    #[error("key backup version not found")]
+    VersionNotFound,
+    #[error("uploads go to the current backup version {current}")]
+    WrongVersion { current: i64 },
+    #[error("{0}")]
+    Invalid(&'static str),
+    #[error(transparent)]
+    Storage(#[from] anyhow::Error),
+}
+
+#[async_trait]
+pub trait KeyBackupStore: Send + Sync {
+    async fn create_version(
+        &self,
+        user_id: Uuid,
+        algorithm: &str,
+        auth_data: &serde_json::Value,
+    ) -> Result<KeyBackupVersion, KeyBackupError>;
+    async fn latest_version(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<KeyBackupVersion>, KeyBackupError>;
+    async fn version(
+        &self,
+        user_id: Uuid,
+        version: i64,
+    ) -> Result<Option<KeyBackupVersion>, KeyBackupError>;
+    async fn delete_version(&self, user_id: Uuid, version: i64) -> Result<bool, KeyBackupError>;
+    /// `None` when `version` is not the user's latest live version.
+    async fn put_keys(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        keys: Vec<NewBackupKey>,
+    ) -> Result<Option<KeyBackupVersion>, KeyBackupError>;
+    async fn keys(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        group_id: Option<&str>,
+    ) -> Result<Vec<BackupKeyRecord>, KeyBackupError>;
+}
+
+#[async_trait]
+impl KeyBackupStore for KeyBackupRepository {
+    async fn create_version(
+        &self,
+        user_id: Uuid,
+        algorithm: &str,
+        auth_data: &serde_json::Value,
+    ) -> Result<KeyBackupVersion, KeyBackupError> {
+        Ok(KeyBackupRepository::create_version(self, user_id, algorithm, auth_data).await?)
+    }
+
+    async fn latest_version(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<KeyBackupVersion>, KeyBackupError> {
+        Ok(KeyBackupRepository::latest_version(self, user_id).await?)
+    }
+
+    async fn version(
+        &self,
+        user_id: Uuid,
+        version: i64,
+    ) -> Result<Option<KeyBackupVersion>, KeyBackupError> {
+        Ok(KeyBackupRepository::version(self, user_id, version).await?)
+    }
+
+    async fn delete_version(&self, user_id: Uuid, version: i64) -> Result<bool, KeyBackupError> {
+        Ok(KeyBackupRepository::delete_version(self, user_id, version).await?)
+    }
+
+    async fn put_keys(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        keys: Vec<NewBackupKey>,
+    ) -> Result<Option<KeyBackupVersion>, KeyBackupError> {
+        Ok(KeyBackupRepository::put_keys(self, user_id, version, &keys).await?)
+    }
+
+    async fn keys(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        group_id: Option<&str>,
+    ) -> Result<Vec<BackupKeyRecord>, KeyBackupError> {
+        Ok(KeyBackupRepository::keys(self, user_id, version, group_id).await?)
+    }
+}
+
+struct InMemoryBackupVersion {
+    algorithm: String,
+    auth_data: serde_json::Value,
+    created_at: DateTime<Utc>,
+    deleted: bool,
+    keys: BTreeMap<(String, i64), BackupKeyRecord>,
+}
+
+impl InMemoryBackupVersion {
+    fn record(&self, user_id: Uuid, version: i64) -> KeyBackupVersion {
+        KeyBackupVersion {
+            user_id,
+            version,
+            algorithm: self.algorithm.clone(),
+            auth_data: self.auth_data.clone(),
+            key_count: self.keys.len() as i64,
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// Non-durable key backups used when Postgres is not configured. A user's versions are
+/// numbered by their position in the list.
+#[derive(Default)]
+pub struct InMemoryKeyBackups {
+    versions: Mutex<HashMap<Uuid, Vec<InMemoryBackupVersion>>>,
+}
+
+#[async_trait]
+impl KeyBackupStore for InMemoryKeyBackups {
+    async fn create_version(
+        &self,
+        user_id: Uuid,
+        algorithm: &str,
+        auth_data: &serde_json::Value,
+    ) -> Result<KeyBackupVersion, KeyBackupError> {
+        let mut versions = self.versions.lock().await;
+        let list = versions.entry(user_id).or_default();
+        list.push(InMemoryBackupVersion {
+            algorithm: algorithm.to_string(),
+            auth_data: auth_data.clone(),
+            created_at: Utc::now(),
+            deleted: false,
+            keys: BTreeMap::new(),
+        });
+        Ok(list[list.len() - 1].record(user_id, list.len() as i64))
+    }
+
+    async fn latest_version(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<KeyBackupVersion>, KeyBackupError> {
+        let versions = self.versions.lock().await;
+        Ok(versions.get(&user_id).and_then(|list| {
+            list.iter()
+                .enumerate()
+                .rev()
+                .find(|(_, entry)| !entry.deleted)
+                .map(|(index, entry)| entry.record(user_id, index as i64 + 1))
+        }))
+    }
+
+    async fn version(
+        &self,
+        user_id: Uuid,
+        version: i64,
+    ) -> Result<Option<KeyBackupVersion>, KeyBackupError> {
+        let versions = self.versions.lock().await;
+        Ok(versions
+            .get(&user_id)
+            .and_then(|list| list.get(usize::try_from(version.checked_sub(1)?).ok()?))
+            .filter(|entry| !entry.deleted)
+            .map(|entry| entry.record(user_id, version)))
+    }
+
+    async fn delete_version(&self, user_id: Uuid, version: i64) -> Result<bool, KeyBackupError> {
+        let mut versions = self.versions.lock().await;
+        let Some(entry) = versions
+            .get_mut(&user_id)
+            .and_then(|list| list.get_mut(usize::try_from(version.checked_sub(1)?).ok()?))
+            .filter(|entry| !entry.deleted)
+        else {
+            return Ok(false);
+        };
+        entry.deleted = true;
+        entry.keys.clear();
+        Ok(true)
+    }
+
+    async fn put_keys(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        keys: Vec<NewBackupKey>,
+    ) -> Result<Option<KeyBackupVersion>, KeyBackupError> {
+        let mut versions = self.versions.lock().await;
+        let Some(list) = versions.get_mut(&user_id) else {
+            return Ok(None);
+        };
+        let Some(current) = list.iter().rposition(|entry| !entry.deleted) else {
+            return Ok(None);
+        };
+        if current as i64 + 1 != version {
+            return Ok(None);
+        }
+        let entry = &mut list[current];
+        let now = Utc::now();
+        for key in keys {
+            entry.keys.insert(
+                (key.group_id.clone(), key.epoch),
+                BackupKeyRecord {
+                    group_id: key.group_id,
+                    epoch: key.epoch,
+                    ciphertext: key.ciphertext,
+                    updated_at: now,
+                },
+            );
+        }
+        Ok(Some(entry.record(user_id, version)))
+    }
+
+    async fn keys(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        group_id: Option<&str>,
+    ) -> Result<Vec<BackupKeyRecord>, KeyBackupError> {
+        let versions = self.versions.lock().await;
+        Ok(versions
+            .get(&user_id)
+            .and_then(|list| list.get(usize::try_from(version.checked_sub(1)?).ok()?))
+            .map(|entry| {
+                entry
+                    .keys
+                    .values()
+                    .filter(|key| group_id.is_none_or(|group_id| key.group_id == group_id))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+pub struct KeyBackupService {
+    store: Arc<dyn KeyBackupStore>,
+}
+
+impl KeyBackupService {
+    pub fn new(store: Arc<dyn KeyBackupStore>) -> Self {
+        Self { store }
+    }
+
+    /// Start a new backup version, which becomes the only one accepting uploads.
+    pub async fn create_version(
+        &self,
+        user_id: Uuid,
+        algorithm: &str,
+        auth_data: serde_json::Value,
+    ) -> Result<KeyBackupVersionResponse, KeyBackupError> {
+        let algorithm = algorithm.trim();
+        if algorithm.is_empty() || algorithm.len() > MAX_ALGORITHM_LENGTH {
+            return Err(KeyBackupError::Invalid("algorithm must be 1-128 bytes"));
+        }
+        if !auth_data.is_object() {
+            return Err(KeyBackupError::Invalid("auth_data must be a JSON object"));
+        }
+        if auth_data.to_string().len() > MAX_AUTH_DATA_LENGTH {
+            return Err(KeyBackupError::Invalid("auth_data exceeds 4 KiB"));
+        }
+        let version = self
+            .store
+            .create_version(user_id, algorithm, &auth_data)
+            .await?;
+        Ok(KeyBackupVersionResponse::from(version))
+    }
+
+    pub async fn latest_version(
+        &self,
+        user_id: Uuid,
+    ) -> Result<KeyBackupVersionResponse, KeyBackupError> {
+        self.store
+            .latest_version(user_id)
+            .await?
+            .map(KeyBackupVersionResponse::from)
+            .ok_or(KeyBackupError::VersionNotFound)
+    }
+
+    pub async fn version(
+        &self,
+        user_id: Uuid,
+        version: i64,
+    ) -> Result<KeyBackupVersionResponse, KeyBackupError> {
+        self.store
+            .version(user_id, version)
+            .await?
+            .map(KeyBackupVersionResponse::from)
+            .ok_or(KeyBackupError::VersionNotFound)
+    }
+
+    pub async fn delete_version(&self, user_id: Uuid, version: i64) -> Result<(), KeyBackupError> {
+        if !self.store.delete_version(user_id, version).await? {
+            return Err(KeyBackupError::VersionNotFound);
+        }
+        Ok(())
+    }
+
+    /// Store entries in the current version, replacing any for the same group epoch.
+    pub async fn put_keys(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        keys: Vec<BackupKeyUpload>,
+    ) -> Result<KeyBackupVersionResponse, KeyBackupError> {
+        if keys.is_empty() || keys.len() > MAX_BACKUP_KEYS_PER_UPLOAD {
+            return Err(KeyBackupError::Invalid(
+                "uploads must carry between 1 and 500 keys",
+            ));
+        }
+        let keys = keys
+            .into_iter()
+            .map(BackupKeyUpload::decode)
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(updated) = self.store.put_keys(user_id, version, keys).await? {
+            return Ok(KeyBackupVersionResponse::from(updated));
+        }
+        match self.store.latest_version(user_id).await? {
+            Some(current) => Err(KeyBackupError::WrongVersion {
+                current: current.version,
+            }),
+            None => Err(KeyBackupError::VersionNotFound),
+        }
+    }
+
+    pub async fn keys(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        group_id: Option<&str>,
+    ) -> Result<BackupKeysResponse, KeyBackupError> {
+        if self.store.version(user_id, version).await?.is_none() {
+            return Err(KeyBackupError::VersionNotFound);
+        }
+        let keys = self.store.keys(user_id, version, group_id).await?;
+        Ok(BackupKeysResponse {
+            version,
+            keys: keys.into_iter().map(BackupKeyResponse::from).collect(),
+        })
+    }
+}
+
+/// Enabled alongside MLS; backups persist when a database is configured.
+pub fn init_key_backups(
+    config: &ServerConfig,
+    pool: Option<StoragePool>,
+) -> Option<KeyBackupService> {
+    if !config.mls.enabled {
+        return None;
+    }
+    let store: Arc<dyn KeyBackupStore> = match pool {
+        Some(pool) => Arc::new(KeyBackupRepository::new(pool.cloned())),
+        None => {
+            tracing::info!("key backup persistence unavailable; using in-memory backups");
+            Arc::new(InMemoryKeyBackups::default())
+        }
+    };
+    Some(KeyBackupService::new(store))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyBackupVersionResponse {
+    pub version: i64,
+    pub algorithm: String,
+    pub auth_data: serde_json::Value,
+    pub key_count: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<KeyBackupVersion> for KeyBackupVersionResponse {
+    fn from(record: KeyBackupVersion) -> Self {
+        Self {
+            version: record.version,
+            algorithm: record.algorithm,
+            auth_data: record.auth_data,
+            key_count: record.key_count,
+            created_at: record.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupKeyResponse {
+    pub group_id: String,
+    pub epoch: i64,
+    /// URL-safe base64 of the client-encrypted key material.
+    pub ciphertext: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<BackupKeyRecord> for BackupKeyResponse {
+    fn from(record: BackupKeyRecord) -> Self {
+        Self {
+            group_id: record.group_id,
+            epoch: record.epoch,
+            ciphertext: URL_SAFE_NO_PAD.encode(record.ciphertext),
+            updated_at: record.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupKeysResponse {
+    pub version: i64,
+    pub keys: Vec<BackupKeyResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateKeyBackupRequest {
+    pub algorithm: String,
+    pub auth_data: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackupKeyUpload {
+    pub group_id: String,
+    pub epoch: u64,
+    pub ciphertext: String,
+}
+
+impl BackupKeyUpload {
+    fn decode(self) -> Result<NewBackupKey, KeyBackupError> {
+        match URL_SAFE_NO_PAD.decode(&self.group_id) {
+            Ok(bytes) if !bytes.is_empty() && bytes.len() <= MAX_GROUP_ID_LENGTH => {}
+            _ => {
+                return Err(KeyBackupError::Invalid(
+                    "group id must be 1-255 bytes of URL-safe base64",
+                ))
+            }
+        }
+        let epoch = i64::try_from(self.epoch)
+            .map_err(|_| KeyBackupError::Invalid("epoch is out of range"))?;
+        let ciphertext = match URL_SAFE_NO_PAD.decode(self.ciphertext.trim()) {
+            Ok(bytes) if !bytes.is_empty() && bytes.len() <= MAX_BACKUP_KEY_LENGTH => bytes,
+            _ => {
+                return Err(KeyBackupError::Invalid(
+                    "ciphertext must be 1 byte to 64 KiB of URL-safe base64",
+                ))
+            }
+        };
+        Ok(NewBackupKey {
+            group_id: self.group_id,
+            epoch,
+            ciphertext,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PutBackupKeysRequest {
+    pub keys: Vec<BackupKeyUpload>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BackupKeysQuery {
+    pub group_id: Option<String>,
+}
+
+fn status_for(state: &AppState, err: &KeyBackupError) -> StatusCode {
+    match err {
+        KeyBackupError::VersionNotFound => StatusCode::NOT_FOUND,
+        KeyBackupError::WrongVersion { .. } => {
+            state.record_messaging_rejection("key_backup_wrong_version");
+            StatusCode::CONFLICT
+        }
+        KeyBackupError::Invalid(reason) => {
+            tracing::debug!(reason, "rejected key backup request");
+            state.record_messaging_rejection("key_backup_invalid");
+            StatusCode::BAD_REQUEST
+        }
+        KeyBackupError::Storage(err) => {
+            tracing::error!(?err, "key backup storage failure");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+pub async fn create_backup_version(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<CreateKeyBackupRequest>,
+) -> Result<(StatusCode, Json<KeyBackupVersionResponse>), StatusCode> {
+    let Some(backups) = state.key_backups() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match backups
+        .create_version(claims.user_id, &body.algorithm, body.auth_data)
+        .await
+    {
+        Ok(version) => {
+            let status = StatusCode::CREATED;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Ok((status, Json(version)));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn latest_backup_version(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<KeyBackupVersionResponse>, StatusCode> {
+    let Some(backups) = state.key_backups() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match backups.latest_version(claims.user_id).await {
+        Ok(version) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(version));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn get_backup_version(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(version): Path<i64>,
+) -> Result<Json<KeyBackupVersionResponse>, StatusCode> {
+    let Some(backups) = state.key_backups() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match backups.version(claims.user_id, version).await {
+        Ok(version) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(version));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn delete_backup_version(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(version): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let Some(backups) = state.key_backups() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match backups.delete_version(claims.user_id, version).await {
+        Ok(()) => {
+            let status = StatusCode::NO_CONTENT;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Ok(status);
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn put_backup_keys(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(version): Path<i64>,
+    Json(body): Json<PutBackupKeysRequest>,
+) -> Result<Json<KeyBackupVersionResponse>, StatusCode> {
+    let Some(backups) = state.key_backups() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match backups.put_keys(claims.user_id, version, body.keys).await {
+        Ok(version) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(version));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn get_backup_keys(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(version): Path<i64>,
+    Query(query): Query<BackupKeysQuery>,
+) -> Result<Json<BackupKeysResponse>, StatusCode> {
+    let Some(backups) = state.key_backups() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match backups
+        .keys(claims.user_id, version, query.group_id.as_deref())
+        .await
+    {
+        Ok(keys) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(keys));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn service() -> KeyBackupService {
+        KeyBackupService::new(Arc::new(InMemoryKeyBackups::default()))
+    }
+
+    fn upload(group: &[u8], epoch: u64, ciphertext: &[u8]) -> BackupKeyUpload {
+        BackupKeyUpload {
+            group_id: URL_SAFE_NO_PAD.encode(group),
+            epoch,
+            ciphertext: URL_SAFE_NO_PAD.encode(ciphertext),
+        }
+    }
+
+    #[tokio::test]
+    async fn new_versions_retire_old_ones_for_uploads() {
+        let backups = service();
+        let alice = Uuid::new_v4();
+        let auth_data = json!({ "public_key": "cmVjb3Zlcnk" });
+
+        assert!(matches!(
+            backups.latest_version(alice).await,
+            Err(KeyBackupError::VersionNotFound)
+        ));
+        assert!(matches!(
+            backups
+                .put_keys(alice, 1, vec![upload(b"g", 0, b"a")])
+                .await,
+            Err(KeyBackupError::VersionNotFound)
+        ));
+
+        let first = backups
+            .create_version(alice, "mls.backup.v1", auth_data.clone())
+            .await
+            .unwrap();
+        assert_eq!(first.version, 1);
+        backups
+            .put_keys(
+                alice,
+                1,
+                vec![upload(b"g", 0, b"epoch 0"), upload(b"g", 1, b"epoch 1")],
+            )
+            .await
+            .unwrap();
+        let updated = backups
+            .put_keys(alice, 1, vec![upload(b"g", 1, b"epoch 1 again")])
+            .await
+            .unwrap();
+        assert_eq!(updated.key_count, 2);
+
+        let second = backups
+            .create_version(alice, "mls.backup.v1", auth_data)
+            .await
+            .unwrap();
+        assert_eq!(second.version, 2);
+        assert!(matches!(
+            backups
+                .put_keys(alice, 1, vec![upload(b"g", 2, b"x")])
+                .await,
+            Err(KeyBackupError::WrongVersion { current: 2 })
+        ));
+
+        // The retired version stays readable until it is deleted.
+        let keys = backups.keys(alice, 1, None).await.unwrap();
+        assert_eq!(keys.keys.len(), 2);
+        assert_eq!(
+            URL_SAFE_NO_PAD.decode(&keys.keys[1].ciphertext).unwrap(),
+            b"epoch 1 again"
+        );
+        backups.delete_version(alice, 1).await.unwrap();
+        assert!(matches!(
+            backups.keys(alice, 1, None).await,
+            Err(KeyBackupError::VersionNotFound)
+        ));
+
+        // Another user cannot see Alice's backup.
+        assert!(matches!(
+            backups.version(Uuid::new_v4(), 2).await,
+            Err(KeyBackupError::VersionNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_backups_are_rejected() {
+        let backups = service();
+        let alice = Uuid::new_v4();
+
+        for (algorithm, auth_data) in [
+            ("", json!({})),
+            ("mls.backup.v1", json!("not an object")),
+            (
+                "mls.backup.v1",
+                json!({ "padding": "x".repeat(MAX_AUTH_DATA_LENGTH) }),
+            ),
+        ] {
+            assert!(matches!(
+                backups.create_version(alice, algorithm, auth_data).await,
+                Err(KeyBackupError::Invalid(_))
+            ));
+        }
+
+        backups
+            .create_version(alice, "mls.backup.v1", json!({}))
+            .await
+            .unwrap();
+        let not_base64 = BackupKeyUpload {
+            ciphertext: "not base64!".into(),
+            ..upload(b"g", 0, b"a")
+        };
+        for keys in [
+            vec![],
+            vec![upload(b"", 0, b"a")],
+            vec![upload(b"g", 0, b"")],
+            vec![upload(b"g", u64::MAX, b"a")],
+            vec![not_base64],
+        ] {
+            assert!(matches!(
+                backups.put_keys(alice, 1, keys).await,
+                Err(KeyBackupError::Invalid(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,1028 @@
+use std::{collections::HashMap, convert::Infallible, fmt::Display, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{
+    body::{Body, Bytes},
+    extract::{MatchedPath, OriginalUri, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::Response,
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures::{Future, Stream};
+use openguild_media::{
+    parse_range, resolve_content_type, stage_upload, strip_metadata, ByteRange, ByteStream,
+    FilesystemStore, ImageError, ImageInfo, ImageLimits, MediaStore, PreviewCache, PreviewError,
+    RemoteFetcher, RemoteMediaCache, RemoteMediaError, S3Store, StageError, ThumbnailMethod,
+    ThumbnailSpec, PREVIEWABLE_TYPES,
+};
+use openguild_storage::{MediaRecord, MediaRepository, MediaUsage, NewMedia, StoragePool};
+use rand::{rng, RngCore};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    config::{MediaBackend, ServerConfig},
+    session, AppState,
+};
+
+const MAX_FILENAME_LENGTH: usize = 255;
+const MEDIA_ID_BYTES: usize = 18;
+/// Thumbnail box served when the client does not ask for a size.
+const DEFAULT_THUMBNAIL_WIDTH: u32 = 320;
+const DEFAULT_THUMBNAIL_HEIGHT: u32 = 240;
+
+#[derive(Debug, Error)]
+pub enum MediaError {
+    #[error("media not found")]
+    NotFound,
+    #[error("upload exceeds the {0} byte limit")]
+    TooLarge(u64),
+    #[error("upload body failed: {0}")]
+    Body(String),
+    #[error("image could not be processed: {0}")]
+    Image(#[from] ImageError),
+    #[error("storage error: {0}")]
+    Storage(#[from] anyhow::Error),
+}
+
+impl From<PreviewError> for MediaError {
+    fn from(err: PreviewError) -> Self {
+        match err {
+            PreviewError::SourceMissing => MediaError::NotFound,
+            PreviewError::Image(err) => MediaError::Image(err),
+            PreviewError::Storage(err) => MediaError::Storage(err),
+        }
+    }
+}
+
+impl From<StageError> for MediaError {
+    fn from(err: StageError) -> Self {
+        match err {
+            StageError::TooLarge(limit) => MediaError::TooLarge(limit),
+            StageError::Body(reason) => MediaError::Body(reason),
+            StageError::Io(err) => MediaError::Storage(err.into()),
+        }
+    }
+}
+
+/// Metadata for uploaded media, keyed by media id.
+#[async_trait]
+pub trait MediaCatalog: Send + Sync {
+    async fn insert_media(&self, media: NewMedia) -> Result<MediaRecord, MediaError>;
+    async fn media_by_id(&self, media_id: &str) -> Result<Option<MediaRecord>, MediaError>;
+    async fn media_by_ids(&self, media_ids: &[String]) -> Result<Vec<MediaRecord>, MediaError>;
+    async fn delete_media(&self, media_id: &str) -> Result<Option<MediaRecord>, MediaError>;
+    async fn content_hash_in_use(&self, content_hash: &str) -> Result<bool, MediaError>;
+    async fn uploader_usage(&self, uploader_id: Uuid) -> Result<MediaUsage, MediaError>;
+}
+
+#[async_trait]
+impl MediaCatalog for MediaRepository {
+    async fn insert_media(&self, media: NewMedia) -> Result<MediaRecord, MediaError> {
+        MediaRepository::insert_media(self, &media)
+            .await
+            .map_err(MediaError::from)
+    }
+
+    async fn media_by_id(&self, media_id: &str) -> Result<Option<MediaRecord>, MediaError> {
+        MediaRepository::media_by_id(self, media_id)
+            .await
+            .map_err(MediaError::from)
+    }
+
+    async fn media_by_ids(&self, media_ids: &[String]) -> Result<Vec<MediaRecord>, MediaError> {
+        MediaRepository::media_by_ids(self, media_ids)
+            .await
+            .map_err(MediaError::from)
+    }
+
+    async fn delete_media(&self, media_id: &str) -> Result<Option<MediaRecord>, MediaError> {
+        MediaRepository::delete_media(self, media_id)
+            .await
+            .map_err(MediaError::from)
+    }
+
+    async fn content_hash_in_use(&self, content_hash: &str) -> Result<bool, MediaError> {
+        MediaRepository::content_hash_in_use(self, content_hash)
+            .await
+            .map_err(MediaError::from)
+    }
+
+    async fn uploader_usage(&self, uploader_id: Uuid) -> Result<MediaUsage, MediaError> {
+        MediaRepository::uploader_usage(self, uploader_id)
+            .await
+            .map_err(MediaError::from)
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryMediaCatalog {
+    records: RwLock<HashMap<String, MediaRecord>>,
+}
+
+#[async_trait]
+impl MediaCatalog for InMemoryMediaCatalog {
+    async fn insert_media(&self, media: NewMedia) -> Result<MediaRecord, MediaError> {
+        let record = MediaRecord {
+            media_id: media.media_id,
+            content_hash: media.content_hash,
+            content_type: media.content_type,
+            size: media.size,
+            filename: media.filename,
+            uploader_id: media.uploader_id,
+            created_at: chrono::Utc::now(),
+        };
+        self.records
+            .write()
+            .await
+            .insert(record.media_id.clone(), record.clone());
+        Ok(record)
+    }
+
+    async fn media_by_id(&self, media_id: &str) -> Result<Option<MediaRecord>, MediaError> {
+        Ok(self.records.read().await.get(media_id).cloned())
+    }
+
+    async fn media_by_ids(&self, media_ids: &[String]) -> Result<Vec<MediaRecord>, MediaError> {
+        let records = self.records.read().await;
+        let mut found: Vec<MediaRecord> = media_ids
+            .iter()
+            .filter_map(|media_id| records.get(media_id).cloned())
+            .collect();
+        found.sort_by_key(|record| record.created_at);
+        found.dedup_by(|a, b| a.media_id == b.media_id);
+        Ok(found)
+    }
+
+    async fn delete_media(&self, media_id: &str) -> Result<Option<MediaRecord>, MediaError> {
+        Ok(self.records.write().await.remove(media_id))
+    }
+
+    async fn content_hash_in_use(&self, content_hash: &str) -> Result<bool, MediaError> {
+        Ok(self
+            .records
+            .read()
+            .await
+            .values()
+            .any(|record| record.content_hash == content_hash))
+    }
+
+    async fn uploader_usage(&self, uploader_id: Uuid) -> Result<MediaUsage, MediaError> {
+        Ok(self
+            .records
+            .read()
+            .await
+            .values()
+            .filter(|record| record.uploader_id == uploader_id)
+            .fold(MediaUsage::default(), |usage, record| MediaUsage {
+                objects: usage.objects + 1,
+                bytes: usage.bytes + record.size,
+            }))
+    }
+}
+
+/// Uploads and serves media: blobs go to a content-addressed `MediaStore`, metadata to a
+/// `MediaCatalog`, and image previews to a `PreviewCache` in the same store. Media hosted
+/// by federation peers is served from an optional `RemoteMediaCache`.
+pub struct MediaService {
+    store: Arc<dyn MediaStore>,
+    catalog: Arc<dyn MediaCatalog>,
+    previews: PreviewCache,
+    remote: Option<RemoteMediaCache>,
+    staging_dir: PathBuf,
+    max_upload_bytes: u64,
+}
+
+impl MediaService {
+    pub fn new(
+        store: Arc<dyn MediaStore>,
+        catalog: Arc<dyn MediaCatalog>,
+        staging_dir: PathBuf,
+        max_upload_bytes: u64,
+    ) -> Self {
+        Self {
+            previews: PreviewCache::new(store.clone(), ImageLimits::default(), max_upload_bytes),
+            remote: None,
+            store,
+            catalog,
+            staging_dir,
+            max_upload_bytes,
+        }
+    }
+
+    /// Serve media from federation peers through `fetcher`, caching up to
+    /// `max_total_bytes` locally. Remote objects obey the local upload limit.
+    pub fn with_remote_cache(
+        mut self,
+        fetcher: Arc<dyn RemoteFetcher>,
+        max_total_bytes: u64,
+    ) -> Self {
+        self.remote = Some(RemoteMediaCache::new(
+            self.store.clone(),
+            fetcher,
+            self.staging_dir.clone(),
+            self.max_upload_bytes,
+            max_total_bytes,
+        ));
+        self
+    }
+
+    pub fn max_upload_bytes(&self) -> u64 {
+        self.max_upload_bytes
+    }
+
+    pub fn remote(&self) -> Option<&RemoteMediaCache> {
+        self.remote.as_ref()
+    }
+
+    /// Stream `body` into the store and record it under a new media id. Images are stored
+    /// without their EXIF/GPS metadata and get their preview info computed up front.
+    pub async fn upload<S, E>(
+        &self,
+        uploader_id: Uuid,
+        declared_type: Option<&str>,
+        filename: Option<String>,
+        body: S,
+    ) -> Result<MediaRecord, MediaError>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        E: Display,
+    {
+        let mut staged = stage_upload(&self.staging_dir, body, self.max_upload_bytes).await?;
+        let content_type = resolve_content_type(declared_type, staged.head());
+
+        let mut source = None;
+        if is_previewable(&content_type) {
+            let bytes = tokio::fs::read(staged.path())
+                .await
+                .map_err(anyhow::Error::from)?;
+            let bytes = match strip_metadata(&bytes) {
+                Some(stripped) => {
+                    let body =
+                        futures::stream::iter([Ok::<_, Infallible>(Bytes::from(stripped.clone()))]);
+                    staged = stage_upload(&self.staging_dir, body, self.max_upload_bytes).await?;
+                    stripped
+                }
+                None => bytes,
+            };
+            source = Some(bytes);
+        }
+
+        self.store
+            .put_file(&staged.key(), staged.path(), staged.size)
+            .await?;
+        if let Some(source) = source {
+            if let Err(err) = self.previews.prime(&staged.content_hash, source).await {
+                tracing::warn!(
+                    %err,
+                    content_hash = %staged.content_hash,
+                    "failed to compute image preview"
+                );
+            }
+        }
+
+        self.catalog
+            .insert_media(NewMedia {
+                media_id: generate_media_id(),
+                content_hash: staged.content_hash.clone(),
+                content_type,
+                size: staged.size as i64,
+                filename,
+                uploader_id,
+            })
+            .await
+    }
+
+    pub async fn media(&self, media_id: &str) -> Result<Option<MediaRecord>, MediaError> {
+        self.catalog.media_by_id(media_id).await
+    }
+
+    pub async fn media_by_ids(&self, media_ids: &[String]) -> Result<Vec<MediaRecord>, MediaError> {
+        self.catalog.media_by_ids(media_ids).await
+    }
+
+    pub async fn uploader_usage(&self, uploader_id: Uuid) -> Result<MediaUsage, MediaError> {
+        self.catalog.uploader_usage(uploader_id).await
+    }
+
+    /// Forget `media_id` and delete its object and previews unless another upload shares
+    /// the same content. Returns the removed record.
+    pub async fn purge(&self, media_id: &str) -> Result<Option<MediaRecord>, MediaError> {
+        let Some(record) = self.catalog.delete_media(media_id).await? else {
+            return Ok(None);
+        };
+        if !self
+            .catalog
+            .content_hash_in_use(&record.content_hash)
+            .await?
+        {
+            self.store
+                .delete(&openguild_media::content_key(&record.content_hash))
+                .await?;
+            self.previews.purge(&record.content_hash).await?;
+        }
+        Ok(Some(record))
+    }
+
+    /// Dimensions and placeholder of an image upload; `None` for other media and images
+    /// that cannot be decoded within the limits.
+    pub async fn image_info(&self, record: &MediaRecord) -> Result<Option<ImageInfo>, MediaError> {
+        if !is_previewable(&record.content_type) {
+            return Ok(None);
+        }
+        match self.previews.info(&record.content_hash).await {
+            Ok(info) => Ok(Some(info)),
+            Err(PreviewError::Image(_)) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Thumbnail of an image upload and its content type; `None` for other media.
+    pub async fn thumbnail(
+        &self,
+        record: &MediaRecord,
+        spec: ThumbnailSpec,
+    ) -> Result<Option<(&'static str, Bytes)>, MediaError> {
+        if !is_previewable(&record.content_type) {
+            return Ok(None);
+        }
+        let (info, bytes) = self.previews.thumbnail(&record.content_hash, spec).await?;
+        Ok(Some((info.thumbnail_content_type(), bytes)))
+    }
+
+    /// Stream the object behind `record`, or only `range` of it.
+    pub async fn open(
+        &self,
+        record: &MediaRecord,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream, MediaError> {
+        let key = openguild_media::content_key(&record.content_hash);
+        self.store.get(&key, range).await?.ok_or_else(|| {
+            error!(
+                media_id = %record.media_id,
+                content_hash = %record.content_hash,
+                "media object missing from store"
+            );
+            MediaError::NotFound
+        })
+    }
+}
+
+fn is_previewable(content_type: &str) -> bool {
+    PREVIEWABLE_TYPES.contains(&content_type)
+}
+
+fn generate_media_id() -> String {
+    let mut bytes = [0u8; MEDIA_ID_BYTES];
+    rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn sanitize_filename(raw: &str) -> Option<String> {
+    let base = raw.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = base
+        .chars()
+        .filter(|ch| !ch.is_control() && *ch != '"')
+        .take(MAX_FILENAME_LENGTH)
+        .collect();
+    let name = name.trim();
+    (!name.is_empty() && name != "." && name != "..").then(|| name.to_string())
+}
+
+/// Media types browsers may render in place; everything else is served as a download.
+fn is_inline_type(content_type: &str) -> bool {
+    ["image/", "video/", "audio/"]
+        .iter()
+        .any(|prefix| content_type.starts_with(prefix))
+        || content_type == "text/plain"
+}
+
+fn content_disposition(content_type: &str, filename: Option<&str>) -> String {
+    let disposition = if is_inline_type(content_type) {
+        "inline"
+    } else {
+        "attachment"
+    };
+    match filename.filter(|name| {
+        name.chars()
+            .all(|ch| ch.is_ascii() && !ch.is_ascii_control())
+    }) {
+        Some(name) => format!("{disposition}; filename=\"{}\"", name.replace('\\', "_")),
+        None => disposition.to_string(),
+    }
+}
+
+pub fn init_media_service(
+    config: &ServerConfig,
+    pool: Option<StoragePool>,
+) -> anyhow::Result<Option<MediaService>> {
+    let media = &config.media;
+    let (store, default_staging): (Arc<dyn MediaStore>, PathBuf) = match media.backend {
+        MediaBackend::Disabled => return Ok(None),
+        MediaBackend::Filesystem => {
+            let root = PathBuf::from(media.path.clone().unwrap_or_default());
+            (
+                Arc::new(FilesystemStore::new(root.join("objects"))),
+                root.join("staging"),
+            )
+        }
+        MediaBackend::S3 => {
+            let settings = media
+                .s3
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("media.s3 settings are missing"))?;
+            (
+                Arc::new(S3Store::new(settings)?),
+                std::env::temp_dir().join("openguild-media"),
+            )
+        }
+    };
+    let staging_dir = media
+        .staging_path
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or(default_staging);
+
+    let catalog: Arc<dyn MediaCatalog> = match pool {
+        Some(pool) => Arc::new(MediaRepository::new(pool.cloned())),
+        None => {
+            info!("media metadata persistence unavailable; using in-memory catalog");
+            Arc::new(InMemoryMediaCatalog::default())
+        }
+    };
+
+    Ok(Some(MediaService::new(
+        store,
+        catalog,
+        staging_dir,
+        media.max_upload_bytes,
+    )))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadMediaQuery {
+    #[serde(default)]
+    pub filename: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadMediaResponse {
+    pub media_id: String,
+    pub content_type: String,
+    pub size: u64,
+    pub content_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ThumbnailQuery {
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub method: Option<ThumbnailMethod>,
+}
+
+pub async fn upload_media(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<UploadMediaQuery>,
+    body: Body,
+) -> Result<(StatusCode, Json<UploadMediaResponse>), StatusCode> {
+    let Some(media) = state.media() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    // Reject oversized uploads up front when the client declares the length.
+    let declared_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared_length.is_some_and(|length| length > media.max_upload_bytes()) {
+        let status = StatusCode::PAYLOAD_TOO_LARGE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    let declared_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let filename = query.filename.as_deref().and_then(sanitize_filename);
+
+    let status = match media
+        .upload(
+            claims.user_id,
+            declared_type,
+            filename,
+            body.into_data_stream(),
+        )
+        .await
+    {
+        Ok(record) => {
+            let preview = media.image_info(&record).await.unwrap_or_else(|err| {
+                tracing::warn!(%err, media_id = %record.media_id, "failed to load image preview");
+                None
+            });
+            let status = StatusCode::CREATED;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Ok((
+                status,
+                Json(UploadMediaResponse {
+                    media_id: record.media_id,
+                    content_type: record.content_type,
+                    size: record.size as u64,
+                    content_hash: record.content_hash,
+                    width: preview.as_ref().map(|info| info.width),
+                    height: preview.as_ref().map(|info| info.height),
+                    blurhash: preview.map(|info| info.blurhash),
+                }),
+            ));
+        }
+        Err(MediaError::TooLarge(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+        Err(MediaError::Body(reason)) => {
+            tracing::warn!(%reason, "media upload body failed");
+            StatusCode::BAD_REQUEST
+        }
+        Err(MediaError::NotFound) => StatusCode::NOT_FOUND,
+        Err(MediaError::Image(_)) => StatusCode::UNPROCESSABLE_ENTITY,
+        Err(MediaError::Storage(err)) => {
+            error!(?err, "failed to store media upload");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn download_media(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(media_id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let Some(media) = state.media() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    serve_local_media(&state, &matched_path, &headers, &media, &media_id).await
+}
+
+/// Download media hosted by `server`: local media is served directly, media of trusted
+/// federation peers through the remote cache.
+pub async fn download_remote_media(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((server, media_id)): Path<(String, String)>,
+) -> Result<Response, StatusCode> {
+    let Some(media) = state.media() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    if server == state.server_name() {
+        return serve_local_media(&state, &matched_path, &headers, &media, &media_id).await;
+    }
+
+    let trusted = state
+        .federation()
+        .is_some_and(|federation| federation.is_trusted(&server));
+    let Some(remote) = media.remote().filter(|_| trusted) else {
+        let status = StatusCode::NOT_FOUND;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let entry = match remote.get(&server, &media_id).await {
+        Ok(entry) => entry,
+        Err(err) => {
+            let status = match err {
+                RemoteMediaError::InvalidReference => StatusCode::BAD_REQUEST,
+                RemoteMediaError::NotFound => StatusCode::NOT_FOUND,
+                RemoteMediaError::Storage(err) => {
+                    error!(?err, server, media_id, "failed to cache remote media");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+                err => {
+                    tracing::warn!(%err, server, media_id, "failed to fetch remote media");
+                    StatusCode::BAD_GATEWAY
+                }
+            };
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    let object = ServedObject {
+        content_hash: &entry.content_hash,
+        content_type: &entry.content_type,
+        size: entry.size,
+        filename: None,
+    };
+    let (entry, server, media_id) = (&entry, &server, &media_id);
+    serve_object(
+        &state,
+        &matched_path,
+        &headers,
+        object,
+        |range| async move {
+            remote.open(entry, range).await.map_err(|err| match err {
+                RemoteMediaError::NotFound => StatusCode::NOT_FOUND,
+                err => {
+                    error!(?err, server, media_id, "failed to open remote media");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })
+        },
+    )
+    .await
+}
+
+/// Serve local media to a trusted federation peer. Requests must be signed like other
+/// federation requests and name this server as the media's host.
+pub async fn federation_media(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    Path((server, media_id)): Path<(String, String)>,
+) -> Result<Response, StatusCode> {
+    let (Some(federation), Some(media)) = (state.federation(), state.media()) else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    crate::authenticate_federation_request(&state, &federation, &method, &uri, &headers, &[])
+        .await
+        .inspect_err(|status| {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            #[cfg(not(feature = "metrics"))]
+            let _ = status;
+        })?;
+
+    if server != state.server_name() {
+        let status = StatusCode::NOT_FOUND;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    serve_local_media(&state, &matched_path, &headers, &media, &media_id).await
+}
+
+async fn serve_local_media(
+    state: &AppState,
+    matched_path: &MatchedPath,
+    headers: &HeaderMap,
+    media: &MediaService,
+    media_id: &str,
+) -> Result<Response, StatusCode> {
+    let record = match media.media(media_id).await {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            let status = StatusCode::NOT_FOUND;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+        Err(err) => {
+            error!(?err, media_id, "failed to load media metadata");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    let object = ServedObject {
+        content_hash: &record.content_hash,
+        content_type: &record.content_type,
+        size: record.size as u64,
+        filename: record.filename.as_deref(),
+    };
+    let record = &record;
+    serve_object(state, matched_path, headers, object, |range| async move {
+        media.open(record, range).await.map_err(|err| match err {
+            MediaError::NotFound => StatusCode::NOT_FOUND,
+            err => {
+                error!(?err, media_id, "failed to open media object");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })
+    })
+    .await
+}
+
+/// What the download endpoints need to describe an object.
+struct ServedObject<'a> {
+    content_hash: &'a str,
+    content_type: &'a str,
+    size: u64,
+    filename: Option<&'a str>,
+}
+
+/// Stream `object` with caching headers, answering `If-None-Match` with 304 and a single
+/// `Range` with 206 or 416. `open` streams the whole object or the requested range.
+async fn serve_object<F, Fut>(
+    state: &AppState,
+    matched_path: &MatchedPath,
+    headers: &HeaderMap,
+    object: ServedObject<'_>,
+    open: F,
+) -> Result<Response, StatusCode>
+where
+    F: FnOnce(Option<ByteRange>) -> Fut,
+    Fut: Future<Output = Result<ByteStream, StatusCode>>,
+{
+    #[cfg(not(feature = "metrics"))]
+    let _ = (state, matched_path);
+
+    let size = object.size;
+    let etag = format!("\"{}\"", object.content_hash);
+    let mut response = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &etag)
+        .header(
+            header::CACHE_CONTROL,
+            "private, max-age=31536000, immutable",
+        );
+
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag))
+    {
+        let status = StatusCode::NOT_MODIFIED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return response
+            .status(status)
+            .body(Body::empty())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let range = match headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| parse_range(value, size))
+        .transpose()
+    {
+        Ok(range) => range.flatten(),
+        Err(_) => {
+            let status = StatusCode::RANGE_NOT_SATISFIABLE;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return response
+                .status(status)
+                .header(header::CONTENT_RANGE, format!("bytes */{size}"))
+                .body(Body::empty())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let stream = open(range).await.inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    let content_type = HeaderValue::from_str(object.content_type)
+        .unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream"));
+    response = response.header(header::CONTENT_TYPE, content_type).header(
+        header::CONTENT_DISPOSITION,
+        content_disposition(object.content_type, object.filename),
+    );
+    let status = match range {
+        Some(range) => {
+            response = response
+                .header(header::CONTENT_RANGE, range.content_range(size))
+                .header(header::CONTENT_LENGTH, range.length());
+            StatusCode::PARTIAL_CONTENT
+        }
+        None => {
+            response = response.header(header::CONTENT_LENGTH, size);
+            StatusCode::OK
+        }
+    };
+
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    response
+        .status(status)
+        .body(Body::from_stream(stream))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn media_thumbnail(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(media_id): Path<String>,
+    Query(query): Query<ThumbnailQuery>,
+) -> Result<Response, StatusCode> {
+    let Some(media) = state.media() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let record = match media.media(&media_id).await {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            let status = StatusCode::NOT_FOUND;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+        Err(err) => {
+            error!(?err, media_id, "failed to load media metadata");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    let spec = ThumbnailSpec::select(
+        query.width.unwrap_or(DEFAULT_THUMBNAIL_WIDTH),
+        query.height.unwrap_or(DEFAULT_THUMBNAIL_HEIGHT),
+        query.method.unwrap_or(ThumbnailMethod::Scale),
+    );
+    let etag = format!(
+        "\"{}-{}-{}x{}\"",
+        record.content_hash,
+        spec.method.as_str(),
+        spec.width,
+        spec.height
+    );
+    let response = Response::builder().header(header::ETAG, &etag).header(
+        header::CACHE_CONTROL,
+        "private, max-age=31536000, immutable",
+    );
+
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag))
+    {
+        let status = StatusCode::NOT_MODIFIED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return response
+            .status(status)
+            .body(Body::empty())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let status = match media.thumbnail(&record, spec).await {
+        Ok(Some((content_type, bytes))) => {
+            let status = StatusCode::OK;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return response
+                .status(status)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, bytes.len())
+                .body(Body::from(bytes))
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        Ok(None) | Err(MediaError::NotFound) => StatusCode::NOT_FOUND,
+        Err(MediaError::Image(err)) => {
+            tracing::warn!(%err, media_id, "cannot render thumbnail");
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        Err(err) => {
+            error!(?err, media_id, "failed to render thumbnail");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filenames_are_reduced_to_a_safe_basename() {
+        assert_eq!(
+            sanitize_filename("../../etc/passwd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            sanitize_filename("C:\\Users\\me\\cat \"1\".png").as_deref(),
+            Some("cat 1.png")
+        );
+        assert_eq!(sanitize_filename("  \n ").as_deref(), None);
+        assert_eq!(sanitize_filename("dir/..").as_deref(), None);
+        assert_eq!(
+            sanitize_filename(&"a".repeat(400)).map(|name| name.len()),
+            Some(MAX_FILENAME_LENGTH)
+        );
+    }
+
+    #[tokio::test]
+    async fn identical_uploads_share_one_object() {
+        let root = tempfile::tempdir().unwrap();
+        let service = MediaService::new(
+            Arc::new(FilesystemStore::new(root.path().join("objects"))),
+            Arc::new(InMemoryMediaCatalog::default()),
+            root.path().join("staging"),
+            1024,
+        );
+        let body = || futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from("same bytes"))]);
+
+        let first = service
+            .upload(Uuid::new_v4(), Some("text/plain"), None, body())
+            .await
+            .unwrap();
+        let second = service
+            .upload(Uuid::new_v4(), None, Some("copy.txt".into()), body())
+            .await
+            .unwrap();
+
+        assert_ne!(first.media_id, second.media_id);
+        assert_eq!(first.content_hash, second.content_hash);
+        assert_eq!(first.content_type, "text/plain");
+        assert_eq!(service.media(&second.media_id).await.unwrap(), Some(second));
+        let object = root
+            .path()
+            .join("objects")
+            .join(openguild_media::content_key(&first.content_hash));
+        assert_eq!(std::fs::read(object).unwrap(), b"same bytes");
+        assert_eq!(
+            std::fs::read_dir(root.path().join("staging"))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+}
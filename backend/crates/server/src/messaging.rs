@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
     sync::{
         atomic::{AtomicI64, Ordering},
         Arc,
@@ -17,13 +17,25 @@ use axum::{
     response::Response,
     Extension, Json,
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use openguild_core::{
-    event::CanonicalEvent,
-    messaging::{MessageAuthorSnapshot, MessagePayload},
+    auth::{self, AuthError, AuthState},
+    canonical_json,
+    event::{CanonicalEvent, MAX_PREV_EVENTS},
+    messaging::{
+        Attachment, EditContent, EncryptedContent, FormattedBody, Mentions, MessageAuthorSnapshot,
+        MessagePayload, ReactionContent, MESSAGE_EVENT, REACTION_EVENT,
+    },
+    redaction::{RedactionContent, REDACTION_EVENT},
+    state::{
+        self, JoinRule, Membership, MembershipContent, PowerLevelsContent, StateContent,
+        StateError, StateMap,
+    },
 };
 use openguild_storage::{
-    Channel, ChannelEvent, ChannelMembership, ChannelMembershipSummary, ChannelUnreadState, Guild,
-    GuildMembership, GuildMembershipSummary, MessagingRepository, StoragePool, UserRepository,
+    Channel, ChannelEvent, ChannelMembership, ChannelMembershipSummary, ChannelUnreadState,
+    EventUsage, Guild, GuildMembership, GuildMembershipSummary, LatestEdit, MessagingRepository,
+    ReactionCount, RetentionPolicy, StoragePool, ThreadStats, ThreadUnreadState, UserRepository,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -36,7 +48,10 @@ use uuid::Uuid;
 
 #[cfg(feature = "metrics")]
 use crate::metrics::MetricsContext;
-use crate::{config::ServerConfig, session, AppState};
+use crate::{
+    config::ServerConfig, federation::FederationSigner, federation_sender::FederationSender,
+    mls::MAX_DEVICE_ID_LENGTH, session, AppState,
+};
 use tower_http::request_id::RequestId;
 use tracing::Instrument;
 
@@ -46,6 +61,16 @@ const SEND_TIMEOUT: Duration = Duration::from_secs(10);
 const MAX_GUILD_NAME_LENGTH: usize = 64;
 const MAX_CHANNEL_NAME_LENGTH: usize = 64;
 const MAX_MESSAGE_LENGTH: usize = 4000;
+const MAX_FORMATTED_LENGTH: usize = 16000;
+const MAX_MENTIONS: usize = 50;
+const MAX_ATTACHMENTS: usize = 10;
+const MAX_MEDIA_ID_LENGTH: usize = 128;
+const MAX_BLURHASH_LENGTH: usize = 200;
+const MAX_REACTION_KEY_LENGTH: usize = 64;
+/// Upper bound on the decoded MLS ciphertext of an `encrypted` event.
+const MAX_CIPHERTEXT_LENGTH: usize = 64 * 1024;
+/// Channel roles allowed to send state events other than their own join or leave.
+const STATE_SENDER_ROLES: [&str; 3] = ["owner", "admin", "moderator"];
 pub(crate) const MESSAGE_RATE_WINDOW: Duration = Duration::from_secs(60);
 pub(crate) const DEFAULT_TIMELINE_LIMIT: i64 = 50;
 pub(crate) const MAX_TIMELINE_LIMIT: i64 = 200;
@@ -67,34 +92,186 @@ pub enum MessagingError {
     GuildNotFound,
     #[error("channel not found")]
     ChannelNotFound,
+    #[error("event '{0}' not found")]
+    EventNotFound(String),
     #[error("invalid room id '{0}'")]
     InvalidRoomId(String),
+    #[error("invalid state event: {0}")]
+    InvalidState(#[from] StateError),
+    #[error("event not authorized: {0}")]
+    Unauthorized(#[from] AuthError),
+    #[error("invalid message relation: {0}")]
+    InvalidRelation(String),
+    #[error("invalid mention: {0}")]
+    InvalidMention(String),
     #[error("storage error: {0}")]
     Storage(#[from] anyhow::Error),
 }
 
+/// What a channel is for. Stored as text in `channels.kind`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelKind {
+    #[default]
+    Text,
+    Voice,
+    Announcement,
+    /// Groups other channels in a guild's channel list.
+    Category,
+}
+
+impl ChannelKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChannelKind::Text => "text",
+            ChannelKind::Voice => "voice",
+            ChannelKind::Announcement => "announcement",
+            ChannelKind::Category => "category",
+        }
+    }
+
+    /// Kind of a stored channel; unknown values read as text.
+    pub fn of(channel: &Channel) -> Self {
+        match channel.kind.as_str() {
+            "voice" => ChannelKind::Voice,
+            "announcement" => ChannelKind::Announcement,
+            "category" => ChannelKind::Category,
+            _ => ChannelKind::Text,
+        }
+    }
+}
+
 #[async_trait]
 pub trait ChannelStore: Send + Sync {
     async fn create_guild(&self, name: &str) -> Result<Guild, MessagingError>;
     async fn list_guilds(&self) -> Result<Vec<Guild>, MessagingError>;
-    async fn create_channel(&self, guild_id: Uuid, name: &str) -> Result<Channel, MessagingError>;
+    async fn create_channel(
+        &self,
+        guild_id: Uuid,
+        name: &str,
+        kind: ChannelKind,
+    ) -> Result<Channel, MessagingError>;
     async fn list_channels_for_guild(&self, guild_id: Uuid)
         -> Result<Vec<Channel>, MessagingError>;
+    /// Append an event, also queueing it for federation to `outbox` when the store holds
+    /// the federation outbox (see [`ChannelStore::writes_outbox`]).
+    #[allow(clippy::too_many_arguments)]
     async fn append_event(
         &self,
         channel_id: Uuid,
         event_id: &str,
         event_type: &str,
         body: &serde_json::Value,
+        prev_events: &[String],
+        auth_events: &[String],
+        outbox: &[String],
     ) -> Result<ChannelEvent, MessagingError>;
+    /// Whether `append_event` writes outbox rows in the event's transaction. Otherwise the
+    /// caller queues the event itself.
+    fn writes_outbox(&self) -> bool {
+        false
+    }
+    async fn forward_extremities(&self, channel_id: Uuid) -> Result<Vec<String>, MessagingError>;
+    async fn record_event_state(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+        state: &StateMap,
+    ) -> Result<(), MessagingError>;
+    async fn event_state(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<Option<StateMap>, MessagingError>;
+    async fn event_by_id(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<Option<ChannelEvent>, MessagingError>;
+    async fn redact_event(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+        redacted_by: Option<&str>,
+        redacted: &CanonicalEvent,
+    ) -> Result<bool, MessagingError>;
+    async fn record_edit(
+        &self,
+        channel_id: Uuid,
+        original_event_id: &str,
+        edit_event_id: &str,
+    ) -> Result<(), MessagingError>;
+    async fn edits_for_event(
+        &self,
+        channel_id: Uuid,
+        original_event_id: &str,
+    ) -> Result<Vec<ChannelEvent>, MessagingError>;
+    async fn latest_edits(
+        &self,
+        channel_id: Uuid,
+        original_event_ids: &[String],
+    ) -> Result<Vec<LatestEdit>, MessagingError>;
+    async fn record_thread_reply(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        event_id: &str,
+    ) -> Result<(), MessagingError>;
+    async fn thread_events(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        since_sequence: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>, MessagingError>;
+    async fn thread_stats(
+        &self,
+        channel_id: Uuid,
+        root_event_ids: &[String],
+    ) -> Result<Vec<ThreadStats>, MessagingError>;
+    async fn record_reaction(
+        &self,
+        channel_id: Uuid,
+        target_event_id: &str,
+        event_id: &str,
+        sender: &str,
+        reaction_key: &str,
+    ) -> Result<(), MessagingError>;
+    async fn remove_reaction(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<bool, MessagingError>;
+    async fn reactions_by_sender(
+        &self,
+        channel_id: Uuid,
+        target_event_id: &str,
+        sender: &str,
+        reaction_key: &str,
+    ) -> Result<Vec<String>, MessagingError>;
+    async fn reaction_counts(
+        &self,
+        channel_id: Uuid,
+        target_event_ids: &[String],
+        viewer: Option<&str>,
+    ) -> Result<Vec<ReactionCount>, MessagingError>;
     async fn recent_events(
         &self,
         channel_id: Uuid,
         since_sequence: Option<i64>,
         limit: i64,
     ) -> Result<Vec<ChannelEvent>, MessagingError>;
+    async fn first_state_event(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Option<ChannelEvent>, MessagingError>;
     async fn channel_exists(&self, channel_id: Uuid) -> Result<bool, MessagingError>;
+    async fn channel_by_id(&self, channel_id: Uuid) -> Result<Option<Channel>, MessagingError>;
     async fn user_ids_for_channel(&self, channel_id: Uuid) -> Result<Vec<Uuid>, MessagingError>;
+    async fn channel_members(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Vec<ChannelMembership>, MessagingError>;
     async fn user_ids_for_guild(&self, guild_id: Uuid) -> Result<Vec<Uuid>, MessagingError>;
     async fn latest_sequence_for_channel(&self, channel_id: Uuid) -> Result<i64, MessagingError>;
     async fn update_last_read_sequence(
@@ -113,6 +290,24 @@ pub trait ChannelStore: Send + Sync {
         &self,
         user_id: Uuid,
     ) -> Result<Vec<ChannelUnreadState>, MessagingError>;
+    async fn update_thread_read_sequence(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        user_id: Uuid,
+        sequence: i64,
+    ) -> Result<(), MessagingError>;
+    async fn ensure_thread_read_state(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        user_id: Uuid,
+        sequence: i64,
+    ) -> Result<(), MessagingError>;
+    async fn user_thread_unread(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<ThreadUnreadState>, MessagingError>;
     #[cfg_attr(not(test), allow(dead_code))]
     async fn upsert_guild_membership(
         &self,
@@ -134,6 +329,31 @@ pub trait ChannelStore: Send + Sync {
         &self,
         user_id: Uuid,
     ) -> Result<Vec<ChannelMembershipSummary>, MessagingError>;
+    async fn set_retention_policy(
+        &self,
+        guild_id: Uuid,
+        max_age_secs: Option<i64>,
+        max_events: Option<i64>,
+    ) -> Result<RetentionPolicy, MessagingError>;
+    async fn clear_retention_policy(&self, guild_id: Uuid) -> Result<bool, MessagingError>;
+    async fn retention_policy(
+        &self,
+        guild_id: Uuid,
+    ) -> Result<Option<RetentionPolicy>, MessagingError>;
+    async fn retention_policies(&self) -> Result<Vec<RetentionPolicy>, MessagingError>;
+    /// Delete up to `limit` of the oldest events created before `older_than` or outside the
+    /// newest `keep_latest`, sparing state events and forward extremities.
+    async fn prune_channel_events(
+        &self,
+        channel_id: Uuid,
+        older_than: Option<chrono::DateTime<chrono::Utc>>,
+        keep_latest: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>, MessagingError>;
+    async fn media_referenced(&self, media_id: &str) -> Result<bool, MessagingError>;
+    async fn guild_event_usage(&self, guild_id: Uuid) -> Result<EventUsage, MessagingError>;
+    async fn user_event_usage(&self, sender: &str) -> Result<EventUsage, MessagingError>;
+    async fn guild_attachment_ids(&self, guild_id: Uuid) -> Result<Vec<String>, MessagingError>;
 }
 
 #[async_trait]
@@ -150,7 +370,12 @@ impl ChannelStore for MessagingRepository {
             .map_err(MessagingError::from)
     }
 
-    async fn create_channel(&self, guild_id: Uuid, name: &str) -> Result<Channel, MessagingError> {
+    async fn create_channel(
+        &self,
+        guild_id: Uuid,
+        name: &str,
+        kind: ChannelKind,
+    ) -> Result<Channel, MessagingError> {
         if !self
             .guild_exists(guild_id)
             .await
@@ -158,7 +383,7 @@ impl ChannelStore for MessagingRepository {
         {
             return Err(MessagingError::GuildNotFound);
         }
-        MessagingRepository::create_channel(self, guild_id, name)
+        MessagingRepository::create_channel(self, guild_id, name, kind.as_str())
             .await
             .map_err(MessagingError::from)
     }
@@ -178,6 +403,9 @@ impl ChannelStore for MessagingRepository {
         event_id: &str,
         event_type: &str,
         body: &serde_json::Value,
+        prev_events: &[String],
+        auth_events: &[String],
+        outbox: &[String],
     ) -> Result<ChannelEvent, MessagingError> {
         if !self
             .channel_exists(channel_id)
@@ -186,7 +414,206 @@ impl ChannelStore for MessagingRepository {
         {
             return Err(MessagingError::ChannelNotFound);
         }
-        MessagingRepository::append_event(self, channel_id, event_id, event_type, body)
+        MessagingRepository::append_event(
+            self,
+            channel_id,
+            event_id,
+            event_type,
+            body,
+            prev_events,
+            auth_events,
+            outbox,
+        )
+        .await
+        .map_err(MessagingError::from)
+    }
+
+    fn writes_outbox(&self) -> bool {
+        true
+    }
+
+    async fn forward_extremities(&self, channel_id: Uuid) -> Result<Vec<String>, MessagingError> {
+        MessagingRepository::forward_extremities(self, channel_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn record_event_state(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+        state: &StateMap,
+    ) -> Result<(), MessagingError> {
+        let snapshot =
+            serde_json::to_value(state).map_err(|err| MessagingError::Storage(err.into()))?;
+        // Identical snapshots share a group, so events that leave state untouched cost one row.
+        let state_group = blake3::hash(canonical_json::encode(&snapshot).as_bytes())
+            .to_hex()
+            .to_string();
+        MessagingRepository::record_event_state(self, channel_id, event_id, &state_group, &snapshot)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn event_state(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<Option<StateMap>, MessagingError> {
+        let Some(snapshot) = MessagingRepository::event_state(self, channel_id, event_id)
+            .await
+            .map_err(MessagingError::from)?
+        else {
+            return Ok(None);
+        };
+        serde_json::from_value(snapshot)
+            .map(Some)
+            .map_err(|err| MessagingError::Storage(err.into()))
+    }
+
+    async fn event_by_id(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<Option<ChannelEvent>, MessagingError> {
+        MessagingRepository::event_by_id(self, channel_id, event_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn redact_event(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+        redacted_by: Option<&str>,
+        redacted: &CanonicalEvent,
+    ) -> Result<bool, MessagingError> {
+        let body =
+            serde_json::to_value(redacted).map_err(|err| MessagingError::Storage(err.into()))?;
+        MessagingRepository::redact_event(self, channel_id, event_id, redacted_by, &body)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn record_edit(
+        &self,
+        channel_id: Uuid,
+        original_event_id: &str,
+        edit_event_id: &str,
+    ) -> Result<(), MessagingError> {
+        MessagingRepository::record_edit(self, channel_id, original_event_id, edit_event_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn edits_for_event(
+        &self,
+        channel_id: Uuid,
+        original_event_id: &str,
+    ) -> Result<Vec<ChannelEvent>, MessagingError> {
+        MessagingRepository::edits_for_event(self, channel_id, original_event_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn latest_edits(
+        &self,
+        channel_id: Uuid,
+        original_event_ids: &[String],
+    ) -> Result<Vec<LatestEdit>, MessagingError> {
+        MessagingRepository::latest_edits(self, channel_id, original_event_ids)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn record_thread_reply(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        event_id: &str,
+    ) -> Result<(), MessagingError> {
+        MessagingRepository::record_thread_reply(self, channel_id, root_event_id, event_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn thread_events(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        since_sequence: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>, MessagingError> {
+        MessagingRepository::thread_events(self, channel_id, root_event_id, since_sequence, limit)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn thread_stats(
+        &self,
+        channel_id: Uuid,
+        root_event_ids: &[String],
+    ) -> Result<Vec<ThreadStats>, MessagingError> {
+        MessagingRepository::thread_stats(self, channel_id, root_event_ids)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn record_reaction(
+        &self,
+        channel_id: Uuid,
+        target_event_id: &str,
+        event_id: &str,
+        sender: &str,
+        reaction_key: &str,
+    ) -> Result<(), MessagingError> {
+        MessagingRepository::record_reaction(
+            self,
+            channel_id,
+            target_event_id,
+            event_id,
+            sender,
+            reaction_key,
+        )
+        .await
+        .map_err(MessagingError::from)
+    }
+
+    async fn remove_reaction(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<bool, MessagingError> {
+        MessagingRepository::remove_reaction(self, channel_id, event_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn reactions_by_sender(
+        &self,
+        channel_id: Uuid,
+        target_event_id: &str,
+        sender: &str,
+        reaction_key: &str,
+    ) -> Result<Vec<String>, MessagingError> {
+        MessagingRepository::reactions_by_sender(
+            self,
+            channel_id,
+            target_event_id,
+            sender,
+            reaction_key,
+        )
+        .await
+        .map_err(MessagingError::from)
+    }
+
+    async fn reaction_counts(
+        &self,
+        channel_id: Uuid,
+        target_event_ids: &[String],
+        viewer: Option<&str>,
+    ) -> Result<Vec<ReactionCount>, MessagingError> {
+        MessagingRepository::reaction_counts(self, channel_id, target_event_ids, viewer)
             .await
             .map_err(MessagingError::from)
     }
@@ -202,18 +629,42 @@ impl ChannelStore for MessagingRepository {
             .map_err(MessagingError::from)
     }
 
+    async fn first_state_event(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Option<ChannelEvent>, MessagingError> {
+        MessagingRepository::first_state_event(self, channel_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
     async fn channel_exists(&self, channel_id: Uuid) -> Result<bool, MessagingError> {
         MessagingRepository::channel_exists(self, channel_id)
             .await
             .map_err(MessagingError::from)
     }
 
+    async fn channel_by_id(&self, channel_id: Uuid) -> Result<Option<Channel>, MessagingError> {
+        MessagingRepository::channel_by_id(self, channel_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
     async fn user_ids_for_channel(&self, channel_id: Uuid) -> Result<Vec<Uuid>, MessagingError> {
         MessagingRepository::user_ids_for_channel(self, channel_id)
             .await
             .map_err(MessagingError::from)
     }
 
+    async fn channel_members(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Vec<ChannelMembership>, MessagingError> {
+        MessagingRepository::channel_members(self, channel_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
     async fn user_ids_for_guild(&self, guild_id: Uuid) -> Result<Vec<Uuid>, MessagingError> {
         MessagingRepository::user_ids_for_guild(self, guild_id)
             .await
@@ -257,6 +708,51 @@ impl ChannelStore for MessagingRepository {
             .map_err(MessagingError::from)
     }
 
+    async fn update_thread_read_sequence(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        user_id: Uuid,
+        sequence: i64,
+    ) -> Result<(), MessagingError> {
+        MessagingRepository::update_thread_read_sequence(
+            self,
+            channel_id,
+            root_event_id,
+            user_id,
+            sequence,
+        )
+        .await
+        .map_err(MessagingError::from)
+    }
+
+    async fn ensure_thread_read_state(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        user_id: Uuid,
+        sequence: i64,
+    ) -> Result<(), MessagingError> {
+        MessagingRepository::ensure_thread_read_state(
+            self,
+            channel_id,
+            root_event_id,
+            user_id,
+            sequence,
+        )
+        .await
+        .map_err(MessagingError::from)
+    }
+
+    async fn user_thread_unread(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<ThreadUnreadState>, MessagingError> {
+        MessagingRepository::user_thread_unread(self, user_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
     #[cfg_attr(not(test), allow(dead_code))]
     async fn upsert_guild_membership(
         &self,
@@ -311,29 +807,161 @@ impl ChannelStore for MessagingRepository {
             .await
             .map_err(MessagingError::from)
     }
-}
 
-struct InMemoryMessaging {
-    guilds: RwLock<HashMap<Uuid, Guild>>,
-    channels: RwLock<HashMap<Uuid, Channel>>,
-    guild_index: RwLock<HashMap<Uuid, Vec<Uuid>>>,
-    events: RwLock<HashMap<Uuid, Vec<ChannelEvent>>>,
-    guild_memberships: RwLock<HashMap<Uuid, HashMap<Uuid, InMemoryGuildMembership>>>,
-    channel_memberships: RwLock<HashMap<Uuid, HashMap<Uuid, InMemoryChannelMembership>>>,
-    channel_reads: RwLock<HashMap<(Uuid, Uuid), i64>>,
-    sequence: AtomicI64,
-}
-
-impl Default for InMemoryMessaging {
-    fn default() -> Self {
-        Self {
-            guilds: RwLock::new(HashMap::new()),
-            channels: RwLock::new(HashMap::new()),
+    async fn set_retention_policy(
+        &self,
+        guild_id: Uuid,
+        max_age_secs: Option<i64>,
+        max_events: Option<i64>,
+    ) -> Result<RetentionPolicy, MessagingError> {
+        if !MessagingRepository::guild_exists(self, guild_id).await? {
+            return Err(MessagingError::GuildNotFound);
+        }
+        MessagingRepository::set_retention_policy(self, guild_id, max_age_secs, max_events)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn clear_retention_policy(&self, guild_id: Uuid) -> Result<bool, MessagingError> {
+        MessagingRepository::clear_retention_policy(self, guild_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn retention_policy(
+        &self,
+        guild_id: Uuid,
+    ) -> Result<Option<RetentionPolicy>, MessagingError> {
+        MessagingRepository::retention_policy(self, guild_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn retention_policies(&self) -> Result<Vec<RetentionPolicy>, MessagingError> {
+        MessagingRepository::retention_policies(self)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn prune_channel_events(
+        &self,
+        channel_id: Uuid,
+        older_than: Option<chrono::DateTime<chrono::Utc>>,
+        keep_latest: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>, MessagingError> {
+        MessagingRepository::prune_channel_events(self, channel_id, older_than, keep_latest, limit)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn media_referenced(&self, media_id: &str) -> Result<bool, MessagingError> {
+        MessagingRepository::media_referenced(self, media_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn guild_event_usage(&self, guild_id: Uuid) -> Result<EventUsage, MessagingError> {
+        MessagingRepository::guild_event_usage(self, guild_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn user_event_usage(&self, sender: &str) -> Result<EventUsage, MessagingError> {
+        MessagingRepository::user_event_usage(self, sender)
+            .await
+            .map_err(MessagingError::from)
+    }
+
+    async fn guild_attachment_ids(&self, guild_id: Uuid) -> Result<Vec<String>, MessagingError> {
+        MessagingRepository::guild_attachment_ids(self, guild_id)
+            .await
+            .map_err(MessagingError::from)
+    }
+}
+
+/// Edits apply in `origin_ts` order (ties broken by event id), matching the Postgres queries.
+fn edit_order(event: &ChannelEvent) -> (i64, String) {
+    let origin_ts = event
+        .body
+        .get("origin_ts")
+        .and_then(serde_json::Value::as_i64)
+        .unwrap_or_default();
+    (origin_ts, event.event_id.clone())
+}
+
+/// Media ids listed in the attachments of a stored event body.
+pub(crate) fn attachment_media_ids(body: &serde_json::Value) -> Vec<String> {
+    body.pointer("/content/attachments")
+        .and_then(serde_json::Value::as_array)
+        .map(|attachments| {
+            attachments
+                .iter()
+                .filter_map(|attachment| attachment.get("media_id")?.as_str())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Size of an event body as accounted in storage usage.
+fn event_body_bytes(body: &serde_json::Value) -> i64 {
+    serde_json::to_vec(body).map_or(0, |bytes| bytes.len() as i64)
+}
+
+/// Role names compare case-insensitively, like the role ranking in `users.rs`.
+fn role_matches(held: &str, mentioned: &str) -> bool {
+    held.trim().eq_ignore_ascii_case(mentioned.trim())
+}
+
+struct InMemoryMessaging {
+    guilds: RwLock<HashMap<Uuid, Guild>>,
+    channels: RwLock<HashMap<Uuid, Channel>>,
+    guild_index: RwLock<HashMap<Uuid, Vec<Uuid>>>,
+    events: RwLock<HashMap<Uuid, Vec<ChannelEvent>>>,
+    guild_memberships: RwLock<HashMap<Uuid, HashMap<Uuid, InMemoryGuildMembership>>>,
+    channel_memberships: RwLock<HashMap<Uuid, HashMap<Uuid, InMemoryChannelMembership>>>,
+    channel_reads: RwLock<HashMap<(Uuid, Uuid), i64>>,
+    thread_reads: RwLock<HashMap<(Uuid, String, Uuid), i64>>,
+    graph: RwLock<HashMap<Uuid, InMemoryEventGraph>>,
+    retention: RwLock<HashMap<Uuid, RetentionPolicy>>,
+    sequence: AtomicI64,
+}
+
+#[derive(Default)]
+struct InMemoryEventGraph {
+    prev_edges: HashMap<String, Vec<String>>,
+    auth_edges: HashMap<String, Vec<String>>,
+    referenced: HashSet<String>,
+    extremities: Vec<(i64, String)>,
+    state_after: HashMap<String, StateMap>,
+    /// Edit event ids keyed by the message they edit.
+    edits: HashMap<String, Vec<String>>,
+    /// Thread reply ids keyed by their root.
+    thread_replies: HashMap<String, Vec<String>>,
+    /// Live reactions keyed by reaction event id.
+    reactions: HashMap<String, InMemoryReaction>,
+}
+
+struct InMemoryReaction {
+    target_event_id: String,
+    sender: String,
+    reaction_key: String,
+}
+
+impl Default for InMemoryMessaging {
+    fn default() -> Self {
+        Self {
+            guilds: RwLock::new(HashMap::new()),
+            channels: RwLock::new(HashMap::new()),
             guild_index: RwLock::new(HashMap::new()),
             events: RwLock::new(HashMap::new()),
             guild_memberships: RwLock::new(HashMap::new()),
             channel_memberships: RwLock::new(HashMap::new()),
             channel_reads: RwLock::new(HashMap::new()),
+            thread_reads: RwLock::new(HashMap::new()),
+            graph: RwLock::new(HashMap::new()),
+            retention: RwLock::new(HashMap::new()),
             sequence: AtomicI64::new(0),
         }
     }
@@ -372,7 +1000,12 @@ impl ChannelStore for InMemoryMessaging {
         Ok(guilds)
     }
 
-    async fn create_channel(&self, guild_id: Uuid, name: &str) -> Result<Channel, MessagingError> {
+    async fn create_channel(
+        &self,
+        guild_id: Uuid,
+        name: &str,
+        kind: ChannelKind,
+    ) -> Result<Channel, MessagingError> {
         if !self.guilds.read().await.contains_key(&guild_id) {
             return Err(MessagingError::GuildNotFound);
         }
@@ -380,6 +1013,7 @@ impl ChannelStore for InMemoryMessaging {
             channel_id: Uuid::new_v4(),
             guild_id,
             name: name.to_string(),
+            kind: kind.as_str().to_string(),
             created_at: chrono::Utc::now(),
         };
         self.channels
@@ -416,6 +1050,9 @@ impl ChannelStore for InMemoryMessaging {
         event_id: &str,
         event_type: &str,
         body: &serde_json::Value,
+        prev_events: &[String],
+        auth_events: &[String],
+        _outbox: &[String],
     ) -> Result<ChannelEvent, MessagingError> {
         if !self.channels.read().await.contains_key(&channel_id) {
             return Err(MessagingError::ChannelNotFound);
@@ -428,6 +1065,8 @@ impl ChannelStore for InMemoryMessaging {
             event_type: event_type.to_string(),
             body: body.clone(),
             created_at: chrono::Utc::now(),
+            redacted: false,
+            redacted_by: None,
         };
         self.events
             .write()
@@ -435,9 +1074,244 @@ impl ChannelStore for InMemoryMessaging {
             .entry(channel_id)
             .or_default()
             .push(event.clone());
+
+        let mut graphs = self.graph.write().await;
+        let graph = graphs.entry(channel_id).or_default();
+        graph
+            .prev_edges
+            .insert(event.event_id.clone(), prev_events.to_vec());
+        graph
+            .auth_edges
+            .insert(event.event_id.clone(), auth_events.to_vec());
+        graph.referenced.extend(prev_events.iter().cloned());
+        graph
+            .extremities
+            .retain(|(_, id)| !prev_events.contains(id));
+        if !graph.referenced.contains(&event.event_id) {
+            graph.extremities.push((sequence, event.event_id.clone()));
+        }
         Ok(event)
     }
 
+    async fn forward_extremities(&self, channel_id: Uuid) -> Result<Vec<String>, MessagingError> {
+        let graphs = self.graph.read().await;
+        let mut extremities = graphs
+            .get(&channel_id)
+            .map(|graph| graph.extremities.clone())
+            .unwrap_or_default();
+        extremities.sort_by_key(|(sequence, _)| *sequence);
+        Ok(extremities.into_iter().map(|(_, id)| id).collect())
+    }
+
+    async fn record_event_state(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+        state: &StateMap,
+    ) -> Result<(), MessagingError> {
+        self.graph
+            .write()
+            .await
+            .entry(channel_id)
+            .or_default()
+            .state_after
+            .insert(event_id.to_string(), state.clone());
+        Ok(())
+    }
+
+    async fn event_state(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<Option<StateMap>, MessagingError> {
+        Ok(self
+            .graph
+            .read()
+            .await
+            .get(&channel_id)
+            .and_then(|graph| graph.state_after.get(event_id))
+            .cloned())
+    }
+
+    async fn event_by_id(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<Option<ChannelEvent>, MessagingError> {
+        let events = self.events.read().await;
+        Ok(events
+            .get(&channel_id)
+            .and_then(|list| list.iter().find(|event| event.event_id == event_id))
+            .cloned())
+    }
+
+    async fn redact_event(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+        redacted_by: Option<&str>,
+        redacted: &CanonicalEvent,
+    ) -> Result<bool, MessagingError> {
+        let body =
+            serde_json::to_value(redacted).map_err(|err| MessagingError::Storage(err.into()))?;
+        let mut events = self.events.write().await;
+        let Some(event) = events
+            .get_mut(&channel_id)
+            .and_then(|list| list.iter_mut().find(|event| event.event_id == event_id))
+        else {
+            return Ok(false);
+        };
+        if event.redacted {
+            return Ok(false);
+        }
+        event.body = body;
+        event.redacted = true;
+        event.redacted_by = redacted_by.map(str::to_string);
+        Ok(true)
+    }
+
+    async fn record_edit(
+        &self,
+        channel_id: Uuid,
+        original_event_id: &str,
+        edit_event_id: &str,
+    ) -> Result<(), MessagingError> {
+        let mut graph = self.graph.write().await;
+        let edits = graph
+            .entry(channel_id)
+            .or_default()
+            .edits
+            .entry(original_event_id.to_string())
+            .or_default();
+        if !edits.iter().any(|id| id == edit_event_id) {
+            edits.push(edit_event_id.to_string());
+        }
+        Ok(())
+    }
+
+    async fn edits_for_event(
+        &self,
+        channel_id: Uuid,
+        original_event_id: &str,
+    ) -> Result<Vec<ChannelEvent>, MessagingError> {
+        let edit_ids = self
+            .graph
+            .read()
+            .await
+            .get(&channel_id)
+            .and_then(|graph| graph.edits.get(original_event_id))
+            .cloned()
+            .unwrap_or_default();
+        let events = self.events.read().await;
+        let mut edits: Vec<ChannelEvent> = events
+            .get(&channel_id)
+            .map(|list| {
+                list.iter()
+                    .filter(|event| edit_ids.contains(&event.event_id))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        edits.sort_by_key(edit_order);
+        Ok(edits)
+    }
+
+    async fn latest_edits(
+        &self,
+        channel_id: Uuid,
+        original_event_ids: &[String],
+    ) -> Result<Vec<LatestEdit>, MessagingError> {
+        let mut latest = Vec::new();
+        for original_event_id in original_event_ids {
+            let edits: Vec<_> = self
+                .edits_for_event(channel_id, original_event_id)
+                .await?
+                .into_iter()
+                .filter(|edit| !edit.redacted)
+                .collect();
+            if let Some(newest) = edits.last() {
+                latest.push(LatestEdit {
+                    original_event_id: original_event_id.clone(),
+                    edit_event_id: newest.event_id.clone(),
+                    body: newest.body.clone(),
+                    edit_count: edits.len() as i64,
+                });
+            }
+        }
+        Ok(latest)
+    }
+
+    async fn record_thread_reply(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        event_id: &str,
+    ) -> Result<(), MessagingError> {
+        let mut graph = self.graph.write().await;
+        let replies = graph
+            .entry(channel_id)
+            .or_default()
+            .thread_replies
+            .entry(root_event_id.to_string())
+            .or_default();
+        if !replies.iter().any(|id| id == event_id) {
+            replies.push(event_id.to_string());
+        }
+        Ok(())
+    }
+
+    async fn thread_events(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        since_sequence: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>, MessagingError> {
+        let reply_ids = self
+            .graph
+            .read()
+            .await
+            .get(&channel_id)
+            .and_then(|graph| graph.thread_replies.get(root_event_id))
+            .cloned()
+            .unwrap_or_default();
+        let mut events: Vec<ChannelEvent> = self
+            .recent_events(channel_id, since_sequence, i64::MAX)
+            .await?
+            .into_iter()
+            .filter(|event| reply_ids.contains(&event.event_id))
+            .collect();
+        if since_sequence.is_some() {
+            events.truncate(limit as usize);
+        } else if events.len() as i64 > limit {
+            events = events[(events.len() - limit as usize)..].to_vec();
+        }
+        Ok(events)
+    }
+
+    async fn thread_stats(
+        &self,
+        channel_id: Uuid,
+        root_event_ids: &[String],
+    ) -> Result<Vec<ThreadStats>, MessagingError> {
+        let mut stats = Vec::new();
+        for root_event_id in root_event_ids {
+            let replies = self
+                .thread_events(channel_id, root_event_id, None, i64::MAX)
+                .await?;
+            if let Some(last) = replies.last() {
+                stats.push(ThreadStats {
+                    root_event_id: root_event_id.clone(),
+                    reply_count: replies.len() as i64,
+                    last_reply_event_id: last.event_id.clone(),
+                    last_reply_sequence: last.sequence,
+                    last_reply_body: last.body.clone(),
+                });
+            }
+        }
+        Ok(stats)
+    }
+
     async fn recent_events(
         &self,
         channel_id: Uuid,
@@ -456,10 +1330,27 @@ impl ChannelStore for InMemoryMessaging {
         Ok(events)
     }
 
+    async fn first_state_event(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Option<ChannelEvent>, MessagingError> {
+        let events = self.events.read().await;
+        Ok(events.get(&channel_id).and_then(|list| {
+            list.iter()
+                .filter(|event| event.body.get("state_key").is_some())
+                .min_by_key(|event| event.sequence)
+                .cloned()
+        }))
+    }
+
     async fn channel_exists(&self, channel_id: Uuid) -> Result<bool, MessagingError> {
         Ok(self.channels.read().await.contains_key(&channel_id))
     }
 
+    async fn channel_by_id(&self, channel_id: Uuid) -> Result<Option<Channel>, MessagingError> {
+        Ok(self.channels.read().await.get(&channel_id).cloned())
+    }
+
     async fn user_ids_for_channel(&self, channel_id: Uuid) -> Result<Vec<Uuid>, MessagingError> {
         let memberships = self.channel_memberships.read().await;
         let mut users = Vec::new();
@@ -471,6 +1362,28 @@ impl ChannelStore for InMemoryMessaging {
         Ok(users)
     }
 
+    async fn channel_members(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Vec<ChannelMembership>, MessagingError> {
+        let memberships = self.channel_memberships.read().await;
+        let mut members: Vec<ChannelMembership> = memberships
+            .iter()
+            .filter_map(|(user_id, channels)| {
+                channels
+                    .get(&channel_id)
+                    .map(|membership| ChannelMembership {
+                        channel_id,
+                        user_id: *user_id,
+                        role: membership.role.clone(),
+                        joined_at: membership.joined_at,
+                    })
+            })
+            .collect();
+        members.sort_by_key(|member| member.joined_at);
+        Ok(members)
+    }
+
     async fn user_ids_for_guild(&self, guild_id: Uuid) -> Result<Vec<Uuid>, MessagingError> {
         let memberships = self.guild_memberships.read().await;
         let mut users = Vec::new();
@@ -545,65 +1458,240 @@ impl ChannelStore for InMemoryMessaging {
         Ok(states)
     }
 
-    #[cfg_attr(not(test), allow(dead_code))]
-    async fn upsert_guild_membership(
+    async fn record_reaction(
         &self,
-        guild_id: Uuid,
-        user_id: Uuid,
-        role: &str,
-    ) -> Result<GuildMembership, MessagingError> {
-        if !self.guilds.read().await.contains_key(&guild_id) {
-            return Err(MessagingError::GuildNotFound);
-        }
-
-        let record = GuildMembership {
-            guild_id,
-            user_id,
-            role: role.to_string(),
-            joined_at: chrono::Utc::now(),
-        };
-
-        let entry = InMemoryGuildMembership {
-            role: record.role.clone(),
-            joined_at: record.joined_at,
-        };
-
-        self.guild_memberships
+        channel_id: Uuid,
+        target_event_id: &str,
+        event_id: &str,
+        sender: &str,
+        reaction_key: &str,
+    ) -> Result<(), MessagingError> {
+        self.graph
             .write()
             .await
-            .entry(user_id)
+            .entry(channel_id)
             .or_default()
-            .insert(guild_id, entry);
+            .reactions
+            .entry(event_id.to_string())
+            .or_insert_with(|| InMemoryReaction {
+                target_event_id: target_event_id.to_string(),
+                sender: sender.to_string(),
+                reaction_key: reaction_key.to_string(),
+            });
+        Ok(())
+    }
 
-        Ok(record)
+    async fn remove_reaction(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<bool, MessagingError> {
+        Ok(self
+            .graph
+            .write()
+            .await
+            .get_mut(&channel_id)
+            .and_then(|graph| graph.reactions.remove(event_id))
+            .is_some())
     }
 
-    async fn guild_memberships_for_user(
+    async fn reactions_by_sender(
         &self,
-        user_id: Uuid,
-    ) -> Result<Vec<GuildMembershipSummary>, MessagingError> {
-        let entries = {
-            let map = self.guild_memberships.read().await;
-            map.get(&user_id).cloned()
-        };
+        channel_id: Uuid,
+        target_event_id: &str,
+        sender: &str,
+        reaction_key: &str,
+    ) -> Result<Vec<String>, MessagingError> {
+        let graph = self.graph.read().await;
+        let mut ids: Vec<String> = graph
+            .get(&channel_id)
+            .map(|graph| {
+                graph
+                    .reactions
+                    .iter()
+                    .filter(|(_, reaction)| {
+                        reaction.target_event_id == target_event_id
+                            && reaction.sender == sender
+                            && reaction.reaction_key == reaction_key
+                    })
+                    .map(|(event_id, _)| event_id.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        ids.sort();
+        Ok(ids)
+    }
 
-        let Some(entries) = entries else {
+    async fn reaction_counts(
+        &self,
+        channel_id: Uuid,
+        target_event_ids: &[String],
+        viewer: Option<&str>,
+    ) -> Result<Vec<ReactionCount>, MessagingError> {
+        let graph = self.graph.read().await;
+        let Some(graph) = graph.get(&channel_id) else {
             return Ok(Vec::new());
         };
-
-        let guilds = self.guilds.read().await;
-        let mut summaries = Vec::new();
-        for (guild_id, membership) in entries {
-            if let Some(guild) = guilds.get(&guild_id) {
-                summaries.push(GuildMembershipSummary {
-                    guild_id,
-                    guild_name: guild.name.clone(),
-                    role: membership.role.clone(),
-                    joined_at: membership.joined_at,
-                });
+        let mut senders: HashMap<(&str, &str), HashSet<&str>> = HashMap::new();
+        for reaction in graph.reactions.values() {
+            if target_event_ids.contains(&reaction.target_event_id) {
+                senders
+                    .entry((&reaction.target_event_id, &reaction.reaction_key))
+                    .or_default()
+                    .insert(&reaction.sender);
             }
         }
-        summaries.sort_by_key(|entry| entry.joined_at);
+        let mut counts: Vec<ReactionCount> = senders
+            .into_iter()
+            .map(|((target_event_id, reaction_key), senders)| ReactionCount {
+                target_event_id: target_event_id.to_string(),
+                reaction_key: reaction_key.to_string(),
+                count: senders.len() as i64,
+                me: viewer.is_some_and(|viewer| senders.contains(viewer)),
+            })
+            .collect();
+        counts.sort_by(|a, b| {
+            a.target_event_id
+                .cmp(&b.target_event_id)
+                .then(b.count.cmp(&a.count))
+                .then(a.reaction_key.cmp(&b.reaction_key))
+        });
+        Ok(counts)
+    }
+
+    async fn update_thread_read_sequence(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        user_id: Uuid,
+        sequence: i64,
+    ) -> Result<(), MessagingError> {
+        let mut reads = self.thread_reads.write().await;
+        let entry = reads
+            .entry((channel_id, root_event_id.to_string(), user_id))
+            .or_insert(0);
+        if sequence > *entry {
+            *entry = sequence;
+        }
+        Ok(())
+    }
+
+    async fn ensure_thread_read_state(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        user_id: Uuid,
+        sequence: i64,
+    ) -> Result<(), MessagingError> {
+        let mut reads = self.thread_reads.write().await;
+        reads
+            .entry((channel_id, root_event_id.to_string(), user_id))
+            .or_insert(sequence);
+        Ok(())
+    }
+
+    async fn user_thread_unread(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<ThreadUnreadState>, MessagingError> {
+        let member_of: HashSet<Uuid> = self
+            .channel_memberships
+            .read()
+            .await
+            .get(&user_id)
+            .map(|channels| channels.keys().copied().collect())
+            .unwrap_or_default();
+        let tracked: Vec<_> = self
+            .thread_reads
+            .read()
+            .await
+            .iter()
+            .filter(|((channel_id, _, reader), _)| {
+                *reader == user_id && member_of.contains(channel_id)
+            })
+            .map(|((channel_id, root_event_id, _), last_read)| {
+                (*channel_id, root_event_id.clone(), *last_read)
+            })
+            .collect();
+
+        let mut states = Vec::with_capacity(tracked.len());
+        for (channel_id, root_event_id, last_read) in tracked {
+            let replies = self
+                .thread_events(channel_id, &root_event_id, None, i64::MAX)
+                .await?;
+            states.push(ThreadUnreadState {
+                channel_id,
+                root_event_id,
+                last_read_sequence: last_read,
+                latest_sequence: replies.last().map(|reply| reply.sequence).unwrap_or(0),
+                unread: replies
+                    .iter()
+                    .filter(|reply| reply.sequence > last_read)
+                    .count() as i64,
+            });
+        }
+        Ok(states)
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    async fn upsert_guild_membership(
+        &self,
+        guild_id: Uuid,
+        user_id: Uuid,
+        role: &str,
+    ) -> Result<GuildMembership, MessagingError> {
+        if !self.guilds.read().await.contains_key(&guild_id) {
+            return Err(MessagingError::GuildNotFound);
+        }
+
+        let record = GuildMembership {
+            guild_id,
+            user_id,
+            role: role.to_string(),
+            joined_at: chrono::Utc::now(),
+        };
+
+        let entry = InMemoryGuildMembership {
+            role: record.role.clone(),
+            joined_at: record.joined_at,
+        };
+
+        self.guild_memberships
+            .write()
+            .await
+            .entry(user_id)
+            .or_default()
+            .insert(guild_id, entry);
+
+        Ok(record)
+    }
+
+    async fn guild_memberships_for_user(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<GuildMembershipSummary>, MessagingError> {
+        let entries = {
+            let map = self.guild_memberships.read().await;
+            map.get(&user_id).cloned()
+        };
+
+        let Some(entries) = entries else {
+            return Ok(Vec::new());
+        };
+
+        let guilds = self.guilds.read().await;
+        let mut summaries = Vec::new();
+        for (guild_id, membership) in entries {
+            if let Some(guild) = guilds.get(&guild_id) {
+                summaries.push(GuildMembershipSummary {
+                    guild_id,
+                    guild_name: guild.name.clone(),
+                    role: membership.role.clone(),
+                    joined_at: membership.joined_at,
+                });
+            }
+        }
+        summaries.sort_by_key(|entry| entry.joined_at);
         Ok(summaries)
     }
 
@@ -668,6 +1756,167 @@ impl ChannelStore for InMemoryMessaging {
         summaries.sort_by_key(|entry| entry.joined_at);
         Ok(summaries)
     }
+
+    async fn set_retention_policy(
+        &self,
+        guild_id: Uuid,
+        max_age_secs: Option<i64>,
+        max_events: Option<i64>,
+    ) -> Result<RetentionPolicy, MessagingError> {
+        if !self.guilds.read().await.contains_key(&guild_id) {
+            return Err(MessagingError::GuildNotFound);
+        }
+        let policy = RetentionPolicy {
+            guild_id,
+            max_age_secs,
+            max_events,
+            updated_at: chrono::Utc::now(),
+        };
+        self.retention
+            .write()
+            .await
+            .insert(guild_id, policy.clone());
+        Ok(policy)
+    }
+
+    async fn clear_retention_policy(&self, guild_id: Uuid) -> Result<bool, MessagingError> {
+        Ok(self.retention.write().await.remove(&guild_id).is_some())
+    }
+
+    async fn retention_policy(
+        &self,
+        guild_id: Uuid,
+    ) -> Result<Option<RetentionPolicy>, MessagingError> {
+        Ok(self.retention.read().await.get(&guild_id).cloned())
+    }
+
+    async fn retention_policies(&self) -> Result<Vec<RetentionPolicy>, MessagingError> {
+        let mut policies: Vec<_> = self.retention.read().await.values().cloned().collect();
+        policies.sort_by_key(|policy| policy.guild_id);
+        Ok(policies)
+    }
+
+    async fn prune_channel_events(
+        &self,
+        channel_id: Uuid,
+        older_than: Option<chrono::DateTime<chrono::Utc>>,
+        keep_latest: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>, MessagingError> {
+        let mut graphs = self.graph.write().await;
+        let mut events = self.events.write().await;
+        let Some(list) = events.get_mut(&channel_id) else {
+            return Ok(Vec::new());
+        };
+        list.sort_by_key(|event| event.sequence);
+        let keep_after = keep_latest
+            .and_then(|keep| list.len().checked_sub(keep as usize + 1))
+            .map(|index| list[index].sequence);
+        let extremities: HashSet<String> = graphs
+            .get(&channel_id)
+            .map(|graph| graph.extremities.iter().map(|(_, id)| id.clone()).collect())
+            .unwrap_or_default();
+
+        let pruned_ids: HashSet<String> = list
+            .iter()
+            .filter(|event| {
+                event.body.get("state_key").is_none()
+                    && !extremities.contains(&event.event_id)
+                    && (older_than.is_some_and(|cutoff| event.created_at < cutoff)
+                        || keep_after.is_some_and(|sequence| event.sequence <= sequence))
+            })
+            .take(limit.max(0) as usize)
+            .map(|event| event.event_id.clone())
+            .collect();
+        if pruned_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (pruned, kept): (Vec<_>, Vec<_>) = list
+            .drain(..)
+            .partition(|event| pruned_ids.contains(&event.event_id));
+        *list = kept;
+
+        if let Some(graph) = graphs.get_mut(&channel_id) {
+            graph.prev_edges.retain(|id, _| !pruned_ids.contains(id));
+            graph.auth_edges.retain(|id, _| !pruned_ids.contains(id));
+            graph.state_after.retain(|id, _| !pruned_ids.contains(id));
+            for links in [&mut graph.edits, &mut graph.thread_replies] {
+                links.retain(|id, _| !pruned_ids.contains(id));
+                for linked in links.values_mut() {
+                    linked.retain(|id| !pruned_ids.contains(id));
+                }
+            }
+            graph.reactions.retain(|id, reaction| {
+                !pruned_ids.contains(id) && !pruned_ids.contains(&reaction.target_event_id)
+            });
+        }
+        self.thread_reads
+            .write()
+            .await
+            .retain(|(channel, root, _), _| *channel != channel_id || !pruned_ids.contains(root));
+        Ok(pruned)
+    }
+
+    async fn media_referenced(&self, media_id: &str) -> Result<bool, MessagingError> {
+        Ok(self.events.read().await.values().flatten().any(|event| {
+            attachment_media_ids(&event.body)
+                .iter()
+                .any(|id| id == media_id)
+        }))
+    }
+
+    async fn guild_event_usage(&self, guild_id: Uuid) -> Result<EventUsage, MessagingError> {
+        let channel_ids = self
+            .guild_index
+            .read()
+            .await
+            .get(&guild_id)
+            .cloned()
+            .unwrap_or_default();
+        let events = self.events.read().await;
+        Ok(channel_ids
+            .iter()
+            .filter_map(|channel_id| events.get(channel_id))
+            .flatten()
+            .fold(EventUsage::default(), |usage, event| EventUsage {
+                events: usage.events + 1,
+                event_bytes: usage.event_bytes + event_body_bytes(&event.body),
+            }))
+    }
+
+    async fn user_event_usage(&self, sender: &str) -> Result<EventUsage, MessagingError> {
+        Ok(self
+            .events
+            .read()
+            .await
+            .values()
+            .flatten()
+            .filter(|event| {
+                event.body.get("sender").and_then(|value| value.as_str()) == Some(sender)
+            })
+            .fold(EventUsage::default(), |usage, event| EventUsage {
+                events: usage.events + 1,
+                event_bytes: usage.event_bytes + event_body_bytes(&event.body),
+            }))
+    }
+
+    async fn guild_attachment_ids(&self, guild_id: Uuid) -> Result<Vec<String>, MessagingError> {
+        let channel_ids = self
+            .guild_index
+            .read()
+            .await
+            .get(&guild_id)
+            .cloned()
+            .unwrap_or_default();
+        let events = self.events.read().await;
+        let ids: HashSet<String> = channel_ids
+            .iter()
+            .filter_map(|channel_id| events.get(channel_id))
+            .flatten()
+            .flat_map(|event| attachment_media_ids(&event.body))
+            .collect();
+        Ok(ids.into_iter().collect())
+    }
 }
 
 #[derive(Clone)]
@@ -679,6 +1928,8 @@ pub struct MessagingService {
     message_rate_limits: Arc<RateLimiter>,
     ip_rate_limits: Arc<RateLimiter>,
     origin_server: String,
+    event_signer: Option<Arc<FederationSigner>>,
+    federation_sender: Option<Arc<FederationSender>>,
     #[cfg(feature = "metrics")]
     metrics: Option<Arc<MetricsContext>>,
 }
@@ -688,6 +1939,78 @@ pub struct OutboundEvent {
     pub sequence: i64,
     pub channel_id: Uuid,
     pub event: serde_json::Value,
+    /// Latest edit of a replayed message. Live edits arrive as their own `edit` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited: Option<EditSummary>,
+    /// Thread summary of a replayed thread root. Live replies carry `content.thread_root`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<ThreadSummary>,
+    /// Reaction counts of a replayed event. Live reactions arrive as `reaction` events.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<ReactionSummary>,
+}
+
+/// Number of distinct users who reacted to an event with `key`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReactionSummary {
+    pub key: String,
+    pub count: i64,
+    /// Whether the requesting user is one of them.
+    pub me: bool,
+}
+
+/// Reply count and latest reply of a thread root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadSummary {
+    pub reply_count: i64,
+    pub last_reply_event_id: String,
+    pub last_reply_sequence: i64,
+    pub last_reply_sender: String,
+    pub last_reply_ts: i64,
+}
+
+impl ThreadSummary {
+    fn from_stats(stats: &ThreadStats) -> Self {
+        let body = &stats.last_reply_body;
+        Self {
+            reply_count: stats.reply_count,
+            last_reply_event_id: stats.last_reply_event_id.clone(),
+            last_reply_sequence: stats.last_reply_sequence,
+            last_reply_sender: body
+                .get("sender")
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            last_reply_ts: body
+                .get("origin_ts")
+                .and_then(serde_json::Value::as_i64)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Newest edit of a message: the body clients display in place of the original content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EditSummary {
+    /// Id of the newest `edit` event.
+    pub event_id: String,
+    pub content: String,
+    pub origin_ts: i64,
+    /// Number of unredacted edits; the full list is served by the edit history endpoint.
+    pub count: i64,
+}
+
+impl EditSummary {
+    fn from_latest(latest: &LatestEdit) -> Option<Self> {
+        let event: CanonicalEvent = serde_json::from_value(latest.body.clone()).ok()?;
+        let content = EditContent::from_event(&event)?;
+        Some(Self {
+            event_id: event.event_id,
+            content: content.content,
+            origin_ts: event.origin_ts,
+            count: latest.edit_count,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -727,11 +2050,24 @@ impl MessagingService {
                 MESSAGE_RATE_WINDOW,
             )),
             origin_server,
+            event_signer: None,
+            federation_sender: None,
             #[cfg(feature = "metrics")]
             metrics: None,
         }
     }
 
+    /// Sign locally created events and hand them to the outbound federation queue.
+    pub fn with_federation(
+        mut self,
+        signer: Option<Arc<FederationSigner>>,
+        sender: Option<Arc<FederationSender>>,
+    ) -> Self {
+        self.event_signer = signer;
+        self.federation_sender = sender;
+        self
+    }
+
     #[cfg(feature = "metrics")]
     pub fn with_metrics(mut self, metrics: Option<Arc<MetricsContext>>) -> Self {
         self.metrics = metrics;
@@ -764,12 +2100,35 @@ impl MessagingService {
         self.store.list_guilds().await
     }
 
+    #[cfg_attr(not(test), allow(dead_code))]
     pub async fn create_channel(
         &self,
         guild_id: Uuid,
         name: &str,
     ) -> Result<Channel, MessagingError> {
-        let channel = self.store.create_channel(guild_id, name).await?;
+        self.create_channel_with_kind(guild_id, name, ChannelKind::Text, None)
+            .await
+    }
+
+    /// Create a channel. With a `creator`, the channel starts with power levels that give
+    /// them level 100, so only they can hand out power afterwards.
+    pub async fn create_channel_with_kind(
+        &self,
+        guild_id: Uuid,
+        name: &str,
+        kind: ChannelKind,
+        creator: Option<Uuid>,
+    ) -> Result<Channel, MessagingError> {
+        let channel = self.store.create_channel(guild_id, name, kind).await?;
+        if let Some(creator) = creator {
+            let creator = creator.to_string();
+            let levels = StateContent::PowerLevels(PowerLevelsContent {
+                users: [(creator.clone(), 100)].into_iter().collect(),
+                ..PowerLevelsContent::default()
+            });
+            self.append_state_event(channel.channel_id, &creator, &levels, "")
+                .await?;
+        }
 
         let notification = Arc::new(NotificationEvent {
             kind: "channel_created".to_string(),
@@ -780,6 +2139,7 @@ impl MessagingService {
                 "channel_id": channel.channel_id,
                 "guild_id": channel.guild_id,
                 "name": channel.name,
+                "kind": kind,
                 "created_at": channel.created_at,
             }),
         });
@@ -800,58 +2160,523 @@ impl MessagingService {
         self.store.latest_sequence_for_channel(channel_id).await
     }
 
+    #[cfg_attr(not(test), allow(dead_code))]
     pub async fn append_message(
         &self,
         channel_id: Uuid,
         author: &MessageAuthorSnapshot,
         content: &str,
+    ) -> Result<ChannelEvent, MessagingError> {
+        self.append_reply(channel_id, author, content, None, None)
+            .await
+    }
+
+    /// Send a message that replies to `reply_to` and/or belongs to the thread under
+    /// `thread_root`. Replies to a message inside a thread join that thread.
+    pub async fn append_reply(
+        &self,
+        channel_id: Uuid,
+        author: &MessageAuthorSnapshot,
+        content: &str,
+        reply_to: Option<&str>,
+        thread_root: Option<&str>,
     ) -> Result<ChannelEvent, MessagingError> {
         let payload = MessagePayload {
-            content: content.to_owned(),
-            author: Some(author.clone()),
+            reply_to: reply_to.map(str::to_string),
+            thread_root: thread_root.map(str::to_string),
+            ..MessagePayload::new(content)
         };
+        self.send_message(channel_id, author, payload).await
+    }
+
+    /// Send `payload` as a message from `author`. Its relations are resolved like
+    /// `append_reply`, and its mentions must name members of the channel, roles they hold
+    /// or channels of the same guild.
+    pub async fn send_message(
+        &self,
+        channel_id: Uuid,
+        author: &MessageAuthorSnapshot,
+        mut payload: MessagePayload,
+    ) -> Result<ChannelEvent, MessagingError> {
+        payload.thread_root = self
+            .resolve_relations(
+                channel_id,
+                payload.reply_to.as_deref(),
+                payload.thread_root.as_deref(),
+            )
+            .await?;
+        self.check_mentions(channel_id, &payload.mentions).await?;
+        payload.author = Some(author.clone());
+        let prev_events = self.prev_events_for(channel_id).await?;
         let event = payload.to_event(
             &self.origin_server,
             &channel_id.to_string(),
             &author.id,
-            Vec::new(),
+            prev_events,
         );
-        let body =
-            serde_json::to_value(&event).map_err(|err| MessagingError::Storage(err.into()))?;
-        let stored = self
-            .store
-            .append_event(channel_id, &event.event_id, &event.event_type, &body)
-            .await?;
+        self.append_local_event(channel_id, event, "channel_message")
+            .await
+    }
 
-        let broadcast_event = Arc::new(OutboundEvent {
-            sequence: stored.sequence,
-            channel_id,
-            event: body,
-        });
-        let send_result = self.broadcast(channel_id, broadcast_event.clone()).await;
+    /// Send an end-to-end encrypted message. The ciphertext is stored and relayed as is;
+    /// only the auth rules apply.
+    pub async fn send_encrypted(
+        &self,
+        channel_id: Uuid,
+        sender: &str,
+        content: EncryptedContent,
+    ) -> Result<ChannelEvent, MessagingError> {
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
+        }
+        let prev_events = self.prev_events_for(channel_id).await?;
+        let event = content.to_event(
+            &self.origin_server,
+            &channel_id.to_string(),
+            sender,
+            prev_events,
+        );
+        self.append_local_event(channel_id, event, "channel_message")
+            .await
+    }
 
-        #[cfg(feature = "metrics")]
-        if let Some(metrics) = self.metrics() {
-            let outcome = match &send_result {
-                Ok(delivered) if *delivered == 0 => "no_subscribers",
-                Ok(_) => "delivered",
-                Err(_) => "dropped",
+    async fn check_mentions(
+        &self,
+        channel_id: Uuid,
+        mentions: &Mentions,
+    ) -> Result<(), MessagingError> {
+        if mentions.is_empty() {
+            return Ok(());
+        }
+        let Some(channel) = self.store.channel_by_id(channel_id).await? else {
+            return Err(MessagingError::ChannelNotFound);
+        };
+        let members = self.store.channel_members(channel_id).await?;
+        for user in &mentions.users {
+            let is_member = Uuid::parse_str(user)
+                .is_ok_and(|user_id| members.iter().any(|member| member.user_id == user_id));
+            if !is_member {
+                return Err(MessagingError::InvalidMention(format!(
+                    "'{user}' is not a member of the channel"
+                )));
+            }
+        }
+        for role in &mentions.roles {
+            if !members
+                .iter()
+                .any(|member| role_matches(&member.role, role))
+            {
+                return Err(MessagingError::InvalidMention(format!(
+                    "no channel member holds the '{role}' role"
+                )));
+            }
+        }
+        for mentioned in &mentions.channels {
+            let same_guild = match Uuid::parse_str(mentioned) {
+                Ok(mentioned_id) => self
+                    .store
+                    .channel_by_id(mentioned_id)
+                    .await?
+                    .is_some_and(|other| other.guild_id == channel.guild_id),
+                Err(_) => false,
             };
-            metrics.increment_messaging_events(outcome);
+            if !same_guild {
+                return Err(MessagingError::InvalidMention(format!(
+                    "'{mentioned}' is not a channel of this guild"
+                )));
+            }
         }
+        Ok(())
+    }
 
-        if let Err(err) = send_result {
-            tracing::warn!(?err, channel_id = %channel_id, "failed to broadcast event");
+    /// Channel members a message mentions, directly or through one of their roles.
+    async fn mentioned_members(
+        &self,
+        channel_id: Uuid,
+        event: &CanonicalEvent,
+    ) -> Result<HashSet<Uuid>, MessagingError> {
+        let Some(payload) = MessagePayload::from_event(event) else {
+            return Ok(HashSet::new());
+        };
+        let mentions = payload.mentions;
+        if mentions.users.is_empty() && mentions.roles.is_empty() {
+            return Ok(HashSet::new());
         }
+        Ok(self
+            .store
+            .channel_members(channel_id)
+            .await?
+            .into_iter()
+            .filter(|member| {
+                mentions.users.contains(&member.user_id.to_string())
+                    || mentions
+                        .roles
+                        .iter()
+                        .any(|role| role_matches(&member.role, role))
+            })
+            .map(|member| member.user_id)
+            .collect())
+    }
 
-        let notification = Arc::new(NotificationEvent {
-            kind: "channel_message".to_string(),
+    /// Check the targets of a new message's relations and return its thread root.
+    async fn resolve_relations(
+        &self,
+        channel_id: Uuid,
+        reply_to: Option<&str>,
+        thread_root: Option<&str>,
+    ) -> Result<Option<String>, MessagingError> {
+        if reply_to.is_none() && thread_root.is_none() {
+            return Ok(None);
+        }
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
+        }
+        if let Some(root_id) = thread_root {
+            if self
+                .relation_target(channel_id, root_id)
+                .await?
+                .thread_root
+                .is_some()
+            {
+                return Err(MessagingError::InvalidRelation(format!(
+                    "'{root_id}' is already in a thread"
+                )));
+            }
+        }
+        let Some(parent_id) = reply_to else {
+            return Ok(thread_root.map(str::to_string));
+        };
+        let parent_thread = self
+            .relation_target(channel_id, parent_id)
+            .await?
+            .thread_root;
+        match (thread_root, parent_thread) {
+            (None, inherited) => Ok(inherited),
+            (Some(root), Some(parent_root)) if parent_root == root => Ok(Some(parent_root)),
+            (Some(root), None) if root == parent_id => Ok(Some(root.to_string())),
+            (Some(root), _) => Err(MessagingError::InvalidRelation(format!(
+                "'{parent_id}' is not in the thread under '{root}'"
+            ))),
+        }
+    }
+
+    async fn relation_target(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<MessagePayload, MessagingError> {
+        let event = self
+            .load_canonical(channel_id, event_id)
+            .await?
+            .ok_or_else(|| MessagingError::InvalidRelation(format!("'{event_id}' is unknown")))?;
+        MessagePayload::from_event(&event).ok_or_else(|| {
+            MessagingError::InvalidRelation(format!("'{event_id}' is not a message"))
+        })
+    }
+
+    /// Send a typed state event (name, topic, membership, power levels) into the channel.
+    pub async fn append_state_event(
+        &self,
+        channel_id: Uuid,
+        sender: &str,
+        content: &StateContent,
+        state_key: &str,
+    ) -> Result<ChannelEvent, MessagingError> {
+        content.validate_state_key(state_key)?;
+        let prev_events = self.prev_events_for(channel_id).await?;
+        let event = content.to_event(
+            &self.origin_server,
+            &channel_id.to_string(),
+            sender,
+            state_key,
+            prev_events,
+        );
+        self.append_local_event(channel_id, event, "channel_state")
+            .await
+    }
+
+    /// Redact `event_id` on behalf of `sender`. The stored copy (and what clients and peers
+    /// see from then on) is replaced by the redacted form; the event keeps its id.
+    pub async fn redact_event(
+        &self,
+        channel_id: Uuid,
+        sender: &str,
+        event_id: &str,
+        reason: Option<String>,
+    ) -> Result<ChannelEvent, MessagingError> {
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
+        }
+        if self
+            .store
+            .event_by_id(channel_id, event_id)
+            .await?
+            .is_none()
+        {
+            return Err(MessagingError::EventNotFound(event_id.to_string()));
+        }
+        self.send_redaction(channel_id, sender, event_id, reason, "channel_redaction")
+            .await
+    }
+
+    async fn send_redaction(
+        &self,
+        channel_id: Uuid,
+        sender: &str,
+        event_id: &str,
+        reason: Option<String>,
+        notification_kind: &str,
+    ) -> Result<ChannelEvent, MessagingError> {
+        let prev_events = self.prev_events_for(channel_id).await?;
+        let event = RedactionContent {
+            redacts: event_id.to_string(),
+            reason,
+        }
+        .to_event(
+            &self.origin_server,
+            &channel_id.to_string(),
+            sender,
+            prev_events,
+        );
+        self.append_local_event(channel_id, event, notification_kind)
+            .await
+    }
+
+    /// React to `event_id` with `key`. Reacting again with the same key returns the
+    /// existing reaction instead of sending a duplicate.
+    pub async fn add_reaction(
+        &self,
+        channel_id: Uuid,
+        sender: &str,
+        event_id: &str,
+        key: &str,
+    ) -> Result<ChannelEvent, MessagingError> {
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
+        }
+        if self
+            .store
+            .event_by_id(channel_id, event_id)
+            .await?
+            .is_none()
+        {
+            return Err(MessagingError::EventNotFound(event_id.to_string()));
+        }
+        let existing = self
+            .store
+            .reactions_by_sender(channel_id, event_id, sender, key)
+            .await?;
+        if let Some(reaction_id) = existing.first() {
+            if let Some(reaction) = self.store.event_by_id(channel_id, reaction_id).await? {
+                return Ok(reaction);
+            }
+        }
+        let prev_events = self.prev_events_for(channel_id).await?;
+        let event = ReactionContent {
+            reacts_to: event_id.to_string(),
+            key: key.to_string(),
+        }
+        .to_event(
+            &self.origin_server,
+            &channel_id.to_string(),
+            sender,
+            prev_events,
+        );
+        self.append_local_event(channel_id, event, "channel_reaction")
+            .await
+    }
+
+    /// Redact every `key` reaction `sender` has on `event_id`. Fails with `EventNotFound`
+    /// when there is nothing to remove.
+    pub async fn remove_reaction(
+        &self,
+        channel_id: Uuid,
+        sender: &str,
+        event_id: &str,
+        key: &str,
+    ) -> Result<Vec<ChannelEvent>, MessagingError> {
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
+        }
+        let reactions = self
+            .store
+            .reactions_by_sender(channel_id, event_id, sender, key)
+            .await?;
+        if reactions.is_empty() {
+            return Err(MessagingError::EventNotFound(event_id.to_string()));
+        }
+        let mut redactions = Vec::with_capacity(reactions.len());
+        for reaction_id in reactions {
+            redactions.push(
+                self.send_redaction(
+                    channel_id,
+                    sender,
+                    &reaction_id,
+                    None,
+                    "channel_reaction_removed",
+                )
+                .await?,
+            );
+        }
+        Ok(redactions)
+    }
+
+    /// Replace the displayed body of message `event_id`. Only its sender may edit it, and
+    /// redacted messages cannot be edited.
+    pub async fn edit_message(
+        &self,
+        channel_id: Uuid,
+        sender: &str,
+        event_id: &str,
+        content: String,
+    ) -> Result<ChannelEvent, MessagingError> {
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
+        }
+        let Some(target) = self.store.event_by_id(channel_id, event_id).await? else {
+            return Err(MessagingError::EventNotFound(event_id.to_string()));
+        };
+        if target.redacted {
+            return Err(AuthError::Edit(format!("'{event_id}' has been redacted")).into());
+        }
+        let prev_events = self.prev_events_for(channel_id).await?;
+        let event = EditContent {
+            edits: event_id.to_string(),
+            content,
+        }
+        .to_event(
+            &self.origin_server,
+            &channel_id.to_string(),
+            sender,
+            prev_events,
+        );
+        self.append_local_event(channel_id, event, "channel_message_edit")
+            .await
+    }
+
+    /// Every edit of message `event_id`, oldest first.
+    pub async fn edit_history(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<Vec<ChannelEvent>, MessagingError> {
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
+        }
+        if self
+            .store
+            .event_by_id(channel_id, event_id)
+            .await?
+            .is_none()
+        {
+            return Err(MessagingError::EventNotFound(event_id.to_string()));
+        }
+        self.store.edits_for_event(channel_id, event_id).await
+    }
+
+    async fn prev_events_for(&self, channel_id: Uuid) -> Result<Vec<String>, MessagingError> {
+        let mut prev_events = self.store.forward_extremities(channel_id).await?;
+        if prev_events.len() > MAX_PREV_EVENTS {
+            prev_events.drain(..prev_events.len() - MAX_PREV_EVENTS);
+        }
+        Ok(prev_events)
+    }
+
+    async fn append_local_event(
+        &self,
+        channel_id: Uuid,
+        mut event: CanonicalEvent,
+        notification_kind: &str,
+    ) -> Result<ChannelEvent, MessagingError> {
+        let state_before = self.state_before(channel_id, &event).await?;
+        event.auth_events = auth::auth_event_ids(&event, &state_before);
+        event.refresh_event_id();
+        self.authorize(channel_id, &event, &state_before).await?;
+
+        if let Some(signer) = &self.event_signer {
+            signer.sign_event(&self.origin_server, &mut event);
+        }
+        let body =
+            serde_json::to_value(&event).map_err(|err| MessagingError::Storage(err.into()))?;
+        let destinations = match &self.federation_sender {
+            Some(sender) => sender.destinations_for(
+                &self
+                    .member_servers(channel_id, &event, &state_before)
+                    .await?,
+            ),
+            None => Vec::new(),
+        };
+        let outbox: &[String] = if self.store.writes_outbox() {
+            &destinations
+        } else {
+            &[]
+        };
+        let stored = self
+            .store
+            .append_event(
+                channel_id,
+                &event.event_id,
+                &event.event_type,
+                &body,
+                &event.prev_events,
+                &event.auth_events,
+                outbox,
+            )
+            .await?;
+        self.record_state(channel_id, &event, state_before).await?;
+        self.apply_redaction(channel_id, &event).await?;
+        self.apply_edit(channel_id, &event).await?;
+        self.apply_thread(channel_id, &event, &stored).await?;
+        self.apply_reaction(channel_id, &event).await?;
+
+        if let Some(sender) = &self.federation_sender {
+            if self.store.writes_outbox() {
+                sender.notify_queued();
+            } else {
+                sender.enqueue(&event, &destinations).await;
+            }
+        }
+
+        let broadcast_event = Arc::new(OutboundEvent {
+            sequence: stored.sequence,
+            channel_id,
+            event: body,
+            edited: None,
+            thread: None,
+            reactions: Vec::new(),
+        });
+        let send_result = self.broadcast(channel_id, broadcast_event.clone()).await;
+
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics() {
+            let outcome = match &send_result {
+                Ok(delivered) if *delivered == 0 => "no_subscribers",
+                Ok(_) => "delivered",
+                Err(_) => "dropped",
+            };
+            metrics.increment_messaging_events(outcome);
+        }
+
+        if let Err(err) = send_result {
+            tracing::warn!(?err, channel_id = %channel_id, "failed to broadcast event");
+        }
+
+        let notification = Arc::new(NotificationEvent {
+            kind: notification_kind.to_string(),
             channel_id: Some(channel_id),
             guild_id: None,
             sequence: Some(stored.sequence),
             event: broadcast_event.event.clone(),
         });
-        self.notify_channel_members(channel_id, notification).await;
+        let mentioned = self
+            .mentioned_members(channel_id, &event)
+            .await
+            .unwrap_or_else(|err| {
+                tracing::debug!(?err, channel_id = %channel_id, "failed to resolve mentions");
+                HashSet::new()
+            });
+        self.notify_channel_members(channel_id, notification, &mentioned)
+            .await;
 
         Ok(stored)
     }
@@ -862,18 +2687,42 @@ impl MessagingService {
     ) -> Result<ChannelEvent, MessagingError> {
         let channel_id = Uuid::parse_str(&event.room_id)
             .map_err(|_| MessagingError::InvalidRoomId(event.room_id.clone()))?;
+        let state_before = self.state_before(channel_id, event).await?;
+        self.authorize(channel_id, event, &state_before).await?;
         let body =
             serde_json::to_value(event).map_err(|err| MessagingError::Storage(err.into()))?;
 
         let stored = self
             .store
-            .append_event(channel_id, &event.event_id, &event.event_type, &body)
+            .append_event(
+                channel_id,
+                &event.event_id,
+                &event.event_type,
+                &body,
+                &event.prev_events,
+                &event.auth_events,
+                &[],
+            )
             .await?;
+        self.record_state(channel_id, event, state_before).await?;
+        self.apply_redaction(channel_id, event).await?;
+        self.apply_edit(channel_id, event).await?;
+        self.apply_thread(channel_id, event, &stored).await?;
+        self.apply_reaction(channel_id, event).await?;
+        if !event.has_valid_content_hash() {
+            // Peers serve events they have redacted in that form; keep the row flagged.
+            self.store
+                .redact_event(channel_id, &event.event_id, None, event)
+                .await?;
+        }
 
         let broadcast_event = Arc::new(OutboundEvent {
             sequence: stored.sequence,
             channel_id,
             event: body,
+            edited: None,
+            thread: None,
+            reactions: Vec::new(),
         });
         let send_result = self.broadcast(channel_id, broadcast_event).await;
 
@@ -894,1013 +2743,1436 @@ impl MessagingService {
         Ok(stored)
     }
 
-    pub async fn recent_events(
+    pub async fn forward_extremities(
         &self,
         channel_id: Uuid,
-        since_sequence: Option<i64>,
-        limit: i64,
-    ) -> Result<Vec<ChannelEvent>, MessagingError> {
-        self.store
-            .recent_events(channel_id, since_sequence, limit)
-            .await
+    ) -> Result<Vec<String>, MessagingError> {
+        self.store.forward_extremities(channel_id).await
     }
 
-    pub async fn channel_exists(&self, channel_id: Uuid) -> Result<bool, MessagingError> {
-        self.store.channel_exists(channel_id).await
+    /// Current channel state: the state after each forward extremity, resolved into one
+    /// snapshot. Returns the state events ordered by `(event_type, state_key)`.
+    pub async fn channel_state(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Vec<CanonicalEvent>, MessagingError> {
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
+        }
+
+        let mut forks = Vec::new();
+        for event_id in self.store.forward_extremities(channel_id).await? {
+            if let Some(state) = self.store.event_state(channel_id, &event_id).await? {
+                forks.push(state);
+            }
+        }
+        let resolved = self.resolve_forks(channel_id, forks).await?;
+
+        let mut events = Vec::with_capacity(resolved.len());
+        for event_id in resolved.event_ids() {
+            if let Some(event) = self.load_canonical(channel_id, event_id).await? {
+                events.push(event);
+            }
+        }
+        Ok(events)
     }
 
-    #[cfg_attr(not(test), allow(dead_code))]
-    pub async fn upsert_guild_membership(
+    /// Whether `server` has a joined member in the channel's current state.
+    pub async fn server_has_joined_member(
         &self,
-        guild_id: Uuid,
-        user_id: Uuid,
-        role: &str,
-    ) -> Result<GuildMembership, MessagingError> {
-        self.store
-            .upsert_guild_membership(guild_id, user_id, role)
-            .await
+        channel_id: Uuid,
+        server: &str,
+    ) -> Result<bool, MessagingError> {
+        let state = self.channel_state(channel_id).await?;
+        Ok(AuthState::from_events(&state, true)
+            .joined_servers()
+            .contains(server))
     }
 
-    pub async fn guild_memberships_for_user(
+    /// Servers with a joined member before or after `event`, so a member's departure still
+    /// reaches their server.
+    async fn member_servers(
         &self,
-        user_id: Uuid,
-    ) -> Result<Vec<GuildMembershipSummary>, MessagingError> {
-        self.store.guild_memberships_for_user(user_id).await
+        channel_id: Uuid,
+        event: &CanonicalEvent,
+        state_before: &StateMap,
+    ) -> Result<BTreeSet<String>, MessagingError> {
+        let mut members = Vec::new();
+        for (_, event_id) in state_before
+            .iter()
+            .filter(|((event_type, _), _)| event_type == state::CHANNEL_MEMBER_EVENT)
+        {
+            members.extend(self.load_canonical(channel_id, event_id).await?);
+        }
+        let mut servers = AuthState::from_events(&members, true).joined_servers();
+        members.push(event.clone());
+        servers.extend(AuthState::from_events(&members, true).joined_servers());
+        Ok(servers)
     }
 
-    pub async fn upsert_channel_membership(
+    /// Resolved state of the known `prev_events` of `event`.
+    async fn state_before(
         &self,
         channel_id: Uuid,
-        user_id: Uuid,
-        role: &str,
-    ) -> Result<ChannelMembership, MessagingError> {
-        self.store
-            .upsert_channel_membership(channel_id, user_id, role)
-            .await
+        event: &CanonicalEvent,
+    ) -> Result<StateMap, MessagingError> {
+        let mut forks = Vec::with_capacity(event.prev_events.len());
+        for prev in &event.prev_events {
+            if let Some(state) = self.store.event_state(channel_id, prev).await? {
+                forks.push(state);
+            }
+        }
+        self.resolve_forks(channel_id, forks).await
     }
 
-    pub async fn ensure_channel_access(
+    /// Run the auth rules against both the `auth_events` the event cites and the state
+    /// before it. Channels without membership state are open to every local sender; other
+    /// servers may only join them once they are public. Local channel members count as
+    /// joined unless the state records their membership.
+    async fn authorize(
         &self,
         channel_id: Uuid,
-        user_id: Uuid,
-        role: &str,
+        event: &CanonicalEvent,
+        state_before: &StateMap,
     ) -> Result<(), MessagingError> {
-        let _ = self
-            .upsert_channel_membership(channel_id, user_id, role)
-            .await?;
-        let latest = match self.latest_sequence_for_channel(channel_id).await {
-            Ok(sequence) => sequence,
-            Err(err) => {
-                tracing::debug!(
-                    ?err,
-                    channel_id = %channel_id,
-                    "failed to resolve latest sequence while bootstrapping membership"
+        let mut cited = Vec::with_capacity(event.auth_events.len());
+        for event_id in &event.auth_events {
+            let auth_event = self
+                .load_canonical(channel_id, event_id)
+                .await?
+                .ok_or_else(|| AuthError::UnknownAuthEvent(event_id.clone()))?;
+            cited.push(auth_event);
+        }
+        let creator = if event.event_type == state::CHANNEL_POWER_LEVELS_EVENT {
+            Some(self.channel_creator(channel_id, event).await?)
+        } else {
+            None
+        };
+        auth::check_auth_events(event, &cited, creator.as_deref())?;
+
+        let mut current = Vec::new();
+        for event_id in auth::auth_event_ids(event, state_before) {
+            if let Some(auth_event) = self.load_canonical(channel_id, &event_id).await? {
+                current.push(auth_event);
+            }
+        }
+        let managed = state_before
+            .iter()
+            .any(|((event_type, _), _)| event_type == state::CHANNEL_MEMBER_EVENT);
+        let mut auth_state = AuthState::from_events(&current, managed).with_creator(creator);
+        if managed && self.is_local_member(channel_id, event).await? {
+            auth_state.admit(auth::qualify_user_id(&event.sender, &event.origin_server));
+        }
+        auth::check_event(event, &auth_state)?;
+        if !managed && event.origin_server != self.origin_server {
+            let public_join = auth_state.join_rule() == JoinRule::Public
+                && matches!(
+                    StateContent::from_event(event),
+                    Ok(Some(StateContent::Member(MembershipContent {
+                        membership: Membership::Join
+                    })))
                 );
-                0
+            if !public_join {
+                return Err(AuthError::NotJoined {
+                    user: auth::qualify_user_id(&event.sender, &event.origin_server),
+                }
+                .into());
             }
-        };
-        self.store
-            .ensure_read_state(channel_id, user_id, latest)
-            .await
+        }
+
+        if event.event_type == REDACTION_EVENT {
+            let content = RedactionContent::from_event(event)
+                .ok_or_else(|| AuthError::Redaction("malformed content".into()))?;
+            // Redactions of events not seen yet are stored but have nothing to apply to.
+            if let Some(target) = self.load_canonical(channel_id, &content.redacts).await? {
+                auth::check_redaction(event, &target, &auth_state)?;
+            }
+        }
+        if let Some(content) = EditContent::from_event(event) {
+            // Likewise, edits of unknown messages are stored but not linked to them.
+            if let Some(target) = self.load_canonical(channel_id, &content.edits).await? {
+                auth::check_edit(event, &target)?;
+            }
+        }
+        if event.event_type == REACTION_EVENT {
+            let content = ReactionContent::from_event(event)
+                .ok_or_else(|| AuthError::Reaction("malformed content".into()))?;
+            if let Some(target) = self.load_canonical(channel_id, &content.reacts_to).await? {
+                auth::check_reaction(event, &target)?;
+            }
+        }
+        Ok(())
     }
 
-    pub async fn channel_memberships_for_user(
+    /// Qualified sender of the channel's first state event, who counts as its creator.
+    /// Before the channel has any state, `event` is that first state event.
+    async fn channel_creator(
         &self,
-        user_id: Uuid,
-    ) -> Result<Vec<ChannelMembershipSummary>, MessagingError> {
-        self.store.channel_memberships_for_user(user_id).await
+        channel_id: Uuid,
+        event: &CanonicalEvent,
+    ) -> Result<String, MessagingError> {
+        let first = match self.store.first_state_event(channel_id).await? {
+            Some(stored) => serde_json::from_value(stored.body)
+                .map_err(|err| MessagingError::Storage(err.into()))?,
+            None => event.clone(),
+        };
+        Ok(auth::qualify_user_id(&first.sender, &first.origin_server))
     }
 
-    pub async fn update_last_read_sequence(
+    /// Whether `event` comes from a local user with a channel membership row. Channels
+    /// that gain membership state keep admitting them until the state says otherwise.
+    async fn is_local_member(
         &self,
         channel_id: Uuid,
-        user_id: Uuid,
-        sequence: i64,
-    ) -> Result<(), MessagingError> {
-        self.store
-            .update_last_read_sequence(channel_id, user_id, sequence)
-            .await
+        event: &CanonicalEvent,
+    ) -> Result<bool, MessagingError> {
+        if event.origin_server != self.origin_server {
+            return Ok(false);
+        }
+        let localpart = event.sender.trim_start_matches('@');
+        let localpart = localpart
+            .split_once(':')
+            .map_or(localpart, |(local, _)| local);
+        let Ok(user_id) = Uuid::parse_str(localpart) else {
+            return Ok(false);
+        };
+        Ok(self
+            .channel_membership(channel_id, user_id)
+            .await?
+            .is_some())
     }
 
-    pub async fn user_channel_unread(
+    /// Replace the target of a redaction event, and any edits of it, with their redacted
+    /// form.
+    async fn apply_redaction(
         &self,
-        user_id: Uuid,
-    ) -> Result<Vec<ChannelUnreadState>, MessagingError> {
-        self.store.user_channel_unread(user_id).await
+        channel_id: Uuid,
+        event: &CanonicalEvent,
+    ) -> Result<(), MessagingError> {
+        let Some(content) = RedactionContent::from_event(event) else {
+            return Ok(());
+        };
+        let Some(target) = self.load_canonical(channel_id, &content.redacts).await? else {
+            return Ok(());
+        };
+        self.store
+            .redact_event(
+                channel_id,
+                &target.event_id,
+                Some(&event.event_id),
+                &target.redacted(),
+            )
+            .await?;
+        if target.event_type == REACTION_EVENT {
+            self.store
+                .remove_reaction(channel_id, &target.event_id)
+                .await?;
+        }
+        for edit in self
+            .store
+            .edits_for_event(channel_id, &target.event_id)
+            .await?
+        {
+            let edit: CanonicalEvent = serde_json::from_value(edit.body)
+                .map_err(|err| MessagingError::Storage(err.into()))?;
+            self.store
+                .redact_event(
+                    channel_id,
+                    &edit.event_id,
+                    Some(&event.event_id),
+                    &edit.redacted(),
+                )
+                .await?;
+        }
+        Ok(())
     }
 
-    async fn broadcast(
+    /// Link an edit event to the message it edits. Edits that arrive after their message
+    /// was redacted are redacted as well.
+    async fn apply_edit(
         &self,
         channel_id: Uuid,
-        event: Arc<OutboundEvent>,
-    ) -> Result<usize, broadcast::error::SendError<Arc<OutboundEvent>>> {
-        let sender = {
-            let read = self.broadcasters.read().await;
-            read.get(&channel_id).cloned()
+        event: &CanonicalEvent,
+    ) -> Result<(), MessagingError> {
+        let Some(content) = EditContent::from_event(event) else {
+            return Ok(());
         };
-
-        let sender = match sender {
-            Some(sender) => sender,
-            None => {
-                let mut write = self.broadcasters.write().await;
-                write
-                    .entry(channel_id)
-                    .or_insert_with(|| broadcast::channel(BROADCAST_CAPACITY).0)
-                    .clone()
-            }
+        let Some(target) = self.store.event_by_id(channel_id, &content.edits).await? else {
+            return Ok(());
         };
+        self.store
+            .record_edit(channel_id, &target.event_id, &event.event_id)
+            .await?;
+        if target.redacted {
+            self.store
+                .redact_event(
+                    channel_id,
+                    &event.event_id,
+                    target.redacted_by.as_deref(),
+                    &event.redacted(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
 
-        let result = sender.send(event);
+    /// Count a reaction towards its target's aggregates.
+    async fn apply_reaction(
+        &self,
+        channel_id: Uuid,
+        event: &CanonicalEvent,
+    ) -> Result<(), MessagingError> {
+        let Some(content) = ReactionContent::from_event(event) else {
+            return Ok(());
+        };
+        if content.key.is_empty()
+            || self
+                .store
+                .event_by_id(channel_id, &content.reacts_to)
+                .await?
+                .is_none()
+        {
+            return Ok(());
+        }
+        self.store
+            .record_reaction(
+                channel_id,
+                &content.reacts_to,
+                &event.event_id,
+                &event.sender,
+                &content.key,
+            )
+            .await?;
+        Ok(())
+    }
 
-        #[cfg(feature = "metrics")]
-        if let Some(metrics) = self.metrics() {
-            metrics.set_websocket_queue_depth(&channel_id.to_string(), sender.len());
+    /// Link a thread reply to its root. The reply's sender has read the thread up to their
+    /// reply, and the root's sender starts tracking the thread.
+    async fn apply_thread(
+        &self,
+        channel_id: Uuid,
+        event: &CanonicalEvent,
+        stored: &ChannelEvent,
+    ) -> Result<(), MessagingError> {
+        let Some(root_id) = MessagePayload::from_event(event).and_then(|reply| reply.thread_root)
+        else {
+            return Ok(());
+        };
+        let Some(root) = self.store.event_by_id(channel_id, &root_id).await? else {
+            return Ok(());
+        };
+        let root_event: CanonicalEvent =
+            serde_json::from_value(root.body).map_err(|err| MessagingError::Storage(err.into()))?;
+        // Only messages outside a thread can be roots.
+        let is_root =
+            MessagePayload::from_event(&root_event).is_some_and(|root| root.thread_root.is_none());
+        if !is_root {
+            return Ok(());
         }
+        self.store
+            .record_thread_reply(channel_id, &root_id, &event.event_id)
+            .await?;
 
-        result
+        if let Ok(root_sender) = Uuid::parse_str(&root_event.sender) {
+            self.store
+                .ensure_thread_read_state(channel_id, &root_id, root_sender, root.sequence)
+                .await?;
+        }
+        if let Ok(sender) = Uuid::parse_str(&event.sender) {
+            self.store
+                .update_thread_read_sequence(channel_id, &root_id, sender, stored.sequence)
+                .await?;
+        }
+        Ok(())
     }
 
-    async fn subscribe(&self, channel_id: Uuid) -> broadcast::Receiver<Arc<OutboundEvent>> {
-        let mut write = self.broadcasters.write().await;
-        write
-            .entry(channel_id)
-            .or_insert_with(|| broadcast::channel(BROADCAST_CAPACITY).0)
-            .subscribe()
+    /// Persist the state after `event`: `state_before` with `event` applied on top when it
+    /// is a state event.
+    async fn record_state(
+        &self,
+        channel_id: Uuid,
+        event: &CanonicalEvent,
+        mut state: StateMap,
+    ) -> Result<(), MessagingError> {
+        state.apply(event);
+        self.store
+            .record_event_state(channel_id, &event.event_id, &state)
+            .await
     }
 
-    async fn notification_sender(
+    async fn resolve_forks(
         &self,
-        user_id: Uuid,
-    ) -> broadcast::Sender<Arc<NotificationEvent>> {
-        let mut write = self.notification_channels.write().await;
-        write
-            .entry(user_id)
-            .or_insert_with(|| broadcast::channel(BROADCAST_CAPACITY).0)
-            .clone()
+        channel_id: Uuid,
+        mut forks: Vec<StateMap>,
+    ) -> Result<StateMap, MessagingError> {
+        if forks.len() <= 1 {
+            return Ok(forks.pop().unwrap_or_default());
+        }
+        let mut events = HashMap::new();
+        for event_id in state::resolution_inputs(&forks) {
+            if let Some(event) = self.load_canonical(channel_id, &event_id).await? {
+                events.insert(event_id, event);
+            }
+        }
+        // Power-levels candidates are ranked and re-checked against the state they cite.
+        let cited: BTreeSet<String> = events
+            .values()
+            .filter(|event| event.event_type == state::CHANNEL_POWER_LEVELS_EVENT)
+            .flat_map(|event| event.auth_events.iter().cloned())
+            .filter(|event_id| !events.contains_key(event_id))
+            .collect();
+        for event_id in cited {
+            if let Some(event) = self.load_canonical(channel_id, &event_id).await? {
+                events.insert(event_id, event);
+            }
+        }
+        Ok(state::resolve_state(&forks, &events))
     }
 
-    async fn cleanup_notification_channel(&self, user_id: Uuid) {
-        let mut write = self.notification_channels.write().await;
-        if let Some(sender) = write.get(&user_id) {
-            if sender.receiver_count() == 0 {
-                write.remove(&user_id);
+    /// Returns the subset of `event_ids` that has not been persisted for the channel.
+    pub async fn unknown_events(
+        &self,
+        channel_id: Uuid,
+        event_ids: &[String],
+    ) -> Result<Vec<String>, MessagingError> {
+        let mut unknown = Vec::new();
+        for event_id in event_ids {
+            if self
+                .store
+                .event_by_id(channel_id, event_id)
+                .await?
+                .is_none()
+            {
+                unknown.push(event_id.clone());
             }
         }
+        Ok(unknown)
     }
 
-    async fn notify_channel_members(&self, channel_id: Uuid, notification: Arc<NotificationEvent>) {
-        let user_ids = match self.store.user_ids_for_channel(channel_id).await {
-            Ok(ids) => ids,
-            Err(err) => {
-                tracing::debug!(
-                    ?err,
-                    channel_id = %channel_id,
-                    "failed to load channel members for notification delivery"
-                );
-                return;
+    /// Walks `prev_events` backwards from `latest_events`, stopping at `earliest_events`,
+    /// and returns up to `limit` ancestors the requester is missing (oldest first).
+    pub async fn missing_events(
+        &self,
+        channel_id: Uuid,
+        earliest_events: &[String],
+        latest_events: &[String],
+        limit: usize,
+    ) -> Result<Vec<CanonicalEvent>, MessagingError> {
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
+        }
+
+        let mut seen: HashSet<String> = earliest_events.iter().cloned().collect();
+        seen.extend(latest_events.iter().cloned());
+        let mut queue = VecDeque::new();
+        for event_id in latest_events {
+            if let Some(event) = self.load_canonical(channel_id, event_id).await? {
+                queue.extend(event.prev_events);
             }
-        };
+        }
 
-        let map = self.notification_channels.read().await;
-        for user_id in user_ids {
-            if let Some(sender) = map.get(&user_id) {
-                if sender.receiver_count() == 0 {
-                    continue;
-                }
-                let _ = sender.send(notification.clone());
+        let mut missing = Vec::new();
+        while let Some(event_id) = queue.pop_front() {
+            if missing.len() >= limit {
+                break;
+            }
+            if !seen.insert(event_id.clone()) {
+                continue;
             }
+            let Some(event) = self.load_canonical(channel_id, &event_id).await? else {
+                continue;
+            };
+            queue.extend(event.prev_events.iter().cloned());
+            missing.push(event);
         }
+
+        missing.reverse();
+        Ok(missing)
     }
 
-    async fn notify_guild_members(&self, guild_id: Uuid, notification: Arc<NotificationEvent>) {
-        let user_ids = match self.store.user_ids_for_guild(guild_id).await {
-            Ok(ids) => ids,
-            Err(err) => {
-                tracing::debug!(
-                    ?err,
-                    guild_id = %guild_id,
-                    "failed to load guild members for notification delivery"
-                );
-                Vec::new()
-            }
+    async fn load_canonical(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<Option<CanonicalEvent>, MessagingError> {
+        let Some(stored) = self.store.event_by_id(channel_id, event_id).await? else {
+            return Ok(None);
         };
+        serde_json::from_value(stored.body)
+            .map(Some)
+            .map_err(|err| MessagingError::Storage(err.into()))
+    }
 
-        let map = self.notification_channels.read().await;
+    pub async fn recent_events(
+        &self,
+        channel_id: Uuid,
+        since_sequence: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>, MessagingError> {
+        self.store
+            .recent_events(channel_id, since_sequence, limit)
+            .await
+    }
 
-        if user_ids.is_empty() {
-            for sender in map.values() {
-                if sender.receiver_count() == 0 {
-                    continue;
-                }
-                let _ = sender.send(notification.clone());
-            }
-            return;
+    /// Like `recent_events`, with edit, thread and reaction summaries attached. `viewer`
+    /// decides the `me` flag of reactions.
+    pub async fn timeline(
+        &self,
+        channel_id: Uuid,
+        since_sequence: Option<i64>,
+        limit: i64,
+        viewer: Option<Uuid>,
+    ) -> Result<Vec<TimelineEvent>, MessagingError> {
+        let events = self
+            .store
+            .recent_events(channel_id, since_sequence, limit)
+            .await?;
+        self.annotate(channel_id, events, viewer).await
+    }
+
+    /// Replies in the thread under `root_event_id`, paged like `timeline`.
+    pub async fn thread_timeline(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        since_sequence: Option<i64>,
+        limit: i64,
+        viewer: Option<Uuid>,
+    ) -> Result<Vec<TimelineEvent>, MessagingError> {
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
+        }
+        if self
+            .store
+            .event_by_id(channel_id, root_event_id)
+            .await?
+            .is_none()
+        {
+            return Err(MessagingError::EventNotFound(root_event_id.to_string()));
         }
+        let events = self
+            .store
+            .thread_events(channel_id, root_event_id, since_sequence, limit)
+            .await?;
+        self.annotate(channel_id, events, viewer).await
+    }
 
-        for user_id in user_ids {
-            if let Some(sender) = map.get(&user_id) {
-                if sender.receiver_count() == 0 {
-                    continue;
+    async fn annotate(
+        &self,
+        channel_id: Uuid,
+        events: Vec<ChannelEvent>,
+        viewer: Option<Uuid>,
+    ) -> Result<Vec<TimelineEvent>, MessagingError> {
+        let mut edits = self.edit_summaries(channel_id, &events).await?;
+        let mut threads = self.thread_summaries(channel_id, &events).await?;
+        let mut reactions = self.reaction_summaries(channel_id, &events, viewer).await?;
+        Ok(events
+            .into_iter()
+            .map(|event| {
+                let edited = edits.remove(&event.event_id);
+                let thread = threads.remove(&event.event_id);
+                let reactions = reactions.remove(&event.event_id).unwrap_or_default();
+                TimelineEvent {
+                    edited,
+                    thread,
+                    reactions,
+                    ..TimelineEvent::from(event)
                 }
-                let _ = sender.send(notification.clone());
-            }
+            })
+            .collect())
+    }
+
+    async fn reaction_summaries(
+        &self,
+        channel_id: Uuid,
+        events: &[ChannelEvent],
+        viewer: Option<Uuid>,
+    ) -> Result<HashMap<String, Vec<ReactionSummary>>, MessagingError> {
+        let targets: Vec<String> = events
+            .iter()
+            .filter(|event| !event.redacted)
+            .map(|event| event.event_id.clone())
+            .collect();
+        if targets.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let viewer = viewer.map(|user_id| user_id.to_string());
+        let mut summaries: HashMap<String, Vec<ReactionSummary>> = HashMap::new();
+        for count in self
+            .store
+            .reaction_counts(channel_id, &targets, viewer.as_deref())
+            .await?
+        {
+            summaries
+                .entry(count.target_event_id)
+                .or_default()
+                .push(ReactionSummary {
+                    key: count.reaction_key,
+                    count: count.count,
+                    me: count.me,
+                });
         }
+        Ok(summaries)
     }
 
-    pub async fn open_websocket(
-        self: Arc<Self>,
+    async fn thread_summaries(
+        &self,
         channel_id: Uuid,
-        request_id: Option<String>,
-        ws: WebSocketUpgrade,
-    ) -> Response {
-        let request_id_label = request_id.unwrap_or_else(|| "unknown".to_string());
+        events: &[ChannelEvent],
+    ) -> Result<HashMap<String, ThreadSummary>, MessagingError> {
+        let messages: Vec<String> = events
+            .iter()
+            .filter(|event| event.event_type == MESSAGE_EVENT)
+            .map(|event| event.event_id.clone())
+            .collect();
+        if messages.is_empty() {
+            return Ok(HashMap::new());
+        }
+        Ok(self
+            .store
+            .thread_stats(channel_id, &messages)
+            .await?
+            .iter()
+            .map(|stats| {
+                (
+                    stats.root_event_id.clone(),
+                    ThreadSummary::from_stats(stats),
+                )
+            })
+            .collect())
+    }
 
-        match self.semaphore.clone().try_acquire_owned() {
-            Ok(permit) => {
-                let svc = self.clone();
-                let request_id_for_span = request_id_label.clone();
-                ws.on_upgrade(move |socket| {
-                    let span = tracing::info_span!(
-                        "websocket.session",
-                        channel_id = %channel_id,
-                        request_id = %request_id_for_span
-                    );
-                    async move {
-                        svc.run_socket(channel_id, socket, permit).await;
-                    }
-                    .instrument(span)
-                })
-            }
-            Err(_) => {
-                #[cfg(feature = "metrics")]
-                if let Some(metrics) = self.metrics() {
-                    metrics.increment_messaging_rejection("websocket_limit");
-                }
-                tracing::warn!(
-                    channel_id = %channel_id,
-                    request_id = %request_id_label,
-                    "websocket connection limit reached"
-                );
-                Response::builder()
-                    .status(StatusCode::TOO_MANY_REQUESTS)
-                    .body(axum::body::Body::from("connection limit reached"))
-                    .unwrap()
-            }
+    async fn edit_summaries(
+        &self,
+        channel_id: Uuid,
+        events: &[ChannelEvent],
+    ) -> Result<HashMap<String, EditSummary>, MessagingError> {
+        let messages: Vec<String> = events
+            .iter()
+            .filter(|event| event.event_type == MESSAGE_EVENT && !event.redacted)
+            .map(|event| event.event_id.clone())
+            .collect();
+        if messages.is_empty() {
+            return Ok(HashMap::new());
         }
+        Ok(self
+            .store
+            .latest_edits(channel_id, &messages)
+            .await?
+            .iter()
+            .filter_map(|latest| {
+                EditSummary::from_latest(latest)
+                    .map(|summary| (latest.original_event_id.clone(), summary))
+            })
+            .collect())
     }
 
-    pub async fn open_notification_socket(
-        self: Arc<Self>,
-        user_id: Uuid,
-        request_id: Option<String>,
-        ws: WebSocketUpgrade,
-    ) -> Response {
-        let request_id_label = request_id.unwrap_or_else(|| "unknown".to_string());
+    pub async fn channel_exists(&self, channel_id: Uuid) -> Result<bool, MessagingError> {
+        self.store.channel_exists(channel_id).await
+    }
 
-        match self.semaphore.clone().try_acquire_owned() {
-            Ok(permit) => {
-                let svc = self.clone();
-                let request_id_for_span = request_id_label.clone();
-                ws.on_upgrade(move |socket| {
-                    let span = tracing::info_span!(
-                        "websocket.notifications",
-                        user_id = %user_id,
-                        request_id = %request_id_for_span
-                    );
-                    async move {
-                        svc.run_notification_socket(user_id, socket, permit).await;
-                    }
-                    .instrument(span)
-                })
-            }
-            Err(_) => {
-                #[cfg(feature = "metrics")]
-                if let Some(metrics) = self.metrics() {
-                    metrics.increment_messaging_rejection("websocket_limit");
-                }
-                tracing::warn!(
-                    user_id = %user_id,
-                    request_id = %request_id_label,
-                    "notification websocket connection limit reached"
-                );
-                Response::builder()
-                    .status(StatusCode::TOO_MANY_REQUESTS)
-                    .body(axum::body::Body::from("connection limit reached"))
-                    .unwrap()
-            }
-        }
+    pub async fn channel_by_id(&self, channel_id: Uuid) -> Result<Option<Channel>, MessagingError> {
+        self.store.channel_by_id(channel_id).await
     }
 
-    async fn run_socket(
-        self: Arc<Self>,
+    /// Deliver a notification about `channel_id` to every member of its guild.
+    pub async fn notify_channel_guild(
+        &self,
         channel_id: Uuid,
-        mut socket: WebSocket,
-        _permit: tokio::sync::OwnedSemaphorePermit,
-    ) {
-        if let Ok(events) = self.store.recent_events(channel_id, None, 50).await {
-            for event in events {
-                let payload = Arc::new(OutboundEvent {
-                    sequence: event.sequence,
-                    channel_id,
-                    event: event.body,
-                });
-                if let Err(err) = timeout(
-                    SEND_TIMEOUT,
-                    socket.send(WsMessage::Text(
-                        serde_json::to_string(&*payload).unwrap_or_default().into(),
-                    )),
-                )
-                .await
-                {
-                    tracing::warn!(?err, "failed to send historical event");
-                    return;
+        kind: &str,
+        event: serde_json::Value,
+    ) -> Result<(), MessagingError> {
+        let channel = self
+            .store
+            .channel_by_id(channel_id)
+            .await?
+            .ok_or(MessagingError::ChannelNotFound)?;
+        let notification = Arc::new(NotificationEvent {
+            kind: kind.to_string(),
+            channel_id: Some(channel_id),
+            guild_id: Some(channel.guild_id),
+            sequence: None,
+            event,
+        });
+        self.notify_guild_members(channel.guild_id, notification)
+            .await;
+        Ok(())
+    }
+
+    /// Deliver `notification` to the given users only.
+    pub async fn notify_users(&self, user_ids: &[Uuid], notification: Arc<NotificationEvent>) {
+        let map = self.notification_channels.read().await;
+        for user_id in user_ids {
+            if let Some(sender) = map.get(user_id) {
+                if sender.receiver_count() > 0 {
+                    let _ = sender.send(notification.clone());
                 }
             }
         }
+    }
 
-        let mut rx = self.subscribe(channel_id).await;
-
-        loop {
-            tokio::select! {
-                result = rx.recv() => {
-                    match result {
-                        Ok(event) => {
-                            if timeout(
-                                SEND_TIMEOUT,
-                                socket.send(WsMessage::Text(serde_json::to_string(&*event).unwrap_or_default().into()))
-                            ).await.is_err() {
-                                tracing::warn!("websocket send timeout");
-                                break;
-                            }
-                        }
-                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
-                            let message = format!("lagged by {skipped} messages");
-                            let _ = socket.send(WsMessage::Close(Some(axum::extract::ws::CloseFrame {
-                                code: axum::extract::ws::close_code::POLICY,
-                                reason: message.into(),
-                            }))).await;
-                            break;
-                        }
-                        Err(_) => break,
-                    }
-                }
-                message = socket.recv() => {
-                    match message {
-                        Some(Ok(WsMessage::Close(_))) | None => break,
-                        Some(Ok(WsMessage::Ping(payload))) => {
-                            if socket.send(WsMessage::Pong(payload)).await.is_err() {
-                                break;
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            }
+    /// Deliver `notification` to `user_id` and everyone who shares a channel with them.
+    pub async fn notify_channel_peers(
+        &self,
+        user_id: Uuid,
+        notification: Arc<NotificationEvent>,
+    ) -> Result<(), MessagingError> {
+        let mut users = HashSet::from([user_id]);
+        for membership in self.store.channel_memberships_for_user(user_id).await? {
+            users.extend(
+                self.store
+                    .user_ids_for_channel(membership.channel_id)
+                    .await?,
+            );
         }
+        let users: Vec<Uuid> = users.into_iter().collect();
+        self.notify_users(&users, notification).await;
+        Ok(())
     }
 
-    async fn run_notification_socket(
-        self: Arc<Self>,
+    /// `user_id`'s membership of `channel_id`, if they have one.
+    pub async fn channel_membership(
+        &self,
+        channel_id: Uuid,
         user_id: Uuid,
-        mut socket: WebSocket,
-        _permit: tokio::sync::OwnedSemaphorePermit,
-    ) {
-        let sender = self.notification_sender(user_id).await;
-        let mut rx = sender.subscribe();
+    ) -> Result<Option<ChannelMembership>, MessagingError> {
+        Ok(self
+            .store
+            .channel_members(channel_id)
+            .await?
+            .into_iter()
+            .find(|member| member.user_id == user_id))
+    }
 
-        loop {
-            tokio::select! {
-                result = rx.recv() => {
-                    match result {
-                        Ok(event) => {
-                            if timeout(
-                                SEND_TIMEOUT,
-                                socket.send(WsMessage::Text(serde_json::to_string(&*event).unwrap_or_default().into()))
-                            ).await.is_err() {
-                                tracing::warn!("notification websocket send timeout");
-                                break;
-                            }
-                        }
-                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
-                            let message = format!("lagged by {skipped} notification messages");
-                            let _ = socket.send(WsMessage::Close(Some(axum::extract::ws::CloseFrame {
-                                code: axum::extract::ws::close_code::POLICY,
-                                reason: message.into(),
-                            }))).await;
-                            break;
-                        }
-                        Err(_) => break,
-                    }
-                }
-                message = socket.recv() => {
-                    match message {
-                        Some(Ok(WsMessage::Close(_))) | None => break,
-                        Some(Ok(WsMessage::Ping(payload))) => {
-                            if socket.send(WsMessage::Pong(payload)).await.is_err() {
-                                break;
-                            }
-                        }
-                        _ => {}
-                    }
-                }
+    /// Whether `user_id` and `other` are both members of at least one channel.
+    pub async fn shares_channel(&self, user_id: Uuid, other: Uuid) -> Result<bool, MessagingError> {
+        for membership in self.store.channel_memberships_for_user(user_id).await? {
+            if self
+                .store
+                .user_ids_for_channel(membership.channel_id)
+                .await?
+                .contains(&other)
+            {
+                return Ok(true);
             }
         }
+        Ok(false)
+    }
 
-        self.cleanup_notification_channel(user_id).await;
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub async fn upsert_guild_membership(
+        &self,
+        guild_id: Uuid,
+        user_id: Uuid,
+        role: &str,
+    ) -> Result<GuildMembership, MessagingError> {
+        self.store
+            .upsert_guild_membership(guild_id, user_id, role)
+            .await
     }
-}
 
-#[derive(Debug, Deserialize)]
-pub struct CreateGuildRequest {
-    pub name: String,
-}
+    pub async fn guild_memberships_for_user(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<GuildMembershipSummary>, MessagingError> {
+        self.store.guild_memberships_for_user(user_id).await
+    }
 
-#[derive(Debug, Serialize)]
-pub struct CreateGuildResponse {
-    pub guild_id: Uuid,
-    pub name: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-}
+    pub async fn upsert_channel_membership(
+        &self,
+        channel_id: Uuid,
+        user_id: Uuid,
+        role: &str,
+    ) -> Result<ChannelMembership, MessagingError> {
+        self.store
+            .upsert_channel_membership(channel_id, user_id, role)
+            .await
+    }
 
-#[derive(Debug, Deserialize)]
-pub struct CreateChannelRequest {
-    pub name: String,
-}
+    pub async fn ensure_channel_access(
+        &self,
+        channel_id: Uuid,
+        user_id: Uuid,
+        role: &str,
+    ) -> Result<(), MessagingError> {
+        let _ = self
+            .upsert_channel_membership(channel_id, user_id, role)
+            .await?;
+        let latest = match self.latest_sequence_for_channel(channel_id).await {
+            Ok(sequence) => sequence,
+            Err(err) => {
+                tracing::debug!(
+                    ?err,
+                    channel_id = %channel_id,
+                    "failed to resolve latest sequence while bootstrapping membership"
+                );
+                0
+            }
+        };
+        self.store
+            .ensure_read_state(channel_id, user_id, latest)
+            .await
+    }
 
-#[derive(Debug, Serialize)]
-pub struct CreateChannelResponse {
-    pub channel_id: Uuid,
-    pub guild_id: Uuid,
-    pub name: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-}
+    pub async fn channel_memberships_for_user(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<ChannelMembershipSummary>, MessagingError> {
+        self.store.channel_memberships_for_user(user_id).await
+    }
 
-#[derive(Debug, Deserialize)]
-pub struct PostMessageRequest {
-    pub sender: String,
-    pub content: String,
-}
+    pub async fn set_retention_policy(
+        &self,
+        guild_id: Uuid,
+        max_age_secs: Option<i64>,
+        max_events: Option<i64>,
+    ) -> Result<RetentionPolicy, MessagingError> {
+        self.store
+            .set_retention_policy(guild_id, max_age_secs, max_events)
+            .await
+    }
 
-#[derive(Debug, Serialize)]
-pub struct PostMessageResponse {
-    pub sequence: i64,
-    pub event_id: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct ChannelReadUpdate {
-    pub sequence: Option<i64>,
-}
-
-#[derive(Debug, Serialize)]
-pub struct ChannelUnreadEntry {
-    pub channel_id: Uuid,
-    pub last_read_sequence: i64,
-    pub latest_sequence: i64,
-    pub unread: i64,
-}
-
-async fn resolve_message_author(
-    state: &AppState,
-    user_id: Uuid,
-    username_hint: Option<String>,
-) -> MessageAuthorSnapshot {
-    let fallback_id = user_id.to_string();
-    let fallback_username = username_hint
-        .as_ref()
-        .map(|value| value.trim().to_string())
-        .filter(|value| !value.is_empty())
-        .unwrap_or_else(|| fallback_id.clone());
-    let fallback = MessageAuthorSnapshot {
-        id: fallback_id.clone(),
-        username: fallback_username,
-        display_name: None,
-    };
-
-    let Some(pool) = state.storage_pool() else {
-        return fallback;
-    };
+    pub async fn clear_retention_policy(&self, guild_id: Uuid) -> Result<bool, MessagingError> {
+        self.store.clear_retention_policy(guild_id).await
+    }
 
-    match UserRepository::find_user_by_id(pool.pool(), user_id).await {
-        Ok(Some(record)) => MessageAuthorSnapshot {
-            id: record.user_id.to_string(),
-            username: record.username,
-            display_name: None,
-        },
-        Ok(None) => fallback,
-        Err(err) => {
-            tracing::warn!(
-                ?err,
-                user_id = %user_id,
-                "failed to resolve message author metadata"
-            );
-            fallback
-        }
+    pub async fn retention_policy(
+        &self,
+        guild_id: Uuid,
+    ) -> Result<Option<RetentionPolicy>, MessagingError> {
+        self.store.retention_policy(guild_id).await
     }
-}
 
-pub async fn create_guild(
-    matched_path: MatchedPath,
-    State(state): State<AppState>,
-    headers: HeaderMap,
-    Json(body): Json<CreateGuildRequest>,
-) -> Result<Json<CreateGuildResponse>, StatusCode> {
-    let Some(messaging) = state.messaging() else {
-        let status = StatusCode::SERVICE_UNAVAILABLE;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
-    };
+    pub async fn retention_policies(&self) -> Result<Vec<RetentionPolicy>, MessagingError> {
+        self.store.retention_policies().await
+    }
 
-    if let Err(status) = session::authenticate_bearer(&state, &headers) {
-        if status == StatusCode::UNAUTHORIZED {
-            state.record_messaging_rejection("unauthorized");
-        }
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
+    pub async fn prune_channel_events(
+        &self,
+        channel_id: Uuid,
+        older_than: Option<chrono::DateTime<chrono::Utc>>,
+        keep_latest: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>, MessagingError> {
+        self.store
+            .prune_channel_events(channel_id, older_than, keep_latest, limit)
+            .await
     }
 
-    let name = body.name.trim();
-    #[cfg(not(feature = "metrics"))]
-    let _ = matched_path;
+    pub async fn media_referenced(&self, media_id: &str) -> Result<bool, MessagingError> {
+        self.store.media_referenced(media_id).await
+    }
 
-    let name_length = name.chars().count();
+    pub async fn guild_event_usage(&self, guild_id: Uuid) -> Result<EventUsage, MessagingError> {
+        self.store.guild_event_usage(guild_id).await
+    }
 
-    if name.is_empty() {
-        state.record_messaging_rejection("guild_name_empty");
-        let status = StatusCode::BAD_REQUEST;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
+    pub async fn user_event_usage(&self, user_id: Uuid) -> Result<EventUsage, MessagingError> {
+        self.store.user_event_usage(&user_id.to_string()).await
     }
 
-    if name_length > MAX_GUILD_NAME_LENGTH {
-        state.record_messaging_rejection("guild_name_length");
-        tracing::warn!(
-            length = name_length,
-            max = MAX_GUILD_NAME_LENGTH,
-            "guild name exceeds maximum length"
-        );
-        let status = StatusCode::BAD_REQUEST;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
+    pub async fn guild_attachment_ids(
+        &self,
+        guild_id: Uuid,
+    ) -> Result<Vec<String>, MessagingError> {
+        self.store.guild_attachment_ids(guild_id).await
     }
 
-    match messaging.create_guild(name).await {
-        Ok(guild) => {
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
-            Ok(Json(CreateGuildResponse {
-                guild_id: guild.guild_id,
-                name: guild.name,
-                created_at: guild.created_at,
-            }))
-        }
-        Err(err) => {
-            tracing::error!(?err, "failed to create guild");
-            let status = StatusCode::INTERNAL_SERVER_ERROR;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            Err(status)
-        }
+    pub async fn update_last_read_sequence(
+        &self,
+        channel_id: Uuid,
+        user_id: Uuid,
+        sequence: i64,
+    ) -> Result<(), MessagingError> {
+        self.store
+            .update_last_read_sequence(channel_id, user_id, sequence)
+            .await
     }
-}
 
-pub async fn list_guilds(
-    matched_path: MatchedPath,
-    State(state): State<AppState>,
-    headers: HeaderMap,
-) -> Result<Json<Vec<CreateGuildResponse>>, StatusCode> {
-    let Some(messaging) = state.messaging() else {
-        let status = StatusCode::SERVICE_UNAVAILABLE;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
-    };
+    pub async fn user_channel_unread(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<ChannelUnreadState>, MessagingError> {
+        self.store.user_channel_unread(user_id).await
+    }
 
-    if let Err(status) = session::authenticate_bearer(&state, &headers) {
-        if status == StatusCode::UNAUTHORIZED {
-            state.record_messaging_rejection("unauthorized");
+    /// Move the user's read position in the thread under `root_event_id` forward, to
+    /// `sequence` or the newest reply.
+    pub async fn mark_thread_read(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        user_id: Uuid,
+        sequence: Option<i64>,
+    ) -> Result<ThreadUnreadState, MessagingError> {
+        if !self.store.channel_exists(channel_id).await? {
+            return Err(MessagingError::ChannelNotFound);
         }
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
+        let Some(root) = self.store.event_by_id(channel_id, root_event_id).await? else {
+            return Err(MessagingError::EventNotFound(root_event_id.to_string()));
+        };
+        let latest_sequence = self
+            .store
+            .thread_stats(channel_id, &[root_event_id.to_string()])
+            .await?
+            .first()
+            .map(|stats| stats.last_reply_sequence)
+            .unwrap_or(root.sequence);
+        let sequence = sequence
+            .unwrap_or(latest_sequence)
+            .clamp(0, latest_sequence);
+        self.store
+            .update_thread_read_sequence(channel_id, root_event_id, user_id, sequence)
+            .await?;
+        let unread = self
+            .store
+            .thread_events(channel_id, root_event_id, Some(sequence), i64::MAX)
+            .await?
+            .len() as i64;
+        Ok(ThreadUnreadState {
+            channel_id,
+            root_event_id: root_event_id.to_string(),
+            last_read_sequence: sequence,
+            latest_sequence,
+            unread,
+        })
     }
 
-    #[cfg(not(feature = "metrics"))]
-    let _ = matched_path;
-
-    match messaging.list_guilds().await {
-        Ok(guilds) => {
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
-            Ok(Json(
-                guilds
-                    .into_iter()
-                    .map(|g| CreateGuildResponse {
-                        guild_id: g.guild_id,
-                        name: g.name,
-                        created_at: g.created_at,
-                    })
-                    .collect(),
-            ))
-        }
-        Err(err) => {
-            tracing::error!(?err, "failed to list guilds");
-            let status = StatusCode::INTERNAL_SERVER_ERROR;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            Err(status)
-        }
+    pub async fn user_thread_unread(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<ThreadUnreadState>, MessagingError> {
+        self.store.user_thread_unread(user_id).await
     }
-}
 
-pub async fn create_channel(
-    matched_path: MatchedPath,
-    State(state): State<AppState>,
-    headers: HeaderMap,
-    Path(guild_id): Path<Uuid>,
-    Json(body): Json<CreateChannelRequest>,
-) -> Result<Json<CreateChannelResponse>, StatusCode> {
-    let Some(messaging) = state.messaging() else {
-        let status = StatusCode::SERVICE_UNAVAILABLE;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
-    };
+    async fn broadcast(
+        &self,
+        channel_id: Uuid,
+        event: Arc<OutboundEvent>,
+    ) -> Result<usize, broadcast::error::SendError<Arc<OutboundEvent>>> {
+        let sender = {
+            let read = self.broadcasters.read().await;
+            read.get(&channel_id).cloned()
+        };
 
-    let claims = match session::authenticate_bearer(&state, &headers) {
-        Ok(claims) => claims,
-        Err(status) => {
-            if status == StatusCode::UNAUTHORIZED {
-                state.record_messaging_rejection("unauthorized");
+        let sender = match sender {
+            Some(sender) => sender,
+            None => {
+                let mut write = self.broadcasters.write().await;
+                write
+                    .entry(channel_id)
+                    .or_insert_with(|| broadcast::channel(BROADCAST_CAPACITY).0)
+                    .clone()
             }
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
-        }
-    };
+        };
 
-    #[cfg(not(feature = "metrics"))]
-    let _ = matched_path;
+        let result = sender.send(event);
 
-    let name = body.name.trim();
-    let name_length = name.chars().count();
-    if name.is_empty() {
-        state.record_messaging_rejection("channel_name_empty");
-        let status = StatusCode::BAD_REQUEST;
         #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
+        if let Some(metrics) = self.metrics() {
+            metrics.set_websocket_queue_depth(&channel_id.to_string(), sender.len());
+        }
+
+        result
     }
-    if name_length > MAX_CHANNEL_NAME_LENGTH {
-        state.record_messaging_rejection("channel_name_length");
-        tracing::warn!(
-            length = name_length,
-            max = MAX_CHANNEL_NAME_LENGTH,
-            guild_id = %guild_id,
-            "channel name exceeds maximum length"
-        );
-        state.record_messaging_rejection("channel_name_length");
-        let status = StatusCode::BAD_REQUEST;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
+
+    async fn subscribe(&self, channel_id: Uuid) -> broadcast::Receiver<Arc<OutboundEvent>> {
+        let mut write = self.broadcasters.write().await;
+        write
+            .entry(channel_id)
+            .or_insert_with(|| broadcast::channel(BROADCAST_CAPACITY).0)
+            .subscribe()
     }
 
-    match messaging.create_channel(guild_id, name).await {
-        Ok(channel) => {
-            if let Err(err) = messaging
-                .ensure_channel_access(channel.channel_id, claims.user_id, "moderator")
-                .await
-            {
+    pub(crate) async fn notification_sender(
+        &self,
+        user_id: Uuid,
+    ) -> broadcast::Sender<Arc<NotificationEvent>> {
+        let mut write = self.notification_channels.write().await;
+        write
+            .entry(user_id)
+            .or_insert_with(|| broadcast::channel(BROADCAST_CAPACITY).0)
+            .clone()
+    }
+
+    async fn cleanup_notification_channel(&self, user_id: Uuid) {
+        let mut write = self.notification_channels.write().await;
+        if let Some(sender) = write.get(&user_id) {
+            if sender.receiver_count() == 0 {
+                write.remove(&user_id);
+            }
+        }
+    }
+
+    /// Deliver `notification` to every channel member; `mentioned` members get it as a
+    /// `channel_mention` instead.
+    async fn notify_channel_members(
+        &self,
+        channel_id: Uuid,
+        notification: Arc<NotificationEvent>,
+        mentioned: &HashSet<Uuid>,
+    ) {
+        let user_ids = match self.store.user_ids_for_channel(channel_id).await {
+            Ok(ids) => ids,
+            Err(err) => {
                 tracing::debug!(
                     ?err,
-                    channel_id = %channel.channel_id,
-                    user_id = %claims.user_id,
-                    "failed to bootstrap channel membership for creator"
+                    channel_id = %channel_id,
+                    "failed to load channel members for notification delivery"
                 );
+                return;
+            }
+        };
+
+        let mention = (!mentioned.is_empty()).then(|| {
+            Arc::new(NotificationEvent {
+                kind: "channel_mention".to_string(),
+                ..(*notification).clone()
+            })
+        });
+        let map = self.notification_channels.read().await;
+        for user_id in user_ids {
+            if let Some(sender) = map.get(&user_id) {
+                if sender.receiver_count() == 0 {
+                    continue;
+                }
+                let notification = match &mention {
+                    Some(mention) if mentioned.contains(&user_id) => mention.clone(),
+                    _ => notification.clone(),
+                };
+                let _ = sender.send(notification);
             }
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
-            Ok(Json(CreateChannelResponse {
-                channel_id: channel.channel_id,
-                guild_id: channel.guild_id,
-                name: channel.name,
-                created_at: channel.created_at,
-            }))
-        }
-        Err(MessagingError::GuildNotFound) => {
-            let status = StatusCode::NOT_FOUND;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            Err(status)
-        }
-        Err(err) => {
-            tracing::error!(?err, "failed to create channel");
-            let status = StatusCode::INTERNAL_SERVER_ERROR;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            Err(status)
         }
     }
-}
-
-pub async fn list_channels(
-    matched_path: MatchedPath,
-    State(state): State<AppState>,
-    headers: HeaderMap,
-    Path(guild_id): Path<Uuid>,
-) -> Result<Json<Vec<CreateChannelResponse>>, StatusCode> {
-    let Some(messaging) = state.messaging() else {
-        let status = StatusCode::SERVICE_UNAVAILABLE;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
-    };
 
-    let claims = match session::authenticate_bearer(&state, &headers) {
-        Ok(claims) => claims,
-        Err(status) => {
-            if status == StatusCode::UNAUTHORIZED {
-                state.record_messaging_rejection("unauthorized");
+    async fn notify_guild_members(&self, guild_id: Uuid, notification: Arc<NotificationEvent>) {
+        let user_ids = match self.store.user_ids_for_guild(guild_id).await {
+            Ok(ids) => ids,
+            Err(err) => {
+                tracing::debug!(
+                    ?err,
+                    guild_id = %guild_id,
+                    "failed to load guild members for notification delivery"
+                );
+                Vec::new()
             }
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
-        }
-    };
+        };
 
-    #[cfg(not(feature = "metrics"))]
-    let _ = matched_path;
+        let map = self.notification_channels.read().await;
 
-    match messaging.list_channels(guild_id).await {
-        Ok(channels) => {
-            for channel in &channels {
-                if let Err(err) = messaging
-                    .ensure_channel_access(channel.channel_id, claims.user_id, "member")
-                    .await
-                {
-                    tracing::debug!(
-                        ?err,
-                        channel_id = %channel.channel_id,
-                        user_id = %claims.user_id,
-                        "failed to bootstrap channel membership from list"
-                    );
+        if user_ids.is_empty() {
+            for sender in map.values() {
+                if sender.receiver_count() == 0 {
+                    continue;
                 }
+                let _ = sender.send(notification.clone());
             }
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
-            Ok(Json(
-                channels
-                    .into_iter()
-                    .map(|c| CreateChannelResponse {
-                        channel_id: c.channel_id,
-                        guild_id: c.guild_id,
-                        name: c.name,
-                        created_at: c.created_at,
-                    })
-                    .collect(),
-            ))
-        }
-        Err(MessagingError::GuildNotFound) => {
-            let status = StatusCode::NOT_FOUND;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            Err(status)
+            return;
         }
-        Err(err) => {
-            tracing::error!(?err, "failed to list channels");
-            let status = StatusCode::INTERNAL_SERVER_ERROR;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            Err(status)
+
+        for user_id in user_ids {
+            if let Some(sender) = map.get(&user_id) {
+                if sender.receiver_count() == 0 {
+                    continue;
+                }
+                let _ = sender.send(notification.clone());
+            }
         }
     }
-}
 
-pub async fn post_message(
-    matched_path: MatchedPath,
-    State(state): State<AppState>,
-    headers: HeaderMap,
-    Path(channel_id): Path<Uuid>,
-    Json(body): Json<PostMessageRequest>,
-) -> Result<Json<PostMessageResponse>, StatusCode> {
-    let Some(messaging) = state.messaging() else {
-        let status = StatusCode::SERVICE_UNAVAILABLE;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
-    };
+    pub async fn open_websocket(
+        self: Arc<Self>,
+        channel_id: Uuid,
+        viewer: Option<Uuid>,
+        request_id: Option<String>,
+        ws: WebSocketUpgrade,
+    ) -> Response {
+        let request_id_label = request_id.unwrap_or_else(|| "unknown".to_string());
 
-    let claims = match session::authenticate_bearer(&state, &headers) {
-        Ok(claims) => claims,
-        Err(status) => {
-            if status == StatusCode::UNAUTHORIZED {
-                state.record_messaging_rejection("unauthorized");
+        match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => {
+                let svc = self.clone();
+                let request_id_for_span = request_id_label.clone();
+                ws.on_upgrade(move |socket| {
+                    let span = tracing::info_span!(
+                        "websocket.session",
+                        channel_id = %channel_id,
+                        request_id = %request_id_for_span
+                    );
+                    async move {
+                        svc.run_socket(channel_id, viewer, socket, permit).await;
+                    }
+                    .instrument(span)
+                })
+            }
+            Err(_) => {
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = self.metrics() {
+                    metrics.increment_messaging_rejection("websocket_limit");
+                }
+                tracing::warn!(
+                    channel_id = %channel_id,
+                    request_id = %request_id_label,
+                    "websocket connection limit reached"
+                );
+                Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .body(axum::body::Body::from("connection limit reached"))
+                    .unwrap()
             }
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
         }
-    };
-
-    #[cfg(not(feature = "metrics"))]
-    let _ = matched_path;
-
-    let sender_claim = claims.user_id.to_string();
-    let requested_sender = body.sender.trim();
-    let content = body.content.trim();
-    let content_length = content.chars().count();
-
-    if content.is_empty() {
-        state.record_messaging_rejection("message_empty");
-        let status = StatusCode::BAD_REQUEST;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
     }
 
-    if content_length > MAX_MESSAGE_LENGTH {
-        state.record_messaging_rejection("message_length");
-        tracing::warn!(
-            length = content_length,
-            max = MAX_MESSAGE_LENGTH,
-            channel_id = %channel_id,
-            "message content exceeds maximum length"
-        );
-        let status = StatusCode::BAD_REQUEST;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
-    }
+    pub async fn open_notification_socket(
+        self: Arc<Self>,
+        user_id: Uuid,
+        request_id: Option<String>,
+        ws: WebSocketUpgrade,
+    ) -> Response {
+        let request_id_label = request_id.unwrap_or_else(|| "unknown".to_string());
 
-    let client_ip = client_ip_from_headers(&headers);
-
-    if !requested_sender.is_empty() && requested_sender != sender_claim {
-        state.record_messaging_rejection("sender_mismatch");
-        let status = StatusCode::FORBIDDEN;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
+        match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => {
+                let svc = self.clone();
+                let request_id_for_span = request_id_label.clone();
+                ws.on_upgrade(move |socket| {
+                    let span = tracing::info_span!(
+                        "websocket.notifications",
+                        user_id = %user_id,
+                        request_id = %request_id_for_span
+                    );
+                    async move {
+                        svc.run_notification_socket(user_id, socket, permit).await;
+                    }
+                    .instrument(span)
+                })
+            }
+            Err(_) => {
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = self.metrics() {
+                    metrics.increment_messaging_rejection("websocket_limit");
+                }
+                tracing::warn!(
+                    user_id = %user_id,
+                    request_id = %request_id_label,
+                    "notification websocket connection limit reached"
+                );
+                Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .body(axum::body::Body::from("connection limit reached"))
+                    .unwrap()
+            }
+        }
     }
 
-    let author_snapshot =
-        resolve_message_author(&state, claims.user_id, claims.username.clone()).await;
+    async fn run_socket(
+        self: Arc<Self>,
+        channel_id: Uuid,
+        viewer: Option<Uuid>,
+        mut socket: WebSocket,
+        _permit: tokio::sync::OwnedSemaphorePermit,
+    ) {
+        if let Ok(events) = self.timeline(channel_id, None, 50, viewer).await {
+            for event in events {
+                let payload = Arc::new(OutboundEvent {
+                    sequence: event.sequence,
+                    channel_id,
+                    event: event.event,
+                    edited: event.edited,
+                    thread: event.thread,
+                    reactions: event.reactions,
+                });
+                if let Err(err) = timeout(
+                    SEND_TIMEOUT,
+                    socket.send(WsMessage::Text(
+                        serde_json::to_string(&*payload).unwrap_or_default().into(),
+                    )),
+                )
+                .await
+                {
+                    tracing::warn!(?err, "failed to send historical event");
+                    return;
+                }
+            }
+        }
 
-    if !messaging.check_ip_rate(&client_ip).await {
-        state.record_messaging_rejection("ip_rate_limit");
-        let status = StatusCode::TOO_MANY_REQUESTS;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
-    }
+        let mut rx = self.subscribe(channel_id).await;
 
-    if !messaging.check_message_rate(&author_snapshot.id).await {
-        state.record_messaging_rejection("message_rate_limit");
-        let status = StatusCode::TOO_MANY_REQUESTS;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
+        loop {
+            tokio::select! {
+                result = rx.recv() => {
+                    match result {
+                        Ok(event) => {
+                            if timeout(
+                                SEND_TIMEOUT,
+                                socket.send(WsMessage::Text(serde_json::to_string(&*event).unwrap_or_default().into()))
+                            ).await.is_err() {
+                                tracing::warn!("websocket send timeout");
+                                break;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            let message = format!("lagged by {skipped} messages");
+                            let _ = socket.send(WsMessage::Close(Some(axum::extract::ws::CloseFrame {
+                                code: axum::extract::ws::close_code::POLICY,
+                                reason: message.into(),
+                            }))).await;
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                message = socket.recv() => {
+                    match message {
+                        Some(Ok(WsMessage::Close(_))) | None => break,
+                        Some(Ok(WsMessage::Ping(payload)))
+                            if socket.send(WsMessage::Pong(payload.clone())).await.is_err() =>
+                        {
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
     }
 
-    match messaging
-        .append_message(channel_id, &author_snapshot, content)
-        .await
-    {
-        Ok(event) => {
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
-            Ok(Json(PostMessageResponse {
-                sequence: event.sequence,
-                event_id: event.event_id,
-                created_at: event.created_at,
-            }))
-        }
-        Err(MessagingError::ChannelNotFound) => {
-            let status = StatusCode::NOT_FOUND;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            Err(status)
-        }
-        Err(err) => {
-            tracing::error!(?err, "failed to append channel event");
-            let status = StatusCode::INTERNAL_SERVER_ERROR;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            Err(status)
+    async fn run_notification_socket(
+        self: Arc<Self>,
+        user_id: Uuid,
+        mut socket: WebSocket,
+        _permit: tokio::sync::OwnedSemaphorePermit,
+    ) {
+        let sender = self.notification_sender(user_id).await;
+        let mut rx = sender.subscribe();
+
+        loop {
+            tokio::select! {
+                result = rx.recv() => {
+                    match result {
+                        Ok(event) => {
+                            if timeout(
+                                SEND_TIMEOUT,
+                                socket.send(WsMessage::Text(serde_json::to_string(&*event).unwrap_or_default().into()))
+                            ).await.is_err() {
+                                tracing::warn!("notification websocket send timeout");
+                                break;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            let message = format!("lagged by {skipped} notification messages");
+                            let _ = socket.send(WsMessage::Close(Some(axum::extract::ws::CloseFrame {
+                                code: axum::extract::ws::close_code::POLICY,
+                                reason: message.into(),
+                            }))).await;
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                message = socket.recv() => {
+                    match message {
+                        Some(Ok(WsMessage::Close(_))) | None => break,
+                        Some(Ok(WsMessage::Ping(payload)))
+                            if socket.send(WsMessage::Pong(payload.clone())).await.is_err() =>
+                        {
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+            }
         }
+
+        self.cleanup_notification_channel(user_id).await;
     }
 }
 
 #[derive(Debug, Deserialize)]
-pub struct TimelineQuery {
-    pub since: Option<i64>,
-    pub limit: Option<i64>,
+pub struct CreateGuildRequest {
+    pub name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct TimelineEvent {
-    pub sequence: i64,
+#[derive(Debug, Serialize)]
+pub struct CreateGuildResponse {
+    pub guild_id: Uuid,
+    pub name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateChannelRequest {
+    pub name: String,
+    #[serde(default)]
+    pub kind: ChannelKind,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateChannelResponse {
     pub channel_id: Uuid,
-    pub event: serde_json::Value,
+    pub guild_id: Uuid,
+    pub name: String,
+    pub kind: ChannelKind,
+    pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
-impl From<ChannelEvent> for TimelineEvent {
-    fn from(event: ChannelEvent) -> Self {
+impl From<Channel> for CreateChannelResponse {
+    fn from(channel: Channel) -> Self {
         Self {
-            sequence: event.sequence,
-            channel_id: event.channel_id,
-            event: event.body,
+            kind: ChannelKind::of(&channel),
+            channel_id: channel.channel_id,
+            guild_id: channel.guild_id,
+            name: channel.name,
+            created_at: channel.created_at,
         }
     }
 }
 
-#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
-pub async fn list_events(
-    matched_path: MatchedPath,
-    State(state): State<AppState>,
-    headers: HeaderMap,
-    Path(channel_id): Path<Uuid>,
-    Query(query): Query<TimelineQuery>,
-) -> Result<Json<Vec<TimelineEvent>>, StatusCode> {
-    let Some(messaging) = state.messaging() else {
-        let status = StatusCode::SERVICE_UNAVAILABLE;
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
-    };
+#[derive(Debug, Deserialize)]
+pub struct PostMessageRequest {
+    pub sender: String,
+    /// Plain-text body, or the fallback for `formatted`. May be empty when the message has
+    /// attachments.
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub formatted: Option<FormattedBody>,
+    #[serde(default)]
+    pub mentions: Mentions,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    #[serde(default)]
+    pub thread_root: Option<String>,
+}
 
-    if let Err(status) = session::authenticate_bearer(&state, &headers) {
-        if status == StatusCode::UNAUTHORIZED {
-            state.record_messaging_rejection("unauthorized");
-        }
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
-    }
+/// Body of an `encrypted` event; see `EncryptedContent`.
+#[derive(Debug, Deserialize)]
+pub struct PostEncryptedRequest {
+    pub ciphertext: String,
+    pub epoch: u64,
+    pub device_id: String,
+}
 
-    match messaging.channel_exists(channel_id).await {
-        Ok(true) => {}
-        Ok(false) => {
-            let status = StatusCode::NOT_FOUND;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
-        }
-        Err(err) => {
-            tracing::error!(?err, channel_id = %channel_id, "failed to verify channel");
-            let status = StatusCode::INTERNAL_SERVER_ERROR;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
-        }
-    }
+#[derive(Debug, Serialize)]
+pub struct PostMessageResponse {
+    pub sequence: i64,
+    pub event_id: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
 
-    #[cfg(not(feature = "metrics"))]
-    let _ = matched_path;
+#[derive(Debug, Deserialize)]
+pub struct SendStateRequest {
+    pub event_type: String,
+    #[serde(default)]
+    pub state_key: String,
+    pub content: serde_json::Value,
+}
 
-    let limit = query
-        .limit
-        .unwrap_or(DEFAULT_TIMELINE_LIMIT)
-        .clamp(1, MAX_TIMELINE_LIMIT);
+#[derive(Debug, Default, Deserialize)]
+pub struct RedactRequest {
+    #[serde(default)]
+    pub reason: Option<String>,
+}
 
-    match messaging
-        .recent_events(channel_id, query.since, limit)
-        .await
-    {
-        Ok(events) => {
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
-            Ok(Json(events.into_iter().map(TimelineEvent::from).collect()))
-        }
-        Err(MessagingError::ChannelNotFound) => {
-            let status = StatusCode::NOT_FOUND;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            Err(status)
+#[derive(Debug, Deserialize)]
+pub struct EditMessageRequest {
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReactionRequest {
+    pub key: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelStateResponse {
+    pub channel_id: Uuid,
+    pub events: Vec<CanonicalEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChannelReadUpdate {
+    pub sequence: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelUnreadEntry {
+    pub channel_id: Uuid,
+    pub last_read_sequence: i64,
+    pub latest_sequence: i64,
+    pub unread: i64,
+    /// Threads the user tracks in this channel.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub threads: Vec<ThreadUnreadEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThreadUnreadEntry {
+    pub root_event_id: String,
+    pub last_read_sequence: i64,
+    pub latest_sequence: i64,
+    /// Replies after `last_read_sequence`.
+    pub unread: i64,
+}
+
+impl From<ThreadUnreadState> for ThreadUnreadEntry {
+    fn from(state: ThreadUnreadState) -> Self {
+        Self {
+            root_event_id: state.root_event_id,
+            last_read_sequence: state.last_read_sequence,
+            latest_sequence: state.latest_sequence,
+            unread: state.unread,
         }
+    }
+}
+
+async fn resolve_message_author(
+    state: &AppState,
+    user_id: Uuid,
+    username_hint: Option<String>,
+) -> MessageAuthorSnapshot {
+    let fallback_id = user_id.to_string();
+    let fallback_username = username_hint
+        .as_ref()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| fallback_id.clone());
+    let fallback = MessageAuthorSnapshot {
+        id: fallback_id.clone(),
+        username: fallback_username,
+        display_name: None,
+    };
+
+    let Some(pool) = state.storage_pool() else {
+        return fallback;
+    };
+
+    match UserRepository::find_user_by_id(pool.pool(), user_id).await {
+        Ok(Some(record)) => MessageAuthorSnapshot {
+            id: record.user_id.to_string(),
+            username: record.username,
+            display_name: None,
+        },
+        Ok(None) => fallback,
         Err(err) => {
-            tracing::error!(?err, channel_id = %channel_id, "failed to list events");
-            let status = StatusCode::INTERNAL_SERVER_ERROR;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            Err(status)
+            tracing::warn!(
+                ?err,
+                user_id = %user_id,
+                "failed to resolve message author metadata"
+            );
+            fallback
         }
     }
 }
 
-#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
-pub async fn mark_channel_read(
+pub async fn create_guild(
     matched_path: MatchedPath,
     State(state): State<AppState>,
     headers: HeaderMap,
-    Path(channel_id): Path<Uuid>,
-    Json(body): Json<ChannelReadUpdate>,
-) -> Result<Json<ChannelUnreadEntry>, StatusCode> {
+    Json(body): Json<CreateGuildRequest>,
+) -> Result<Json<CreateGuildResponse>, StatusCode> {
     let Some(messaging) = state.messaging() else {
         let status = StatusCode::SERVICE_UNAVAILABLE;
         #[cfg(feature = "metrics")]
@@ -1908,82 +4180,118 @@ pub async fn mark_channel_read(
         return Err(status);
     };
 
-    let claims = match session::authenticate_bearer(&state, &headers) {
-        Ok(claims) => claims,
-        Err(status) => {
-            if status == StatusCode::UNAUTHORIZED {
-                state.record_messaging_rejection("unauthorized");
-            }
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
+    if let Err(status) = session::authenticate_bearer(&state, &headers) {
+        if status == StatusCode::UNAUTHORIZED {
+            state.record_messaging_rejection("unauthorized");
         }
-    };
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
 
+    let name = body.name.trim();
     #[cfg(not(feature = "metrics"))]
     let _ = matched_path;
 
-    match messaging.channel_exists(channel_id).await {
-        Ok(true) => {}
-        Ok(false) => {
-            let status = StatusCode::NOT_FOUND;
+    let name_length = name.chars().count();
+
+    if name.is_empty() {
+        state.record_messaging_rejection("guild_name_empty");
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    if name_length > MAX_GUILD_NAME_LENGTH {
+        state.record_messaging_rejection("guild_name_length");
+        tracing::warn!(
+            length = name_length,
+            max = MAX_GUILD_NAME_LENGTH,
+            "guild name exceeds maximum length"
+        );
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    match messaging.create_guild(name).await {
+        Ok(guild) => {
             #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(CreateGuildResponse {
+                guild_id: guild.guild_id,
+                name: guild.name,
+                created_at: guild.created_at,
+            }))
         }
         Err(err) => {
-            tracing::error!(?err, channel_id = %channel_id, "failed to verify channel");
+            tracing::error!(?err, "failed to create guild");
             let status = StatusCode::INTERNAL_SERVER_ERROR;
             #[cfg(feature = "metrics")]
             state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
+            Err(status)
         }
     }
+}
 
-    let latest_sequence = match messaging.latest_sequence_for_channel(channel_id).await {
-        Ok(seq) => seq,
-        Err(err) => {
-            tracing::error!(?err, channel_id = %channel_id, "failed to resolve latest sequence");
-            let status = StatusCode::INTERNAL_SERVER_ERROR;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
-        }
+pub async fn list_guilds(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<CreateGuildResponse>>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
     };
 
-    let requested = body.sequence.unwrap_or(latest_sequence);
-    let sequence = requested.clamp(0, latest_sequence);
-
-    if let Err(err) = messaging
-        .update_last_read_sequence(channel_id, claims.user_id, sequence)
-        .await
-    {
-        tracing::error!(?err, channel_id = %channel_id, user_id = %claims.user_id, "failed to update last read sequence");
-        let status = StatusCode::INTERNAL_SERVER_ERROR;
+    if let Err(status) = session::authenticate_bearer(&state, &headers) {
+        if status == StatusCode::UNAUTHORIZED {
+            state.record_messaging_rejection("unauthorized");
+        }
         #[cfg(feature = "metrics")]
         state.record_http_request(matched_path.as_str(), status.as_u16());
         return Err(status);
     }
 
-    let entry = ChannelUnreadEntry {
-        channel_id,
-        last_read_sequence: sequence,
-        latest_sequence,
-        unread: (latest_sequence - sequence).max(0),
-    };
-
-    #[cfg(feature = "metrics")]
-    state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
 
-    Ok(Json(entry))
+    match messaging.list_guilds().await {
+        Ok(guilds) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(
+                guilds
+                    .into_iter()
+                    .map(|g| CreateGuildResponse {
+                        guild_id: g.guild_id,
+                        name: g.name,
+                        created_at: g.created_at,
+                    })
+                    .collect(),
+            ))
+        }
+        Err(err) => {
+            tracing::error!(?err, "failed to list guilds");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+    }
 }
 
-#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
-pub async fn list_unread_states(
+pub async fn create_channel(
     matched_path: MatchedPath,
     State(state): State<AppState>,
     headers: HeaderMap,
-) -> Result<Json<Vec<ChannelUnreadEntry>>, StatusCode> {
+    Path(guild_id): Path<Uuid>,
+    Json(body): Json<CreateChannelRequest>,
+) -> Result<Json<CreateChannelResponse>, StatusCode> {
     let Some(messaging) = state.messaging() else {
         let status = StatusCode::SERVICE_UNAVAILABLE;
         #[cfg(feature = "metrics")]
@@ -2006,51 +4314,72 @@ pub async fn list_unread_states(
     #[cfg(not(feature = "metrics"))]
     let _ = matched_path;
 
-    let states = match messaging.user_channel_unread(claims.user_id).await {
-        Ok(states) => states,
-        Err(err) => {
-            tracing::error!(?err, user_id = %claims.user_id, "failed to load unread states");
-            let status = StatusCode::INTERNAL_SERVER_ERROR;
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
-        }
-    };
-
-    #[cfg(feature = "metrics")]
-    state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
-
-    Ok(Json(
-        states
-            .into_iter()
-            .map(|state| ChannelUnreadEntry {
-                channel_id: state.channel_id,
-                last_read_sequence: state.last_read_sequence,
-                latest_sequence: state.latest_sequence,
-                unread: (state.latest_sequence - state.last_read_sequence).max(0),
-            })
-            .collect(),
-    ))
-}
-#[derive(Debug, Deserialize)]
-pub struct ChannelSocketQuery {
-    pub access_token: Option<String>,
-}
+    let name = body.name.trim();
+    let name_length = name.chars().count();
+    if name.is_empty() {
+        state.record_messaging_rejection("channel_name_empty");
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+    if name_length > MAX_CHANNEL_NAME_LENGTH {
+        state.record_messaging_rejection("channel_name_length");
+        tracing::warn!(
+            length = name_length,
+            max = MAX_CHANNEL_NAME_LENGTH,
+            guild_id = %guild_id,
+            "channel name exceeds maximum length"
+        );
+        state.record_messaging_rejection("channel_name_length");
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
 
-#[derive(Debug, Deserialize)]
-pub struct NotificationSocketQuery {
-    pub access_token: Option<String>,
+    match messaging
+        .create_channel_with_kind(guild_id, name, body.kind, Some(claims.user_id))
+        .await
+    {
+        Ok(channel) => {
+            if let Err(err) = messaging
+                .ensure_channel_access(channel.channel_id, claims.user_id, "moderator")
+                .await
+            {
+                tracing::debug!(
+                    ?err,
+                    channel_id = %channel.channel_id,
+                    user_id = %claims.user_id,
+                    "failed to bootstrap channel membership for creator"
+                );
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(CreateChannelResponse::from(channel)))
+        }
+        Err(MessagingError::GuildNotFound) => {
+            let status = StatusCode::NOT_FOUND;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+        Err(err) => {
+            tracing::error!(?err, "failed to create channel");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+    }
 }
 
-pub async fn channel_socket(
+pub async fn list_channels(
     matched_path: MatchedPath,
     State(state): State<AppState>,
     headers: HeaderMap,
-    Path(channel_id): Path<Uuid>,
-    Query(query): Query<ChannelSocketQuery>,
-    Extension(request_id): Extension<RequestId>,
-    ws: WebSocketUpgrade,
-) -> Result<Response, StatusCode> {
+    Path(guild_id): Path<Uuid>,
+) -> Result<Json<Vec<CreateChannelResponse>>, StatusCode> {
     let Some(messaging) = state.messaging() else {
         let status = StatusCode::SERVICE_UNAVAILABLE;
         #[cfg(feature = "metrics")]
@@ -2058,88 +4387,68 @@ pub async fn channel_socket(
         return Err(status);
     };
 
-    let mut auth_error: Option<StatusCode> = None;
-    let authorized = match session::authenticate_bearer(&state, &headers) {
-        Ok(_) => true,
-        Err(StatusCode::UNAUTHORIZED) => {
-            if let Some(token) = query
-                .access_token
-                .as_deref()
-                .map(str::trim)
-                .filter(|value| !value.is_empty())
-            {
-                match state.session().verify_access_token(token) {
-                    Ok(Some(_claims)) => true,
-                    Ok(None) => {
-                        auth_error = Some(StatusCode::UNAUTHORIZED);
-                        false
-                    }
-                    Err(err) => {
-                        tracing::error!(?err, "failed to verify access token from query string");
-                        auth_error = Some(StatusCode::INTERNAL_SERVER_ERROR);
-                        false
-                    }
-                }
-            } else {
-                auth_error = Some(StatusCode::UNAUTHORIZED);
-                false
-            }
-        }
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
         Err(status) => {
-            auth_error = Some(status);
-            false
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
         }
     };
 
-    if !authorized {
-        let status = auth_error.unwrap_or(StatusCode::UNAUTHORIZED);
-        if status == StatusCode::UNAUTHORIZED {
-            state.record_messaging_rejection("unauthorized");
-        }
-        #[cfg(feature = "metrics")]
-        state.record_http_request(matched_path.as_str(), status.as_u16());
-        return Err(status);
-    }
-
     #[cfg(not(feature = "metrics"))]
     let _ = matched_path;
 
-    match messaging.channel_exists(channel_id).await {
-        Ok(true) => {}
-        Ok(false) => {
+    match messaging.list_channels(guild_id).await {
+        Ok(channels) => {
+            for channel in &channels {
+                if let Err(err) = messaging
+                    .ensure_channel_access(channel.channel_id, claims.user_id, "member")
+                    .await
+                {
+                    tracing::debug!(
+                        ?err,
+                        channel_id = %channel.channel_id,
+                        user_id = %claims.user_id,
+                        "failed to bootstrap channel membership from list"
+                    );
+                }
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(
+                channels
+                    .into_iter()
+                    .map(CreateChannelResponse::from)
+                    .collect(),
+            ))
+        }
+        Err(MessagingError::GuildNotFound) => {
             let status = StatusCode::NOT_FOUND;
             #[cfg(feature = "metrics")]
             state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
+            Err(status)
         }
         Err(err) => {
-            tracing::error!(?err, "failed to determine channel existence");
+            tracing::error!(?err, "failed to list channels");
             let status = StatusCode::INTERNAL_SERVER_ERROR;
             #[cfg(feature = "metrics")]
             state.record_http_request(matched_path.as_str(), status.as_u16());
-            return Err(status);
+            Err(status)
         }
     }
-
-    let request_id_value = request_id
-        .header_value()
-        .to_str()
-        .ok()
-        .map(|value| value.to_string());
-
-    Ok(messaging
-        .open_websocket(channel_id, request_id_value, ws)
-        .await)
 }
 
-pub async fn notification_socket(
+pub async fn post_message(
     matched_path: MatchedPath,
     State(state): State<AppState>,
     headers: HeaderMap,
-    Query(query): Query<NotificationSocketQuery>,
-    Extension(request_id): Extension<RequestId>,
-    ws: WebSocketUpgrade,
-) -> Result<Response, StatusCode> {
+    Path(channel_id): Path<Uuid>,
+    Json(body): Json<PostMessageRequest>,
+) -> Result<Json<PostMessageResponse>, StatusCode> {
     let Some(messaging) = state.messaging() else {
         let status = StatusCode::SERVICE_UNAVAILABLE;
         #[cfg(feature = "metrics")]
@@ -2147,229 +4456,2771 @@ pub async fn notification_socket(
         return Err(status);
     };
 
-    let mut auth_error: Option<StatusCode> = None;
     let claims = match session::authenticate_bearer(&state, &headers) {
-        Ok(claims) => Some(claims),
-        Err(StatusCode::UNAUTHORIZED) => {
-            if let Some(token) = query
-                .access_token
-                .as_deref()
-                .map(str::trim)
-                .filter(|value| !value.is_empty())
-            {
-                match state.session().verify_access_token(token) {
-                    Ok(Some(claims)) => Some(claims),
-                    Ok(None) => {
-                        auth_error = Some(StatusCode::UNAUTHORIZED);
-                        None
-                    }
-                    Err(err) => {
-                        tracing::error!(?err, "failed to verify notification socket access token");
-                        auth_error = Some(StatusCode::INTERNAL_SERVER_ERROR);
-                        None
-                    }
-                }
-            } else {
-                auth_error = Some(StatusCode::UNAUTHORIZED);
-                None
-            }
-        }
+        Ok(claims) => claims,
         Err(status) => {
-            auth_error = Some(status);
-            None
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
         }
     };
 
-    let Some(claims) = claims else {
-        let status = auth_error.unwrap_or(StatusCode::UNAUTHORIZED);
-        if status == StatusCode::UNAUTHORIZED {
-            state.record_messaging_rejection("unauthorized");
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let sender_claim = claims.user_id.to_string();
+    let requested_sender = body.sender.trim();
+    let content = body.content.trim();
+    let content_length = content.chars().count();
+
+    if content.is_empty() && body.attachments.is_empty() {
+        state.record_messaging_rejection("message_empty");
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    if let Err(reason) = validate_rich_content(&body) {
+        state.record_messaging_rejection(reason);
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    // Attachments must reference uploads this server knows about when media is enabled.
+    if let Some(media) = state.media() {
+        for attachment in &body.attachments {
+            let status = match media.media(attachment.media_id.trim()).await {
+                Ok(Some(_)) => continue,
+                Ok(None) => {
+                    state.record_messaging_rejection("invalid_attachment");
+                    StatusCode::BAD_REQUEST
+                }
+                Err(err) => {
+                    tracing::error!(?err, "failed to look up attachment media");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            };
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
         }
+    }
+
+    if content_length > MAX_MESSAGE_LENGTH {
+        state.record_messaging_rejection("message_length");
+        tracing::warn!(
+            length = content_length,
+            max = MAX_MESSAGE_LENGTH,
+            channel_id = %channel_id,
+            "message content exceeds maximum length"
+        );
+        let status = StatusCode::BAD_REQUEST;
         #[cfg(feature = "metrics")]
         state.record_http_request(matched_path.as_str(), status.as_u16());
         return Err(status);
-    };
+    }
+
+    let client_ip = client_ip_from_headers(&headers);
+
+    if !requested_sender.is_empty() && requested_sender != sender_claim {
+        state.record_messaging_rejection("sender_mismatch");
+        let status = StatusCode::FORBIDDEN;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    let author_snapshot =
+        resolve_message_author(&state, claims.user_id, claims.username.clone()).await;
+
+    if !messaging.check_ip_rate(&client_ip).await {
+        state.record_messaging_rejection("ip_rate_limit");
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    if !messaging.check_message_rate(&author_snapshot.id).await {
+        state.record_messaging_rejection("message_rate_limit");
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    let relation = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+    let payload = MessagePayload {
+        formatted: body
+            .formatted
+            .filter(|formatted| !formatted.body.trim().is_empty()),
+        mentions: body.mentions,
+        attachments: body.attachments,
+        reply_to: relation(&body.reply_to),
+        thread_root: relation(&body.thread_root),
+        ..MessagePayload::new(content)
+    };
+
+    match messaging
+        .send_message(channel_id, &author_snapshot, payload)
+        .await
+    {
+        Ok(event) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(PostMessageResponse {
+                sequence: event.sequence,
+                event_id: event.event_id,
+                created_at: event.created_at,
+            }))
+        }
+        Err(MessagingError::ChannelNotFound) => {
+            let status = StatusCode::NOT_FOUND;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+        Err(MessagingError::InvalidRelation(reason)) => {
+            tracing::debug!(%reason, channel_id = %channel_id, "message relation rejected");
+            state.record_messaging_rejection("invalid_relation");
+            let status = StatusCode::BAD_REQUEST;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+        Err(MessagingError::InvalidMention(reason)) => {
+            tracing::debug!(%reason, channel_id = %channel_id, "message mention rejected");
+            state.record_messaging_rejection("invalid_mention");
+            let status = StatusCode::BAD_REQUEST;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+        Err(MessagingError::Unauthorized(err)) => {
+            tracing::debug!(%err, channel_id = %channel_id, "message rejected by auth rules");
+            state.record_messaging_rejection("auth_rules");
+            let status = StatusCode::FORBIDDEN;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+        Err(err) => {
+            tracing::error!(?err, "failed to append channel event");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+    }
+}
+
+/// Shape checks for the rich parts of a message; membership of mentions is checked by the
+/// service. Returns the rejection reason.
+fn validate_rich_content(body: &PostMessageRequest) -> Result<(), &'static str> {
+    if body
+        .formatted
+        .as_ref()
+        .is_some_and(|formatted| formatted.body.chars().count() > MAX_FORMATTED_LENGTH)
+    {
+        return Err("message_length");
+    }
+    let mentions = &body.mentions;
+    if mentions.users.len() + mentions.roles.len() + mentions.channels.len() > MAX_MENTIONS {
+        return Err("invalid_mention");
+    }
+    if body.attachments.len() > MAX_ATTACHMENTS
+        || body.attachments.iter().any(|attachment| {
+            let media_id = attachment.media_id.trim();
+            media_id.is_empty()
+                || media_id.len() > MAX_MEDIA_ID_LENGTH
+                || attachment
+                    .blurhash
+                    .as_ref()
+                    .is_some_and(|blurhash| blurhash.len() > MAX_BLURHASH_LENGTH)
+        })
+    {
+        return Err("invalid_attachment");
+    }
+    Ok(())
+}
+
+pub async fn post_encrypted(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+    Json(body): Json<PostEncryptedRequest>,
+) -> Result<Json<PostMessageResponse>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let device_id = body.device_id.trim();
+    if device_id.is_empty() || device_id.len() > MAX_DEVICE_ID_LENGTH {
+        state.record_messaging_rejection("invalid_device");
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+    // The ciphertext is opaque to the server; it only has to be well-formed base64.
+    match URL_SAFE_NO_PAD.decode(&body.ciphertext) {
+        Ok(bytes) if !bytes.is_empty() && bytes.len() <= MAX_CIPHERTEXT_LENGTH => {}
+        _ => {
+            state.record_messaging_rejection("invalid_ciphertext");
+            let status = StatusCode::BAD_REQUEST;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    }
+
+    let sender = claims.user_id.to_string();
+    let client_ip = client_ip_from_headers(&headers);
+    if !messaging.check_ip_rate(&client_ip).await {
+        state.record_messaging_rejection("ip_rate_limit");
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+    if !messaging.check_message_rate(&sender).await {
+        state.record_messaging_rejection("message_rate_limit");
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    let content = EncryptedContent {
+        ciphertext: body.ciphertext,
+        epoch: body.epoch,
+        device_id: device_id.to_string(),
+    };
+    match messaging.send_encrypted(channel_id, &sender, content).await {
+        Ok(event) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(PostMessageResponse {
+                sequence: event.sequence,
+                event_id: event.event_id,
+                created_at: event.created_at,
+            }))
+        }
+        Err(err) => {
+            let status = match err {
+                MessagingError::ChannelNotFound => StatusCode::NOT_FOUND,
+                MessagingError::Unauthorized(err) => {
+                    tracing::debug!(%err, channel_id = %channel_id, "encrypted message rejected by auth rules");
+                    state.record_messaging_rejection("auth_rules");
+                    StatusCode::FORBIDDEN
+                }
+                err => {
+                    tracing::error!(?err, channel_id = %channel_id, "failed to append encrypted message");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            };
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+    }
+}
+
+pub async fn send_state_event(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+    Json(body): Json<SendStateRequest>,
+) -> Result<Json<PostMessageResponse>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let content = match StateContent::parse(body.event_type.trim(), &body.content) {
+        Ok(Some(content)) if content.validate_state_key(&body.state_key).is_ok() => content,
+        _ => {
+            state.record_messaging_rejection("state_invalid");
+            let status = StatusCode::BAD_REQUEST;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    let sender = claims.user_id.to_string();
+    // Anyone may join or leave for themselves; other state needs a moderating role.
+    let own_membership = matches!(content, StateContent::Member(_)) && body.state_key == sender;
+    if !own_membership {
+        let allowed = match messaging
+            .channel_membership(channel_id, claims.user_id)
+            .await
+        {
+            Ok(membership) => membership.is_some_and(|membership| {
+                STATE_SENDER_ROLES
+                    .iter()
+                    .any(|role| membership.role.trim().eq_ignore_ascii_case(role))
+            }),
+            Err(err) => {
+                tracing::error!(?err, channel_id = %channel_id, "failed to load channel membership");
+                let status = StatusCode::INTERNAL_SERVER_ERROR;
+                #[cfg(feature = "metrics")]
+                state.record_http_request(matched_path.as_str(), status.as_u16());
+                return Err(status);
+            }
+        };
+        if !allowed {
+            state.record_messaging_rejection("state_forbidden");
+            let status = StatusCode::FORBIDDEN;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    }
+
+    if !messaging.check_message_rate(&sender).await {
+        state.record_messaging_rejection("message_rate_limit");
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    match messaging
+        .append_state_event(channel_id, &sender, &content, &body.state_key)
+        .await
+    {
+        Ok(event) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(PostMessageResponse {
+                sequence: event.sequence,
+                event_id: event.event_id,
+                created_at: event.created_at,
+            }))
+        }
+        Err(err) => {
+            let status = match err {
+                MessagingError::ChannelNotFound => StatusCode::NOT_FOUND,
+                MessagingError::InvalidState(_) => {
+                    state.record_messaging_rejection("state_invalid");
+                    StatusCode::BAD_REQUEST
+                }
+                MessagingError::Unauthorized(err) => {
+                    tracing::debug!(%err, channel_id = %channel_id, "state event rejected by auth rules");
+                    state.record_messaging_rejection("auth_rules");
+                    StatusCode::FORBIDDEN
+                }
+                err => {
+                    tracing::error!(?err, channel_id = %channel_id, "failed to append state event");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            };
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+    }
+}
+
+pub async fn redact_event(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((channel_id, event_id)): Path<(Uuid, String)>,
+    body: Option<Json<RedactRequest>>,
+) -> Result<Json<PostMessageResponse>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let sender = claims.user_id.to_string();
+    if !messaging.check_message_rate(&sender).await {
+        state.record_messaging_rejection("message_rate_limit");
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    let reason = body.and_then(|Json(body)| body.reason);
+    match messaging
+        .redact_event(channel_id, &sender, &event_id, reason)
+        .await
+    {
+        Ok(event) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(PostMessageResponse {
+                sequence: event.sequence,
+                event_id: event.event_id,
+                created_at: event.created_at,
+            }))
+        }
+        Err(err) => {
+            let status = match err {
+                MessagingError::ChannelNotFound | MessagingError::EventNotFound(_) => {
+                    StatusCode::NOT_FOUND
+                }
+                MessagingError::Unauthorized(err) => {
+                    tracing::debug!(%err, channel_id = %channel_id, "redaction rejected by auth rules");
+                    state.record_messaging_rejection("auth_rules");
+                    StatusCode::FORBIDDEN
+                }
+                err => {
+                    tracing::error!(?err, channel_id = %channel_id, "failed to redact event");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            };
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+    }
+}
+
+pub async fn edit_message(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((channel_id, event_id)): Path<(Uuid, String)>,
+    Json(body): Json<EditMessageRequest>,
+) -> Result<Json<PostMessageResponse>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let content = body.content.trim();
+    if content.is_empty() {
+        state.record_messaging_rejection("message_empty");
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+    if content.chars().count() > MAX_MESSAGE_LENGTH {
+        state.record_messaging_rejection("message_length");
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    let sender = claims.user_id.to_string();
+    if !messaging.check_message_rate(&sender).await {
+        state.record_messaging_rejection("message_rate_limit");
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    match messaging
+        .edit_message(channel_id, &sender, &event_id, content.to_string())
+        .await
+    {
+        Ok(event) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(PostMessageResponse {
+                sequence: event.sequence,
+                event_id: event.event_id,
+                created_at: event.created_at,
+            }))
+        }
+        Err(err) => {
+            let status = match err {
+                MessagingError::ChannelNotFound | MessagingError::EventNotFound(_) => {
+                    StatusCode::NOT_FOUND
+                }
+                MessagingError::Unauthorized(err) => {
+                    tracing::debug!(%err, channel_id = %channel_id, "edit rejected by auth rules");
+                    state.record_messaging_rejection("auth_rules");
+                    StatusCode::FORBIDDEN
+                }
+                err => {
+                    tracing::error!(?err, channel_id = %channel_id, "failed to edit message");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            };
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+    }
+}
+
+pub async fn list_message_edits(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((channel_id, event_id)): Path<(Uuid, String)>,
+) -> Result<Json<Vec<TimelineEvent>>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    if let Err(status) = session::authenticate_bearer(&state, &headers) {
+        if status == StatusCode::UNAUTHORIZED {
+            state.record_messaging_rejection("unauthorized");
+        }
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match messaging.edit_history(channel_id, &event_id).await {
+        Ok(edits) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(edits.into_iter().map(TimelineEvent::from).collect()));
+        }
+        Err(MessagingError::ChannelNotFound | MessagingError::EventNotFound(_)) => {
+            StatusCode::NOT_FOUND
+        }
+        Err(err) => {
+            tracing::error!(?err, channel_id = %channel_id, "failed to list message edits");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+/// Trimmed reaction key, or `None` when it is empty or too long.
+fn normalize_reaction_key(key: &str) -> Option<&str> {
+    let key = key.trim();
+    (!key.is_empty() && key.chars().count() <= MAX_REACTION_KEY_LENGTH).then_some(key)
+}
+
+pub async fn add_reaction(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((channel_id, event_id)): Path<(Uuid, String)>,
+    Json(body): Json<ReactionRequest>,
+) -> Result<Json<PostMessageResponse>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let Some(key) = normalize_reaction_key(&body.key) else {
+        state.record_messaging_rejection("reaction_key");
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let sender = claims.user_id.to_string();
+    if !messaging.check_message_rate(&sender).await {
+        state.record_messaging_rejection("message_rate_limit");
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    let status = match messaging
+        .add_reaction(channel_id, &sender, &event_id, key)
+        .await
+    {
+        Ok(event) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(PostMessageResponse {
+                sequence: event.sequence,
+                event_id: event.event_id,
+                created_at: event.created_at,
+            }));
+        }
+        Err(MessagingError::ChannelNotFound | MessagingError::EventNotFound(_)) => {
+            StatusCode::NOT_FOUND
+        }
+        Err(MessagingError::Unauthorized(err)) => {
+            tracing::debug!(%err, channel_id = %channel_id, "reaction rejected by auth rules");
+            state.record_messaging_rejection("auth_rules");
+            StatusCode::FORBIDDEN
+        }
+        Err(err) => {
+            tracing::error!(?err, channel_id = %channel_id, "failed to add reaction");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn remove_reaction(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((channel_id, event_id, key)): Path<(Uuid, String, String)>,
+) -> Result<StatusCode, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let Some(key) = normalize_reaction_key(&key) else {
+        state.record_messaging_rejection("reaction_key");
+        let status = StatusCode::BAD_REQUEST;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let sender = claims.user_id.to_string();
+    let status = match messaging
+        .remove_reaction(channel_id, &sender, &event_id, key)
+        .await
+    {
+        Ok(_) => {
+            let status = StatusCode::NO_CONTENT;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Ok(status);
+        }
+        Err(MessagingError::ChannelNotFound | MessagingError::EventNotFound(_)) => {
+            StatusCode::NOT_FOUND
+        }
+        Err(MessagingError::Unauthorized(err)) => {
+            tracing::debug!(%err, channel_id = %channel_id, "reaction removal rejected by auth rules");
+            state.record_messaging_rejection("auth_rules");
+            StatusCode::FORBIDDEN
+        }
+        Err(err) => {
+            tracing::error!(?err, channel_id = %channel_id, "failed to remove reaction");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn get_channel_state(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+) -> Result<Json<ChannelStateResponse>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    if let Err(status) = session::authenticate_bearer(&state, &headers) {
+        if status == StatusCode::UNAUTHORIZED {
+            state.record_messaging_rejection("unauthorized");
+        }
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match messaging.channel_state(channel_id).await {
+        Ok(events) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(ChannelStateResponse { channel_id, events }));
+        }
+        Err(MessagingError::ChannelNotFound) => StatusCode::NOT_FOUND,
+        Err(err) => {
+            tracing::error!(?err, channel_id = %channel_id, "failed to resolve channel state");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimelineQuery {
+    pub since: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimelineEvent {
+    pub sequence: i64,
+    pub channel_id: Uuid,
+    /// The canonical event; its redacted form once `redacted` is set.
+    pub event: serde_json::Value,
+    #[serde(default)]
+    pub redacted: bool,
+    /// Id of the redaction event, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redacted_by: Option<String>,
+    /// Latest edit, for edited messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited: Option<EditSummary>,
+    /// Reply count and latest reply, for thread roots.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread: Option<ThreadSummary>,
+    /// Reaction counts per key.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<ReactionSummary>,
+}
+
+impl From<ChannelEvent> for TimelineEvent {
+    fn from(event: ChannelEvent) -> Self {
+        Self {
+            sequence: event.sequence,
+            channel_id: event.channel_id,
+            event: event.body,
+            redacted: event.redacted,
+            redacted_by: event.redacted_by,
+            edited: None,
+            thread: None,
+            reactions: Vec::new(),
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub async fn list_events(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+    Query(query): Query<TimelineQuery>,
+) -> Result<Json<Vec<TimelineEvent>>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    match messaging.channel_exists(channel_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let status = StatusCode::NOT_FOUND;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+        Err(err) => {
+            tracing::error!(?err, channel_id = %channel_id, "failed to verify channel");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TIMELINE_LIMIT)
+        .clamp(1, MAX_TIMELINE_LIMIT);
+
+    match messaging
+        .timeline(channel_id, query.since, limit, Some(claims.user_id))
+        .await
+    {
+        Ok(events) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(events))
+        }
+        Err(MessagingError::ChannelNotFound) => {
+            let status = StatusCode::NOT_FOUND;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+        Err(err) => {
+            tracing::error!(?err, channel_id = %channel_id, "failed to list events");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub async fn mark_channel_read(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+    Json(body): Json<ChannelReadUpdate>,
+) -> Result<Json<ChannelUnreadEntry>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    match messaging.channel_exists(channel_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let status = StatusCode::NOT_FOUND;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+        Err(err) => {
+            tracing::error!(?err, channel_id = %channel_id, "failed to verify channel");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    }
+
+    let latest_sequence = match messaging.latest_sequence_for_channel(channel_id).await {
+        Ok(seq) => seq,
+        Err(err) => {
+            tracing::error!(?err, channel_id = %channel_id, "failed to resolve latest sequence");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    let requested = body.sequence.unwrap_or(latest_sequence);
+    let sequence = requested.clamp(0, latest_sequence);
+
+    if let Err(err) = messaging
+        .update_last_read_sequence(channel_id, claims.user_id, sequence)
+        .await
+    {
+        tracing::error!(?err, channel_id = %channel_id, user_id = %claims.user_id, "failed to update last read sequence");
+        let status = StatusCode::INTERNAL_SERVER_ERROR;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+
+    let entry = ChannelUnreadEntry {
+        channel_id,
+        last_read_sequence: sequence,
+        latest_sequence,
+        unread: (latest_sequence - sequence).max(0),
+        threads: Vec::new(),
+    };
+
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+
+    Ok(Json(entry))
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub async fn list_unread_states(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ChannelUnreadEntry>>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let states = match messaging.user_channel_unread(claims.user_id).await {
+        Ok(states) => states,
+        Err(err) => {
+            tracing::error!(?err, user_id = %claims.user_id, "failed to load unread states");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    let mut threads: HashMap<Uuid, Vec<ThreadUnreadEntry>> = HashMap::new();
+    match messaging.user_thread_unread(claims.user_id).await {
+        Ok(states) => {
+            for thread in states {
+                threads
+                    .entry(thread.channel_id)
+                    .or_default()
+                    .push(ThreadUnreadEntry::from(thread));
+            }
+        }
+        Err(err) => {
+            tracing::error!(?err, user_id = %claims.user_id, "failed to load thread unread states");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+
+    Ok(Json(
+        states
+            .into_iter()
+            .map(|state| {
+                let mut channel_threads = threads.remove(&state.channel_id).unwrap_or_default();
+                channel_threads.sort_by(|a, b| a.root_event_id.cmp(&b.root_event_id));
+                ChannelUnreadEntry {
+                    channel_id: state.channel_id,
+                    last_read_sequence: state.last_read_sequence,
+                    latest_sequence: state.latest_sequence,
+                    unread: (state.latest_sequence - state.last_read_sequence).max(0),
+                    threads: channel_threads,
+                }
+            })
+            .collect(),
+    ))
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub async fn list_thread_events(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((channel_id, event_id)): Path<(Uuid, String)>,
+    Query(query): Query<TimelineQuery>,
+) -> Result<Json<Vec<TimelineEvent>>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TIMELINE_LIMIT)
+        .clamp(1, MAX_TIMELINE_LIMIT);
+
+    let status = match messaging
+        .thread_timeline(
+            channel_id,
+            &event_id,
+            query.since,
+            limit,
+            Some(claims.user_id),
+        )
+        .await
+    {
+        Ok(events) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(events));
+        }
+        Err(MessagingError::ChannelNotFound | MessagingError::EventNotFound(_)) => {
+            StatusCode::NOT_FOUND
+        }
+        Err(err) => {
+            tracing::error!(?err, channel_id = %channel_id, "failed to list thread events");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub async fn mark_thread_read(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((channel_id, event_id)): Path<(Uuid, String)>,
+    Json(body): Json<ChannelReadUpdate>,
+) -> Result<Json<ThreadUnreadEntry>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => claims,
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match messaging
+        .mark_thread_read(channel_id, &event_id, claims.user_id, body.sequence)
+        .await
+    {
+        Ok(thread) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(ThreadUnreadEntry::from(thread)));
+        }
+        Err(MessagingError::ChannelNotFound | MessagingError::EventNotFound(_)) => {
+            StatusCode::NOT_FOUND
+        }
+        Err(err) => {
+            tracing::error!(?err, channel_id = %channel_id, user_id = %claims.user_id, "failed to update thread read sequence");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+#[derive(Debug, Deserialize)]
+pub struct ChannelSocketQuery {
+    pub access_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationSocketQuery {
+    pub access_token: Option<String>,
+}
+
+pub async fn channel_socket(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+    Query(query): Query<ChannelSocketQuery>,
+    Extension(request_id): Extension<RequestId>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let mut auth_error: Option<StatusCode> = None;
+    let viewer = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => Some(claims.user_id),
+        Err(StatusCode::UNAUTHORIZED) => {
+            if let Some(token) = query
+                .access_token
+                .as_deref()
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+            {
+                match state.session().verify_access_token(token) {
+                    Ok(Some(claims)) => Some(claims.user_id),
+                    Ok(None) => {
+                        auth_error = Some(StatusCode::UNAUTHORIZED);
+                        None
+                    }
+                    Err(err) => {
+                        tracing::error!(?err, "failed to verify access token from query string");
+                        auth_error = Some(StatusCode::INTERNAL_SERVER_ERROR);
+                        None
+                    }
+                }
+            } else {
+                auth_error = Some(StatusCode::UNAUTHORIZED);
+                None
+            }
+        }
+        Err(status) => {
+            auth_error = Some(status);
+            None
+        }
+    };
+
+    let Some(viewer) = viewer else {
+        let status = auth_error.unwrap_or(StatusCode::UNAUTHORIZED);
+        if status == StatusCode::UNAUTHORIZED {
+            state.record_messaging_rejection("unauthorized");
+        }
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    match messaging.channel_exists(channel_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let status = StatusCode::NOT_FOUND;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+        Err(err) => {
+            tracing::error!(?err, "failed to determine channel existence");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    }
+
+    let request_id_value = request_id
+        .header_value()
+        .to_str()
+        .ok()
+        .map(|value| value.to_string());
+
+    Ok(messaging
+        .open_websocket(channel_id, Some(viewer), request_id_value, ws)
+        .await)
+}
+
+pub async fn notification_socket(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<NotificationSocketQuery>,
+    Extension(request_id): Extension<RequestId>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let mut auth_error: Option<StatusCode> = None;
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Ok(claims) => Some(claims),
+        Err(StatusCode::UNAUTHORIZED) => {
+            if let Some(token) = query
+                .access_token
+                .as_deref()
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+            {
+                match state.session().verify_access_token(token) {
+                    Ok(Some(claims)) => Some(claims),
+                    Ok(None) => {
+                        auth_error = Some(StatusCode::UNAUTHORIZED);
+                        None
+                    }
+                    Err(err) => {
+                        tracing::error!(?err, "failed to verify notification socket access token");
+                        auth_error = Some(StatusCode::INTERNAL_SERVER_ERROR);
+                        None
+                    }
+                }
+            } else {
+                auth_error = Some(StatusCode::UNAUTHORIZED);
+                None
+            }
+        }
+        Err(status) => {
+            auth_error = Some(status);
+            None
+        }
+    };
+
+    let Some(claims) = claims else {
+        let status = auth_error.unwrap_or(StatusCode::UNAUTHORIZED);
+        if status == StatusCode::UNAUTHORIZED {
+            state.record_messaging_rejection("unauthorized");
+        }
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let request_id_value = request_id
+        .header_value()
+        .to_str()
+        .ok()
+        .map(|value| value.to_string());
+
+    Ok(messaging
+        .open_notification_socket(claims.user_id, request_id_value, ws)
+        .await)
+}
+
+struct RateLimiter {
+    limits: Mutex<HashMap<String, RateWindow>>,
+    limit: usize,
+    window: Duration,
+}
+
+impl RateLimiter {
+    fn new(limit: usize, window: Duration) -> Self {
+        Self {
+            limits: Mutex::new(HashMap::new()),
+            limit,
+            window,
+        }
+    }
+
+    async fn check_and_increment(&self, key: &str) -> bool {
+        let mut limits = self.limits.lock().await;
+        let now = Instant::now();
+        let entry = limits
+            .entry(key.to_string())
+            .or_insert_with(|| RateWindow::new(now, self.window));
+
+        if now >= entry.reset_at {
+            entry.reset(now, self.window);
+        }
+
+        if entry.count >= self.limit {
+            return false;
+        }
+
+        entry.count += 1;
+        true
+    }
+}
+
+struct RateWindow {
+    count: usize,
+    reset_at: Instant,
+}
+
+impl RateWindow {
+    fn new(now: Instant, window: Duration) -> Self {
+        Self {
+            count: 0,
+            reset_at: now + window,
+        }
+    }
+
+    fn reset(&mut self, now: Instant, window: Duration) {
+        self.count = 0;
+        self.reset_at = now + window;
+    }
+}
+
+fn client_ip_from_headers(headers: &HeaderMap) -> String {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(feature = "metrics")]
+pub fn init_messaging_service(
+    config: &ServerConfig,
+    pool: Option<StoragePool>,
+    metrics: Option<Arc<MetricsContext>>,
+) -> MessagingService {
+    let origin = config.server_name().to_string();
+    let service = if let Some(pool) = pool {
+        MessagingService::new_with_pool(pool, origin)
+    } else {
+        MessagingService::new_in_memory(origin)
+    };
+    service.with_metrics(metrics)
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn init_messaging_service(
+    config: &ServerConfig,
+    pool: Option<StoragePool>,
+) -> MessagingService {
+    let origin = config.server_name().to_string();
+    if let Some(pool) = pool {
+        MessagingService::new_with_pool(pool, origin)
+    } else {
+        MessagingService::new_in_memory(origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openguild_core::messaging::MessageAuthorSnapshot;
+
+    #[tokio::test]
+    async fn ensure_channel_access_enrolls_membership_and_reads() {
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Notifications").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let user_id = Uuid::new_v4();
+        let author = MessageAuthorSnapshot {
+            id: user_id.to_string(),
+            username: "tester".into(),
+            display_name: None,
+        };
+
+        service
+            .append_message(channel.channel_id, &author, "hello world")
+            .await
+            .unwrap();
+
+        assert!(service
+            .user_channel_unread(user_id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        service
+            .ensure_channel_access(channel.channel_id, user_id, "member")
+            .await
+            .unwrap();
+
+        let unread = service.user_channel_unread(user_id).await.unwrap();
+        assert_eq!(unread.len(), 1);
+        let entry = &unread[0];
+        assert_eq!(entry.channel_id, channel.channel_id);
+        assert_eq!(entry.latest_sequence, entry.last_read_sequence);
+    }
+
+    #[tokio::test]
+    async fn guild_notifications_broadcast_when_membership_unknown() {
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Guild").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "updates")
+            .await
+            .unwrap();
+
+        let user_id = Uuid::new_v4();
+        let sender = service.notification_sender(user_id).await;
+        let mut rx = sender.subscribe();
+
+        let notification = Arc::new(NotificationEvent {
+            kind: "channel_created".to_string(),
+            channel_id: Some(channel.channel_id),
+            guild_id: Some(guild.guild_id),
+            sequence: None,
+            event: json!({
+                "channel_id": channel.channel_id,
+                "guild_id": guild.guild_id,
+                "name": channel.name,
+            }),
+        });
+
+        service
+            .notify_guild_members(guild.guild_id, notification)
+            .await;
+
+        let delivered = rx.recv().await.expect("notification delivered");
+        assert_eq!(delivered.kind, "channel_created");
+    }
+
+    #[tokio::test]
+    async fn append_message_links_previous_forward_extremity() {
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Graph").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let author = MessageAuthorSnapshot {
+            id: Uuid::new_v4().to_string(),
+            username: "tester".into(),
+            display_name: None,
+        };
+
+        let first = service
+            .append_message(channel.channel_id, &author, "first")
+            .await
+            .unwrap();
+        let second = service
+            .append_message(channel.channel_id, &author, "second")
+            .await
+            .unwrap();
+
+        let second_event: CanonicalEvent = serde_json::from_value(second.body).unwrap();
+        assert_eq!(second_event.prev_events, vec![first.event_id]);
+        assert_eq!(
+            service
+                .store
+                .forward_extremities(channel.channel_id)
+                .await
+                .unwrap(),
+            vec![second.event_id]
+        );
+    }
+
+    /// Make the channel public, join `local` and ingest a join from
+    /// `@peer:remote.example.org`; peers cannot send into channels without membership state.
+    async fn join_peer(service: &MessagingService, channel_id: Uuid, local: &str) {
+        use openguild_core::state::JoinRulesContent;
+
+        let join = StateContent::Member(MembershipContent {
+            membership: Membership::Join,
+        });
+        service
+            .append_state_event(
+                channel_id,
+                local,
+                &StateContent::JoinRules(JoinRulesContent {
+                    join_rule: JoinRule::Public,
+                }),
+                "",
+            )
+            .await
+            .unwrap();
+        service
+            .append_state_event(channel_id, local, &join, local)
+            .await
+            .unwrap();
+        let peer_join = join.to_event(
+            "remote.example.org",
+            &channel_id.to_string(),
+            "@peer:remote.example.org",
+            "@peer:remote.example.org",
+            service.forward_extremities(channel_id).await.unwrap(),
+        );
+        service.ingest_event(&peer_join).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn remote_senders_need_a_public_join_in_channels_without_members() {
+        use openguild_core::state::JoinRulesContent;
+
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Legacy").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let room_id = channel.channel_id.to_string();
+        let join = StateContent::Member(MembershipContent {
+            membership: Membership::Join,
+        });
+        let message = MessagePayload::new("hi").to_event(
+            "remote.example.org",
+            &room_id,
+            "@peer:remote.example.org",
+            Vec::new(),
+        );
+        let invite_only_join = join.to_event(
+            "remote.example.org",
+            &room_id,
+            "@peer:remote.example.org",
+            "@peer:remote.example.org",
+            Vec::new(),
+        );
+        for event in [message, invite_only_join] {
+            assert!(matches!(
+                service.ingest_event(&event).await,
+                Err(MessagingError::Unauthorized(AuthError::NotJoined { .. }))
+            ));
+        }
+
+        let public = service
+            .append_state_event(
+                channel.channel_id,
+                "@local",
+                &StateContent::JoinRules(JoinRulesContent {
+                    join_rule: JoinRule::Public,
+                }),
+                "",
+            )
+            .await
+            .unwrap();
+        let public_join = join.to_event(
+            "remote.example.org",
+            &room_id,
+            "@peer:remote.example.org",
+            "@peer:remote.example.org",
+            vec![public.event_id],
+        );
+        service.ingest_event(&public_join).await.unwrap();
+        let message = MessagePayload::new("hi").to_event(
+            "remote.example.org",
+            &room_id,
+            "@peer:remote.example.org",
+            vec![public_join.event_id.clone()],
+        );
+        service.ingest_event(&message).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_members_keep_posting_once_membership_state_exists() {
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Mixed").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let member = Uuid::new_v4();
+        service
+            .ensure_channel_access(channel.channel_id, member, "member")
+            .await
+            .unwrap();
+        let author = |id: &str| MessageAuthorSnapshot {
+            id: id.into(),
+            username: "member".into(),
+            display_name: None,
+        };
+
+        let join = StateContent::Member(MembershipContent {
+            membership: Membership::Join,
+        });
+        service
+            .append_state_event(channel.channel_id, "@alice", &join, "@alice")
+            .await
+            .unwrap();
+
+        let message = service
+            .append_message(
+                channel.channel_id,
+                &author(&member.to_string()),
+                "still here",
+            )
+            .await
+            .unwrap();
+        service
+            .add_reaction(
+                channel.channel_id,
+                &member.to_string(),
+                &message.event_id,
+                "👍",
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            service
+                .append_message(
+                    channel.channel_id,
+                    &author(&Uuid::new_v4().to_string()),
+                    "hi"
+                )
+                .await,
+            Err(MessagingError::Unauthorized(AuthError::NotJoined { .. }))
+        ));
+
+        // A leave in the state takes precedence over the membership row.
+        let leave = StateContent::Member(MembershipContent {
+            membership: Membership::Leave,
+        });
+        service
+            .append_state_event(
+                channel.channel_id,
+                &member.to_string(),
+                &leave,
+                &member.to_string(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            service
+                .append_message(channel.channel_id, &author(&member.to_string()), "gone")
+                .await,
+            Err(MessagingError::Unauthorized(AuthError::NotJoined { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn power_levels_start_with_the_channel_creator() {
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Levels").await.unwrap();
+        let creator = Uuid::new_v4();
+        let channel = service
+            .create_channel_with_kind(guild.guild_id, "general", ChannelKind::Text, Some(creator))
+            .await
+            .unwrap();
+        let state = service.channel_state(channel.channel_id).await.unwrap();
+        assert_eq!(state.len(), 1);
+        assert_eq!(state[0].event_type, state::CHANNEL_POWER_LEVELS_EVENT);
+        assert_eq!(state[0].content["users"][creator.to_string()], 100);
+
+        // Channels created without power levels only let their first state sender add them.
+        let legacy = service
+            .create_channel(guild.guild_id, "legacy")
+            .await
+            .unwrap();
+        let join = StateContent::Member(MembershipContent {
+            membership: Membership::Join,
+        });
+        service
+            .append_state_event(legacy.channel_id, "@alice", &join, "@alice")
+            .await
+            .unwrap();
+        let mallory = Uuid::new_v4();
+        service
+            .ensure_channel_access(legacy.channel_id, mallory, "member")
+            .await
+            .unwrap();
+        let grab = StateContent::PowerLevels(PowerLevelsContent {
+            users: [(mallory.to_string(), 100)].into_iter().collect(),
+            ..PowerLevelsContent::default()
+        });
+        assert!(matches!(
+            service
+                .append_state_event(legacy.channel_id, &mallory.to_string(), &grab, "")
+                .await,
+            Err(MessagingError::Unauthorized(AuthError::PowerLevels(_)))
+        ));
+        let levels = StateContent::PowerLevels(PowerLevelsContent {
+            users: [("@alice".to_string(), 100)].into_iter().collect(),
+            ..PowerLevelsContent::default()
+        });
+        service
+            .append_state_event(legacy.channel_id, "@alice", &levels, "")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn ingest_event_merges_forward_extremities() {
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Graph").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let author = MessageAuthorSnapshot {
+            id: Uuid::new_v4().to_string(),
+            username: "tester".into(),
+            display_name: None,
+        };
+        let room_id = channel.channel_id.to_string();
+        join_peer(&service, channel.channel_id, &author.id).await;
+
+        let root = service
+            .append_message(channel.channel_id, &author, "root")
+            .await
+            .unwrap();
+        let remote_branch = MessagePayload::new("remote").to_event(
+            "remote.example.org",
+            &room_id,
+            "@peer:remote.example.org",
+            vec![root.event_id.clone()],
+        );
+        let local_branch = service
+            .append_message(channel.channel_id, &author, "local")
+            .await
+            .unwrap();
+        service.ingest_event(&remote_branch).await.unwrap();
+
+        let extremities = service
+            .store
+            .forward_extremities(channel.channel_id)
+            .await
+            .unwrap();
+        assert_eq!(
+            extremities,
+            vec![
+                local_branch.event_id.clone(),
+                remote_branch.event_id.clone()
+            ]
+        );
+
+        let merge = service
+            .append_message(channel.channel_id, &author, "merge")
+            .await
+            .unwrap();
+        let merge_event: CanonicalEvent = serde_json::from_value(merge.body).unwrap();
+        assert_eq!(
+            merge_event.prev_events,
+            vec![local_branch.event_id, remote_branch.event_id]
+        );
+    }
+
+    #[tokio::test]
+    async fn channel_state_resolves_forked_state_events() {
+        use openguild_core::state::{
+            ChannelNameContent, ChannelTopicContent, CHANNEL_NAME_EVENT, CHANNEL_TOPIC_EVENT,
+        };
+
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("State").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let room_id = channel.channel_id.to_string();
+        join_peer(&service, channel.channel_id, "@local").await;
+
+        let topic = service
+            .append_state_event(
+                channel.channel_id,
+                "@local",
+                &StateContent::Topic(ChannelTopicContent {
+                    topic: "shared".into(),
+                }),
+                "",
+            )
+            .await
+            .unwrap();
+        let mut remote_name = StateContent::Name(ChannelNameContent {
+            name: "remote".into(),
+        })
+        .to_event(
+            "remote.example.org",
+            &room_id,
+            "@peer:remote.example.org",
+            "",
+            vec![topic.event_id.clone()],
+        );
+        // Give the remote fork the newer timestamp so it wins the conflict.
+        remote_name.origin_ts = i64::MAX;
+        remote_name.refresh_event_id();
+        service
+            .append_state_event(
+                channel.channel_id,
+                "@local",
+                &StateContent::Name(ChannelNameContent {
+                    name: "local".into(),
+                }),
+                "",
+            )
+            .await
+            .unwrap();
+        service.ingest_event(&remote_name).await.unwrap();
+
+        let state = service.channel_state(channel.channel_id).await.unwrap();
+        let slots: Vec<_> = state
+            .iter()
+            .filter(|event| event.event_type != state::CHANNEL_MEMBER_EVENT)
+            .filter(|event| event.event_type != state::CHANNEL_JOIN_RULES_EVENT)
+            .map(|event| (event.event_type.as_str(), event.event_id.clone()))
+            .collect();
+        assert_eq!(
+            slots,
+            vec![
+                (CHANNEL_NAME_EVENT, remote_name.event_id.clone()),
+                (CHANNEL_TOPIC_EVENT, topic.event_id.clone()),
+            ]
+        );
+
+        // A message merging both forks carries the resolved state forward.
+        let author = MessageAuthorSnapshot {
+            id: "@local".into(),
+            username: "local".into(),
+            display_name: None,
+        };
+        let merge = service
+            .append_message(channel.channel_id, &author, "merge")
+            .await
+            .unwrap();
+        let merged = service
+            .store
+            .event_state(channel.channel_id, &merge.event_id)
+            .await
+            .unwrap()
+            .expect("state recorded");
+        assert_eq!(
+            merged.get(CHANNEL_NAME_EVENT, ""),
+            Some(&remote_name.event_id)
+        );
+
+        assert!(matches!(
+            service
+                .append_state_event(
+                    channel.channel_id,
+                    "@local",
+                    &StateContent::Name(ChannelNameContent { name: "x".into() }),
+                    "@local",
+                )
+                .await,
+            Err(MessagingError::InvalidState(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn events_are_checked_against_auth_rules() {
+        use openguild_core::{
+            auth::AuthError,
+            state::{ChannelNameContent, Membership, MembershipContent, PowerLevelsContent},
+        };
+
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Auth").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let join = StateContent::Member(MembershipContent {
+            membership: Membership::Join,
+        });
+        let author = |id: &str| MessageAuthorSnapshot {
+            id: id.into(),
+            username: id.trim_start_matches('@').into(),
+            display_name: None,
+        };
+
+        let alice_join = service
+            .append_state_event(channel.channel_id, "@alice", &join, "@alice")
+            .await
+            .unwrap();
+        let message = service
+            .append_message(channel.channel_id, &author("@alice"), "hello")
+            .await
+            .unwrap();
+        let message = service
+            .load_canonical(channel.channel_id, &message.event_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.auth_events, vec![alice_join.event_id.clone()]);
+
+        // Once membership state exists, only joined users may post.
+        assert!(matches!(
+            service
+                .append_message(channel.channel_id, &author("@bob"), "hi")
+                .await,
+            Err(MessagingError::Unauthorized(AuthError::NotJoined { .. }))
+        ));
+
+        let levels = StateContent::PowerLevels(PowerLevelsContent {
+            users: [("@alice".to_string(), 100)].into_iter().collect(),
+            ..PowerLevelsContent::default()
+        });
+        service
+            .append_state_event(channel.channel_id, "@alice", &levels, "")
+            .await
+            .unwrap();
+        // Without a join rules event the channel is invite-only.
+        assert!(matches!(
+            service
+                .append_state_event(channel.channel_id, "@bob", &join, "@bob")
+                .await,
+            Err(MessagingError::Unauthorized(AuthError::Membership(_)))
+        ));
+        let invite = StateContent::Member(MembershipContent {
+            membership: Membership::Invite,
+        });
+        service
+            .append_state_event(channel.channel_id, "@alice", &invite, "@bob")
+            .await
+            .unwrap();
+        service
+            .append_state_event(channel.channel_id, "@bob", &join, "@bob")
+            .await
+            .unwrap();
+        let rename = StateContent::Name(ChannelNameContent {
+            name: "bob's".into(),
+        });
+        assert!(matches!(
+            service
+                .append_state_event(channel.channel_id, "@bob", &rename, "")
+                .await,
+            Err(MessagingError::Unauthorized(
+                AuthError::InsufficientPower { .. }
+            ))
+        ));
+
+        // Federated events must cite auth events this server knows about.
+        let unknown = openguild_core::EventBuilder::new(
+            "remote.example.org",
+            channel.channel_id.to_string(),
+            "message",
+        )
+        .sender("@carol:remote.example.org")
+        .auth_events(vec!["$missing".into()])
+        .build();
+        assert!(matches!(
+            service.ingest_event(&unknown).await,
+            Err(MessagingError::Unauthorized(AuthError::UnknownAuthEvent(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn redactions_respect_authorship_and_power() {
+        use openguild_core::{auth::AuthError, state::PowerLevelsContent};
+
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Redactions").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let author = |id: &str| MessageAuthorSnapshot {
+            id: id.into(),
+            username: id.trim_start_matches('@').into(),
+            display_name: None,
+        };
+        service
+            .append_state_event(
+                channel.channel_id,
+                "@mod",
+                &StateContent::PowerLevels(PowerLevelsContent {
+                    users: [("@mod".to_string(), 50)].into_iter().collect(),
+                    ..PowerLevelsContent::default()
+                }),
+                "",
+            )
+            .await
+            .unwrap();
+        let first = service
+            .append_message(channel.channel_id, &author("@alice"), "first")
+            .await
+            .unwrap();
+        let second = service
+            .append_message(channel.channel_id, &author("@alice"), "second")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            service
+                .redact_event(channel.channel_id, "@bob", &first.event_id, None)
+                .await,
+            Err(MessagingError::Unauthorized(
+                AuthError::InsufficientPower { .. }
+            ))
+        ));
+        let own = service
+            .redact_event(channel.channel_id, "@alice", &first.event_id, None)
+            .await
+            .unwrap();
+        service
+            .redact_event(
+                channel.channel_id,
+                "@mod",
+                &second.event_id,
+                Some("spam".into()),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            service
+                .redact_event(channel.channel_id, "@alice", "$missing", None)
+                .await,
+            Err(MessagingError::EventNotFound(_))
+        ));
+
+        let stored = service
+            .store
+            .event_by_id(channel.channel_id, &first.event_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stored.redacted);
+        assert_eq!(stored.redacted_by.as_deref(), Some(own.event_id.as_str()));
+        let redacted = service
+            .load_canonical(channel.channel_id, &first.event_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(redacted.content, json!({}));
+        assert_eq!(
+            CanonicalEvent::event_id_from_hash(&redacted.canonical_hash()),
+            first.event_id
+        );
+    }
+
+    #[tokio::test]
+    async fn edits_are_limited_to_the_sender_and_follow_redactions() {
+        use openguild_core::auth::AuthError;
+
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Edits").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let author = MessageAuthorSnapshot {
+            id: "@alice".into(),
+            username: "alice".into(),
+            display_name: None,
+        };
+        let original = service
+            .append_message(channel.channel_id, &author, "helo")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            service
+                .edit_message(
+                    channel.channel_id,
+                    "@bob",
+                    &original.event_id,
+                    "hijack".into()
+                )
+                .await,
+            Err(MessagingError::Unauthorized(AuthError::Edit(_)))
+        ));
+        assert!(matches!(
+            service
+                .edit_message(channel.channel_id, "@alice", "$missing", "hello".into())
+                .await,
+            Err(MessagingError::EventNotFound(_))
+        ));
+        let first = service
+            .edit_message(
+                channel.channel_id,
+                "@alice",
+                &original.event_id,
+                "hello".into(),
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let second = service
+            .edit_message(
+                channel.channel_id,
+                "@alice",
+                &original.event_id,
+                "hello!".into(),
+            )
+            .await
+            .unwrap();
+
+        let timeline = service
+            .timeline(channel.channel_id, None, 10, None)
+            .await
+            .unwrap();
+        assert_eq!(timeline.len(), 3);
+        assert_eq!(timeline[0].event["content"]["content"], "helo");
+        let edited = timeline[0]
+            .edited
+            .as_ref()
+            .expect("original carries its edit");
+        assert_eq!(edited.event_id, second.event_id);
+        assert_eq!(edited.content, "hello!");
+        assert_eq!(edited.count, 2);
+        assert!(timeline[1].edited.is_none());
+
+        let history: Vec<_> = service
+            .edit_history(channel.channel_id, &original.event_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|edit| edit.event_id)
+            .collect();
+        assert_eq!(
+            history,
+            vec![first.event_id.clone(), second.event_id.clone()]
+        );
+
+        // Redacting the message takes its edits with it.
+        service
+            .redact_event(channel.channel_id, "@alice", &original.event_id, None)
+            .await
+            .unwrap();
+        let history = service
+            .edit_history(channel.channel_id, &original.event_id)
+            .await
+            .unwrap();
+        assert!(history.iter().all(|edit| edit.redacted));
+        assert_eq!(
+            history[0].body["content"],
+            json!({ "edits": original.event_id })
+        );
+        let timeline = service
+            .timeline(channel.channel_id, None, 10, None)
+            .await
+            .unwrap();
+        assert!(timeline[0].edited.is_none());
+        assert!(matches!(
+            service
+                .edit_message(
+                    channel.channel_id,
+                    "@alice",
+                    &original.event_id,
+                    "again".into()
+                )
+                .await,
+            Err(MessagingError::Unauthorized(AuthError::Edit(_)))
+        ));
+    }
 
-    #[cfg(not(feature = "metrics"))]
-    let _ = matched_path;
+    #[tokio::test]
+    async fn encrypted_channels_relay_ciphertext_and_refuse_plaintext() {
+        use openguild_core::{auth::AuthError, state::EncryptionContent};
 
-    let request_id_value = request_id
-        .header_value()
-        .to_str()
-        .ok()
-        .map(|value| value.to_string());
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Private").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "secrets")
+            .await
+            .unwrap();
+        let author = MessageAuthorSnapshot {
+            id: "@alice".into(),
+            username: "alice".into(),
+            display_name: None,
+        };
+        let plaintext = service
+            .append_message(channel.channel_id, &author, "before")
+            .await
+            .unwrap();
+        let levels = StateContent::PowerLevels(PowerLevelsContent {
+            users: [("@alice".to_string(), 100)].into_iter().collect(),
+            ..PowerLevelsContent::default()
+        });
+        service
+            .append_state_event(channel.channel_id, "@alice", &levels, "")
+            .await
+            .unwrap();
+        service
+            .append_state_event(
+                channel.channel_id,
+                "@alice",
+                &StateContent::Encryption(EncryptionContent {
+                    algorithm: "mls".into(),
+                }),
+                "",
+            )
+            .await
+            .unwrap();
 
-    Ok(messaging
-        .open_notification_socket(claims.user_id, request_id_value, ws)
-        .await)
-}
+        let mut rx = service.subscribe(channel.channel_id).await;
+        let content = EncryptedContent {
+            ciphertext: "AAEAAgAD".into(),
+            epoch: 4,
+            device_id: "laptop".into(),
+        };
+        let sent = service
+            .send_encrypted(channel.channel_id, "@alice", content.clone())
+            .await
+            .unwrap();
+        let outbound = rx.recv().await.unwrap();
+        assert_eq!(outbound.event["event_id"], sent.event_id);
+        assert_eq!(
+            outbound.event["content"],
+            serde_json::to_value(&content).unwrap()
+        );
 
-struct RateLimiter {
-    limits: Mutex<HashMap<String, RateWindow>>,
-    limit: usize,
-    window: Duration,
-}
+        assert!(matches!(
+            service
+                .append_message(channel.channel_id, &author, "after")
+                .await,
+            Err(MessagingError::Unauthorized(AuthError::Encrypted(_)))
+        ));
+        assert!(matches!(
+            service
+                .edit_message(
+                    channel.channel_id,
+                    "@alice",
+                    &plaintext.event_id,
+                    "leak".into()
+                )
+                .await,
+            Err(MessagingError::Unauthorized(AuthError::Encrypted(_)))
+        ));
 
-impl RateLimiter {
-    fn new(limit: usize, window: Duration) -> Self {
-        Self {
-            limits: Mutex::new(HashMap::new()),
-            limit,
-            window,
-        }
+        let timeline = service
+            .timeline(channel.channel_id, None, 10, None)
+            .await
+            .unwrap();
+        let stored = timeline
+            .iter()
+            .find(|event| event.event["event_id"] == sent.event_id)
+            .expect("encrypted event is paginated");
+        assert_eq!(stored.event["event_type"], "encrypted");
+        assert_eq!(stored.event["content"]["epoch"], 4);
     }
 
-    async fn check_and_increment(&self, key: &str) -> bool {
-        let mut limits = self.limits.lock().await;
-        let now = Instant::now();
-        let entry = limits
-            .entry(key.to_string())
-            .or_insert_with(|| RateWindow::new(now, self.window));
-
-        if now >= entry.reset_at {
-            entry.reset(now, self.window);
+    #[tokio::test]
+    async fn threads_track_replies_summaries_and_unread() {
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Threads").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        for user in [alice_id, bob_id] {
+            service
+                .ensure_channel_access(channel.channel_id, user, "member")
+                .await
+                .unwrap();
         }
+        let author = |id: Uuid| MessageAuthorSnapshot {
+            id: id.to_string(),
+            username: "user".into(),
+            display_name: None,
+        };
 
-        if entry.count >= self.limit {
-            return false;
-        }
+        let root = service
+            .append_message(channel.channel_id, &author(alice_id), "root")
+            .await
+            .unwrap();
+        let first = service
+            .append_reply(
+                channel.channel_id,
+                &author(bob_id),
+                "first",
+                None,
+                Some(&root.event_id),
+            )
+            .await
+            .unwrap();
+        // Replying to a reply joins its thread.
+        let second = service
+            .append_reply(
+                channel.channel_id,
+                &author(bob_id),
+                "second",
+                Some(&first.event_id),
+                None,
+            )
+            .await
+            .unwrap();
+        let stored = service
+            .load_canonical(channel.channel_id, &second.event_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.content["thread_root"], root.event_id.as_str());
+        assert_eq!(stored.content["reply_to"], first.event_id.as_str());
+
+        assert!(matches!(
+            service
+                .append_reply(
+                    channel.channel_id,
+                    &author(bob_id),
+                    "nested",
+                    None,
+                    Some(&first.event_id),
+                )
+                .await,
+            Err(MessagingError::InvalidRelation(_))
+        ));
+        assert!(matches!(
+            service
+                .append_reply(
+                    channel.channel_id,
+                    &author(bob_id),
+                    "orphan",
+                    Some("$missing"),
+                    None,
+                )
+                .await,
+            Err(MessagingError::InvalidRelation(_))
+        ));
 
-        entry.count += 1;
-        true
+        let thread = service
+            .thread_timeline(channel.channel_id, &root.event_id, None, 10, None)
+            .await
+            .unwrap();
+        let ids: Vec<_> = thread
+            .iter()
+            .map(|event| event.event["event_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, vec![first.event_id.as_str(), second.event_id.as_str()]);
+
+        let timeline = service
+            .timeline(channel.channel_id, None, 10, None)
+            .await
+            .unwrap();
+        let summary = timeline[0].thread.as_ref().expect("root carries a summary");
+        assert_eq!(summary.reply_count, 2);
+        assert_eq!(summary.last_reply_event_id, second.event_id);
+        assert_eq!(summary.last_reply_sender, bob_id.to_string());
+        assert!(timeline[1].thread.is_none());
+
+        // The root's author tracks the thread; the replier has read up to their reply.
+        let alice_threads = service.user_thread_unread(alice_id).await.unwrap();
+        assert_eq!(alice_threads.len(), 1);
+        assert_eq!(alice_threads[0].unread, 2);
+        let bob_threads = service.user_thread_unread(bob_id).await.unwrap();
+        assert_eq!(bob_threads[0].unread, 0);
+
+        let marked = service
+            .mark_thread_read(
+                channel.channel_id,
+                &root.event_id,
+                alice_id,
+                Some(first.sequence),
+            )
+            .await
+            .unwrap();
+        assert_eq!(marked.unread, 1);
+        assert_eq!(marked.latest_sequence, second.sequence);
+        assert_eq!(
+            service.user_thread_unread(alice_id).await.unwrap()[0].unread,
+            1
+        );
     }
-}
 
-struct RateWindow {
-    count: usize,
-    reset_at: Instant,
-}
+    #[tokio::test]
+    async fn reactions_collapse_per_user_and_aggregate_per_key() {
+        use openguild_core::auth::AuthError;
 
-impl RateWindow {
-    fn new(now: Instant, window: Duration) -> Self {
-        Self {
-            count: 0,
-            reset_at: now + window,
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Reactions").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        for user in [alice_id, bob_id] {
+            service
+                .ensure_channel_access(channel.channel_id, user, "member")
+                .await
+                .unwrap();
         }
-    }
+        let (alice, bob) = (alice_id.to_string(), bob_id.to_string());
+        let author = MessageAuthorSnapshot {
+            id: alice.clone(),
+            username: "alice".into(),
+            display_name: None,
+        };
+        let message = service
+            .append_message(channel.channel_id, &author, "ship it")
+            .await
+            .unwrap();
 
-    fn reset(&mut self, now: Instant, window: Duration) {
-        self.count = 0;
-        self.reset_at = now + window;
+        let mut channel_rx = service.subscribe(channel.channel_id).await;
+        let mut notifications = service.notification_sender(bob_id).await.subscribe();
+        let first = service
+            .add_reaction(channel.channel_id, &alice, &message.event_id, "👍")
+            .await
+            .unwrap();
+        assert_eq!(
+            channel_rx.recv().await.unwrap().event["event_type"],
+            "reaction"
+        );
+        assert_eq!(notifications.recv().await.unwrap().kind, "channel_reaction");
+        let again = service
+            .add_reaction(channel.channel_id, &alice, &message.event_id, "👍")
+            .await
+            .unwrap();
+        assert_eq!(again.event_id, first.event_id);
+        service
+            .add_reaction(channel.channel_id, &bob, &message.event_id, "👍")
+            .await
+            .unwrap();
+        service
+            .add_reaction(channel.channel_id, &bob, &message.event_id, "🎉")
+            .await
+            .unwrap();
+        assert!(matches!(
+            service
+                .add_reaction(channel.channel_id, &alice, &first.event_id, "👍")
+                .await,
+            Err(MessagingError::Unauthorized(AuthError::Reaction(_)))
+        ));
+        assert!(matches!(
+            service
+                .add_reaction(channel.channel_id, &alice, "$missing", "👍")
+                .await,
+            Err(MessagingError::EventNotFound(_))
+        ));
+
+        let timeline = service
+            .timeline(channel.channel_id, None, 10, Some(alice_id))
+            .await
+            .unwrap();
+        assert_eq!(timeline.len(), 4);
+        assert_eq!(
+            timeline[0].reactions,
+            vec![
+                ReactionSummary {
+                    key: "👍".into(),
+                    count: 2,
+                    me: true,
+                },
+                ReactionSummary {
+                    key: "🎉".into(),
+                    count: 1,
+                    me: false,
+                },
+            ]
+        );
+
+        let mut notifications = service.notification_sender(bob_id).await.subscribe();
+        let removed = service
+            .remove_reaction(channel.channel_id, &alice, &message.event_id, "👍")
+            .await
+            .unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].event_type, REDACTION_EVENT);
+        assert_eq!(
+            notifications.recv().await.unwrap().kind,
+            "channel_reaction_removed"
+        );
+        assert!(matches!(
+            service
+                .remove_reaction(channel.channel_id, &alice, &message.event_id, "👍")
+                .await,
+            Err(MessagingError::EventNotFound(_))
+        ));
+        let timeline = service
+            .timeline(channel.channel_id, None, 10, Some(alice_id))
+            .await
+            .unwrap();
+        assert_eq!(timeline[0].reactions[0].count, 1);
+        assert!(!timeline[0].reactions[0].me);
     }
-}
 
-fn client_ip_from_headers(headers: &HeaderMap) -> String {
-    headers
-        .get("x-forwarded-for")
-        .and_then(|value| value.to_str().ok())
-        .and_then(|value| value.split(',').next())
-        .map(|value| value.trim())
-        .filter(|value| !value.is_empty())
-        .map(|value| value.to_string())
-        .unwrap_or_else(|| "unknown".to_string())
-}
+    #[tokio::test]
+    async fn mentions_are_validated_and_notify_with_their_own_kind() {
+        use openguild_core::messaging::Mentions;
 
-#[cfg(feature = "metrics")]
-pub fn init_messaging_service(
-    config: &ServerConfig,
-    pool: Option<StoragePool>,
-    metrics: Option<Arc<MetricsContext>>,
-) -> MessagingService {
-    let origin = config.server_name().to_string();
-    let service = if let Some(pool) = pool {
-        MessagingService::new_with_pool(pool, origin)
-    } else {
-        MessagingService::new_in_memory(origin)
-    };
-    service.with_metrics(metrics)
-}
+        let service = MessagingService::new_in_memory("local.test".to_string());
+        let guild = service.create_guild("Mentions").await.unwrap();
+        let channel = service
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let other_guild = service.create_guild("Elsewhere").await.unwrap();
+        let foreign = service
+            .create_channel(other_guild.guild_id, "general")
+            .await
+            .unwrap();
+        let sibling = service
+            .create_channel(guild.guild_id, "random")
+            .await
+            .unwrap();
+        let (alice_id, bob_id, carol_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        for (user, role) in [
+            (alice_id, "member"),
+            (bob_id, "member"),
+            (carol_id, "Moderator"),
+        ] {
+            service
+                .ensure_channel_access(channel.channel_id, user, role)
+                .await
+                .unwrap();
+        }
+        let author = MessageAuthorSnapshot {
+            id: alice_id.to_string(),
+            username: "alice".into(),
+            display_name: None,
+        };
+        let send = |mentions: Mentions| {
+            service.send_message(
+                channel.channel_id,
+                &author,
+                MessagePayload {
+                    mentions,
+                    ..MessagePayload::new("hey")
+                },
+            )
+        };
 
-#[cfg(not(feature = "metrics"))]
-pub fn init_messaging_service(
-    config: &ServerConfig,
-    pool: Option<StoragePool>,
-) -> MessagingService {
-    let origin = config.server_name().to_string();
-    if let Some(pool) = pool {
-        MessagingService::new_with_pool(pool, origin)
-    } else {
-        MessagingService::new_in_memory(origin)
-    }
-}
+        for mentions in [
+            Mentions {
+                users: vec![Uuid::new_v4().to_string()],
+                ..Mentions::default()
+            },
+            Mentions {
+                roles: vec!["admin".into()],
+                ..Mentions::default()
+            },
+            Mentions {
+                channels: vec![foreign.channel_id.to_string()],
+                ..Mentions::default()
+            },
+        ] {
+            assert!(matches!(
+                send(mentions).await,
+                Err(MessagingError::InvalidMention(_))
+            ));
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use openguild_core::messaging::MessageAuthorSnapshot;
+        let mut notifications: Vec<_> = Vec::new();
+        for user in [bob_id, carol_id, alice_id] {
+            notifications.push(service.notification_sender(user).await.subscribe());
+        }
+        send(Mentions {
+            users: vec![bob_id.to_string()],
+            roles: vec!["moderator".into()],
+            channels: vec![sibling.channel_id.to_string()],
+        })
+        .await
+        .unwrap();
+        let kinds: Vec<_> = notifications
+            .iter_mut()
+            .map(|rx| rx.try_recv().unwrap().kind.clone())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec!["channel_mention", "channel_mention", "channel_message"]
+        );
+    }
 
     #[tokio::test]
-    async fn ensure_channel_access_enrolls_membership_and_reads() {
-        let service = MessagingService::new_in_memory("local.test".to_string());
-        let guild = service.create_guild("Notifications").await.unwrap();
+    async fn append_message_signs_and_queues_for_federation() {
+        use crate::{
+            config::{FederatedServerConfig, FederationConfig},
+            federation::HttpFederationClient,
+            federation_sender::{InMemoryOutboundQueue, OutboundQueue},
+        };
+
+        let config = FederationConfig {
+            trusted_servers: vec![FederatedServerConfig {
+                server_name: "remote.example.org".into(),
+                key_id: "1".into(),
+                verifying_key: None,
+                base_url: None,
+            }],
+            ..FederationConfig::default()
+        };
+        let signer = Arc::new(FederationSigner::from_config(&config).unwrap());
+        let queue = Arc::new(InMemoryOutboundQueue::default());
+        let client =
+            Arc::new(HttpFederationClient::new("local.test".into(), signer.clone()).unwrap());
+        let sender = Arc::new(FederationSender::new(
+            "local.test".into(),
+            &config,
+            queue.clone(),
+            client,
+        ));
+        let service = MessagingService::new_in_memory("local.test".to_string())
+            .with_federation(Some(signer), Some(sender));
+        let guild = service.create_guild("Federated").await.unwrap();
         let channel = service
             .create_channel(guild.guild_id, "general")
             .await
             .unwrap();
-        let user_id = Uuid::new_v4();
         let author = MessageAuthorSnapshot {
-            id: user_id.to_string(),
+            id: "@tester".into(),
             username: "tester".into(),
             display_name: None,
         };
-
+        let join = StateContent::Member(MembershipContent {
+            membership: Membership::Join,
+        });
         service
-            .append_message(channel.channel_id, &author, "hello world")
+            .append_state_event(channel.channel_id, "@tester", &join, "@tester")
             .await
             .unwrap();
 
-        assert!(service
-            .user_channel_unread(user_id)
+        // Servers without members in the channel are not sent its events.
+        service
+            .append_message(channel.channel_id, &author, "nobody remote yet")
+            .await
+            .unwrap();
+        assert!(queue
+            .pending("remote.example.org", 10)
             .await
             .unwrap()
             .is_empty());
 
+        let invite = StateContent::Member(MembershipContent {
+            membership: Membership::Invite,
+        });
         service
-            .ensure_channel_access(channel.channel_id, user_id, "member")
+            .append_state_event(
+                channel.channel_id,
+                "@tester",
+                &invite,
+                "@peer:remote.example.org",
+            )
             .await
             .unwrap();
+        let remote_join = join.to_event(
+            "remote.example.org",
+            &channel.channel_id.to_string(),
+            "@peer:remote.example.org",
+            "@peer:remote.example.org",
+            service
+                .forward_extremities(channel.channel_id)
+                .await
+                .unwrap(),
+        );
+        service.ingest_event(&remote_join).await.unwrap();
 
-        let unread = service.user_channel_unread(user_id).await.unwrap();
-        assert_eq!(unread.len(), 1);
-        let entry = &unread[0];
-        assert_eq!(entry.channel_id, channel.channel_id);
-        assert_eq!(entry.latest_sequence, entry.last_read_sequence);
-    }
-
-    #[tokio::test]
-    async fn guild_notifications_broadcast_when_membership_unknown() {
-        let service = MessagingService::new_in_memory("local.test".to_string());
-        let guild = service.create_guild("Guild").await.unwrap();
-        let channel = service
-            .create_channel(guild.guild_id, "updates")
+        let stored = service
+            .append_message(channel.channel_id, &author, "hello peers")
             .await
             .unwrap();
 
-        let user_id = Uuid::new_v4();
-        let sender = service.notification_sender(user_id).await;
-        let mut rx = sender.subscribe();
-
-        let notification = Arc::new(NotificationEvent {
-            kind: "channel_created".to_string(),
-            channel_id: Some(channel.channel_id),
-            guild_id: Some(guild.guild_id),
-            sequence: None,
-            event: json!({
-                "channel_id": channel.channel_id,
-                "guild_id": guild.guild_id,
-                "name": channel.name,
-            }),
-        });
-
-        service
-            .notify_guild_members(guild.guild_id, notification)
-            .await;
-
-        let delivered = rx.recv().await.expect("notification delivered");
-        assert_eq!(delivered.kind, "channel_created");
+        let event: CanonicalEvent = serde_json::from_value(stored.body).unwrap();
+        assert!(event.signatures["local.test"].contains_key("ed25519:1"));
+        let queued = queue.pending("remote.example.org", 10).await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].event_id, stored.event_id);
     }
 }
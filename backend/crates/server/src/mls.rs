@@ -1,41 +1,111 @@
-use std::{
-    collections::{HashMap, HashSet},
-    sync::RwLock,
-};
+//! MLS key package directory. Devices generate and sign their own key packages and upload
+//! them in batches; the server validates the public parts and hands each package out once
+//! (RFC 9420 §16.8). A per-device last-resort package is returned when the stock runs dry.
+
+use std::sync::Arc;
 
-use anyhow::{self, Context};
+use async_trait::async_trait;
+use axum::{
+    extract::{MatchedPath, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
-use openguild_crypto::{
-    generate_signing_key, sign_message, signing_key_from_base64, verifying_key_from, SigningKey,
+use chrono::{DateTime, Utc};
+use openguild_crypto::{sign_message, verifying_key_from, SigningKey};
+use openguild_storage::{
+    DeviceKeyPackageStock, MlsKeyPackageRecord, MlsKeyPackageStore, NewMlsKeyPackage,
 };
-use openguild_storage::{MlsKeyPackageStore, NewMlsKeyPackage};
-use rand::{rng, RngCore};
+use openmls::prelude::{
+    tls_codec::Deserialize as _, BasicCredential, MlsMessageBodyIn, MlsMessageIn, ProtocolVersion,
+};
+use openmls_rust_crypto::RustCrypto;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use thiserror::Error;
+use tokio::sync::Mutex;
+use uuid::Uuid;
 
-use crate::{session, AppState};
-use axum::{
-    extract::{MatchedPath, Path, State},
-    http::{HeaderMap, StatusCode},
-    Json,
+use crate::{
+    messaging::{MessagingService, NotificationEvent},
+    session, AppState,
 };
-use tracing::error;
+
+/// Longest device id a key package may be filed under.
+pub const MAX_DEVICE_ID_LENGTH: usize = 128;
+/// Upper bound on a single TLS-encoded key package.
+pub const MAX_KEY_PACKAGE_LENGTH: usize = 16 * 1024;
+/// Single-use packages accepted in one upload.
+const MAX_UPLOAD_BATCH: usize = 100;
+/// Unclaimed single-use packages a device may keep on the server.
+const MAX_DEVICE_STOCK: i64 = 500;
+/// Owners are told to upload more once a device has fewer packages than this.
+pub const LOW_KEY_PACKAGE_THRESHOLD: i64 = 10;
 
 #[derive(Debug, Error)]
 pub enum MlsError {
-    #[error("identity '{0}' is not managed by this server")]
-    UnknownIdentity(String),
-    #[error("failed to persist MLS key package: {0}")]
-    Persistence(String),
+    #[error("{0}")]
+    Invalid(&'static str),
+    #[error("device '{0}' already holds {MAX_DEVICE_STOCK} unclaimed key packages")]
+    StockFull(String),
+    #[error("key packages can only be claimed for users who share a channel with the claimer")]
+    NoSharedChannel,
+    #[error(transparent)]
+    Storage(#[from] anyhow::Error),
 }
 
+/// A key package as published by the directory. `key_package` is only meaningful to the
+/// client that adds this device to a group.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PublicKeyPackage {
+    pub user_id: Uuid,
+    pub device_id: String,
+    /// URL-safe base64 of the `KeyPackageRef`.
+    pub key_package_ref: String,
+    /// Identity from the package's basic credential.
     pub identity: String,
     pub ciphersuite: String,
     pub signature_key: String,
     pub hpke_public_key: String,
+    /// URL-safe base64 of the uploaded TLS-encoded `MLSMessage`.
+    pub key_package: String,
+    pub last_resort: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<MlsKeyPackageRecord> for PublicKeyPackage {
+    fn from(record: MlsKeyPackageRecord) -> Self {
+        Self {
+            user_id: record.user_id,
+            device_id: record.device_id,
+            key_package_ref: record.key_package_ref,
+            identity: record.identity,
+            ciphersuite: record.ciphersuite,
+            signature_key: record.signature_key,
+            hpke_public_key: record.hpke_public_key,
+            key_package: URL_SAFE_NO_PAD.encode(&record.key_package),
+            last_resort: record.last_resort,
+            created_at: record.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyPackageStock {
+    pub device_id: String,
+    pub available: i64,
+    pub last_resort: bool,
+}
+
+impl From<DeviceKeyPackageStock> for KeyPackageStock {
+    fn from(stock: DeviceKeyPackageStock) -> Self {
+        Self {
+            device_id: stock.device_id,
+            available: stock.available,
+            last_resort: stock.last_resort,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,184 +117,551 @@ pub struct HandshakeTestVector {
     pub verifying_key: String,
 }
 
-struct StoredKeyPackage {
-    identity: String,
-    ciphersuite: String,
-    signing: SigningKey,
-    hpke_public: [u8; 32],
+#[async_trait]
+pub trait KeyPackageStore: Send + Sync {
+    /// Store `packages`, or return `None` without storing anything when a device would
+    /// hold more than `max_stock` single-use packages.
+    async fn upload(
+        &self,
+        packages: Vec<NewMlsKeyPackage>,
+        max_stock: i64,
+    ) -> Result<Option<u64>, MlsError>;
+    async fn claim(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<MlsKeyPackageRecord>, MlsError>;
+    async fn last_resort_package(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<MlsKeyPackageRecord>, MlsError>;
+    async fn stock(&self, user_id: Uuid) -> Result<Vec<DeviceKeyPackageStock>, MlsError>;
+    async fn packages_for_user(&self, user_id: Uuid) -> Result<Vec<MlsKeyPackageRecord>, MlsError>;
 }
 
-impl StoredKeyPackage {
-    fn generate(identity: &str, ciphersuite: &str) -> Self {
-        let signing = generate_signing_key();
-        let mut hpke_public = [0u8; 32];
-        rng().fill_bytes(&mut hpke_public);
-        Self {
-            identity: identity.to_owned(),
-            ciphersuite: ciphersuite.to_owned(),
-            signing,
-            hpke_public,
-        }
+#[async_trait]
+impl KeyPackageStore for MlsKeyPackageStore {
+    async fn upload(
+        &self,
+        packages: Vec<NewMlsKeyPackage>,
+        max_stock: i64,
+    ) -> Result<Option<u64>, MlsError> {
+        Ok(MlsKeyPackageStore::upload(self, &packages, max_stock).await?)
     }
 
-    fn from_persisted(record: &openguild_storage::MlsKeyPackageRecord) -> anyhow::Result<Self> {
-        let signing = signing_key_from_base64(&record.signing_key)
-            .map_err(|err| anyhow::anyhow!(err))
-            .context("decoding persisted MLS signing key")?;
-        let decoded = URL_SAFE_NO_PAD
-            .decode(record.hpke_public_key.as_bytes())
-            .context("decoding persisted MLS HPKE public key")?;
-        let hpke_public: [u8; 32] = decoded
-            .try_into()
-            .map_err(|_| anyhow::anyhow!("hpke public key must be 32 bytes"))?;
-
-        Ok(Self {
-            identity: record.identity.clone(),
-            ciphersuite: record.ciphersuite.clone(),
-            signing,
-            hpke_public,
-        })
+    async fn claim(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<MlsKeyPackageRecord>, MlsError> {
+        Ok(MlsKeyPackageStore::claim(self, user_id, device_id).await?)
     }
 
-    fn package(&self) -> PublicKeyPackage {
-        let verifying = verifying_key_from(&self.signing);
-        PublicKeyPackage {
-            identity: self.identity.clone(),
-            ciphersuite: self.ciphersuite.clone(),
-            signature_key: URL_SAFE_NO_PAD.encode(verifying.to_bytes()),
-            hpke_public_key: URL_SAFE_NO_PAD.encode(self.hpke_public),
+    async fn last_resort_package(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<MlsKeyPackageRecord>, MlsError> {
+        Ok(MlsKeyPackageStore::last_resort_package(self, user_id, device_id).await?)
+    }
+
+    async fn stock(&self, user_id: Uuid) -> Result<Vec<DeviceKeyPackageStock>, MlsError> {
+        Ok(MlsKeyPackageStore::stock(self, user_id).await?)
+    }
+
+    async fn packages_for_user(&self, user_id: Uuid) -> Result<Vec<MlsKeyPackageRecord>, MlsError> {
+        Ok(MlsKeyPackageStore::packages_for_user(self, user_id).await?)
+    }
+}
+
+/// Non-durable key package store used when Postgres is not configured.
+#[derive(Default)]
+pub struct InMemoryKeyPackages {
+    packages: Mutex<Vec<MlsKeyPackageRecord>>,
+}
+
+#[async_trait]
+impl KeyPackageStore for InMemoryKeyPackages {
+    async fn upload(
+        &self,
+        packages: Vec<NewMlsKeyPackage>,
+        max_stock: i64,
+    ) -> Result<Option<u64>, MlsError> {
+        let mut stored = self.packages.lock().await;
+        for package in packages.iter().filter(|package| !package.last_resort) {
+            let same_device = |other: &&MlsKeyPackageRecord| {
+                !other.last_resort
+                    && other.user_id == package.user_id
+                    && other.device_id == package.device_id
+            };
+            let uploaded = packages
+                .iter()
+                .filter(|other| {
+                    !other.last_resort
+                        && other.user_id == package.user_id
+                        && other.device_id == package.device_id
+                })
+                .count();
+            if (stored.iter().filter(same_device).count() + uploaded) as i64 > max_stock {
+                return Ok(None);
+            }
+        }
+        let mut count = 0;
+        for package in packages {
+            if stored
+                .iter()
+                .any(|existing| existing.key_package_ref == package.key_package_ref)
+            {
+                continue;
+            }
+            if package.last_resort {
+                stored.retain(|existing| {
+                    !(existing.last_resort
+                        && existing.user_id == package.user_id
+                        && existing.device_id == package.device_id)
+                });
+            }
+            stored.push(MlsKeyPackageRecord {
+                id: Uuid::new_v4(),
+                user_id: package.user_id,
+                device_id: package.device_id,
+                key_package_ref: package.key_package_ref,
+                identity: package.identity,
+                ciphersuite: package.ciphersuite,
+                signature_key: package.signature_key,
+                hpke_public_key: package.hpke_public_key,
+                key_package: package.key_package,
+                last_resort: package.last_resort,
+                created_at: Utc::now(),
+            });
+            count += 1;
         }
+        Ok(Some(count))
     }
 
-    fn to_new_package(&self) -> NewMlsKeyPackage {
-        let signing_key = URL_SAFE_NO_PAD.encode(self.signing.to_bytes());
-        let verifying_key = URL_SAFE_NO_PAD.encode(verifying_key_from(&self.signing).to_bytes());
-        let hpke_public_key = URL_SAFE_NO_PAD.encode(self.hpke_public);
-        NewMlsKeyPackage {
-            identity: self.identity.clone(),
-            ciphersuite: self.ciphersuite.clone(),
-            signing_key,
-            signature_key: verifying_key,
-            hpke_public_key,
+    async fn claim(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<MlsKeyPackageRecord>, MlsError> {
+        let mut stored = self.packages.lock().await;
+        let position = stored.iter().position(|package| {
+            !package.last_resort && package.user_id == user_id && package.device_id == device_id
+        });
+        if let Some(position) = position {
+            return Ok(Some(stored.remove(position)));
         }
+        Ok(stored
+            .iter()
+            .find(|package| {
+                package.last_resort && package.user_id == user_id && package.device_id == device_id
+            })
+            .cloned())
+    }
+
+    async fn last_resort_package(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<MlsKeyPackageRecord>, MlsError> {
+        Ok(self
+            .packages
+            .lock()
+            .await
+            .iter()
+            .find(|package| {
+                package.last_resort && package.user_id == user_id && package.device_id == device_id
+            })
+            .cloned())
+    }
+
+    async fn stock(&self, user_id: Uuid) -> Result<Vec<DeviceKeyPackageStock>, MlsError> {
+        let stored = self.packages.lock().await;
+        let mut stock: Vec<DeviceKeyPackageStock> = Vec::new();
+        for package in stored.iter().filter(|package| package.user_id == user_id) {
+            let index = match stock
+                .iter()
+                .position(|entry| entry.device_id == package.device_id)
+            {
+                Some(index) => index,
+                None => {
+                    stock.push(DeviceKeyPackageStock {
+                        device_id: package.device_id.clone(),
+                        available: 0,
+                        last_resort: false,
+                    });
+                    stock.len() - 1
+                }
+            };
+            if package.last_resort {
+                stock[index].last_resort = true;
+            } else {
+                stock[index].available += 1;
+            }
+        }
+        stock.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        Ok(stock)
+    }
+
+    async fn packages_for_user(&self, user_id: Uuid) -> Result<Vec<MlsKeyPackageRecord>, MlsError> {
+        let mut packages: Vec<_> = self
+            .packages
+            .lock()
+            .await
+            .iter()
+            .filter(|package| package.user_id == user_id)
+            .cloned()
+            .collect();
+        packages.sort_by(|a, b| {
+            (&a.device_id, a.last_resort, a.created_at).cmp(&(
+                &b.device_id,
+                b.last_resort,
+                b.created_at,
+            ))
+        });
+        Ok(packages)
     }
 }
 
+/// SFrame cipher suite (RFC 9605) using the same AEAD and hash as an MLS ciphersuite, or
+/// `None` when SFrame defines no match (ChaCha20-Poly1305 and SHA-384 suites).
+pub fn sframe_suite_for(ciphersuite: &str) -> Option<&'static str> {
+    if ciphersuite.contains("_AES128GCM_SHA256_") {
+        Some("AES_128_GCM_SHA256_128")
+    } else if ciphersuite.contains("_AES256GCM_SHA512_") {
+        Some("AES_256_GCM_SHA512_128")
+    } else {
+        None
+    }
+}
+
+fn validate_device_id(device_id: &str) -> Result<(), MlsError> {
+    if device_id.trim().is_empty() || device_id.len() > MAX_DEVICE_ID_LENGTH {
+        return Err(MlsError::Invalid("device_id must be 1-128 bytes"));
+    }
+    Ok(())
+}
+
+/// Key packages a device uploads in one request, each a URL-safe base64 `MLSMessage`.
+#[derive(Debug, Deserialize)]
+pub struct UploadKeyPackagesRequest {
+    pub device_id: String,
+    #[serde(default)]
+    pub key_packages: Vec<String>,
+    /// Replaces the device's last-resort package; must carry the `last_resort` extension.
+    #[serde(default)]
+    pub last_resort: Option<String>,
+}
+
 pub struct MlsKeyStore {
     ciphersuite: String,
-    packages: RwLock<HashMap<String, StoredKeyPackage>>,
-    persistence: Option<MlsKeyPackageStore>,
+    store: Arc<dyn KeyPackageStore>,
+    messaging: Option<Arc<MessagingService>>,
 }
 
 impl MlsKeyStore {
-    pub fn new(ciphersuite: impl Into<String>, identities: Vec<String>) -> Self {
-        let ciphersuite = ciphersuite.into();
-        let packages = identities
-            .into_iter()
-            .map(|identity| {
-                (
-                    identity.clone(),
-                    StoredKeyPackage::generate(&identity, &ciphersuite),
-                )
-            })
-            .collect();
-        Self::from_parts(ciphersuite, packages, None)
+    /// Directory that keeps uploaded packages in memory.
+    pub fn new(ciphersuite: impl Into<String>) -> Self {
+        Self::with_store(ciphersuite, Arc::new(InMemoryKeyPackages::default()))
     }
 
-    pub async fn with_persistence(
-        ciphersuite: impl Into<String>,
-        identities: Vec<String>,
-        store: MlsKeyPackageStore,
-    ) -> anyhow::Result<Self> {
-        let ciphersuite = ciphersuite.into();
-        let mut packages = HashMap::new();
-        let mut remaining: HashSet<String> = identities.iter().cloned().collect();
+    pub fn with_persistence(ciphersuite: impl Into<String>, store: MlsKeyPackageStore) -> Self {
+        Self::with_store(ciphersuite, Arc::new(store))
+    }
 
-        let persisted = store.latest_packages().await?;
-        for record in persisted {
-            if !remaining.contains(&record.identity) {
-                continue;
-            }
-            let stored = StoredKeyPackage::from_persisted(&record)
-                .with_context(|| format!("reconstructing MLS package for '{}'", record.identity))?;
-            remaining.remove(&record.identity);
-            packages.insert(record.identity.clone(), stored);
+    fn with_store(ciphersuite: impl Into<String>, store: Arc<dyn KeyPackageStore>) -> Self {
+        Self {
+            ciphersuite: ciphersuite.into(),
+            store,
+            messaging: None,
         }
+    }
 
-        for identity in remaining {
-            let package = StoredKeyPackage::generate(&identity, &ciphersuite);
-            store
-                .record_package(&package.to_new_package())
-                .await
-                .with_context(|| format!("persisting MLS package for '{identity}'"))?;
-            packages.insert(identity.clone(), package);
+    /// Let users claim packages of anyone they share a channel with, and send
+    /// `mls_key_packages_low` to owners whose devices are running out of packages. Without
+    /// it users can only claim their own packages.
+    pub fn with_messaging(mut self, messaging: Arc<MessagingService>) -> Self {
+        self.messaging = Some(messaging);
+        self
+    }
+
+    /// SFrame suite for voice media keys derived from this store's ciphersuite.
+    pub fn sframe_suite(&self) -> Option<&'static str> {
+        sframe_suite_for(&self.ciphersuite)
+    }
+
+    /// Check signatures, lifetime and ciphersuite of an uploaded `MLSMessage` carrying a
+    /// `KeyPackage`, and read out the public fields the directory publishes.
+    fn parse_key_package(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+        encoded: &str,
+        last_resort: bool,
+    ) -> Result<NewMlsKeyPackage, MlsError> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|_| MlsError::Invalid("key packages must be URL-safe base64"))?;
+        if bytes.len() > MAX_KEY_PACKAGE_LENGTH {
+            return Err(MlsError::Invalid("key package exceeds 16 KiB"));
+        }
+        let message = MlsMessageIn::tls_deserialize_exact(&bytes)
+            .map_err(|_| MlsError::Invalid("key package is not a TLS-encoded MLSMessage"))?;
+        let MlsMessageBodyIn::KeyPackage(package) = message.extract() else {
+            return Err(MlsError::Invalid("MLSMessage does not carry a KeyPackage"));
+        };
+        let crypto = RustCrypto::default();
+        let package = package
+            .validate(&crypto, ProtocolVersion::Mls10)
+            .map_err(|_| MlsError::Invalid("key package signature or lifetime is invalid"))?;
+        if package.ciphersuite().to_string() != self.ciphersuite {
+            return Err(MlsError::Invalid(
+                "key package ciphersuite does not match the server's",
+            ));
+        }
+        if package.last_resort() != last_resort {
+            return Err(MlsError::Invalid(
+                "only the last-resort package may carry the last_resort extension",
+            ));
+        }
+        let credential = BasicCredential::try_from(package.leaf_node().credential().clone())
+            .map_err(|_| MlsError::Invalid("key package must use a basic credential"))?;
+        if credential.identity() != user_id.to_string().as_bytes() {
+            return Err(MlsError::Invalid(
+                "credential identity must be the uploader's user id",
+            ));
+        }
+        let identity = user_id.to_string();
+        let key_package_ref = package
+            .hash_ref(&crypto)
+            .map_err(|_| MlsError::Invalid("key package reference cannot be computed"))?;
+
+        Ok(NewMlsKeyPackage {
+            user_id,
+            device_id: device_id.to_string(),
+            key_package_ref: URL_SAFE_NO_PAD.encode(key_package_ref.as_slice()),
+            identity,
+            ciphersuite: self.ciphersuite.clone(),
+            signature_key: URL_SAFE_NO_PAD.encode(package.leaf_node().signature_key().as_slice()),
+            hpke_public_key: URL_SAFE_NO_PAD.encode(package.hpke_init_key().as_slice()),
+            key_package: bytes,
+            last_resort,
+        })
+    }
+
+    /// Store a device's batch and return its stock afterwards.
+    pub async fn upload(
+        &self,
+        user_id: Uuid,
+        request: UploadKeyPackagesRequest,
+    ) -> Result<KeyPackageStock, MlsError> {
+        let device_id = request.device_id.trim();
+        validate_device_id(device_id)?;
+        if request.key_packages.is_empty() && request.last_resort.is_none() {
+            return Err(MlsError::Invalid("upload at least one key package"));
+        }
+        if request.key_packages.len() > MAX_UPLOAD_BATCH {
+            return Err(MlsError::Invalid("upload at most 100 key packages at once"));
         }
 
-        Ok(Self::from_parts(ciphersuite, packages, Some(store)))
+        let mut packages = Vec::with_capacity(request.key_packages.len() + 1);
+        for encoded in &request.key_packages {
+            packages.push(self.parse_key_package(user_id, device_id, encoded, false)?);
+        }
+        if let Some(encoded) = &request.last_resort {
+            packages.push(self.parse_key_package(user_id, device_id, encoded, true)?);
+        }
+
+        if self
+            .store
+            .upload(packages, MAX_DEVICE_STOCK)
+            .await?
+            .is_none()
+        {
+            return Err(MlsError::StockFull(device_id.to_string()));
+        }
+        self.device_stock(user_id, device_id).await
     }
 
-    fn from_parts(
-        ciphersuite: String,
-        packages: HashMap<String, StoredKeyPackage>,
-        persistence: Option<MlsKeyPackageStore>,
-    ) -> Self {
-        Self {
-            ciphersuite,
-            packages: RwLock::new(packages),
-            persistence,
+    async fn device_stock(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<KeyPackageStock, MlsError> {
+        let stock = self
+            .store
+            .stock(user_id)
+            .await?
+            .into_iter()
+            .find(|stock| stock.device_id == device_id)
+            .map(KeyPackageStock::from)
+            .unwrap_or(KeyPackageStock {
+                device_id: device_id.to_string(),
+                available: 0,
+                last_resort: false,
+            });
+        Ok(stock)
+    }
+
+    /// Hand out one package for each of `user_id`'s devices, or only for `device_ids`.
+    /// Devices with neither a single-use nor a last-resort package are left out. `claimer`
+    /// must be `user_id` or share a channel with them, so strangers cannot drain the stock.
+    pub async fn claim(
+        &self,
+        claimer: Uuid,
+        user_id: Uuid,
+        device_ids: Option<Vec<String>>,
+    ) -> Result<Vec<PublicKeyPackage>, MlsError> {
+        if claimer != user_id {
+            let shares_channel = match &self.messaging {
+                Some(messaging) => messaging
+                    .shares_channel(claimer, user_id)
+                    .await
+                    .map_err(|err| MlsError::Storage(err.into()))?,
+                None => false,
+            };
+            if !shares_channel {
+                return Err(MlsError::NoSharedChannel);
+            }
+        }
+        let device_ids = match device_ids {
+            Some(device_ids) => {
+                if device_ids.len() > MAX_UPLOAD_BATCH {
+                    return Err(MlsError::Invalid("claim at most 100 devices at once"));
+                }
+                for device_id in &device_ids {
+                    validate_device_id(device_id)?;
+                }
+                device_ids
+            }
+            None => self
+                .store
+                .stock(user_id)
+                .await?
+                .into_iter()
+                .map(|stock| stock.device_id)
+                .collect(),
+        };
+
+        let mut claimed = Vec::with_capacity(device_ids.len());
+        for device_id in &device_ids {
+            if let Some(record) = self.store.claim(user_id, device_id).await? {
+                claimed.push(PublicKeyPackage::from(record));
+            }
+        }
+        if !claimed.is_empty() {
+            self.notify_low_stock(user_id, &claimed).await;
+        }
+        Ok(claimed)
+    }
+
+    async fn notify_low_stock(&self, user_id: Uuid, claimed: &[PublicKeyPackage]) {
+        let Some(messaging) = &self.messaging else {
+            return;
+        };
+        let stock = match self.store.stock(user_id).await {
+            Ok(stock) => stock,
+            Err(err) => {
+                tracing::warn!(?err, %user_id, "failed to read MLS key package stock");
+                return;
+            }
+        };
+        for device in stock {
+            if device.available >= LOW_KEY_PACKAGE_THRESHOLD
+                || !claimed
+                    .iter()
+                    .any(|package| package.device_id == device.device_id)
+            {
+                continue;
+            }
+            let notification = Arc::new(NotificationEvent {
+                kind: "mls_key_packages_low".to_string(),
+                channel_id: None,
+                guild_id: None,
+                sequence: None,
+                event: json!({
+                    "device_id": device.device_id,
+                    "available": device.available,
+                    "last_resort": device.last_resort,
+                }),
+            });
+            messaging.notify_users(&[user_id], notification).await;
         }
     }
 
-    pub fn list_packages(&self) -> Vec<PublicKeyPackage> {
-        let packages = self.packages.read().expect("packages lock poisoned");
-        let mut result: Vec<_> = packages.values().map(|pkg| pkg.package()).collect();
-        result.sort_by(|a, b| a.identity.cmp(&b.identity));
-        result
+    /// The caller's own unclaimed packages across all of their devices.
+    pub async fn packages_for_user(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<PublicKeyPackage>, MlsError> {
+        Ok(self
+            .store
+            .packages_for_user(user_id)
+            .await?
+            .into_iter()
+            .map(PublicKeyPackage::from)
+            .collect())
+    }
+
+    /// A device's reusable last-resort package, which voice media keys are sealed to.
+    pub async fn last_resort_package(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<PublicKeyPackage>, MlsError> {
+        Ok(self
+            .store
+            .last_resort_package(user_id, device_id)
+            .await?
+            .map(PublicKeyPackage::from))
     }
 
+    /// Signature over a constant message with a key derived from a public seed, so clients
+    /// can check their Ed25519 implementation against the server's.
     pub fn handshake_vectors(&self) -> Vec<HandshakeTestVector> {
         const MESSAGE: &str = "OpenGuild MLS handshake test vector v1";
-        let packages = self.packages.read().expect("packages lock poisoned");
-        let mut vectors: Vec<_> = packages
-            .values()
-            .map(|pkg| {
-                let signature = sign_message(&pkg.signing, MESSAGE.as_bytes());
-                HandshakeTestVector {
-                    identity: pkg.identity.clone(),
-                    ciphersuite: pkg.ciphersuite.clone(),
-                    message: MESSAGE.to_string(),
-                    signature: URL_SAFE_NO_PAD.encode(signature.to_bytes()),
-                    verifying_key: URL_SAFE_NO_PAD
-                        .encode(verifying_key_from(&pkg.signing).to_bytes()),
-                }
-            })
-            .collect();
-        vectors.sort_by(|a, b| a.identity.cmp(&b.identity));
-        vectors
+        const IDENTITY: &str = "openguild-test-vector";
+        let seed = blake3::hash(format!("{IDENTITY}:{}", self.ciphersuite).as_bytes());
+        let signing = SigningKey::from_bytes(seed.as_bytes());
+        let signature = sign_message(&signing, MESSAGE.as_bytes());
+        vec![HandshakeTestVector {
+            identity: IDENTITY.to_string(),
+            ciphersuite: self.ciphersuite.clone(),
+            message: MESSAGE.to_string(),
+            signature: URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            verifying_key: URL_SAFE_NO_PAD.encode(verifying_key_from(&signing).to_bytes()),
+        }]
     }
+}
 
-    pub async fn rotate(&self, identity: &str) -> Result<PublicKeyPackage, MlsError> {
-        if !self
-            .packages
-            .read()
-            .expect("packages lock poisoned")
-            .contains_key(identity)
-        {
-            return Err(MlsError::UnknownIdentity(identity.to_owned()));
-        }
+#[derive(Debug, Deserialize)]
+pub struct ClaimKeyPackagesRequest {
+    pub user_id: Uuid,
+    /// Devices to claim for; every device with packages when omitted.
+    #[serde(default)]
+    pub device_ids: Option<Vec<String>>,
+}
 
-        let new_package = StoredKeyPackage::generate(identity, &self.ciphersuite);
-        if let Some(store) = &self.persistence {
-            store
-                .record_package(&new_package.to_new_package())
-                .await
-                .map_err(|err| MlsError::Persistence(err.to_string()))?;
+fn status_for(state: &AppState, err: &MlsError) -> StatusCode {
+    match err {
+        MlsError::Invalid(reason) => {
+            tracing::debug!(reason, "rejected MLS key package request");
+            state.record_messaging_rejection("mls_invalid_key_package");
+            StatusCode::BAD_REQUEST
+        }
+        MlsError::StockFull(_) => {
+            state.record_messaging_rejection("mls_key_package_stock_full");
+            StatusCode::CONFLICT
+        }
+        MlsError::NoSharedChannel => {
+            state.record_messaging_rejection("mls_key_package_claim_forbidden");
+            StatusCode::FORBIDDEN
+        }
+        MlsError::Storage(err) => {
+            tracing::error!(?err, "MLS key package storage failure");
+            StatusCode::INTERNAL_SERVER_ERROR
         }
-
-        let public = new_package.package();
-        let mut packages = self.packages.write().expect("packages lock poisoned");
-        packages.insert(identity.to_owned(), new_package);
-        Ok(public)
     }
 }
 
@@ -240,7 +677,7 @@ pub async fn list_key_packages(
         return Err(status);
     };
 
-    session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
         #[cfg(feature = "metrics")]
         state.record_http_request(matched_path.as_str(), status.as_u16());
         #[cfg(not(feature = "metrics"))]
@@ -250,19 +687,25 @@ pub async fn list_key_packages(
     #[cfg(not(feature = "metrics"))]
     let _ = matched_path;
 
-    let packages = mls.list_packages();
+    let status = match mls.packages_for_user(claims.user_id).await {
+        Ok(packages) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(packages));
+        }
+        Err(err) => status_for(&state, &err),
+    };
     #[cfg(feature = "metrics")]
-    state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
-
-    Ok(Json(packages))
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
 }
 
-pub async fn rotate_key_package(
+pub async fn upload_key_packages(
     matched_path: MatchedPath,
-    Path(identity): Path<String>,
     State(state): State<AppState>,
     headers: HeaderMap,
-) -> Result<Json<PublicKeyPackage>, StatusCode> {
+    Json(body): Json<UploadKeyPackagesRequest>,
+) -> Result<(StatusCode, Json<KeyPackageStock>), StatusCode> {
     let Some(mls) = state.mls() else {
         let status = StatusCode::NOT_IMPLEMENTED;
         #[cfg(feature = "metrics")]
@@ -270,7 +713,7 @@ pub async fn rotate_key_package(
         return Err(status);
     };
 
-    session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
         #[cfg(feature = "metrics")]
         state.record_http_request(matched_path.as_str(), status.as_u16());
         #[cfg(not(feature = "metrics"))]
@@ -280,27 +723,57 @@ pub async fn rotate_key_package(
     #[cfg(not(feature = "metrics"))]
     let _ = matched_path;
 
-    match mls.rotate(&identity).await {
-        Ok(package) => {
+    let status = match mls.upload(claims.user_id, body).await {
+        Ok(stock) => {
+            let status = StatusCode::CREATED;
             #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
-            Ok(Json(package))
-        }
-        Err(MlsError::UnknownIdentity(_)) => {
-            #[cfg(feature = "metrics")]
-            state.record_http_request(matched_path.as_str(), StatusCode::NOT_FOUND.as_u16());
-            Err(StatusCode::NOT_FOUND)
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Ok((status, Json(stock)));
         }
-        Err(MlsError::Persistence(err)) => {
-            error!(identity, %err, "failed to persist MLS key package rotation");
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn claim_key_packages(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<ClaimKeyPackagesRequest>,
+) -> Result<Json<Vec<PublicKeyPackage>>, StatusCode> {
+    let Some(mls) = state.mls() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match mls
+        .claim(claims.user_id, body.user_id, body.device_ids)
+        .await
+    {
+        Ok(packages) => {
             #[cfg(feature = "metrics")]
-            state.record_http_request(
-                matched_path.as_str(),
-                StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            );
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(packages));
         }
-    }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
 }
 
 pub async fn handshake_test_vectors(
@@ -335,43 +808,146 @@ pub async fn handshake_test_vectors(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mls_groups::test_client::{TestClient, CIPHERSUITE};
     use openguild_crypto::{verifying_key_from_base64, Signature};
     use std::convert::TryInto;
 
-    #[tokio::test]
-    async fn generates_and_rotates_packages() {
-        let store = MlsKeyStore::new(
-            "MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519",
-            vec!["alice".into(), "bob".into()],
-        );
+    fn store() -> MlsKeyStore {
+        MlsKeyStore::new(CIPHERSUITE.to_string())
+    }
 
-        let packages = store.list_packages();
-        assert_eq!(packages.len(), 2);
+    fn upload(device_id: &str, client: &TestClient, count: usize) -> UploadKeyPackagesRequest {
+        UploadKeyPackagesRequest {
+            device_id: device_id.into(),
+            key_packages: (0..count)
+                .map(|_| client.encoded_key_package(false))
+                .collect(),
+            last_resort: Some(client.encoded_key_package(true)),
+        }
+    }
 
-        let alice_before = packages
-            .iter()
-            .find(|pkg| pkg.identity == "alice")
+    #[tokio::test]
+    async fn uploaded_packages_are_claimed_once() {
+        let messaging = Arc::new(MessagingService::new_in_memory("example.org".into()));
+        let store = store().with_messaging(messaging.clone());
+        let alice = Uuid::new_v4();
+        let mut notifications = messaging.notification_sender(alice).await.subscribe();
+        let client = TestClient::new(&alice.to_string());
+
+        let stock = store
+            .upload(alice, upload("laptop", &client, 2))
+            .await
+            .expect("upload succeeds");
+        assert_eq!(stock.available, 2);
+        assert!(stock.last_resort);
+
+        let first = store
+            .claim(alice, alice, None)
+            .await
+            .expect("claim succeeds");
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].identity, alice.to_string());
+        assert!(!first[0].last_resort);
+        let second = store
+            .claim(alice, alice, Some(vec!["laptop".into()]))
+            .await
+            .unwrap();
+        assert_ne!(first[0].key_package_ref, second[0].key_package_ref);
+        let low = notifications.try_recv().expect("low stock notification");
+        assert_eq!(low.kind, "mls_key_packages_low");
+        assert_eq!(low.event["device_id"], "laptop");
+        assert_eq!(low.event["available"], 1);
+
+        for _ in 0..2 {
+            let fallback = store.claim(alice, alice, None).await.unwrap();
+            assert!(fallback[0].last_resort, "last resort is reused");
+        }
+        assert_eq!(store.packages_for_user(alice).await.unwrap().len(), 1);
+        assert!(store
+            .claim(alice, alice, Some(vec!["phone".into()]))
+            .await
             .unwrap()
-            .signature_key
-            .clone();
+            .is_empty());
+
+        let bob = Uuid::new_v4();
+        assert!(matches!(
+            store.claim(bob, alice, None).await,
+            Err(MlsError::NoSharedChannel)
+        ));
+        let guild = messaging.create_guild("guild").await.unwrap();
+        let channel = messaging
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        for user_id in [alice, bob] {
+            messaging
+                .upsert_channel_membership(channel.channel_id, user_id, "member")
+                .await
+                .unwrap();
+        }
+        let shared = store.claim(bob, alice, None).await.unwrap();
+        assert_eq!(shared.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_forged_or_mislabelled_packages() {
+        let store = store();
+        let alice = Uuid::new_v4();
+        let client = TestClient::new(&alice.to_string());
+        let impostor = TestClient::new(&Uuid::new_v4().to_string());
+
+        let mut tampered = URL_SAFE_NO_PAD
+            .decode(client.encoded_key_package(false))
+            .unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0xff;
+        let cases = [
+            vec![URL_SAFE_NO_PAD.encode(tampered)],
+            vec!["not base64!".into()],
+            vec![client.encoded_key_package(true)],
+            vec![impostor.encoded_key_package(false)],
+        ];
+        for key_packages in cases {
+            let request = UploadKeyPackagesRequest {
+                device_id: "laptop".into(),
+                key_packages,
+                last_resort: None,
+            };
+            assert!(matches!(
+                store.upload(alice, request).await,
+                Err(MlsError::Invalid(_))
+            ));
+        }
 
-        let rotated = store.rotate("alice").await.expect("rotation succeeds");
-        assert_eq!(rotated.identity, "alice");
-        assert_ne!(rotated.signature_key, alice_before);
-        assert!(store.rotate("carol").await.is_err());
+        let other_suite = MlsKeyStore::new("MLS_256_DHKEMX448_AES256GCM_SHA512_Ed448");
+        assert!(matches!(
+            other_suite
+                .upload(alice, upload("laptop", &client, 1))
+                .await,
+            Err(MlsError::Invalid(_))
+        ));
+        assert!(store.packages_for_user(alice).await.unwrap().is_empty());
     }
 
     #[test]
-    fn handshake_vectors_sign_constant_message() {
-        let store = MlsKeyStore::new(
-            "MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519",
-            vec!["alice".into()],
+    fn sframe_suites_follow_the_mls_aead() {
+        assert_eq!(store().sframe_suite(), Some("AES_128_GCM_SHA256_128"));
+        assert_eq!(
+            sframe_suite_for("MLS_256_DHKEMX448_AES256GCM_SHA512_Ed448"),
+            Some("AES_256_GCM_SHA512_128")
         );
+        assert_eq!(
+            sframe_suite_for("MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519"),
+            None
+        );
+    }
 
-        let vectors = store.handshake_vectors();
+    #[test]
+    fn handshake_vectors_sign_constant_message() {
+        let vectors = store().handshake_vectors();
         assert_eq!(vectors.len(), 1);
         let vector = &vectors[0];
-        assert_eq!(vector.identity, "alice");
+        assert_eq!(vector.identity, "openguild-test-vector");
         assert_eq!(
             vector.message,
             "OpenGuild MLS handshake test vector v1".to_string()
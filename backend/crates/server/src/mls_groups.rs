@@ -0,0 +1,1405 @@
+//! MLS delivery service (RFC 9420 §4): one group per private channel, with handshake and
+//! application messages ordered and fanned out without being decrypted.
+//!
+//! Clients run the MLS protocol themselves. The server reads only the cleartext framing of
+//! each `MLSMessage` (group id, epoch, content type) so it can accept exactly one commit per
+//! epoch, and keeps a roster of member user ids to decide who receives what.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{MatchedPath, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use openguild_storage::{
+    ChannelMembership, MlsGroupRecord, MlsGroupRepository, MlsMessageRecord, NewMlsMessage,
+    StoragePool,
+};
+use openmls::prelude::{tls_codec::Deserialize as _, ContentType, MlsMessageBodyIn, MlsMessageIn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    config::ServerConfig,
+    messaging::{MessagingService, NotificationEvent, DEFAULT_TIMELINE_LIMIT, MAX_TIMELINE_LIMIT},
+    session, AppState,
+};
+
+/// Upper bound on MLS group ids, which clients choose when creating a group.
+pub const MAX_GROUP_ID_LENGTH: usize = 255;
+/// Upper bound on a single TLS-encoded `MLSMessage`.
+pub const MAX_MLS_MESSAGE_LENGTH: usize = 256 * 1024;
+/// Members a single commit may add or remove.
+const MAX_ROSTER_CHANGES: usize = 100;
+/// Channel roles allowed to create the channel's MLS group.
+const GROUP_CREATOR_ROLES: &[&str] = &["owner", "admin", "moderator"];
+
+#[derive(Debug, Error)]
+pub enum MlsGroupError {
+    #[error("channel not found")]
+    ChannelNotFound,
+    #[error("MLS group not found")]
+    GroupNotFound,
+    #[error("channel already has an MLS group or the group id is taken")]
+    GroupExists,
+    #[error("user is not a member of this MLS group")]
+    NotMember,
+    #[error("user '{0}' is not a member of this channel")]
+    NotChannelMember(Uuid),
+    #[error("only channel moderators can create its MLS group")]
+    NotModerator,
+    #[error("message is for epoch {message} but the group is at epoch {current}")]
+    WrongEpoch { message: u64, current: u64 },
+    #[error("{0}")]
+    Invalid(&'static str),
+    #[error(transparent)]
+    Storage(#[from] anyhow::Error),
+}
+
+/// What an `MLSMessage` carries, as far as the delivery service can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MlsMessageKind {
+    Commit,
+    Proposal,
+    Application,
+    Welcome,
+}
+
+impl MlsMessageKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MlsMessageKind::Commit => "commit",
+            MlsMessageKind::Proposal => "proposal",
+            MlsMessageKind::Application => "application",
+            MlsMessageKind::Welcome => "welcome",
+        }
+    }
+}
+
+/// Cleartext framing of a `PublicMessage` or `PrivateMessage`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageFraming {
+    pub group_id: Vec<u8>,
+    pub epoch: u64,
+    pub kind: MlsMessageKind,
+}
+
+/// Read the framing of a TLS-encoded group message without decrypting its content.
+pub fn inspect_message(bytes: &[u8]) -> Result<MessageFraming, MlsGroupError> {
+    let message = MlsMessageIn::tls_deserialize_exact(bytes)
+        .map_err(|_| MlsGroupError::Invalid("message is not a TLS-encoded MLSMessage"))?;
+    let protocol = message
+        .try_into_protocol_message()
+        .map_err(|_| MlsGroupError::Invalid("expected a PublicMessage or PrivateMessage"))?;
+    let kind = match protocol.content_type() {
+        ContentType::Commit => MlsMessageKind::Commit,
+        ContentType::Proposal => MlsMessageKind::Proposal,
+        ContentType::Application => MlsMessageKind::Application,
+    };
+    Ok(MessageFraming {
+        group_id: protocol.group_id().as_slice().to_vec(),
+        epoch: protocol.epoch().as_u64(),
+        kind,
+    })
+}
+
+fn is_welcome(bytes: &[u8]) -> bool {
+    matches!(
+        MlsMessageIn::tls_deserialize_exact(bytes).map(MlsMessageIn::extract),
+        Ok(MlsMessageBodyIn::Welcome(_))
+    )
+}
+
+fn validate_group_id(group_id: &str) -> Result<(), MlsGroupError> {
+    match URL_SAFE_NO_PAD.decode(group_id) {
+        Ok(bytes) if !bytes.is_empty() && bytes.len() <= MAX_GROUP_ID_LENGTH => Ok(()),
+        _ => Err(MlsGroupError::Invalid(
+            "group id must be 1-255 bytes of URL-safe base64",
+        )),
+    }
+}
+
+#[async_trait]
+pub trait MlsGroupStore: Send + Sync {
+    async fn create_group(
+        &self,
+        group_id: &str,
+        channel_id: Uuid,
+        creator_id: Uuid,
+    ) -> Result<Option<MlsGroupRecord>, MlsGroupError>;
+    async fn group(&self, group_id: &str) -> Result<Option<MlsGroupRecord>, MlsGroupError>;
+    async fn group_for_channel(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Option<MlsGroupRecord>, MlsGroupError>;
+    async fn members(&self, group_id: &str) -> Result<Vec<Uuid>, MlsGroupError>;
+    async fn append_message(
+        &self,
+        group_id: &str,
+        message: NewMlsMessage,
+    ) -> Result<MlsMessageRecord, MlsGroupError>;
+    /// Advance the group past `epoch`; `None` when another commit got there first.
+    async fn commit(
+        &self,
+        group_id: &str,
+        epoch: i64,
+        added: &[Uuid],
+        removed: &[Uuid],
+        messages: Vec<NewMlsMessage>,
+    ) -> Result<Option<Vec<MlsMessageRecord>>, MlsGroupError>;
+    async fn messages_for(
+        &self,
+        group_id: &str,
+        user_id: Uuid,
+        since: i64,
+        limit: i64,
+    ) -> Result<Vec<MlsMessageRecord>, MlsGroupError>;
+}
+
+#[async_trait]
+impl MlsGroupStore for MlsGroupRepository {
+    async fn create_group(
+        &self,
+        group_id: &str,
+        channel_id: Uuid,
+        creator_id: Uuid,
+    ) -> Result<Option<MlsGroupRecord>, MlsGroupError> {
+        Ok(MlsGroupRepository::create_group(self, group_id, channel_id, creator_id).await?)
+    }
+
+    async fn group(&self, group_id: &str) -> Result<Option<MlsGroupRecord>, MlsGroupError> {
+        Ok(MlsGroupRepository::group(self, group_id).await?)
+    }
+
+    async fn group_for_channel(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Option<MlsGroupRecord>, MlsGroupError> {
+        Ok(MlsGroupRepository::group_for_channel(self, channel_id).await?)
+    }
+
+    async fn members(&self, group_id: &str) -> Result<Vec<Uuid>, MlsGroupError> {
+        Ok(MlsGroupRepository::members(self, group_id).await?)
+    }
+
+    async fn append_message(
+        &self,
+        group_id: &str,
+        message: NewMlsMessage,
+    ) -> Result<MlsMessageRecord, MlsGroupError> {
+        Ok(MlsGroupRepository::append_message(self, group_id, &message).await?)
+    }
+
+    async fn commit(
+        &self,
+        group_id: &str,
+        epoch: i64,
+        added: &[Uuid],
+        removed: &[Uuid],
+        messages: Vec<NewMlsMessage>,
+    ) -> Result<Option<Vec<MlsMessageRecord>>, MlsGroupError> {
+        Ok(MlsGroupRepository::commit(self, group_id, epoch, added, removed, &messages).await?)
+    }
+
+    async fn messages_for(
+        &self,
+        group_id: &str,
+        user_id: Uuid,
+        since: i64,
+        limit: i64,
+    ) -> Result<Vec<MlsMessageRecord>, MlsGroupError> {
+        Ok(MlsGroupRepository::messages_for(self, group_id, user_id, since, limit).await?)
+    }
+}
+
+#[derive(Default)]
+struct InMemoryGroupsState {
+    groups: HashMap<String, MlsGroupRecord>,
+    /// Member user ids and the epoch each one joined at.
+    members: HashMap<String, BTreeMap<Uuid, i64>>,
+    messages: Vec<MlsMessageRecord>,
+}
+
+impl InMemoryGroupsState {
+    fn push(&mut self, group_id: &str, message: NewMlsMessage) -> MlsMessageRecord {
+        let record = MlsMessageRecord {
+            sequence: self.messages.len() as i64 + 1,
+            group_id: group_id.to_string(),
+            epoch: message.epoch,
+            kind: message.kind,
+            sender_id: message.sender_id,
+            recipient_id: message.recipient_id,
+            message: message.message,
+            created_at: Utc::now(),
+        };
+        self.messages.push(record.clone());
+        record
+    }
+}
+
+/// Non-durable group store used when Postgres is not configured.
+#[derive(Default)]
+pub struct InMemoryMlsGroups {
+    state: Mutex<InMemoryGroupsState>,
+}
+
+#[async_trait]
+impl MlsGroupStore for InMemoryMlsGroups {
+    async fn create_group(
+        &self,
+        group_id: &str,
+        channel_id: Uuid,
+        creator_id: Uuid,
+    ) -> Result<Option<MlsGroupRecord>, MlsGroupError> {
+        let mut state = self.state.lock().await;
+        if state.groups.contains_key(group_id)
+            || state
+                .groups
+                .values()
+                .any(|group| group.channel_id == channel_id)
+        {
+            return Ok(None);
+        }
+        let record = MlsGroupRecord {
+            group_id: group_id.to_string(),
+            channel_id,
+            creator_id,
+            epoch: 0,
+            created_at: Utc::now(),
+        };
+        state.groups.insert(group_id.to_string(), record.clone());
+        state
+            .members
+            .insert(group_id.to_string(), BTreeMap::from([(creator_id, 0)]));
+        Ok(Some(record))
+    }
+
+    async fn group(&self, group_id: &str) -> Result<Option<MlsGroupRecord>, MlsGroupError> {
+        Ok(self.state.lock().await.groups.get(group_id).cloned())
+    }
+
+    async fn group_for_channel(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Option<MlsGroupRecord>, MlsGroupError> {
+        Ok(self
+            .state
+            .lock()
+            .await
+            .groups
+            .values()
+            .find(|group| group.channel_id == channel_id)
+            .cloned())
+    }
+
+    async fn members(&self, group_id: &str) -> Result<Vec<Uuid>, MlsGroupError> {
+        Ok(self
+            .state
+            .lock()
+            .await
+            .members
+            .get(group_id)
+            .map(|members| members.keys().copied().collect())
+            .unwrap_or_default())
+    }
+
+    async fn append_message(
+        &self,
+        group_id: &str,
+        message: NewMlsMessage,
+    ) -> Result<MlsMessageRecord, MlsGroupError> {
+        Ok(self.state.lock().await.push(group_id, message))
+    }
+
+    async fn commit(
+        &self,
+        group_id: &str,
+        epoch: i64,
+        added: &[Uuid],
+        removed: &[Uuid],
+        messages: Vec<NewMlsMessage>,
+    ) -> Result<Option<Vec<MlsMessageRecord>>, MlsGroupError> {
+        let mut state = self.state.lock().await;
+        match state.groups.get_mut(group_id) {
+            Some(group) if group.epoch == epoch => group.epoch += 1,
+            _ => return Ok(None),
+        }
+        let members = state.members.entry(group_id.to_string()).or_default();
+        for user_id in removed {
+            members.remove(user_id);
+        }
+        for user_id in added {
+            members.entry(*user_id).or_insert(epoch + 1);
+        }
+        Ok(Some(
+            messages
+                .into_iter()
+                .map(|message| state.push(group_id, message))
+                .collect(),
+        ))
+    }
+
+    async fn messages_for(
+        &self,
+        group_id: &str,
+        user_id: Uuid,
+        since: i64,
+        limit: i64,
+    ) -> Result<Vec<MlsMessageRecord>, MlsGroupError> {
+        let state = self.state.lock().await;
+        let Some(joined_epoch) = state
+            .members
+            .get(group_id)
+            .and_then(|members| members.get(&user_id).copied())
+        else {
+            return Ok(Vec::new());
+        };
+        Ok(state
+            .messages
+            .iter()
+            .filter(|message| message.group_id == group_id && message.sequence > since)
+            .filter(|message| match message.recipient_id {
+                Some(recipient) => recipient == user_id,
+                None => message.epoch >= joined_epoch,
+            })
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+/// A message as submitted by a member: the `MLSMessage` plus the roster changes a commit
+/// makes, which the server cannot read from a `PrivateMessage` itself.
+#[derive(Debug, Clone, Default)]
+pub struct Submission {
+    pub message: Vec<u8>,
+    pub welcome: Option<Vec<u8>>,
+    pub added: Vec<Uuid>,
+    pub removed: Vec<Uuid>,
+}
+
+pub struct MlsDeliveryService {
+    store: Arc<dyn MlsGroupStore>,
+    messaging: Arc<MessagingService>,
+}
+
+impl MlsDeliveryService {
+    pub fn new(store: Arc<dyn MlsGroupStore>, messaging: Arc<MessagingService>) -> Self {
+        Self { store, messaging }
+    }
+
+    /// Register `group_id` for `channel_id` at epoch 0 with `creator_id` as its only member.
+    /// Only channel moderators and above may create it, since a channel has one group.
+    pub async fn create_group(
+        &self,
+        channel_id: Uuid,
+        creator_id: Uuid,
+        group_id: &str,
+    ) -> Result<MlsGroupResponse, MlsGroupError> {
+        validate_group_id(group_id)?;
+        match self.messaging.channel_exists(channel_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(MlsGroupError::ChannelNotFound),
+            Err(err) => return Err(MlsGroupError::Storage(err.into())),
+        }
+        let membership = self.channel_membership(channel_id, creator_id).await?;
+        if !GROUP_CREATOR_ROLES
+            .iter()
+            .any(|role| membership.role.trim().eq_ignore_ascii_case(role))
+        {
+            return Err(MlsGroupError::NotModerator);
+        }
+        let group = self
+            .store
+            .create_group(group_id, channel_id, creator_id)
+            .await?
+            .ok_or(MlsGroupError::GroupExists)?;
+        Ok(MlsGroupResponse::new(group, vec![creator_id]))
+    }
+
+    /// The channel's group and roster, for members of the channel.
+    pub async fn group_for_channel(
+        &self,
+        channel_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<MlsGroupResponse, MlsGroupError> {
+        self.channel_membership(channel_id, user_id).await?;
+        let group = self
+            .store
+            .group_for_channel(channel_id)
+            .await?
+            .ok_or(MlsGroupError::GroupNotFound)?;
+        let members = self.store.members(&group.group_id).await?;
+        Ok(MlsGroupResponse::new(group, members))
+    }
+
+    async fn channel_membership(
+        &self,
+        channel_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<ChannelMembership, MlsGroupError> {
+        self.messaging
+            .channel_membership(channel_id, user_id)
+            .await
+            .map_err(|err| MlsGroupError::Storage(err.into()))?
+            .ok_or(MlsGroupError::NotChannelMember(user_id))
+    }
+
+    /// Accept a member's message for delivery. Commits must target the group's current
+    /// epoch and move it forward; the first commit for an epoch wins, and may only add
+    /// members of the group's channel. Returns the stored group message followed by any
+    /// welcomes.
+    pub async fn submit(
+        &self,
+        group_id: &str,
+        sender_id: Uuid,
+        submission: Submission,
+    ) -> Result<Vec<MlsMessageRecord>, MlsGroupError> {
+        let group = self
+            .store
+            .group(group_id)
+            .await?
+            .ok_or(MlsGroupError::GroupNotFound)?;
+        let members = self.store.members(group_id).await?;
+        if !members.contains(&sender_id) {
+            return Err(MlsGroupError::NotMember);
+        }
+        if submission.message.len() > MAX_MLS_MESSAGE_LENGTH
+            || submission
+                .welcome
+                .as_ref()
+                .is_some_and(|welcome| welcome.len() > MAX_MLS_MESSAGE_LENGTH)
+        {
+            return Err(MlsGroupError::Invalid("MLS message is too large"));
+        }
+
+        let framing = inspect_message(&submission.message)?;
+        if URL_SAFE_NO_PAD.encode(&framing.group_id) != group.group_id {
+            return Err(MlsGroupError::Invalid(
+                "message belongs to a different group",
+            ));
+        }
+        let current = group.epoch as u64;
+        let epoch_ok = match framing.kind {
+            MlsMessageKind::Commit | MlsMessageKind::Proposal => framing.epoch == current,
+            // Members may still be catching up on the latest commit.
+            _ => framing.epoch <= current,
+        };
+        if !epoch_ok {
+            return Err(MlsGroupError::WrongEpoch {
+                message: framing.epoch,
+                current,
+            });
+        }
+
+        let group_message = NewMlsMessage {
+            epoch: framing.epoch as i64,
+            kind: framing.kind.as_str().to_string(),
+            sender_id,
+            recipient_id: None,
+            message: submission.message,
+        };
+        let recipients: Vec<Uuid> = members
+            .iter()
+            .copied()
+            .filter(|member| *member != sender_id)
+            .collect();
+
+        if framing.kind != MlsMessageKind::Commit {
+            if !submission.added.is_empty()
+                || !submission.removed.is_empty()
+                || submission.welcome.is_some()
+            {
+                return Err(MlsGroupError::Invalid(
+                    "only commits change group membership",
+                ));
+            }
+            let record = self.store.append_message(group_id, group_message).await?;
+            self.fan_out(&group, &record, &recipients).await;
+            return Ok(vec![record]);
+        }
+
+        let added: HashSet<Uuid> = submission.added.iter().copied().collect();
+        let removed: HashSet<Uuid> = submission.removed.iter().copied().collect();
+        if added.len() != submission.added.len()
+            || removed.len() != submission.removed.len()
+            || added.len() + removed.len() > MAX_ROSTER_CHANGES
+        {
+            return Err(MlsGroupError::Invalid(
+                "added and removed users must be distinct and at most 100",
+            ));
+        }
+        if added.iter().any(|user| members.contains(user))
+            || removed.iter().any(|user| !members.contains(user))
+        {
+            return Err(MlsGroupError::Invalid(
+                "added users must be outside the group and removed users inside it",
+            ));
+        }
+        for user_id in &submission.added {
+            self.channel_membership(group.channel_id, *user_id).await?;
+        }
+        let welcome = match (submission.welcome, added.is_empty()) {
+            (None, true) => None,
+            (Some(welcome), false) if is_welcome(&welcome) => Some(welcome),
+            (Some(_), false) => return Err(MlsGroupError::Invalid("welcome is not a Welcome")),
+            (None, false) => return Err(MlsGroupError::Invalid("adding members needs a welcome")),
+            (Some(_), true) => return Err(MlsGroupError::Invalid("welcome without added users")),
+        };
+
+        let mut messages = vec![group_message];
+        if let Some(welcome) = welcome {
+            messages.extend(submission.added.iter().map(|user_id| NewMlsMessage {
+                epoch: framing.epoch as i64,
+                kind: MlsMessageKind::Welcome.as_str().to_string(),
+                sender_id,
+                recipient_id: Some(*user_id),
+                message: welcome.clone(),
+            }));
+        }
+        let Some(records) = self
+            .store
+            .commit(
+                group_id,
+                group.epoch,
+                &submission.added,
+                &submission.removed,
+                messages,
+            )
+            .await?
+        else {
+            let current = self
+                .store
+                .group(group_id)
+                .await?
+                .map_or(current + 1, |group| group.epoch as u64);
+            return Err(MlsGroupError::WrongEpoch {
+                message: framing.epoch,
+                current,
+            });
+        };
+
+        // Removed members still receive the commit that removes them.
+        self.fan_out(&group, &records[0], &recipients).await;
+        for welcome in &records[1..] {
+            if let Some(recipient) = welcome.recipient_id {
+                self.fan_out(&group, welcome, &[recipient]).await;
+            }
+        }
+        Ok(records)
+    }
+
+    /// Messages for a member after `since`, oldest first.
+    pub async fn messages(
+        &self,
+        group_id: &str,
+        user_id: Uuid,
+        since: i64,
+        limit: i64,
+    ) -> Result<Vec<MlsMessageRecord>, MlsGroupError> {
+        if self.store.group(group_id).await?.is_none() {
+            return Err(MlsGroupError::GroupNotFound);
+        }
+        if !self.store.members(group_id).await?.contains(&user_id) {
+            return Err(MlsGroupError::NotMember);
+        }
+        self.store
+            .messages_for(group_id, user_id, since, limit)
+            .await
+    }
+
+    async fn fan_out(&self, group: &MlsGroupRecord, record: &MlsMessageRecord, users: &[Uuid]) {
+        let notification = Arc::new(NotificationEvent {
+            kind: "mls_message".to_string(),
+            channel_id: Some(group.channel_id),
+            guild_id: None,
+            sequence: Some(record.sequence),
+            event: serde_json::to_value(MlsMessageResponse::from(record.clone()))
+                .unwrap_or_default(),
+        });
+        self.messaging.notify_users(users, notification).await;
+    }
+}
+
+/// Enabled alongside MLS key packages; groups persist when a database is configured.
+pub fn init_mls_delivery_service(
+    config: &ServerConfig,
+    pool: Option<StoragePool>,
+    messaging: Arc<MessagingService>,
+) -> Option<MlsDeliveryService> {
+    if !config.mls.enabled {
+        return None;
+    }
+    let store: Arc<dyn MlsGroupStore> = match pool {
+        Some(pool) => Arc::new(MlsGroupRepository::new(pool.cloned())),
+        None => {
+            tracing::info!("MLS group persistence unavailable; using in-memory delivery queue");
+            Arc::new(InMemoryMlsGroups::default())
+        }
+    };
+    Some(MlsDeliveryService::new(store, messaging))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MlsGroupResponse {
+    pub group_id: String,
+    pub channel_id: Uuid,
+    pub creator_id: Uuid,
+    pub epoch: i64,
+    pub members: Vec<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl MlsGroupResponse {
+    fn new(group: MlsGroupRecord, members: Vec<Uuid>) -> Self {
+        Self {
+            group_id: group.group_id,
+            channel_id: group.channel_id,
+            creator_id: group.creator_id,
+            epoch: group.epoch,
+            members,
+            created_at: group.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MlsMessageResponse {
+    pub sequence: i64,
+    pub group_id: String,
+    pub epoch: i64,
+    pub kind: String,
+    pub sender_id: Uuid,
+    /// URL-safe base64 of the TLS-encoded `MLSMessage`.
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<MlsMessageRecord> for MlsMessageResponse {
+    fn from(record: MlsMessageRecord) -> Self {
+        Self {
+            sequence: record.sequence,
+            group_id: record.group_id,
+            epoch: record.epoch,
+            kind: record.kind,
+            sender_id: record.sender_id,
+            message: URL_SAFE_NO_PAD.encode(&record.message),
+            created_at: record.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateMlsGroupRequest {
+    pub group_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubmitMlsMessageRequest {
+    pub message: String,
+    #[serde(default)]
+    pub welcome: Option<String>,
+    #[serde(default)]
+    pub added_users: Vec<Uuid>,
+    #[serde(default)]
+    pub removed_users: Vec<Uuid>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MlsMessagesQuery {
+    pub since: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+fn status_for(state: &AppState, err: &MlsGroupError) -> StatusCode {
+    match err {
+        MlsGroupError::ChannelNotFound | MlsGroupError::GroupNotFound => StatusCode::NOT_FOUND,
+        MlsGroupError::GroupExists | MlsGroupError::WrongEpoch { .. } => StatusCode::CONFLICT,
+        MlsGroupError::NotMember => {
+            state.record_messaging_rejection("mls_not_member");
+            StatusCode::FORBIDDEN
+        }
+        MlsGroupError::NotChannelMember(_) => {
+            state.record_messaging_rejection("mls_not_channel_member");
+            StatusCode::FORBIDDEN
+        }
+        MlsGroupError::NotModerator => {
+            state.record_messaging_rejection("mls_group_create_forbidden");
+            StatusCode::FORBIDDEN
+        }
+        MlsGroupError::Invalid(reason) => {
+            tracing::debug!(reason, "rejected MLS group request");
+            state.record_messaging_rejection("mls_invalid_message");
+            StatusCode::BAD_REQUEST
+        }
+        MlsGroupError::Storage(err) => {
+            tracing::error!(?err, "MLS delivery service storage failure");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+pub async fn create_group(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+    Json(body): Json<CreateMlsGroupRequest>,
+) -> Result<(StatusCode, Json<MlsGroupResponse>), StatusCode> {
+    let Some(groups) = state.mls_groups() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match groups
+        .create_group(channel_id, claims.user_id, &body.group_id)
+        .await
+    {
+        Ok(group) => {
+            let status = StatusCode::CREATED;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Ok((status, Json(group)));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn get_channel_group(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+) -> Result<Json<MlsGroupResponse>, StatusCode> {
+    let Some(groups) = state.mls_groups() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match groups.group_for_channel(channel_id, claims.user_id).await {
+        Ok(group) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(group));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn submit_message(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(group_id): Path<String>,
+    Json(body): Json<SubmitMlsMessageRequest>,
+) -> Result<(StatusCode, Json<MlsMessageResponse>), StatusCode> {
+    let Some(groups) = state.mls_groups() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let decode = |value: &str| URL_SAFE_NO_PAD.decode(value);
+    let submission = match (
+        decode(&body.message),
+        body.welcome.as_deref().map(decode).transpose(),
+    ) {
+        (Ok(message), Ok(welcome)) => Submission {
+            message,
+            welcome,
+            added: body.added_users,
+            removed: body.removed_users,
+        },
+        _ => {
+            state.record_messaging_rejection("mls_invalid_message");
+            let status = StatusCode::BAD_REQUEST;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Err(status);
+        }
+    };
+
+    let status = match groups.submit(&group_id, claims.user_id, submission).await {
+        Ok(mut records) => {
+            let status = StatusCode::CREATED;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            return Ok((status, Json(MlsMessageResponse::from(records.remove(0)))));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn list_messages(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(group_id): Path<String>,
+    Query(query): Query<MlsMessagesQuery>,
+) -> Result<Json<Vec<MlsMessageResponse>>, StatusCode> {
+    let Some(groups) = state.mls_groups() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TIMELINE_LIMIT)
+        .clamp(1, MAX_TIMELINE_LIMIT);
+    let status = match groups
+        .messages(&group_id, claims.user_id, query.since.unwrap_or(0), limit)
+        .await
+    {
+        Ok(records) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            return Ok(Json(
+                records.into_iter().map(MlsMessageResponse::from).collect(),
+            ));
+        }
+        Err(err) => status_for(&state, &err),
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+/// A real MLS client for exercising the delivery service in tests.
+#[cfg(test)]
+pub(crate) mod test_client {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use openmls::prelude::{
+        tls_codec::{Deserialize as _, Serialize as _},
+        *,
+    };
+    use openmls_basic_credential::SignatureKeyPair;
+    use openmls_rust_crypto::OpenMlsRustCrypto;
+
+    pub const CIPHERSUITE: Ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
+
+    pub struct TestClient {
+        pub provider: OpenMlsRustCrypto,
+        pub signer: SignatureKeyPair,
+        pub credential: CredentialWithKey,
+    }
+
+    impl TestClient {
+        pub fn new(name: &str) -> Self {
+            let provider = OpenMlsRustCrypto::default();
+            let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).unwrap();
+            signer.store(provider.storage()).unwrap();
+            let credential = CredentialWithKey {
+                credential: BasicCredential::new(name.as_bytes().to_vec()).into(),
+                signature_key: signer.public().into(),
+            };
+            Self {
+                provider,
+                signer,
+                credential,
+            }
+        }
+
+        pub fn key_package(&self) -> KeyPackage {
+            KeyPackage::builder()
+                .build(
+                    CIPHERSUITE,
+                    &self.provider,
+                    &self.signer,
+                    self.credential.clone(),
+                )
+                .unwrap()
+                .key_package()
+                .clone()
+        }
+
+        /// URL-safe base64 `MLSMessage` carrying a fresh key package, as devices upload them.
+        pub fn encoded_key_package(&self, last_resort: bool) -> String {
+            let mut builder = KeyPackage::builder();
+            if last_resort {
+                builder = builder
+                    .leaf_node_capabilities(Capabilities::new(
+                        None,
+                        None,
+                        Some(&[ExtensionType::LastResort]),
+                        None,
+                        None,
+                    ))
+                    .mark_as_last_resort();
+            }
+            let package = builder
+                .build(
+                    CIPHERSUITE,
+                    &self.provider,
+                    &self.signer,
+                    self.credential.clone(),
+                )
+                .unwrap()
+                .key_package()
+                .clone();
+            URL_SAFE_NO_PAD.encode(encode(&MlsMessageOut::from(package)))
+        }
+
+        pub fn create_group(&self, group_id: &[u8]) -> MlsGroup {
+            let config = MlsGroupCreateConfig::builder()
+                .ciphersuite(CIPHERSUITE)
+                .use_ratchet_tree_extension(true)
+                .build();
+            MlsGroup::new_with_group_id(
+                &self.provider,
+                &self.signer,
+                &config,
+                GroupId::from_slice(group_id),
+                self.credential.clone(),
+            )
+            .unwrap()
+        }
+
+        /// Commit adding `member`; returns the commit and the welcome.
+        pub fn add(&self, group: &mut MlsGroup, member: &TestClient) -> (Vec<u8>, Vec<u8>) {
+            let (commit, welcome, _) = group
+                .add_members(&self.provider, &self.signer, &[member.key_package()])
+                .unwrap();
+            group.merge_pending_commit(&self.provider).unwrap();
+            (encode(&commit), encode(&welcome))
+        }
+
+        pub fn join(&self, welcome: &[u8]) -> MlsGroup {
+            let MlsMessageBodyIn::Welcome(welcome) = decode(welcome).extract() else {
+                panic!("expected a welcome");
+            };
+            StagedWelcome::new_from_welcome(
+                &self.provider,
+                &MlsGroupJoinConfig::default(),
+                welcome,
+                None,
+            )
+            .unwrap()
+            .into_group(&self.provider)
+            .unwrap()
+        }
+
+        pub fn encrypt(&self, group: &mut MlsGroup, plaintext: &[u8]) -> Vec<u8> {
+            encode(
+                &group
+                    .create_message(&self.provider, &self.signer, plaintext)
+                    .unwrap(),
+            )
+        }
+
+        pub fn decrypt(&self, group: &mut MlsGroup, message: &[u8]) -> Vec<u8> {
+            let message = decode(message).try_into_protocol_message().unwrap();
+            match group
+                .process_message(&self.provider, message)
+                .unwrap()
+                .into_content()
+            {
+                ProcessedMessageContent::ApplicationMessage(message) => message.into_bytes(),
+                other => panic!("expected an application message, got {other:?}"),
+            }
+        }
+
+        pub fn propose_update(&self, group: &mut MlsGroup) -> Vec<u8> {
+            let (proposal, _) = group
+                .propose_self_update(&self.provider, &self.signer, LeafNodeParameters::default())
+                .unwrap();
+            encode(&proposal)
+        }
+
+        pub fn remove(&self, group: &mut MlsGroup, member: LeafNodeIndex) -> Vec<u8> {
+            let (commit, _, _) = group
+                .remove_members(&self.provider, &self.signer, &[member])
+                .unwrap();
+            group.merge_pending_commit(&self.provider).unwrap();
+            encode(&commit)
+        }
+    }
+
+    fn encode(message: &MlsMessageOut) -> Vec<u8> {
+        message.tls_serialize_detached().unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> MlsMessageIn {
+        MlsMessageIn::tls_deserialize_exact(bytes).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_client::TestClient;
+    use super::*;
+
+    async fn service() -> (MlsDeliveryService, Arc<MessagingService>, Uuid) {
+        let messaging = Arc::new(MessagingService::new_in_memory("test.openguild".into()));
+        let guild = messaging.create_guild("Private").await.unwrap();
+        let channel = messaging
+            .create_channel(guild.guild_id, "secret")
+            .await
+            .unwrap();
+        let service =
+            MlsDeliveryService::new(Arc::new(InMemoryMlsGroups::default()), messaging.clone());
+        (service, messaging, channel.channel_id)
+    }
+
+    #[test]
+    fn framing_is_read_without_decrypting() {
+        let alice = TestClient::new("alice");
+        let bob = TestClient::new("bob");
+        let mut group = alice.create_group(b"framing");
+        let (commit, welcome) = alice.add(&mut group, &bob);
+
+        let framing = inspect_message(&commit).unwrap();
+        assert_eq!(framing.group_id, b"framing");
+        assert_eq!((framing.epoch, framing.kind), (0, MlsMessageKind::Commit));
+        let application = alice.encrypt(&mut group, b"secret");
+        let framing = inspect_message(&application).unwrap();
+        assert_eq!(
+            (framing.epoch, framing.kind),
+            (1, MlsMessageKind::Application)
+        );
+
+        assert!(is_welcome(&welcome));
+        assert!(!is_welcome(&commit));
+        assert!(matches!(
+            inspect_message(&welcome),
+            Err(MlsGroupError::Invalid(_))
+        ));
+        assert!(matches!(
+            inspect_message(b"not mls"),
+            Err(MlsGroupError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn non_members_cannot_create_groups() {
+        let (service, messaging, channel_id) = service().await;
+        let stranger = Uuid::new_v4();
+        let group_id = URL_SAFE_NO_PAD.encode(b"private-channel");
+
+        assert!(matches!(
+            service.create_group(channel_id, stranger, &group_id).await,
+            Err(MlsGroupError::NotChannelMember(user)) if user == stranger
+        ));
+        // Membership of another channel does not count.
+        let guild = messaging.create_guild("Elsewhere").await.unwrap();
+        let other = messaging
+            .create_channel(guild.guild_id, "public")
+            .await
+            .unwrap();
+        messaging
+            .upsert_channel_membership(other.channel_id, stranger, "moderator")
+            .await
+            .unwrap();
+        assert!(matches!(
+            service.create_group(channel_id, stranger, &group_id).await,
+            Err(MlsGroupError::NotChannelMember(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn non_members_cannot_read_the_roster() {
+        let (service, messaging, channel_id) = service().await;
+        let (alice_id, stranger) = (Uuid::new_v4(), Uuid::new_v4());
+        messaging
+            .upsert_channel_membership(channel_id, alice_id, "moderator")
+            .await
+            .unwrap();
+        let group_id = URL_SAFE_NO_PAD.encode(b"private-channel");
+        service
+            .create_group(channel_id, alice_id, &group_id)
+            .await
+            .unwrap();
+
+        assert!(service
+            .group_for_channel(channel_id, alice_id)
+            .await
+            .is_ok());
+        assert!(matches!(
+            service.group_for_channel(channel_id, stranger).await,
+            Err(MlsGroupError::NotChannelMember(user)) if user == stranger
+        ));
+    }
+
+    #[tokio::test]
+    async fn non_members_cannot_be_added() {
+        let (service, messaging, channel_id) = service().await;
+        let (alice_id, stranger_id) = (Uuid::new_v4(), Uuid::new_v4());
+        messaging
+            .upsert_channel_membership(channel_id, alice_id, "moderator")
+            .await
+            .unwrap();
+        let group_id = URL_SAFE_NO_PAD.encode(b"private-channel");
+        service
+            .create_group(channel_id, alice_id, &group_id)
+            .await
+            .unwrap();
+
+        let alice = TestClient::new("alice");
+        let stranger = TestClient::new("stranger");
+        let mut group = alice.create_group(b"private-channel");
+        let (commit, welcome) = alice.add(&mut group, &stranger);
+        assert!(matches!(
+            service
+                .submit(
+                    &group_id,
+                    alice_id,
+                    Submission {
+                        message: commit,
+                        welcome: Some(welcome),
+                        added: vec![stranger_id],
+                        removed: Vec::new(),
+                    },
+                )
+                .await,
+            Err(MlsGroupError::NotChannelMember(user)) if user == stranger_id
+        ));
+        let group = service
+            .group_for_channel(channel_id, alice_id)
+            .await
+            .unwrap();
+        assert_eq!((group.epoch, group.members), (0, vec![alice_id]));
+    }
+
+    #[tokio::test]
+    async fn commits_are_ordered_and_ciphertext_reaches_members() {
+        let (service, messaging, channel_id) = service().await;
+        let (alice_id, bob_id, carol_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let alice = TestClient::new("alice");
+        let bob = TestClient::new("bob");
+        let mut bob_notifications = messaging.notification_sender(bob_id).await.subscribe();
+
+        let group_id = URL_SAFE_NO_PAD.encode(b"private-channel");
+        assert!(matches!(
+            service.create_group(channel_id, alice_id, &group_id).await,
+            Err(MlsGroupError::NotChannelMember(user)) if user == alice_id
+        ));
+        for (user_id, role) in [(alice_id, "moderator"), (bob_id, "member")] {
+            messaging
+                .upsert_channel_membership(channel_id, user_id, role)
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            service.create_group(channel_id, bob_id, &group_id).await,
+            Err(MlsGroupError::NotModerator)
+        ));
+        let created = service
+            .create_group(channel_id, alice_id, &group_id)
+            .await
+            .unwrap();
+        assert_eq!((created.epoch, created.members), (0, vec![alice_id]));
+        assert!(matches!(
+            service.create_group(channel_id, alice_id, "b3RoZXI").await,
+            Err(MlsGroupError::GroupExists)
+        ));
+        assert!(matches!(
+            service.group_for_channel(channel_id, carol_id).await,
+            Err(MlsGroupError::NotChannelMember(_))
+        ));
+
+        let mut alice_group = alice.create_group(b"private-channel");
+        let (commit, welcome) = alice.add(&mut alice_group, &bob);
+        assert!(matches!(
+            service
+                .submit(
+                    &group_id,
+                    alice_id,
+                    Submission {
+                        message: commit.clone(),
+                        added: vec![bob_id],
+                        ..Default::default()
+                    },
+                )
+                .await,
+            Err(MlsGroupError::Invalid(_))
+        ));
+        assert!(matches!(
+            service
+                .submit(
+                    &group_id,
+                    alice_id,
+                    Submission {
+                        message: commit.clone(),
+                        welcome: Some(welcome.clone()),
+                        added: vec![carol_id],
+                        removed: Vec::new(),
+                    },
+                )
+                .await,
+            Err(MlsGroupError::NotChannelMember(user)) if user == carol_id
+        ));
+        let stored = service
+            .submit(
+                &group_id,
+                alice_id,
+                Submission {
+                    message: commit.clone(),
+                    welcome: Some(welcome.clone()),
+                    added: vec![bob_id],
+                    removed: Vec::new(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[1].recipient_id, Some(bob_id));
+
+        // A second commit for epoch 0 loses.
+        assert!(matches!(
+            service
+                .submit(
+                    &group_id,
+                    alice_id,
+                    Submission {
+                        message: commit,
+                        ..Default::default()
+                    },
+                )
+                .await,
+            Err(MlsGroupError::WrongEpoch {
+                message: 0,
+                current: 1
+            })
+        ));
+
+        let notification = bob_notifications.try_recv().unwrap();
+        assert_eq!(notification.kind, "mls_message");
+        assert_eq!(notification.event["kind"], "welcome");
+
+        let inbox = service.messages(&group_id, bob_id, 0, 50).await.unwrap();
+        assert_eq!(inbox.len(), 1, "bob only sees his welcome");
+        let mut bob_group = bob.join(&inbox[0].message);
+
+        let ciphertext = alice.encrypt(&mut alice_group, b"hello bob");
+        let sent = service
+            .submit(
+                &group_id,
+                alice_id,
+                Submission {
+                    message: ciphertext.clone(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(sent[0].message, ciphertext, "stored byte for byte");
+        let inbox = service
+            .messages(&group_id, bob_id, inbox[0].sequence, 50)
+            .await
+            .unwrap();
+        assert_eq!(inbox.len(), 1);
+        assert_eq!(bob.decrypt(&mut bob_group, &inbox[0].message), b"hello bob");
+
+        let update = bob.propose_update(&mut bob_group);
+        let proposal = service
+            .submit(
+                &group_id,
+                bob_id,
+                Submission {
+                    message: update,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(proposal[0].kind, "proposal");
+        assert!(matches!(
+            service
+                .submit(
+                    &group_id,
+                    carol_id,
+                    Submission {
+                        message: ciphertext,
+                        ..Default::default()
+                    },
+                )
+                .await,
+            Err(MlsGroupError::NotMember)
+        ));
+
+        let removal = alice.remove(&mut alice_group, bob_group.own_leaf_index());
+        service
+            .submit(
+                &group_id,
+                alice_id,
+                Submission {
+                    message: removal,
+                    removed: vec![bob_id],
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let group = service
+            .group_for_channel(channel_id, alice_id)
+            .await
+            .unwrap();
+        assert_eq!((group.epoch, group.members), (2, vec![alice_id]));
+        assert!(matches!(
+            service.messages(&group_id, bob_id, 0, 50).await,
+            Err(MlsGroupError::NotMember)
+        ));
+    }
+}
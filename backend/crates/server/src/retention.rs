@@ -0,0 +1,757 @@
+//! Guild retention policies, the reaper that enforces them, and storage accounting.
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{MatchedPath, Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use openguild_storage::RetentionPolicy;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    media::{MediaError, MediaService},
+    messaging::{attachment_media_ids, MessagingError, MessagingService},
+    session, AppState,
+};
+
+/// Events deleted per channel in one transaction; the reaper repeats until a channel is
+/// within its policy.
+const PRUNE_BATCH_SIZE: i64 = 500;
+/// Guild roles allowed to change retention and read guild usage.
+const MANAGER_ROLES: &[&str] = &["owner", "admin"];
+
+#[derive(Debug, Error)]
+pub enum RetentionError {
+    #[error(transparent)]
+    Messaging(#[from] MessagingError),
+    #[error(transparent)]
+    Media(#[from] MediaError),
+}
+
+/// What one sweep removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SweepReport {
+    pub guilds: u64,
+    pub events_pruned: u64,
+    pub media_deleted: u64,
+    pub media_bytes_freed: u64,
+}
+
+/// Storage held by a guild: its channel events and the media they attach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuildUsage {
+    pub guild_id: Uuid,
+    pub events: i64,
+    pub event_bytes: i64,
+    pub media_objects: i64,
+    pub media_bytes: i64,
+}
+
+/// Storage held by a user: the events they sent and the media they uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserUsage {
+    pub user_id: Uuid,
+    pub events: i64,
+    pub event_bytes: i64,
+    pub media_objects: i64,
+    pub media_bytes: i64,
+}
+
+/// Prunes channel events under each guild's retention policy and deletes media that no
+/// remaining event attaches.
+pub struct RetentionReaper {
+    messaging: Arc<MessagingService>,
+    media: Option<Arc<MediaService>>,
+}
+
+impl RetentionReaper {
+    pub fn new(messaging: Arc<MessagingService>, media: Option<Arc<MediaService>>) -> Self {
+        Self { messaging, media }
+    }
+
+    /// Apply every retention policy once.
+    pub async fn sweep(&self) -> Result<SweepReport, RetentionError> {
+        self.sweep_at(Utc::now()).await
+    }
+
+    async fn sweep_at(&self, now: DateTime<Utc>) -> Result<SweepReport, RetentionError> {
+        let mut report = SweepReport::default();
+        for policy in self.messaging.retention_policies().await? {
+            self.sweep_guild(&policy, now, &mut report).await?;
+            report.guilds += 1;
+        }
+        Ok(report)
+    }
+
+    async fn sweep_guild(
+        &self,
+        policy: &RetentionPolicy,
+        now: DateTime<Utc>,
+        report: &mut SweepReport,
+    ) -> Result<(), RetentionError> {
+        let older_than = policy
+            .max_age_secs
+            .map(|secs| now - chrono::Duration::seconds(secs));
+        let mut detached = HashSet::new();
+        for channel in self.messaging.list_channels(policy.guild_id).await? {
+            loop {
+                let pruned = self
+                    .messaging
+                    .prune_channel_events(
+                        channel.channel_id,
+                        older_than,
+                        policy.max_events,
+                        PRUNE_BATCH_SIZE,
+                    )
+                    .await?;
+                report.events_pruned += pruned.len() as u64;
+                for event in &pruned {
+                    detached.extend(attachment_media_ids(&event.body));
+                }
+                if (pruned.len() as i64) < PRUNE_BATCH_SIZE {
+                    break;
+                }
+            }
+        }
+
+        let Some(media) = &self.media else {
+            return Ok(());
+        };
+        for media_id in detached {
+            if self.messaging.media_referenced(&media_id).await? {
+                continue;
+            }
+            if let Some(record) = media.purge(&media_id).await? {
+                report.media_deleted += 1;
+                report.media_bytes_freed += record.size as u64;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn guild_usage(&self, guild_id: Uuid) -> Result<GuildUsage, RetentionError> {
+        let events = self.messaging.guild_event_usage(guild_id).await?;
+        let mut usage = GuildUsage {
+            guild_id,
+            events: events.events,
+            event_bytes: events.event_bytes,
+            media_objects: 0,
+            media_bytes: 0,
+        };
+        if let Some(media) = &self.media {
+            let media_ids = self.messaging.guild_attachment_ids(guild_id).await?;
+            for record in media.media_by_ids(&media_ids).await? {
+                usage.media_objects += 1;
+                usage.media_bytes += record.size;
+            }
+        }
+        Ok(usage)
+    }
+
+    pub async fn user_usage(&self, user_id: Uuid) -> Result<UserUsage, RetentionError> {
+        let events = self.messaging.user_event_usage(user_id).await?;
+        let uploads = match &self.media {
+            Some(media) => media.uploader_usage(user_id).await?,
+            None => Default::default(),
+        };
+        Ok(UserUsage {
+            user_id,
+            events: events.events,
+            event_bytes: events.event_bytes,
+            media_objects: uploads.objects,
+            media_bytes: uploads.bytes,
+        })
+    }
+
+    /// Sweep every `interval` until the task is aborted. Failed sweeps are logged and
+    /// retried on the next tick.
+    pub fn spawn(self: Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                match self.sweep().await {
+                    Ok(report) if report.events_pruned > 0 || report.media_deleted > 0 => {
+                        info!(?report, "retention sweep pruned storage");
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!(error = %err, "retention sweep failed"),
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RetentionPolicyRequest {
+    #[serde(default)]
+    pub max_age_secs: Option<i64>,
+    #[serde(default)]
+    pub max_events: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RetentionPolicyResponse {
+    pub guild_id: Uuid,
+    pub max_age_secs: Option<i64>,
+    pub max_events: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<RetentionPolicy> for RetentionPolicyResponse {
+    fn from(policy: RetentionPolicy) -> Self {
+        Self {
+            guild_id: policy.guild_id,
+            max_age_secs: policy.max_age_secs,
+            max_events: policy.max_events,
+            updated_at: policy.updated_at,
+        }
+    }
+}
+
+/// Authenticate the caller and require an owner or admin role in the guild.
+async fn authorize_guild_manager(
+    state: &AppState,
+    messaging: &MessagingService,
+    headers: &HeaderMap,
+    guild_id: Uuid,
+) -> Result<(), StatusCode> {
+    let claims = session::authenticate_bearer(state, headers).inspect_err(|status| {
+        if *status == StatusCode::UNAUTHORIZED {
+            state.record_messaging_rejection("unauthorized");
+        }
+    })?;
+    let memberships = messaging
+        .guild_memberships_for_user(claims.user_id)
+        .await
+        .map_err(|err| {
+            tracing::error!(?err, "failed to load guild memberships");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let manages = memberships.iter().any(|membership| {
+        membership.guild_id == guild_id
+            && MANAGER_ROLES
+                .iter()
+                .any(|role| membership.role.trim().eq_ignore_ascii_case(role))
+    });
+    if manages {
+        Ok(())
+    } else {
+        state.record_messaging_rejection("retention_forbidden");
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+pub async fn get_retention_policy(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(guild_id): Path<Uuid>,
+) -> Result<Json<RetentionPolicyResponse>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match authorize_guild_manager(&state, &messaging, &headers, guild_id).await {
+        Err(status) => status,
+        Ok(()) => match messaging.retention_policy(guild_id).await {
+            Ok(Some(policy)) => {
+                #[cfg(feature = "metrics")]
+                state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+                return Ok(Json(policy.into()));
+            }
+            Ok(None) => StatusCode::NOT_FOUND,
+            Err(err) => {
+                tracing::error!(?err, %guild_id, "failed to load retention policy");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        },
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn set_retention_policy(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(guild_id): Path<Uuid>,
+    Json(body): Json<RetentionPolicyRequest>,
+) -> Result<Json<RetentionPolicyResponse>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let valid = (body.max_age_secs.is_some() || body.max_events.is_some())
+        && body.max_age_secs.is_none_or(|secs| secs > 0)
+        && body.max_events.is_none_or(|events| events > 0);
+    let status = match authorize_guild_manager(&state, &messaging, &headers, guild_id).await {
+        Err(status) => status,
+        Ok(()) if !valid => {
+            state.record_messaging_rejection("invalid_retention_policy");
+            StatusCode::BAD_REQUEST
+        }
+        Ok(()) => match messaging
+            .set_retention_policy(guild_id, body.max_age_secs, body.max_events)
+            .await
+        {
+            Ok(policy) => {
+                #[cfg(feature = "metrics")]
+                state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+                return Ok(Json(policy.into()));
+            }
+            Err(MessagingError::GuildNotFound) => StatusCode::NOT_FOUND,
+            Err(err) => {
+                tracing::error!(?err, %guild_id, "failed to store retention policy");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        },
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn clear_retention_policy(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(guild_id): Path<Uuid>,
+) -> StatusCode {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return status;
+    };
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match authorize_guild_manager(&state, &messaging, &headers, guild_id).await {
+        Err(status) => status,
+        Ok(()) => match messaging.clear_retention_policy(guild_id).await {
+            Ok(true) => StatusCode::NO_CONTENT,
+            Ok(false) => StatusCode::NOT_FOUND,
+            Err(err) => {
+                tracing::error!(?err, %guild_id, "failed to clear retention policy");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        },
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    status
+}
+
+pub async fn guild_usage(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(guild_id): Path<Uuid>,
+) -> Result<Json<GuildUsage>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let status = match authorize_guild_manager(&state, &messaging, &headers, guild_id).await {
+        Err(status) => status,
+        Ok(()) => {
+            let reaper = RetentionReaper::new(messaging, state.media());
+            match reaper.guild_usage(guild_id).await {
+                Ok(usage) => {
+                    #[cfg(feature = "metrics")]
+                    state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+                    return Ok(Json(usage));
+                }
+                Err(err) => {
+                    tracing::error!(?err, %guild_id, "failed to compute guild usage");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            }
+        }
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    Err(status)
+}
+
+pub async fn my_usage(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<UserUsage>, StatusCode> {
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        #[cfg(not(feature = "metrics"))]
+        let _ = status;
+    })?;
+
+    let reaper = RetentionReaper::new(messaging, state.media());
+    match reaper.user_usage(claims.user_id).await {
+        Ok(usage) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            Ok(Json(usage))
+        }
+        Err(err) => {
+            tracing::error!(?err, user_id = %claims.user_id, "failed to compute user usage");
+            let status = StatusCode::INTERNAL_SERVER_ERROR;
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), status.as_u16());
+            Err(status)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::{InMemoryMediaCatalog, MediaCatalog};
+    use openguild_core::messaging::{Attachment, MessageAuthorSnapshot, MessagePayload};
+    use openguild_media::FilesystemStore;
+    use openguild_storage::MediaRecord;
+
+    fn attachment(media_id: &str) -> Attachment {
+        Attachment {
+            media_id: media_id.to_string(),
+            filename: None,
+            content_type: None,
+            size: None,
+            width: None,
+            height: None,
+            blurhash: None,
+        }
+    }
+
+    fn media_service(
+        root: &std::path::Path,
+        catalog: Arc<InMemoryMediaCatalog>,
+    ) -> Arc<MediaService> {
+        Arc::new(MediaService::new(
+            Arc::new(FilesystemStore::new(root.join("objects"))),
+            catalog,
+            root.join("staging"),
+            1024,
+        ))
+    }
+
+    async fn upload(media: &MediaService, user_id: Uuid, bytes: &[u8]) -> MediaRecord {
+        media
+            .upload(
+                user_id,
+                Some("text/plain"),
+                None,
+                futures::stream::iter([Ok::<_, std::convert::Infallible>(
+                    axum::body::Bytes::copy_from_slice(bytes),
+                )]),
+            )
+            .await
+            .unwrap()
+    }
+
+    async fn post(
+        messaging: &MessagingService,
+        channel_id: Uuid,
+        user_id: Uuid,
+        attachments: &[&MediaRecord],
+    ) {
+        let author = MessageAuthorSnapshot {
+            id: user_id.to_string(),
+            username: "alice".into(),
+            display_name: None,
+        };
+        let payload = MessagePayload {
+            attachments: attachments
+                .iter()
+                .map(|record| attachment(&record.media_id))
+                .collect(),
+            ..MessagePayload::new("hello")
+        };
+        messaging
+            .send_message(channel_id, &author, payload)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shared_media_outlives_pruned_events() {
+        let root = tempfile::tempdir().unwrap();
+        let messaging = Arc::new(MessagingService::new_in_memory("localhost".into()));
+        let catalog = Arc::new(InMemoryMediaCatalog::default());
+        let media = media_service(root.path(), catalog.clone());
+        let reaper = RetentionReaper::new(messaging.clone(), Some(media.clone()));
+        let guild = messaging.create_guild("Shared").await.unwrap();
+        let channel = messaging
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let user_id = Uuid::new_v4();
+
+        let reused = upload(&media, user_id, b"reused attachment").await;
+        let duplicate = upload(&media, user_id, b"reused attachment").await;
+        assert_eq!(reused.content_hash, duplicate.content_hash);
+        post(
+            &messaging,
+            channel.channel_id,
+            user_id,
+            &[&reused, &duplicate],
+        )
+        .await;
+        post(&messaging, channel.channel_id, user_id, &[&reused]).await;
+        post(&messaging, channel.channel_id, user_id, &[]).await;
+
+        messaging
+            .set_retention_policy(guild.guild_id, None, Some(2))
+            .await
+            .unwrap();
+        let report = reaper.sweep().await.unwrap();
+        assert_eq!((report.events_pruned, report.media_deleted), (1, 1));
+
+        // `reused` is still attached to a remaining event.
+        assert!(messaging.media_referenced(&reused.media_id).await.unwrap());
+        assert!(media.media(&reused.media_id).await.unwrap().is_some());
+        // `duplicate` lost its only reference, but its content lives on under `reused`.
+        assert!(!messaging
+            .media_referenced(&duplicate.media_id)
+            .await
+            .unwrap());
+        assert!(media.media(&duplicate.media_id).await.unwrap().is_none());
+        assert!(catalog
+            .content_hash_in_use(&reused.content_hash)
+            .await
+            .unwrap());
+        assert!(media.open(&reused, None).await.is_ok());
+        assert!(root
+            .path()
+            .join("objects")
+            .join(openguild_media::content_key(&reused.content_hash))
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn sweeps_only_prune_past_the_policy_cutoff() {
+        let messaging = Arc::new(MessagingService::new_in_memory("localhost".into()));
+        let reaper = RetentionReaper::new(messaging.clone(), None);
+        let guild = messaging.create_guild("Cutoff").await.unwrap();
+        let channel = messaging
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let user_id = Uuid::new_v4();
+        for _ in 0..3 {
+            post(&messaging, channel.channel_id, user_id, &[]).await;
+        }
+
+        // Three events fit under a limit of three.
+        messaging
+            .set_retention_policy(guild.guild_id, None, Some(3))
+            .await
+            .unwrap();
+        assert_eq!(reaper.sweep().await.unwrap().events_pruned, 0);
+
+        // Events younger than the age limit stay.
+        messaging
+            .set_retention_policy(guild.guild_id, Some(3600), None)
+            .await
+            .unwrap();
+        let report = reaper
+            .sweep_at(Utc::now() + chrono::Duration::seconds(60))
+            .await
+            .unwrap();
+        assert_eq!(report.events_pruned, 0);
+        let report = reaper
+            .sweep_at(Utc::now() + chrono::Duration::seconds(7200))
+            .await
+            .unwrap();
+        assert_eq!(report.events_pruned, 2);
+        let usage = reaper.guild_usage(guild.guild_id).await.unwrap();
+        assert_eq!(usage.events, 1);
+    }
+
+    #[tokio::test]
+    async fn sweeps_leave_other_guilds_alone() {
+        let root = tempfile::tempdir().unwrap();
+        let messaging = Arc::new(MessagingService::new_in_memory("localhost".into()));
+        let media = media_service(root.path(), Arc::default());
+        let reaper = RetentionReaper::new(messaging.clone(), Some(media.clone()));
+        let user_id = Uuid::new_v4();
+
+        let mut guilds = Vec::new();
+        for name in ["Pruned", "Untouched"] {
+            let guild = messaging.create_guild(name).await.unwrap();
+            let channel = messaging
+                .create_channel(guild.guild_id, "general")
+                .await
+                .unwrap();
+            let record = upload(&media, user_id, name.as_bytes()).await;
+            post(&messaging, channel.channel_id, user_id, &[&record]).await;
+            post(&messaging, channel.channel_id, user_id, &[]).await;
+            guilds.push((guild, record));
+        }
+        let (pruned, pruned_media) = &guilds[0];
+        let (untouched, untouched_media) = &guilds[1];
+
+        messaging
+            .set_retention_policy(pruned.guild_id, None, Some(1))
+            .await
+            .unwrap();
+        let report = reaper.sweep().await.unwrap();
+        assert_eq!(
+            report,
+            SweepReport {
+                guilds: 1,
+                events_pruned: 1,
+                media_deleted: 1,
+                media_bytes_freed: pruned_media.size as u64,
+            }
+        );
+        assert!(media.media(&pruned_media.media_id).await.unwrap().is_none());
+
+        let usage = reaper.guild_usage(untouched.guild_id).await.unwrap();
+        assert_eq!((usage.events, usage.media_objects), (2, 1));
+        assert!(media
+            .media(&untouched_media.media_id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn sweeps_prune_events_and_unreferenced_media() {
+        let root = tempfile::tempdir().unwrap();
+        let objects = root.path().join("objects");
+        let messaging = Arc::new(MessagingService::new_in_memory("localhost".into()));
+        let media = Arc::new(MediaService::new(
+            Arc::new(FilesystemStore::new(&objects)),
+            Arc::new(InMemoryMediaCatalog::default()),
+            root.path().join("staging"),
+            1024,
+        ));
+        let reaper = RetentionReaper::new(messaging.clone(), Some(media.clone()));
+
+        let guild = messaging.create_guild("Archive").await.unwrap();
+        let channel = messaging
+            .create_channel(guild.guild_id, "general")
+            .await
+            .unwrap();
+        let user_id = Uuid::new_v4();
+        let author = MessageAuthorSnapshot {
+            id: user_id.to_string(),
+            username: "alice".into(),
+            display_name: None,
+        };
+
+        let upload = |bytes: &'static [u8]| {
+            let media = media.clone();
+            async move {
+                media
+                    .upload(
+                        user_id,
+                        Some("text/plain"),
+                        None,
+                        futures::stream::iter([Ok::<_, std::convert::Infallible>(
+                            axum::body::Bytes::from_static(bytes),
+                        )]),
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+        let old = upload(b"old attachment").await;
+        let shared = upload(b"kept attachment").await;
+        let kept = upload(b"kept attachment").await;
+
+        for attachments in [vec![&old, &shared], vec![], vec![&kept]] {
+            let payload = MessagePayload {
+                attachments: attachments
+                    .into_iter()
+                    .map(|record| attachment(&record.media_id))
+                    .collect(),
+                ..MessagePayload::new("hello")
+            };
+            messaging
+                .send_message(channel.channel_id, &author, payload)
+                .await
+                .unwrap();
+        }
+
+        let usage = reaper.guild_usage(guild.guild_id).await.unwrap();
+        assert_eq!((usage.events, usage.media_objects), (3, 3));
+        let user = reaper.user_usage(user_id).await.unwrap();
+        assert_eq!((user.events, user.media_objects), (3, 3));
+        assert_eq!(user.event_bytes, usage.event_bytes);
+
+        // Without a policy nothing is touched.
+        assert_eq!(reaper.sweep().await.unwrap(), SweepReport::default());
+
+        messaging
+            .set_retention_policy(guild.guild_id, None, Some(2))
+            .await
+            .unwrap();
+        let report = reaper.sweep().await.unwrap();
+        assert_eq!(
+            report,
+            SweepReport {
+                guilds: 1,
+                events_pruned: 1,
+                media_deleted: 2,
+                media_bytes_freed: 29,
+            }
+        );
+        assert!(media.media(&old.media_id).await.unwrap().is_none());
+        assert!(media.media(&shared.media_id).await.unwrap().is_none());
+        // `kept` shares its object with the purged `shared` upload, so the object stays.
+        assert!(media.open(&kept, None).await.is_ok());
+        assert!(!objects
+            .join(openguild_media::content_key(&old.content_hash))
+            .exists());
+
+        // An age limit prunes everything but the newest event, which is the channel tip.
+        messaging
+            .set_retention_policy(guild.guild_id, Some(60), None)
+            .await
+            .unwrap();
+        let report = reaper
+            .sweep_at(Utc::now() + chrono::Duration::seconds(120))
+            .await
+            .unwrap();
+        assert_eq!(report.events_pruned, 1);
+        assert_eq!(report.media_deleted, 0);
+        let usage = reaper.guild_usage(guild.guild_id).await.unwrap();
+        assert_eq!((usage.events, usage.media_objects), (1, 1));
+    }
+}
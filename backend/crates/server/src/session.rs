@@ -117,6 +117,8 @@ pub struct AccessTokenClaims {
     pub session_id: Uuid,
     pub user_id: Uuid,
     pub username: Option<String>,
+    /// Device the session logged in from; absent in tokens issued before devices were bound.
+    pub device_id: Option<String>,
     #[allow(dead_code)]
     pub issued_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
@@ -152,7 +154,11 @@ impl SessionContext {
             None => return Ok(None),
         };
 
-        let record = self.build_record(user.user_id, user.username.clone());
+        let record = self.build_record(
+            user.user_id,
+            user.username.clone(),
+            &attempt.device.device_id,
+        );
         let access_token = self.signer.sign(&record)?;
         self.repository.persist_session(&record).await?;
 
@@ -202,7 +208,7 @@ impl SessionContext {
                 None
             }
         };
-        let session_record = self.build_record(stored.user_id, username);
+        let session_record = self.build_record(stored.user_id, username, &stored.device.device_id);
         let access_token = self.signer.sign(&session_record)?;
         self.repository.persist_session(&session_record).await?;
 
@@ -223,6 +229,10 @@ impl SessionContext {
         }))
     }
 
+    pub fn signer(&self) -> &SessionSigner {
+        &self.signer
+    }
+
     pub fn verify_access_token(&self, token: &str) -> Result<Option<AccessTokenClaims>> {
         match self.signer.verify(token) {
             Ok(claims) => {
@@ -260,13 +270,19 @@ impl SessionContext {
         Ok(true)
     }
 
-    fn build_record(&self, user_id: Uuid, username: Option<String>) -> SessionRecord {
+    fn build_record(
+        &self,
+        user_id: Uuid,
+        username: Option<String>,
+        device_id: &str,
+    ) -> SessionRecord {
         let issued_at = Utc::now();
         let expires_at = issued_at + self.ttl;
         SessionRecord {
             session_id: Uuid::new_v4(),
             user_id,
             username,
+            device_id: Some(device_id.to_string()),
             issued_at,
             expires_at,
         }
@@ -372,7 +388,12 @@ impl SessionSigner {
     }
 
     pub fn sign(&self, record: &SessionRecord) -> Result<String> {
-        let payload = serde_json::to_vec(&SessionClaims::from(record))?;
+        self.sign_claims(&SessionClaims::from(record))
+    }
+
+    /// Sign arbitrary claims in the access token format, e.g. voice join tokens.
+    pub fn sign_claims<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let payload = serde_json::to_vec(claims)?;
         let signature = self.key_ring.sign(&payload);
 
         let token = format!(
@@ -415,6 +436,7 @@ impl SessionSigner {
             session_id: claims.session_id,
             user_id: claims.user_id,
             username: claims.username,
+            device_id: claims.device_id,
             issued_at: claims.issued_at,
             expires_at: claims.expires_at,
         })
@@ -427,6 +449,8 @@ pub struct SessionRecord {
     pub user_id: Uuid,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
     pub issued_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
 }
@@ -437,6 +461,8 @@ struct SessionClaims<'a> {
     user_id: &'a Uuid,
     #[serde(skip_serializing_if = "Option::is_none")]
     username: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<&'a str>,
     issued_at: &'a DateTime<Utc>,
     expires_at: &'a DateTime<Utc>,
 }
@@ -447,6 +473,7 @@ impl<'a> From<&'a SessionRecord> for SessionClaims<'a> {
             session_id: &value.session_id,
             user_id: &value.user_id,
             username: value.username.as_deref(),
+            device_id: value.device_id.as_deref(),
             issued_at: &value.issued_at,
             expires_at: &value.expires_at,
         }
@@ -458,6 +485,8 @@ struct SessionClaimsOwned {
     session_id: Uuid,
     user_id: Uuid,
     username: Option<String>,
+    #[serde(default)]
+    device_id: Option<String>,
     issued_at: DateTime<Utc>,
     expires_at: DateTime<Utc>,
 }
@@ -1116,6 +1145,12 @@ pub mod tests {
             .expect("refresh response");
 
         assert_ne!(refreshed.refresh_token, login.refresh_token);
+        let claims = harness
+            .context
+            .verify_access_token(&refreshed.access_token)
+            .expect("token verifies")
+            .expect("token is live");
+        assert_eq!(claims.device_id.as_deref(), Some("carol-device"));
         assert!(
             harness
                 .context
@@ -0,0 +1,1260 @@
+//! Voice channel signaling: a WebSocket per participant, relayed to the SFU.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{
+        ws::{Message as WsMessage, WebSocket},
+        MatchedPath, Path, Query, State, WebSocketUpgrade,
+    },
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use openguild_sfu_client::{
+    ClientSignal, HttpSfuClient, JoinClaims, MediaEncryption, MediaKeyPackage, ParticipantState,
+    ServerSignal, SfuClient, SfuError, SignalErrorCode, SignalingAnswer, SignalingOffer, TrackInfo,
+    JOIN_TOKEN_TYPE,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::{mpsc, Mutex, OwnedMutexGuard};
+use tracing::{warn, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    config::ServerConfig,
+    messaging::{ChannelKind, MessagingService},
+    mls::MlsKeyStore,
+    session, AppState,
+};
+
+/// Signals queued for one participant before further ones are dropped.
+const OUTBOX_CAPACITY: usize = 256;
+
+/// How long a join token admits its holder to the SFU.
+const JOIN_TOKEN_TTL_SECS: i64 = 120;
+
+#[derive(Debug, Error)]
+pub enum VoiceError {
+    #[error("{0}")]
+    InvalidState(&'static str),
+    #[error("participant is not in this channel")]
+    UnknownParticipant,
+    #[error("track not found")]
+    UnknownTrack,
+    #[error("track is already published")]
+    DuplicateTrack,
+    #[error("media key epoch {0} is not current")]
+    StaleEpoch(u64),
+    #[error("no key package for '{0}'")]
+    UnknownKeyPackage(String),
+    #[error(transparent)]
+    Sfu(#[from] SfuError),
+}
+
+impl VoiceError {
+    fn code(&self) -> SignalErrorCode {
+        match self {
+            VoiceError::InvalidState(_) => SignalErrorCode::InvalidState,
+            VoiceError::UnknownParticipant => SignalErrorCode::UnknownParticipant,
+            VoiceError::UnknownTrack => SignalErrorCode::UnknownTrack,
+            VoiceError::DuplicateTrack => SignalErrorCode::DuplicateTrack,
+            VoiceError::StaleEpoch(_) => SignalErrorCode::StaleEpoch,
+            VoiceError::UnknownKeyPackage(_) => SignalErrorCode::UnknownKeyPackage,
+            VoiceError::Sfu(SfuError::Unavailable(_)) => SignalErrorCode::SfuUnavailable,
+            VoiceError::Sfu(_) => SignalErrorCode::SfuRejected,
+        }
+    }
+}
+
+/// Where a participant's peer connection is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Joined; the client has not sent its first offer yet.
+    AwaitingOffer,
+    Connected,
+    /// The SFU sent a renegotiation offer and is waiting for the client's answer.
+    Renegotiating,
+}
+
+struct Participant {
+    user_id: Uuid,
+    /// Device of the session that joined; only its key package may be registered.
+    device_id: Option<String>,
+    session_id: String,
+    phase: Phase,
+    muted: bool,
+    deafened: bool,
+    tracks: BTreeMap<String, TrackInfo>,
+    /// `(publisher, track)` pairs forwarded to this participant.
+    subscriptions: BTreeSet<(Uuid, String)>,
+    /// Where other participants seal their media keys; `None` until registered.
+    key_package: Option<MediaKeyPackage>,
+    outbox: mpsc::Sender<ServerSignal>,
+}
+
+impl Participant {
+    fn state(&self, participant_id: Uuid) -> ParticipantState {
+        ParticipantState {
+            participant_id,
+            user_id: self.user_id,
+            muted: self.muted,
+            deafened: self.deafened,
+            tracks: self.tracks.values().cloned().collect(),
+            key_package: self.key_package.clone(),
+        }
+    }
+
+    fn send(&self, signal: ServerSignal) {
+        if let Err(err) = self.outbox.try_send(signal) {
+            if matches!(err, mpsc::error::TrySendError::Full(_)) {
+                warn!(user_id = %self.user_id, "voice signaling queue full; dropping signal");
+            }
+        }
+    }
+
+    fn require_connected(&self) -> Result<(), VoiceError> {
+        match self.phase {
+            Phase::AwaitingOffer => Err(VoiceError::InvalidState("send an offer first")),
+            Phase::Connected | Phase::Renegotiating => Ok(()),
+        }
+    }
+
+    fn require_stable(&self) -> Result<(), VoiceError> {
+        match self.phase {
+            Phase::AwaitingOffer => Err(VoiceError::InvalidState("send an offer first")),
+            Phase::Connected => Ok(()),
+            Phase::Renegotiating => Err(VoiceError::InvalidState("answer the pending offer first")),
+        }
+    }
+}
+
+#[derive(Default)]
+struct VoiceRoom {
+    participants: HashMap<Uuid, Participant>,
+    /// Media key epoch; bumped whenever a key holder joins or leaves.
+    key_epoch: u64,
+}
+
+impl VoiceRoom {
+    fn participant(&mut self, participant_id: Uuid) -> Result<&mut Participant, VoiceError> {
+        self.participants
+            .get_mut(&participant_id)
+            .ok_or(VoiceError::UnknownParticipant)
+    }
+
+    fn broadcast(&self, from: Uuid, signal: ServerSignal) {
+        for (participant_id, participant) in &self.participants {
+            if *participant_id != from {
+                participant.send(signal.clone());
+            }
+        }
+    }
+
+    /// Start a new media key epoch and ask every key holder for a fresh key, so departed
+    /// participants cannot decrypt new media and newcomers cannot decrypt old media.
+    fn rotate_media_key(&mut self) {
+        self.key_epoch += 1;
+        let epoch = self.key_epoch;
+        for participant in self.participants.values() {
+            if participant.key_package.is_some() {
+                participant.send(ServerSignal::RotateMediaKey { epoch });
+            }
+        }
+    }
+
+    /// Forget every subscription to `publisher`'s tracks (or just `track_id`).
+    fn drop_subscriptions_to(&mut self, publisher: Uuid, track_id: Option<&str>) {
+        for participant in self.participants.values_mut() {
+            participant.subscriptions.retain(|(id, track)| {
+                *id != publisher || track_id.is_some_and(|track_id| track_id != track)
+            });
+        }
+    }
+}
+
+/// Voice rooms keyed by channel. Each room is locked for the whole of an SFU call so
+/// signals from one room apply in order; the room map is only ever locked briefly, and
+/// never while waiting for a room.
+pub struct VoiceService {
+    sfu: Arc<dyn SfuClient>,
+    rooms: Mutex<HashMap<Uuid, Arc<Mutex<VoiceRoom>>>>,
+    notifications: Option<Arc<MessagingService>>,
+    /// Key packages and SFrame suite for end-to-end media encryption.
+    media_keys: Option<(Arc<MlsKeyStore>, &'static str)>,
+}
+
+impl VoiceService {
+    pub fn new(sfu: Arc<dyn SfuClient>) -> Self {
+        Self {
+            sfu,
+            rooms: Mutex::new(HashMap::new()),
+            notifications: None,
+            media_keys: None,
+        }
+    }
+
+    /// Coordinate SFrame keys sealed to each device's last-resort key package in `mls`.
+    /// Stays off when the MLS ciphersuite has no SFrame counterpart.
+    pub fn with_media_encryption(mut self, mls: Arc<MlsKeyStore>) -> Self {
+        match mls.sframe_suite() {
+            Some(suite) => self.media_keys = Some((mls, suite)),
+            None => warn!("mls ciphersuite has no sframe suite; voice media keys disabled"),
+        }
+        self
+    }
+
+    fn media_encryption(&self, epoch: u64) -> Option<MediaEncryption> {
+        self.media_keys.as_ref().map(|(_, suite)| MediaEncryption {
+            sframe_suite: suite.to_string(),
+            epoch,
+        })
+    }
+
+    /// Announce joins, leaves and mute/deafen changes to the channel's guild.
+    pub fn with_notifications(mut self, messaging: Arc<MessagingService>) -> Self {
+        self.notifications = Some(messaging);
+        self
+    }
+
+    async fn notify(&self, channel_id: Uuid, kind: &str, event: serde_json::Value) {
+        let Some(messaging) = &self.notifications else {
+            return;
+        };
+        if let Err(err) = messaging
+            .notify_channel_guild(channel_id, kind, event)
+            .await
+        {
+            warn!(?err, %channel_id, kind, "failed to deliver voice notification");
+        }
+    }
+
+    async fn room(&self, channel_id: Uuid) -> Result<Arc<Mutex<VoiceRoom>>, VoiceError> {
+        self.rooms
+            .lock()
+            .await
+            .get(&channel_id)
+            .cloned()
+            .ok_or(VoiceError::UnknownParticipant)
+    }
+
+    /// Lock the room for `channel_id`, creating it if needed. A room is only dropped from
+    /// the map while locked, so a lock on a room still in the map is safe to join.
+    async fn lock_or_create_room(&self, channel_id: Uuid) -> OwnedMutexGuard<VoiceRoom> {
+        loop {
+            let room = self
+                .rooms
+                .lock()
+                .await
+                .entry(channel_id)
+                .or_default()
+                .clone();
+            let guard = room.clone().lock_owned().await;
+            let current = self.rooms.lock().await.get(&channel_id).cloned();
+            if current.is_some_and(|current| Arc::ptr_eq(&current, &room)) {
+                return guard;
+            }
+        }
+    }
+
+    /// Drop `room` from the map once nobody is in it; called with the room locked.
+    async fn forget_room_if_empty(&self, channel_id: Uuid, room: &OwnedMutexGuard<VoiceRoom>) {
+        if !room.participants.is_empty() {
+            return;
+        }
+        let mut rooms = self.rooms.lock().await;
+        if rooms
+            .get(&channel_id)
+            .is_some_and(|current| Arc::ptr_eq(current, OwnedMutexGuard::mutex(room)))
+        {
+            rooms.remove(&channel_id);
+        }
+    }
+
+    /// Participants currently connected to `channel_id`.
+    pub async fn participants(&self, channel_id: Uuid) -> Vec<ParticipantState> {
+        let Some(room) = self.rooms.lock().await.get(&channel_id).cloned() else {
+            return Vec::new();
+        };
+        let room = room.lock().await;
+        let mut participants: Vec<_> = room
+            .participants
+            .iter()
+            .map(|(id, participant)| participant.state(*id))
+            .collect();
+        participants.sort_by_key(|participant| participant.participant_id);
+        participants
+    }
+
+    /// Open an SFU session for `user_id` on `device_id` in `channel_id`. The returned
+    /// receiver yields `Joined` first, then everything addressed to the new participant.
+    pub async fn join(
+        &self,
+        channel_id: Uuid,
+        user_id: Uuid,
+        device_id: Option<String>,
+    ) -> Result<(Uuid, mpsc::Receiver<ServerSignal>), VoiceError> {
+        let participant_id = Uuid::new_v4();
+        let mut room = self.lock_or_create_room(channel_id).await;
+        let session = match self
+            .sfu
+            .create_session(&channel_id.to_string(), &participant_id.to_string())
+            .await
+        {
+            Ok(session) => session,
+            Err(err) => {
+                self.forget_room_if_empty(channel_id, &room).await;
+                return Err(err.into());
+            }
+        };
+
+        let (outbox, inbox) = mpsc::channel(OUTBOX_CAPACITY);
+        let participant = Participant {
+            user_id,
+            device_id,
+            session_id: session.session_id,
+            phase: Phase::AwaitingOffer,
+            muted: false,
+            deafened: false,
+            tracks: BTreeMap::new(),
+            subscriptions: BTreeSet::new(),
+            key_package: None,
+            outbox,
+        };
+        let mut participants: Vec<_> = room
+            .participants
+            .iter()
+            .map(|(id, other)| other.state(*id))
+            .collect();
+        participants.sort_by_key(|participant| participant.participant_id);
+        participant.send(ServerSignal::Joined {
+            participant_id,
+            participants,
+            media_encryption: self.media_encryption(room.key_epoch),
+        });
+        let state = participant.state(participant_id);
+        room.broadcast(
+            participant_id,
+            ServerSignal::ParticipantJoined {
+                participant: state.clone(),
+            },
+        );
+        room.participants.insert(participant_id, participant);
+        self.notify(
+            channel_id,
+            "voice_participant_joined",
+            json!({ "channel_id": channel_id, "participant": state }),
+        )
+        .await;
+        Ok((participant_id, inbox))
+    }
+
+    /// Apply one client signal. Replies and fan-out go through participants' outboxes;
+    /// `Leave` is handled by the caller through `leave`.
+    pub async fn handle(
+        &self,
+        channel_id: Uuid,
+        participant_id: Uuid,
+        signal: ClientSignal,
+    ) -> Result<(), VoiceError> {
+        let room = self.room(channel_id).await?;
+        let mut room = room.lock().await;
+        let sfu = self.sfu.as_ref();
+
+        match signal {
+            ClientSignal::Offer { sdp } => {
+                let participant = room.participant(participant_id)?;
+                if participant.phase == Phase::Renegotiating {
+                    return Err(VoiceError::InvalidState("answer the pending offer first"));
+                }
+                let answer = sfu
+                    .negotiate(&participant.session_id, SignalingOffer { sdp })
+                    .await?;
+                participant.phase = Phase::Connected;
+                participant.send(ServerSignal::Answer { sdp: answer.sdp });
+            }
+            ClientSignal::Answer { sdp } => {
+                let participant = room.participant(participant_id)?;
+                if participant.phase != Phase::Renegotiating {
+                    return Err(VoiceError::InvalidState("no offer is pending"));
+                }
+                sfu.accept_answer(&participant.session_id, SignalingAnswer { sdp })
+                    .await?;
+                participant.phase = Phase::Connected;
+            }
+            ClientSignal::IceCandidate { candidate } => {
+                let participant = room.participant(participant_id)?;
+                participant.require_connected()?;
+                sfu.add_ice_candidate(&participant.session_id, &candidate)
+                    .await?;
+            }
+            ClientSignal::Publish { track_id, kind } => {
+                let participant = room.participant(participant_id)?;
+                participant.require_connected()?;
+                if participant.tracks.contains_key(&track_id) {
+                    return Err(VoiceError::DuplicateTrack);
+                }
+                let track = TrackInfo {
+                    track_id: track_id.clone(),
+                    kind,
+                    muted: false,
+                };
+                sfu.publish(&participant.session_id, &track).await?;
+                participant.tracks.insert(track_id, track.clone());
+                room.broadcast(
+                    participant_id,
+                    ServerSignal::TrackPublished {
+                        participant_id,
+                        track,
+                    },
+                );
+            }
+            ClientSignal::Unpublish { track_id } => {
+                let participant = room.participant(participant_id)?;
+                if !participant.tracks.contains_key(&track_id) {
+                    return Err(VoiceError::UnknownTrack);
+                }
+                sfu.unpublish(&participant.session_id, &track_id).await?;
+                participant.tracks.remove(&track_id);
+                room.drop_subscriptions_to(participant_id, Some(&track_id));
+                room.broadcast(
+                    participant_id,
+                    ServerSignal::TrackUnpublished {
+                        participant_id,
+                        track_id,
+                    },
+                );
+            }
+            ClientSignal::Subscribe {
+                participant_id: publisher_id,
+                track_id,
+            } => {
+                if publisher_id == participant_id {
+                    return Err(VoiceError::InvalidState(
+                        "cannot subscribe to your own track",
+                    ));
+                }
+                let publisher = room
+                    .participants
+                    .get(&publisher_id)
+                    .ok_or(VoiceError::UnknownParticipant)?;
+                if !publisher.tracks.contains_key(&track_id) {
+                    return Err(VoiceError::UnknownTrack);
+                }
+                let publisher_session = publisher.session_id.clone();
+                let participant = room.participant(participant_id)?;
+                participant.require_stable()?;
+                let offer = sfu
+                    .subscribe(&participant.session_id, &publisher_session, &track_id)
+                    .await?;
+                participant.subscriptions.insert((publisher_id, track_id));
+                participant.phase = Phase::Renegotiating;
+                participant.send(ServerSignal::Offer { sdp: offer.sdp });
+            }
+            ClientSignal::Unsubscribe {
+                participant_id: publisher_id,
+                track_id,
+            } => {
+                let publisher_session = room
+                    .participants
+                    .get(&publisher_id)
+                    .map(|publisher| publisher.session_id.clone())
+                    .ok_or(VoiceError::UnknownParticipant)?;
+                let participant = room.participant(participant_id)?;
+                participant.require_stable()?;
+                let key = (publisher_id, track_id);
+                if !participant.subscriptions.contains(&key) {
+                    return Err(VoiceError::UnknownTrack);
+                }
+                let offer = sfu
+                    .unsubscribe(&participant.session_id, &publisher_session, &key.1)
+                    .await?;
+                participant.subscriptions.remove(&key);
+                participant.phase = Phase::Renegotiating;
+                participant.send(ServerSignal::Offer { sdp: offer.sdp });
+            }
+            ClientSignal::Mute { track_id, muted } => {
+                let participant = room.participant(participant_id)?;
+                if !participant.tracks.contains_key(&track_id) {
+                    return Err(VoiceError::UnknownTrack);
+                }
+                sfu.set_muted(&participant.session_id, &track_id, muted)
+                    .await?;
+                if let Some(track) = participant.tracks.get_mut(&track_id) {
+                    track.muted = muted;
+                }
+                room.broadcast(
+                    participant_id,
+                    ServerSignal::MuteChanged {
+                        participant_id,
+                        track_id,
+                        muted,
+                    },
+                );
+            }
+            ClientSignal::VoiceState { muted, deafened } => {
+                let participant = room.participant(participant_id)?;
+                participant.muted = muted || deafened;
+                participant.deafened = deafened;
+                let muted = participant.muted;
+                room.broadcast(
+                    participant_id,
+                    ServerSignal::VoiceStateChanged {
+                        participant_id,
+                        muted,
+                        deafened,
+                    },
+                );
+                self.notify(
+                    channel_id,
+                    "voice_state_updated",
+                    json!({
+                        "channel_id": channel_id,
+                        "participant_id": participant_id,
+                        "muted": muted,
+                        "deafened": deafened,
+                    }),
+                )
+                .await;
+            }
+            ClientSignal::KeyPackage { device_id } => {
+                let Some((mls, _)) = &self.media_keys else {
+                    return Err(VoiceError::InvalidState("media encryption is not enabled"));
+                };
+                let participant = room.participant(participant_id)?;
+                if participant.device_id.as_deref() != Some(device_id.as_str()) {
+                    return Err(VoiceError::InvalidState(
+                        "only the session's own device can register a key package",
+                    ));
+                }
+                let user_id = participant.user_id;
+                let package = match mls.last_resort_package(user_id, &device_id).await {
+                    Ok(package) => package,
+                    Err(err) => {
+                        warn!(?err, %user_id, "failed to load voice key package");
+                        None
+                    }
+                };
+                // Media keys are only sealed to packages whose credential names the user.
+                let package = package
+                    .filter(|package| package.identity == user_id.to_string())
+                    .ok_or(VoiceError::UnknownKeyPackage(device_id))?;
+                let key_package = MediaKeyPackage {
+                    identity: package.identity,
+                    ciphersuite: package.ciphersuite,
+                    hpke_public_key: package.hpke_public_key,
+                };
+                room.participant(participant_id)?.key_package = Some(key_package.clone());
+                room.broadcast(
+                    participant_id,
+                    ServerSignal::KeyPackageChanged {
+                        participant_id,
+                        key_package,
+                    },
+                );
+                room.rotate_media_key();
+            }
+            ClientSignal::MediaKey {
+                epoch,
+                key_id,
+                keys,
+            } => {
+                if self.media_keys.is_none() {
+                    return Err(VoiceError::InvalidState("media encryption is not enabled"));
+                }
+                if epoch != room.key_epoch {
+                    return Err(VoiceError::StaleEpoch(epoch));
+                }
+                if room.participant(participant_id)?.key_package.is_none() {
+                    return Err(VoiceError::InvalidState("register a key package first"));
+                }
+                let mut recipients = Vec::with_capacity(keys.len());
+                for key in keys {
+                    if key.participant_id == participant_id {
+                        return Err(VoiceError::InvalidState(
+                            "media keys are not sent to yourself",
+                        ));
+                    }
+                    let recipient = room
+                        .participants
+                        .get(&key.participant_id)
+                        .ok_or(VoiceError::UnknownParticipant)?;
+                    if recipient.key_package.is_none() {
+                        return Err(VoiceError::UnknownKeyPackage(
+                            key.participant_id.to_string(),
+                        ));
+                    }
+                    recipients.push((recipient, key.ciphertext));
+                }
+                for (recipient, ciphertext) in recipients {
+                    recipient.send(ServerSignal::MediaKey {
+                        participant_id,
+                        epoch,
+                        key_id,
+                        ciphertext,
+                    });
+                }
+            }
+            ClientSignal::Leave => {}
+        }
+        Ok(())
+    }
+
+    /// Remove the participant and close its SFU session. Safe to call more than once.
+    pub async fn leave(&self, channel_id: Uuid, participant_id: Uuid) {
+        let Ok(room) = self.room(channel_id).await else {
+            return;
+        };
+        let mut room = room.lock_owned().await;
+        let Some(participant) = room.participants.remove(&participant_id) else {
+            return;
+        };
+        room.drop_subscriptions_to(participant_id, None);
+        room.broadcast(
+            participant_id,
+            ServerSignal::ParticipantLeft { participant_id },
+        );
+        if participant.key_package.is_some() {
+            room.rotate_media_key();
+        }
+        self.notify(
+            channel_id,
+            "voice_participant_left",
+            json!({
+                "channel_id": channel_id,
+                "participant_id": participant_id,
+                "user_id": participant.user_id,
+            }),
+        )
+        .await;
+        self.forget_room_if_empty(channel_id, &room).await;
+        drop(room);
+
+        if let Err(err) = self.sfu.close_session(&participant.session_id).await {
+            warn!(?err, %channel_id, %participant_id, "failed to close sfu session");
+        }
+    }
+
+    async fn run_socket(
+        self: Arc<Self>,
+        channel_id: Uuid,
+        user_id: Uuid,
+        device_id: Option<String>,
+        mut socket: WebSocket,
+    ) {
+        let (participant_id, mut inbox) = match self.join(channel_id, user_id, device_id).await {
+            Ok(joined) => joined,
+            Err(err) => {
+                warn!(?err, "failed to join voice channel");
+                let _ = send_signal(&mut socket, &error_signal(err.code(), err.to_string())).await;
+                return;
+            }
+        };
+
+        loop {
+            tokio::select! {
+                signal = inbox.recv() => {
+                    let Some(signal) = signal else { break };
+                    if send_signal(&mut socket, &signal).await.is_err() {
+                        break;
+                    }
+                }
+                message = socket.recv() => {
+                    let text = match message {
+                        Some(Ok(WsMessage::Text(text))) => text,
+                        Some(Ok(WsMessage::Close(_))) | None | Some(Err(_)) => break,
+                        Some(Ok(WsMessage::Ping(payload))) => {
+                            if socket.send(WsMessage::Pong(payload)).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        Some(Ok(_)) => continue,
+                    };
+                    let signal = match serde_json::from_str::<ClientSignal>(&text) {
+                        Ok(signal) => signal,
+                        Err(err) => {
+                            let reply = error_signal(SignalErrorCode::InvalidMessage, err.to_string());
+                            if send_signal(&mut socket, &reply).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    if signal == ClientSignal::Leave {
+                        break;
+                    }
+                    let result = match signal.validate() {
+                        Ok(()) => self.handle(channel_id, participant_id, signal).await,
+                        Err(reason) => {
+                            let reply = error_signal(SignalErrorCode::InvalidMessage, reason);
+                            if send_signal(&mut socket, &reply).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    if let Err(err) = result {
+                        let reply = error_signal(err.code(), err.to_string());
+                        if send_signal(&mut socket, &reply).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.leave(channel_id, participant_id).await;
+        let _ = socket.send(WsMessage::Close(None)).await;
+    }
+}
+
+fn error_signal(code: SignalErrorCode, message: String) -> ServerSignal {
+    ServerSignal::Error { code, message }
+}
+
+async fn send_signal(socket: &mut WebSocket, signal: &ServerSignal) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(signal).unwrap_or_default();
+    socket.send(WsMessage::Text(text.into())).await
+}
+
+/// Voice rooms backed by the configured SFU, or `None` when voice is not configured.
+pub fn init_voice_service(
+    config: &ServerConfig,
+    messaging: Arc<MessagingService>,
+    mls: Option<Arc<MlsKeyStore>>,
+) -> anyhow::Result<Option<VoiceService>> {
+    let Some(url) = config
+        .voice
+        .sfu_url
+        .as_deref()
+        .filter(|url| !url.trim().is_empty())
+    else {
+        return Ok(None);
+    };
+    let mut client = HttpSfuClient::new(url)?;
+    if let Some(token) = &config.voice.sfu_token {
+        client = client.with_token(token.clone());
+    }
+    let mut voice = VoiceService::new(Arc::new(client)).with_notifications(messaging);
+    if let Some(mls) = mls {
+        voice = voice.with_media_encryption(mls);
+    }
+    Ok(Some(voice))
+}
+
+/// 404 for unknown channels, 400 for channels that are not voice channels.
+async fn require_voice_channel(
+    state: &AppState,
+    messaging: &MessagingService,
+    channel_id: Uuid,
+) -> Result<(), StatusCode> {
+    match messaging.channel_by_id(channel_id).await {
+        Ok(Some(channel)) if ChannelKind::of(&channel) == ChannelKind::Voice => Ok(()),
+        Ok(Some(_)) => {
+            state.record_messaging_rejection("not_voice_channel");
+            Err(StatusCode::BAD_REQUEST)
+        }
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            tracing::error!(?err, "failed to load voice channel");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VoiceSocketQuery {
+    pub access_token: Option<String>,
+}
+
+pub async fn voice_socket(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+    Query(query): Query<VoiceSocketQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let Some(voice) = state.voice() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+
+    let claims = match session::authenticate_bearer(&state, &headers) {
+        Err(StatusCode::UNAUTHORIZED) => match query
+            .access_token
+            .as_deref()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+        {
+            Some(token) => match state.session().verify_access_token(token) {
+                Ok(Some(claims)) => Ok(claims),
+                Ok(None) => Err(StatusCode::UNAUTHORIZED),
+                Err(err) => {
+                    tracing::error!(?err, "failed to verify voice socket access token");
+                    Err(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            },
+            None => Err(StatusCode::UNAUTHORIZED),
+        },
+        other => other,
+    };
+    let status = match claims {
+        Ok(claims) => match require_voice_channel(&state, &messaging, channel_id).await {
+            Ok(()) => {
+                #[cfg(not(feature = "metrics"))]
+                let _ = matched_path;
+                let user_id = claims.user_id;
+                let device_id = claims.device_id;
+                return Ok(ws.on_upgrade(move |socket| {
+                    let span = tracing::info_span!(
+                        "websocket.voice",
+                        channel_id = %channel_id,
+                        user_id = %user_id
+                    );
+                    voice
+                        .run_socket(channel_id, user_id, device_id, socket)
+                        .instrument(span)
+                }));
+            }
+            Err(status) => status,
+        },
+        Err(status) => {
+            if status == StatusCode::UNAUTHORIZED {
+                state.record_messaging_rejection("unauthorized");
+            }
+            status
+        }
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+    Err(status)
+}
+
+pub async fn list_participants(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+) -> Result<Json<Vec<ParticipantState>>, StatusCode> {
+    let Some(voice) = state.voice() else {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        if *status == StatusCode::UNAUTHORIZED {
+            state.record_messaging_rejection("unauthorized");
+        }
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+    })?;
+
+    let status = match require_voice_channel(&state, &messaging, channel_id).await {
+        Ok(()) => {
+            #[cfg(feature = "metrics")]
+            state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+            #[cfg(not(feature = "metrics"))]
+            let _ = matched_path;
+            return Ok(Json(voice.participants(channel_id).await));
+        }
+        Err(status) => status,
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+    Err(status)
+}
+
+#[derive(Debug, Serialize)]
+pub struct JoinTokenResponse {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Sign a join token with the session key for the SFU to verify offline.
+pub fn issue_join_token(
+    signer: &session::SessionSigner,
+    channel_id: Uuid,
+    user_id: Uuid,
+) -> anyhow::Result<JoinTokenResponse> {
+    let issued_at = Utc::now();
+    let expires_at = issued_at + Duration::seconds(JOIN_TOKEN_TTL_SECS);
+    let token = signer.sign_claims(&JoinClaims {
+        typ: JOIN_TOKEN_TYPE.to_string(),
+        channel_id,
+        user_id,
+        issued_at,
+        expires_at,
+    })?;
+    Ok(JoinTokenResponse { token, expires_at })
+}
+
+pub async fn create_join_token(
+    matched_path: MatchedPath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel_id): Path<Uuid>,
+) -> Result<Json<JoinTokenResponse>, StatusCode> {
+    if state.voice().is_none() {
+        let status = StatusCode::NOT_IMPLEMENTED;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    }
+    let Some(messaging) = state.messaging() else {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+        return Err(status);
+    };
+    let claims = session::authenticate_bearer(&state, &headers).inspect_err(|status| {
+        if *status == StatusCode::UNAUTHORIZED {
+            state.record_messaging_rejection("unauthorized");
+        }
+        #[cfg(feature = "metrics")]
+        state.record_http_request(matched_path.as_str(), status.as_u16());
+    })?;
+
+    let status = match require_voice_channel(&state, &messaging, channel_id).await {
+        Ok(()) => match issue_join_token(state.session().signer(), channel_id, claims.user_id) {
+            Ok(token) => {
+                #[cfg(feature = "metrics")]
+                state.record_http_request(matched_path.as_str(), StatusCode::OK.as_u16());
+                #[cfg(not(feature = "metrics"))]
+                let _ = matched_path;
+                return Ok(Json(token));
+            }
+            Err(err) => {
+                tracing::error!(?err, "failed to sign voice join token");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        },
+        Err(status) => status,
+    };
+    #[cfg(feature = "metrics")]
+    state.record_http_request(matched_path.as_str(), status.as_u16());
+    #[cfg(not(feature = "metrics"))]
+    let _ = matched_path;
+    Err(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use openguild_sfu_client::{IceCandidate, MockSfu, TrackKind};
+
+    use super::*;
+    use crate::{mls::UploadKeyPackagesRequest, mls_groups::test_client::TestClient};
+
+    fn next(inbox: &mut mpsc::Receiver<ServerSignal>) -> ServerSignal {
+        inbox.try_recv().expect("signal queued")
+    }
+
+    #[tokio::test]
+    async fn participants_move_through_the_signaling_states() {
+        let sfu = Arc::new(MockSfu::new());
+        let voice = VoiceService::new(sfu.clone());
+        let channel_id = Uuid::new_v4();
+
+        let (alice, mut alice_inbox) = voice.join(channel_id, Uuid::new_v4(), None).await.unwrap();
+        assert!(matches!(
+            next(&mut alice_inbox),
+            ServerSignal::Joined { participants, .. } if participants.is_empty()
+        ));
+        let (bob, mut bob_inbox) = voice.join(channel_id, Uuid::new_v4(), None).await.unwrap();
+        assert!(matches!(
+            next(&mut bob_inbox),
+            ServerSignal::Joined { participants, .. } if participants.len() == 1
+        ));
+        assert!(matches!(
+            next(&mut alice_inbox),
+            ServerSignal::ParticipantJoined { participant } if participant.participant_id == bob
+        ));
+
+        // Nothing but an offer is accepted before the connection is negotiated.
+        let candidate = ClientSignal::IceCandidate {
+            candidate: IceCandidate {
+                candidate: "candidate:1 1 udp 2122260223 10.0.0.2 54321 typ host".into(),
+                sdp_mid: Some("0".into()),
+                sdp_mline_index: Some(0),
+            },
+        };
+        assert!(matches!(
+            voice.handle(channel_id, alice, candidate.clone()).await,
+            Err(VoiceError::InvalidState(_))
+        ));
+        for (participant, inbox) in [(alice, &mut alice_inbox), (bob, &mut bob_inbox)] {
+            let offer = ClientSignal::Offer { sdp: "v=0".into() };
+            voice.handle(channel_id, participant, offer).await.unwrap();
+            assert!(matches!(next(inbox), ServerSignal::Answer { .. }));
+        }
+        voice.handle(channel_id, alice, candidate).await.unwrap();
+
+        let publish = ClientSignal::Publish {
+            track_id: "mic".into(),
+            kind: TrackKind::Audio,
+        };
+        voice
+            .handle(channel_id, alice, publish.clone())
+            .await
+            .unwrap();
+        assert!(matches!(
+            voice.handle(channel_id, alice, publish).await,
+            Err(VoiceError::DuplicateTrack)
+        ));
+        assert!(matches!(
+            next(&mut bob_inbox),
+            ServerSignal::TrackPublished { participant_id, .. } if participant_id == alice
+        ));
+
+        let subscribe = ClientSignal::Subscribe {
+            participant_id: alice,
+            track_id: "mic".into(),
+        };
+        voice.handle(channel_id, bob, subscribe).await.unwrap();
+        assert!(matches!(next(&mut bob_inbox), ServerSignal::Offer { .. }));
+        // A second renegotiation waits for the first to be answered.
+        let unsubscribe = ClientSignal::Unsubscribe {
+            participant_id: alice,
+            track_id: "mic".into(),
+        };
+        assert!(matches!(
+            voice.handle(channel_id, bob, unsubscribe).await,
+            Err(VoiceError::InvalidState(_))
+        ));
+        let answer = ClientSignal::Answer { sdp: "v=0".into() };
+        voice.handle(channel_id, bob, answer.clone()).await.unwrap();
+        assert!(matches!(
+            voice.handle(channel_id, bob, answer).await,
+            Err(VoiceError::InvalidState(_))
+        ));
+
+        let mute = ClientSignal::Mute {
+            track_id: "mic".into(),
+            muted: true,
+        };
+        voice.handle(channel_id, alice, mute).await.unwrap();
+        assert!(matches!(
+            next(&mut bob_inbox),
+            ServerSignal::MuteChanged { muted: true, .. }
+        ));
+        assert!(voice
+            .participants(channel_id)
+            .await
+            .iter()
+            .any(|participant| {
+                participant.participant_id == alice && participant.tracks[0].muted
+            }));
+
+        voice.leave(channel_id, alice).await;
+        assert!(matches!(
+            next(&mut bob_inbox),
+            ServerSignal::ParticipantLeft { participant_id } if participant_id == alice
+        ));
+        assert_eq!(sfu.session_count(), 1);
+        voice.leave(channel_id, bob).await;
+        assert_eq!(sfu.session_count(), 0);
+        assert!(voice.participants(channel_id).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn voice_state_reaches_the_room_and_the_guild() {
+        let messaging = Arc::new(MessagingService::new_in_memory("local.test".into()));
+        let guild = messaging.create_guild("Voice").await.unwrap();
+        let channel = messaging
+            .create_channel_with_kind(guild.guild_id, "lounge", ChannelKind::Voice, None)
+            .await
+            .unwrap();
+        let mut notifications = messaging
+            .notification_sender(Uuid::new_v4())
+            .await
+            .subscribe();
+        let voice =
+            VoiceService::new(Arc::new(MockSfu::new())).with_notifications(messaging.clone());
+        let channel_id = channel.channel_id;
+
+        let alice_id = Uuid::new_v4();
+        let (alice, _alice_inbox) = voice.join(channel_id, alice_id, None).await.unwrap();
+        let (bob, mut bob_inbox) = voice.join(channel_id, Uuid::new_v4(), None).await.unwrap();
+        next(&mut bob_inbox);
+        let joined = notifications.try_recv().unwrap();
+        assert_eq!(joined.kind, "voice_participant_joined");
+        assert_eq!(joined.guild_id, Some(guild.guild_id));
+        assert_eq!(joined.event["participant"]["user_id"], json!(alice_id));
+        assert_eq!(
+            notifications.try_recv().unwrap().kind,
+            "voice_participant_joined"
+        );
+
+        // Deafening implies muting.
+        let deafen = ClientSignal::VoiceState {
+            muted: false,
+            deafened: true,
+        };
+        voice.handle(channel_id, alice, deafen).await.unwrap();
+        assert!(matches!(
+            next(&mut bob_inbox),
+            ServerSignal::VoiceStateChanged { participant_id, muted: true, deafened: true }
+                if participant_id == alice
+        ));
+        let updated = notifications.try_recv().unwrap();
+        assert_eq!(updated.kind, "voice_state_updated");
+        assert_eq!(updated.event["muted"], true);
+        let participants = voice.participants(channel_id).await;
+        let state = participants
+            .iter()
+            .find(|participant| participant.participant_id == alice)
+            .unwrap();
+        assert!(state.muted && state.deafened);
+
+        voice.leave(channel_id, alice).await;
+        let left = notifications.try_recv().unwrap();
+        assert_eq!(left.kind, "voice_participant_left");
+        assert_eq!(left.event["user_id"], json!(alice_id));
+        voice.leave(channel_id, bob).await;
+    }
+
+    #[tokio::test]
+    async fn media_keys_rotate_as_key_holders_come_and_go() {
+        let mls = Arc::new(MlsKeyStore::new(
+            "MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519",
+        ));
+        let (alice_user, bob_user) = (Uuid::new_v4(), Uuid::new_v4());
+        for user_id in [alice_user, bob_user] {
+            let client = TestClient::new(&user_id.to_string());
+            let upload = UploadKeyPackagesRequest {
+                device_id: "laptop".into(),
+                key_packages: Vec::new(),
+                last_resort: Some(client.encoded_key_package(true)),
+            };
+            mls.upload(user_id, upload).await.unwrap();
+        }
+        let alice_package = mls
+            .last_resort_package(alice_user, "laptop")
+            .await
+            .unwrap()
+            .unwrap();
+        let voice = VoiceService::new(Arc::new(MockSfu::new())).with_media_encryption(mls.clone());
+        let channel_id = Uuid::new_v4();
+
+        let (alice, mut alice_inbox) = voice
+            .join(channel_id, alice_user, Some("laptop".into()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next(&mut alice_inbox),
+            ServerSignal::Joined { media_encryption: Some(encryption), .. }
+                if encryption.sframe_suite == "AES_128_GCM_SHA256_128" && encryption.epoch == 0
+        ));
+        let (bob, mut bob_inbox) = voice
+            .join(channel_id, bob_user, Some("laptop".into()))
+            .await
+            .unwrap();
+        next(&mut bob_inbox);
+        next(&mut alice_inbox);
+
+        let register = |device_id: &str| ClientSignal::KeyPackage {
+            device_id: device_id.into(),
+        };
+        assert!(matches!(
+            voice.handle(channel_id, alice, register("phone")).await,
+            Err(VoiceError::InvalidState(_))
+        ));
+        let other_channel = Uuid::new_v4();
+        let (carol, _carol_inbox) = voice
+            .join(other_channel, Uuid::new_v4(), Some("laptop".into()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            voice.handle(other_channel, carol, register("laptop")).await,
+            Err(VoiceError::UnknownKeyPackage(_))
+        ));
+        voice
+            .handle(channel_id, alice, register("laptop"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next(&mut alice_inbox),
+            ServerSignal::RotateMediaKey { epoch: 1 }
+        ));
+        assert!(matches!(
+            next(&mut bob_inbox),
+            ServerSignal::KeyPackageChanged { key_package, .. }
+                if key_package.hpke_public_key == alice_package.hpke_public_key
+                    && key_package.identity == alice_user.to_string()
+        ));
+        voice
+            .handle(channel_id, bob, register("laptop"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next(&mut alice_inbox),
+            ServerSignal::KeyPackageChanged { participant_id, .. } if participant_id == bob
+        ));
+        assert!(matches!(
+            next(&mut alice_inbox),
+            ServerSignal::RotateMediaKey { epoch: 2 }
+        ));
+        assert!(matches!(
+            next(&mut bob_inbox),
+            ServerSignal::RotateMediaKey { epoch: 2 }
+        ));
+
+        let media_key = |epoch| ClientSignal::MediaKey {
+            epoch,
+            key_id: 42,
+            keys: vec![openguild_sfu_client::SealedMediaKey {
+                participant_id: bob,
+                ciphertext: "c2VhbGVkLWZvci1ib2I".into(),
+            }],
+        };
+        assert!(matches!(
+            voice.handle(channel_id, alice, media_key(1)).await,
+            Err(VoiceError::StaleEpoch(1))
+        ));
+        voice.handle(channel_id, alice, media_key(2)).await.unwrap();
+        assert!(matches!(
+            next(&mut bob_inbox),
+            ServerSignal::MediaKey { participant_id, epoch: 2, key_id: 42, ciphertext }
+                if participant_id == alice && ciphertext == "c2VhbGVkLWZvci1ib2I"
+        ));
+        assert!(alice_inbox.try_recv().is_err());
+
+        // Whoever stays behind moves to a key the departed participant never saw.
+        voice.leave(channel_id, bob).await;
+        assert!(matches!(
+            next(&mut alice_inbox),
+            ServerSignal::ParticipantLeft { .. }
+        ));
+        assert!(matches!(
+            next(&mut alice_inbox),
+            ServerSignal::RotateMediaKey { epoch: 3 }
+        ));
+        voice.leave(channel_id, alice).await;
+    }
+}
@@ -0,0 +1,498 @@
+//! Control API of the selective forwarding unit.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use reqwest::{Method, StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::{
+    protocol::{IceCandidate, TrackInfo},
+    SignalingAnswer, SignalingOffer,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A participant's peer connection on the SFU.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SfuSession {
+    pub session_id: String,
+}
+
+#[derive(Debug, Error)]
+pub enum SfuError {
+    #[error("sfu session or track not found")]
+    NotFound,
+    #[error("sfu rejected the request: {0}")]
+    Rejected(String),
+    #[error("sfu unavailable: {0}")]
+    Unavailable(String),
+}
+
+/// Operations the homeserver needs from an SFU. Rooms and participants are opaque names
+/// chosen by the caller; sessions are named by the SFU.
+///
+/// The SFU gathers its own ICE candidates before answering, so only client candidates
+/// are trickled.
+#[async_trait]
+pub trait SfuClient: Send + Sync {
+    /// Open a session for `participant` in `room`.
+    async fn create_session(&self, room: &str, participant: &str) -> Result<SfuSession, SfuError>;
+
+    /// Apply a client offer and return the SFU's answer.
+    async fn negotiate(
+        &self,
+        session_id: &str,
+        offer: SignalingOffer,
+    ) -> Result<SignalingAnswer, SfuError>;
+
+    /// Complete a renegotiation the SFU started with `subscribe` or `unsubscribe`.
+    async fn accept_answer(
+        &self,
+        session_id: &str,
+        answer: SignalingAnswer,
+    ) -> Result<(), SfuError>;
+
+    async fn add_ice_candidate(
+        &self,
+        session_id: &str,
+        candidate: &IceCandidate,
+    ) -> Result<(), SfuError>;
+
+    async fn publish(&self, session_id: &str, track: &TrackInfo) -> Result<(), SfuError>;
+
+    async fn unpublish(&self, session_id: &str, track_id: &str) -> Result<(), SfuError>;
+
+    /// Forward `publisher_session_id`'s track to `session_id`; returns the offer the
+    /// subscriber must answer.
+    async fn subscribe(
+        &self,
+        session_id: &str,
+        publisher_session_id: &str,
+        track_id: &str,
+    ) -> Result<SignalingOffer, SfuError>;
+
+    /// Stop forwarding a track; returns the offer the subscriber must answer.
+    async fn unsubscribe(
+        &self,
+        session_id: &str,
+        publisher_session_id: &str,
+        track_id: &str,
+    ) -> Result<SignalingOffer, SfuError>;
+
+    async fn set_muted(
+        &self,
+        session_id: &str,
+        track_id: &str,
+        muted: bool,
+    ) -> Result<(), SfuError>;
+
+    /// Tear the session down along with everything it published or subscribed to.
+    async fn close_session(&self, session_id: &str) -> Result<(), SfuError>;
+}
+
+/// `SfuClient` over the SFU's JSON control API.
+#[derive(Debug, Clone)]
+pub struct HttpSfuClient {
+    client: reqwest::Client,
+    base_url: Url,
+    token: Option<String>,
+}
+
+impl HttpSfuClient {
+    pub fn new(base_url: &str) -> anyhow::Result<Self> {
+        let base_url = Url::parse(base_url.trim_end_matches('/'))
+            .with_context(|| format!("invalid sfu url '{base_url}'"))?;
+        if base_url.cannot_be_a_base() {
+            anyhow::bail!("sfu url '{base_url}' cannot carry a path");
+        }
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("failed to build sfu http client")?;
+        Ok(Self {
+            client,
+            base_url,
+            token: None,
+        })
+    }
+
+    /// Send `token` as a bearer credential with every request.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("checked in HttpSfuClient::new")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        segments: &[&str],
+        body: Option<serde_json::Value>,
+    ) -> Result<reqwest::Response, SfuError> {
+        let mut request = self.client.request(method, self.url(segments));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|err| SfuError::Unavailable(err.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let reason = response.text().await.unwrap_or_default();
+        Err(match status {
+            StatusCode::NOT_FOUND => SfuError::NotFound,
+            status if status.is_client_error() => SfuError::Rejected(if reason.is_empty() {
+                status.to_string()
+            } else {
+                reason
+            }),
+            status => SfuError::Unavailable(status.to_string()),
+        })
+    }
+
+    async fn send_json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        segments: &[&str],
+        body: Option<serde_json::Value>,
+    ) -> Result<T, SfuError> {
+        self.send(method, segments, body)
+            .await?
+            .json()
+            .await
+            .map_err(|err| SfuError::Unavailable(format!("invalid sfu response: {err}")))
+    }
+}
+
+#[async_trait]
+impl SfuClient for HttpSfuClient {
+    async fn create_session(&self, room: &str, participant: &str) -> Result<SfuSession, SfuError> {
+        self.send_json(
+            Method::POST,
+            &["rooms", room, "sessions"],
+            Some(json!({ "participant": participant })),
+        )
+        .await
+    }
+
+    async fn negotiate(
+        &self,
+        session_id: &str,
+        offer: SignalingOffer,
+    ) -> Result<SignalingAnswer, SfuError> {
+        self.send_json(
+            Method::POST,
+            &["sessions", session_id, "offer"],
+            Some(json!(offer)),
+        )
+        .await
+    }
+
+    async fn accept_answer(
+        &self,
+        session_id: &str,
+        answer: SignalingAnswer,
+    ) -> Result<(), SfuError> {
+        self.send(
+            Method::POST,
+            &["sessions", session_id, "answer"],
+            Some(json!(answer)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn add_ice_candidate(
+        &self,
+        session_id: &str,
+        candidate: &IceCandidate,
+    ) -> Result<(), SfuError> {
+        self.send(
+            Method::POST,
+            &["sessions", session_id, "candidates"],
+            Some(json!(candidate)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn publish(&self, session_id: &str, track: &TrackInfo) -> Result<(), SfuError> {
+        self.send(
+            Method::POST,
+            &["sessions", session_id, "tracks"],
+            Some(json!(track)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn unpublish(&self, session_id: &str, track_id: &str) -> Result<(), SfuError> {
+        self.send(
+            Method::DELETE,
+            &["sessions", session_id, "tracks", track_id],
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        session_id: &str,
+        publisher_session_id: &str,
+        track_id: &str,
+    ) -> Result<SignalingOffer, SfuError> {
+        self.send_json(
+            Method::POST,
+            &["sessions", session_id, "subscriptions"],
+            Some(json!({
+                "publisher_session_id": publisher_session_id,
+                "track_id": track_id,
+            })),
+        )
+        .await
+    }
+
+    async fn unsubscribe(
+        &self,
+        session_id: &str,
+        publisher_session_id: &str,
+        track_id: &str,
+    ) -> Result<SignalingOffer, SfuError> {
+        self.send_json(
+            Method::DELETE,
+            &[
+                "sessions",
+                session_id,
+                "subscriptions",
+                publisher_session_id,
+                track_id,
+            ],
+            None,
+        )
+        .await
+    }
+
+    async fn set_muted(
+        &self,
+        session_id: &str,
+        track_id: &str,
+        muted: bool,
+    ) -> Result<(), SfuError> {
+        self.send(
+            Method::PUT,
+            &["sessions", session_id, "tracks", track_id, "muted"],
+            Some(json!({ "muted": muted })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn close_session(&self, session_id: &str) -> Result<(), SfuError> {
+        self.send(Method::DELETE, &["sessions", session_id], None)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        extract::{Path, State},
+        http::StatusCode as HttpStatus,
+        routing::{delete, post, put},
+        Json, Router,
+    };
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{MockSfu, TrackKind};
+
+    type Sfu = State<Arc<MockSfu>>;
+    type Reply = Result<Json<Value>, (HttpStatus, String)>;
+
+    fn reply<T: Serialize>(result: Result<T, SfuError>) -> Reply {
+        result.map(|value| Json(json!(value))).map_err(|err| {
+            let status = match err {
+                SfuError::NotFound => HttpStatus::NOT_FOUND,
+                SfuError::Rejected(_) => HttpStatus::CONFLICT,
+                SfuError::Unavailable(_) => HttpStatus::SERVICE_UNAVAILABLE,
+            };
+            (status, err.to_string())
+        })
+    }
+
+    fn field(body: &Value, name: &str) -> String {
+        body[name].as_str().unwrap_or_default().to_string()
+    }
+
+    /// The control API `HttpSfuClient` expects, served by a `MockSfu`.
+    fn control_api(sfu: Arc<MockSfu>) -> Router {
+        Router::new()
+            .route(
+                "/sfu/rooms/{room}/sessions",
+                post(|State(sfu): Sfu, Path(room): Path<String>, Json(body): Json<Value>| async move {
+                    reply(sfu.create_session(&room, &field(&body, "participant")).await)
+                }),
+            )
+            .route(
+                "/sfu/sessions/{id}",
+                delete(|State(sfu): Sfu, Path(id): Path<String>| async move {
+                    reply(sfu.close_session(&id).await)
+                }),
+            )
+            .route(
+                "/sfu/sessions/{id}/offer",
+                post(|State(sfu): Sfu, Path(id): Path<String>, Json(offer): Json<SignalingOffer>| async move {
+                    reply(sfu.negotiate(&id, offer).await)
+                }),
+            )
+            .route(
+                "/sfu/sessions/{id}/answer",
+                post(|State(sfu): Sfu, Path(id): Path<String>, Json(answer): Json<SignalingAnswer>| async move {
+                    reply(sfu.accept_answer(&id, answer).await)
+                }),
+            )
+            .route(
+                "/sfu/sessions/{id}/candidates",
+                post(|State(sfu): Sfu, Path(id): Path<String>, Json(candidate): Json<IceCandidate>| async move {
+                    reply(sfu.add_ice_candidate(&id, &candidate).await)
+                }),
+            )
+            .route(
+                "/sfu/sessions/{id}/tracks",
+                post(|State(sfu): Sfu, Path(id): Path<String>, Json(track): Json<TrackInfo>| async move {
+                    reply(sfu.publish(&id, &track).await)
+                }),
+            )
+            .route(
+                "/sfu/sessions/{id}/tracks/{track}",
+                delete(|State(sfu): Sfu, Path((id, track)): Path<(String, String)>| async move {
+                    reply(sfu.unpublish(&id, &track).await)
+                }),
+            )
+            .route(
+                "/sfu/sessions/{id}/tracks/{track}/muted",
+                put(|State(sfu): Sfu, Path((id, track)): Path<(String, String)>, Json(body): Json<Value>| async move {
+                    reply(sfu.set_muted(&id, &track, body["muted"].as_bool().unwrap_or_default()).await)
+                }),
+            )
+            .route(
+                "/sfu/sessions/{id}/subscriptions",
+                post(|State(sfu): Sfu, Path(id): Path<String>, Json(body): Json<Value>| async move {
+                    reply(sfu.subscribe(&id, &field(&body, "publisher_session_id"), &field(&body, "track_id")).await)
+                }),
+            )
+            .route(
+                "/sfu/sessions/{id}/subscriptions/{publisher}/{track}",
+                delete(|State(sfu): Sfu, Path((id, publisher, track)): Path<(String, String, String)>| async move {
+                    reply(sfu.unsubscribe(&id, &publisher, &track).await)
+                }),
+            )
+            .with_state(sfu)
+    }
+
+    #[tokio::test]
+    async fn http_client_drives_the_control_api() {
+        let sfu = Arc::new(MockSfu::new());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = control_api(sfu.clone());
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = HttpSfuClient::new(&format!("http://{addr}/sfu/"))
+            .unwrap()
+            .with_token("secret");
+        let alice = client.create_session("room", "alice").await.unwrap();
+        let bob = client.create_session("room", "bob").await.unwrap();
+
+        // Candidates are refused until the session has been negotiated.
+        let candidate = IceCandidate {
+            candidate: "candidate:1 1 udp 2122260223 10.0.0.2 54321 typ host".into(),
+            sdp_mid: Some("0".into()),
+            sdp_mline_index: Some(0),
+        };
+        assert!(matches!(
+            client
+                .add_ice_candidate(&alice.session_id, &candidate)
+                .await,
+            Err(SfuError::Rejected(_))
+        ));
+        for session in [&alice, &bob] {
+            let answer = client
+                .negotiate(&session.session_id, SignalingOffer { sdp: "v=0".into() })
+                .await
+                .unwrap();
+            assert!(answer.sdp.starts_with("v=0"));
+        }
+        client
+            .add_ice_candidate(&alice.session_id, &candidate)
+            .await
+            .unwrap();
+        assert_eq!(sfu.candidates(&alice.session_id), vec![candidate]);
+
+        let mic = TrackInfo {
+            track_id: "mic/0".into(),
+            kind: TrackKind::Audio,
+            muted: false,
+        };
+        client.publish(&alice.session_id, &mic).await.unwrap();
+        client
+            .set_muted(&alice.session_id, "mic/0", true)
+            .await
+            .unwrap();
+        assert!(sfu.track(&alice.session_id, "mic/0").unwrap().muted);
+
+        client
+            .subscribe(&bob.session_id, &alice.session_id, "mic/0")
+            .await
+            .unwrap();
+        client
+            .accept_answer(&bob.session_id, SignalingAnswer { sdp: "v=0".into() })
+            .await
+            .unwrap();
+        assert_eq!(
+            sfu.subscriptions(&bob.session_id),
+            vec![(alice.session_id.clone(), "mic/0".to_string())]
+        );
+        client
+            .unsubscribe(&bob.session_id, &alice.session_id, "mic/0")
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.unpublish(&alice.session_id, "camera").await,
+            Err(SfuError::NotFound)
+        ));
+        client.unpublish(&alice.session_id, "mic/0").await.unwrap();
+
+        client.close_session(&alice.session_id).await.unwrap();
+        assert_eq!(sfu.session_count(), 1);
+        sfu.set_available(false);
+        assert!(matches!(
+            client.close_session(&bob.session_id).await,
+            Err(SfuError::Unavailable(_))
+        ));
+
+        server.abort();
+    }
+}
@@ -1,13 +1,33 @@
-//! Voice SFU signaling helpers (placeholder).
+//! Voice SFU signaling.
+//!
+//! Clients speak the JSON protocol in `protocol` (`ClientSignal` in, `ServerSignal` out)
+//! over a WebSocket to the homeserver, which drives the selective forwarding unit through
+//! its control API (`SfuClient`). `HttpSfuClient` talks to a real SFU over HTTP; `MockSfu`
+//! keeps sessions in memory so the whole flow can be exercised without media servers.
+//! Media connections to the SFU are admitted with join tokens (`token`). SFrame media keys
+//! travel between participants sealed to their MLS key packages and never reach the SFU.
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+mod client;
+mod mock;
+mod protocol;
+mod token;
+
+pub use client::{HttpSfuClient, SfuClient, SfuError, SfuSession};
+pub use mock::MockSfu;
+pub use protocol::{
+    ClientSignal, IceCandidate, MediaEncryption, MediaKeyPackage, ParticipantState, SealedMediaKey,
+    ServerSignal, SignalErrorCode, TrackInfo, TrackKind,
+};
+pub use token::{verify_join_token, JoinClaims, JoinTokenError, JOIN_TOKEN_TYPE};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct SignalingOffer {
     pub sdp: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct SignalingAnswer {
     pub sdp: String,
 }
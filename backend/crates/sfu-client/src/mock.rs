@@ -0,0 +1,284 @@
+//! In-memory SFU for tests and local development.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    client::{SfuClient, SfuError, SfuSession},
+    protocol::{IceCandidate, TrackInfo},
+    SignalingAnswer, SignalingOffer,
+};
+
+#[derive(Debug)]
+struct MockSession {
+    room: String,
+    negotiated: bool,
+    awaiting_answer: bool,
+    candidates: Vec<IceCandidate>,
+    tracks: HashMap<String, TrackInfo>,
+    /// `(publisher session, track)` pairs forwarded to this session.
+    subscriptions: BTreeSet<(String, String)>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    next_session: u64,
+    next_sdp_version: u64,
+    sessions: HashMap<String, MockSession>,
+}
+
+impl MockState {
+    fn session(&mut self, session_id: &str) -> Result<&mut MockSession, SfuError> {
+        self.sessions.get_mut(session_id).ok_or(SfuError::NotFound)
+    }
+
+    fn sdp(&mut self, session_id: &str) -> String {
+        self.next_sdp_version += 1;
+        format!(
+            "v=0\r\no=mock-sfu {} {} IN IP4 127.0.0.1\r\ns={session_id}\r\nt=0 0\r\n",
+            self.next_sdp_version, self.next_sdp_version
+        )
+    }
+
+    fn drop_subscriptions_to(&mut self, publisher: &str, track_id: Option<&str>) {
+        for session in self.sessions.values_mut() {
+            session.subscriptions.retain(|(session_id, track)| {
+                session_id != publisher || track_id.is_some_and(|id| id != track)
+            });
+        }
+    }
+}
+
+/// Enforces the same ordering rules a real SFU would (offer before candidates and
+/// tracks, subscriptions only within a room) without moving any media.
+#[derive(Debug, Default)]
+pub struct MockSfu {
+    state: Mutex<MockState>,
+    unavailable: AtomicBool,
+}
+
+impl MockSfu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail every call with `SfuError::Unavailable` until switched back.
+    pub fn set_available(&self, available: bool) {
+        self.unavailable.store(!available, Ordering::SeqCst);
+    }
+
+    pub fn session_count(&self) -> usize {
+        self.lock().sessions.len()
+    }
+
+    /// Candidates trickled into `session_id`, in arrival order.
+    pub fn candidates(&self, session_id: &str) -> Vec<IceCandidate> {
+        self.lock()
+            .sessions
+            .get(session_id)
+            .map(|session| session.candidates.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn track(&self, session_id: &str, track_id: &str) -> Option<TrackInfo> {
+        self.lock()
+            .sessions
+            .get(session_id)
+            .and_then(|session| session.tracks.get(track_id).cloned())
+    }
+
+    /// `(publisher session, track)` pairs forwarded to `session_id`.
+    pub fn subscriptions(&self, session_id: &str) -> Vec<(String, String)> {
+        self.lock()
+            .sessions
+            .get(session_id)
+            .map(|session| session.subscriptions.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().expect("mock sfu poisoned")
+    }
+
+    fn available(&self) -> Result<std::sync::MutexGuard<'_, MockState>, SfuError> {
+        if self.unavailable.load(Ordering::SeqCst) {
+            return Err(SfuError::Unavailable("mock sfu offline".into()));
+        }
+        Ok(self.lock())
+    }
+}
+
+fn require_negotiated(session: &MockSession) -> Result<(), SfuError> {
+    if session.negotiated {
+        Ok(())
+    } else {
+        Err(SfuError::Rejected("session has not been negotiated".into()))
+    }
+}
+
+#[async_trait]
+impl SfuClient for MockSfu {
+    async fn create_session(&self, room: &str, _participant: &str) -> Result<SfuSession, SfuError> {
+        let mut state = self.available()?;
+        state.next_session += 1;
+        let session_id = format!("mock-session-{}", state.next_session);
+        state.sessions.insert(
+            session_id.clone(),
+            MockSession {
+                room: room.to_string(),
+                negotiated: false,
+                awaiting_answer: false,
+                candidates: Vec::new(),
+                tracks: HashMap::new(),
+                subscriptions: BTreeSet::new(),
+            },
+        );
+        Ok(SfuSession { session_id })
+    }
+
+    async fn negotiate(
+        &self,
+        session_id: &str,
+        _offer: SignalingOffer,
+    ) -> Result<SignalingAnswer, SfuError> {
+        let mut state = self.available()?;
+        let session = state.session(session_id)?;
+        session.negotiated = true;
+        session.awaiting_answer = false;
+        Ok(SignalingAnswer {
+            sdp: state.sdp(session_id),
+        })
+    }
+
+    async fn accept_answer(
+        &self,
+        session_id: &str,
+        _answer: SignalingAnswer,
+    ) -> Result<(), SfuError> {
+        let mut state = self.available()?;
+        let session = state.session(session_id)?;
+        if !session.awaiting_answer {
+            return Err(SfuError::Rejected("no renegotiation in progress".into()));
+        }
+        session.awaiting_answer = false;
+        Ok(())
+    }
+
+    async fn add_ice_candidate(
+        &self,
+        session_id: &str,
+        candidate: &IceCandidate,
+    ) -> Result<(), SfuError> {
+        let mut state = self.available()?;
+        let session = state.session(session_id)?;
+        require_negotiated(session)?;
+        session.candidates.push(candidate.clone());
+        Ok(())
+    }
+
+    async fn publish(&self, session_id: &str, track: &TrackInfo) -> Result<(), SfuError> {
+        let mut state = self.available()?;
+        let session = state.session(session_id)?;
+        require_negotiated(session)?;
+        if session.tracks.contains_key(&track.track_id) {
+            return Err(SfuError::Rejected(format!(
+                "track '{}' is already published",
+                track.track_id
+            )));
+        }
+        session.tracks.insert(track.track_id.clone(), track.clone());
+        Ok(())
+    }
+
+    async fn unpublish(&self, session_id: &str, track_id: &str) -> Result<(), SfuError> {
+        let mut state = self.available()?;
+        state
+            .session(session_id)?
+            .tracks
+            .remove(track_id)
+            .ok_or(SfuError::NotFound)?;
+        state.drop_subscriptions_to(session_id, Some(track_id));
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        session_id: &str,
+        publisher_session_id: &str,
+        track_id: &str,
+    ) -> Result<SignalingOffer, SfuError> {
+        let mut state = self.available()?;
+        let publisher_room = {
+            let publisher = state.session(publisher_session_id)?;
+            if !publisher.tracks.contains_key(track_id) {
+                return Err(SfuError::NotFound);
+            }
+            publisher.room.clone()
+        };
+        let session = state.session(session_id)?;
+        require_negotiated(session)?;
+        if session.room != publisher_room {
+            return Err(SfuError::Rejected("publisher is in another room".into()));
+        }
+        session
+            .subscriptions
+            .insert((publisher_session_id.to_string(), track_id.to_string()));
+        session.awaiting_answer = true;
+        Ok(SignalingOffer {
+            sdp: state.sdp(session_id),
+        })
+    }
+
+    async fn unsubscribe(
+        &self,
+        session_id: &str,
+        publisher_session_id: &str,
+        track_id: &str,
+    ) -> Result<SignalingOffer, SfuError> {
+        let mut state = self.available()?;
+        let session = state.session(session_id)?;
+        if !session
+            .subscriptions
+            .remove(&(publisher_session_id.to_string(), track_id.to_string()))
+        {
+            return Err(SfuError::NotFound);
+        }
+        session.awaiting_answer = true;
+        Ok(SignalingOffer {
+            sdp: state.sdp(session_id),
+        })
+    }
+
+    async fn set_muted(
+        &self,
+        session_id: &str,
+        track_id: &str,
+        muted: bool,
+    ) -> Result<(), SfuError> {
+        let mut state = self.available()?;
+        state
+            .session(session_id)?
+            .tracks
+            .get_mut(track_id)
+            .ok_or(SfuError::NotFound)?
+            .muted = muted;
+        Ok(())
+    }
+
+    async fn close_session(&self, session_id: &str) -> Result<(), SfuError> {
+        let mut state = self.available()?;
+        state
+            .sessions
+            .remove(session_id)
+            .ok_or(SfuError::NotFound)?;
+        state.drop_subscriptions_to(session_id, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+//! Messages exchanged with clients over the voice signaling socket.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Longest track identifier accepted from a client.
+pub const MAX_TRACK_ID_LENGTH: usize = 64;
+
+/// Largest SDP blob accepted from a client.
+pub const MAX_SDP_LENGTH: usize = 64 * 1024;
+
+/// Longest device id a participant may name for its key package.
+pub const MAX_DEVICE_ID_LENGTH: usize = 128;
+
+/// Most recipients one `MediaKey` message may address.
+pub const MAX_MEDIA_KEY_RECIPIENTS: usize = 100;
+
+/// Largest sealed media key accepted from a client, in base64 characters.
+pub const MAX_SEALED_KEY_LENGTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackKind {
+    Audio,
+    Video,
+    Screen,
+}
+
+/// A track a participant publishes into the room.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TrackInfo {
+    pub track_id: String,
+    pub kind: TrackKind,
+    #[serde(default)]
+    pub muted: bool,
+}
+
+/// A trickled ICE candidate, as produced by `RTCPeerConnection.onicecandidate`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IceCandidate {
+    pub candidate: String,
+    #[serde(default)]
+    pub sdp_mid: Option<String>,
+    #[serde(default)]
+    pub sdp_mline_index: Option<u16>,
+}
+
+/// The MLS key package a participant's SFrame keys are sealed to.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MediaKeyPackage {
+    pub identity: String,
+    pub ciphersuite: String,
+    /// URL-safe base64 HPKE public key.
+    pub hpke_public_key: String,
+}
+
+/// A sender's SFrame key sealed to one recipient's HPKE public key. Neither the
+/// homeserver nor the SFU can open it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SealedMediaKey {
+    pub participant_id: Uuid,
+    /// URL-safe base64 HPKE ciphertext.
+    pub ciphertext: String,
+}
+
+/// End-to-end media encryption in effect for a voice room.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MediaEncryption {
+    /// SFrame cipher suite matching the homeserver's MLS ciphersuite, e.g.
+    /// `AES_128_GCM_SHA256_128`.
+    pub sframe_suite: String,
+    /// Key epoch senders must use; it moves whenever someone gains or loses access.
+    pub epoch: u64,
+}
+
+/// Someone connected to a voice channel.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ParticipantState {
+    pub participant_id: Uuid,
+    pub user_id: Uuid,
+    /// Self-mute: the participant is not sending audio.
+    #[serde(default)]
+    pub muted: bool,
+    /// Self-deafen: the participant is not listening. Deafened participants are also muted.
+    #[serde(default)]
+    pub deafened: bool,
+    pub tracks: Vec<TrackInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_package: Option<MediaKeyPackage>,
+}
+
+/// Messages a client sends.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientSignal {
+    /// Start or renegotiate the client's peer connection with the SFU.
+    Offer {
+        sdp: String,
+    },
+    /// Accept an `Offer` the server sent after a subscription changed.
+    Answer {
+        sdp: String,
+    },
+    IceCandidate {
+        candidate: IceCandidate,
+    },
+    Publish {
+        track_id: String,
+        kind: TrackKind,
+    },
+    Unpublish {
+        track_id: String,
+    },
+    Subscribe {
+        participant_id: Uuid,
+        track_id: String,
+    },
+    Unsubscribe {
+        participant_id: Uuid,
+        track_id: String,
+    },
+    Mute {
+        track_id: String,
+        muted: bool,
+    },
+    /// Set the participant's own mute and deafen flags.
+    VoiceState {
+        muted: bool,
+        deafened: bool,
+    },
+    /// Receive media keys sealed to the last-resort key package of the device the
+    /// participant's session logged in from.
+    KeyPackage {
+        device_id: String,
+    },
+    /// Distribute the sender's SFrame key for `epoch`, sealed once per recipient.
+    MediaKey {
+        epoch: u64,
+        key_id: u64,
+        keys: Vec<SealedMediaKey>,
+    },
+    Leave,
+}
+
+impl ClientSignal {
+    /// Check field sizes before the message reaches the SFU.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ClientSignal::Offer { sdp } | ClientSignal::Answer { sdp } => {
+                if sdp.trim().is_empty() {
+                    return Err("sdp cannot be empty".into());
+                }
+                if sdp.len() > MAX_SDP_LENGTH {
+                    return Err(format!("sdp exceeds {MAX_SDP_LENGTH} bytes"));
+                }
+            }
+            ClientSignal::IceCandidate { candidate } => {
+                if candidate.candidate.len() > MAX_SDP_LENGTH {
+                    return Err(format!("candidate exceeds {MAX_SDP_LENGTH} bytes"));
+                }
+            }
+            ClientSignal::Publish { track_id, .. }
+            | ClientSignal::Unpublish { track_id }
+            | ClientSignal::Subscribe { track_id, .. }
+            | ClientSignal::Unsubscribe { track_id, .. }
+            | ClientSignal::Mute { track_id, .. } => {
+                if track_id.trim().is_empty() || track_id.len() > MAX_TRACK_ID_LENGTH {
+                    return Err(format!("track_id must be 1-{MAX_TRACK_ID_LENGTH} bytes"));
+                }
+            }
+            ClientSignal::KeyPackage { device_id } => {
+                if device_id.trim().is_empty() || device_id.len() > MAX_DEVICE_ID_LENGTH {
+                    return Err(format!("device_id must be 1-{MAX_DEVICE_ID_LENGTH} bytes"));
+                }
+            }
+            ClientSignal::MediaKey { keys, .. } => {
+                if keys.is_empty() || keys.len() > MAX_MEDIA_KEY_RECIPIENTS {
+                    return Err(format!(
+                        "keys must address 1-{MAX_MEDIA_KEY_RECIPIENTS} recipients"
+                    ));
+                }
+                let mut recipients = std::collections::HashSet::new();
+                for key in keys {
+                    if key.ciphertext.is_empty() || key.ciphertext.len() > MAX_SEALED_KEY_LENGTH {
+                        return Err(format!(
+                            "ciphertext must be 1-{MAX_SEALED_KEY_LENGTH} characters"
+                        ));
+                    }
+                    if !recipients.insert(key.participant_id) {
+                        return Err("each recipient may only appear once".into());
+                    }
+                }
+            }
+            ClientSignal::VoiceState { .. } | ClientSignal::Leave => {}
+        }
+        Ok(())
+    }
+}
+
+/// Why the server refused a client message.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignalErrorCode {
+    InvalidMessage,
+    InvalidState,
+    UnknownParticipant,
+    UnknownTrack,
+    DuplicateTrack,
+    /// A `MediaKey` named an epoch other than the room's current one.
+    StaleEpoch,
+    UnknownKeyPackage,
+    SfuRejected,
+    SfuUnavailable,
+}
+
+/// Messages the server sends.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerSignal {
+    /// First message on every socket: the caller's id and who is already in the room.
+    Joined {
+        participant_id: Uuid,
+        participants: Vec<ParticipantState>,
+        /// Absent when the homeserver does not coordinate end-to-end media encryption.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        media_encryption: Option<MediaEncryption>,
+    },
+    /// The SFU's answer to a client `Offer`.
+    Answer {
+        sdp: String,
+    },
+    /// A renegotiation offer the client must `Answer`.
+    Offer {
+        sdp: String,
+    },
+    ParticipantJoined {
+        participant: ParticipantState,
+    },
+    ParticipantLeft {
+        participant_id: Uuid,
+    },
+    TrackPublished {
+        participant_id: Uuid,
+        track: TrackInfo,
+    },
+    TrackUnpublished {
+        participant_id: Uuid,
+        track_id: String,
+    },
+    MuteChanged {
+        participant_id: Uuid,
+        track_id: String,
+        muted: bool,
+    },
+    VoiceStateChanged {
+        participant_id: Uuid,
+        muted: bool,
+        deafened: bool,
+    },
+    /// Another participant can now receive media keys.
+    KeyPackageChanged {
+        participant_id: Uuid,
+        key_package: MediaKeyPackage,
+    },
+    /// Someone gained or lost access: generate a fresh SFrame key and send it for `epoch`.
+    RotateMediaKey {
+        epoch: u64,
+    },
+    /// `participant_id`'s SFrame key for `epoch`, sealed to the recipient.
+    MediaKey {
+        participant_id: Uuid,
+        epoch: u64,
+        key_id: u64,
+        ciphertext: String,
+    },
+    Error {
+        code: SignalErrorCode,
+        message: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn signals_use_tagged_snake_case_json() {
+        let signal: ClientSignal = serde_json::from_value(json!({
+            "type": "ice_candidate",
+            "candidate": { "candidate": "candidate:1 1 udp 2122260223 10.0.0.2 54321 typ host", "sdp_mid": "0" }
+        }))
+        .unwrap();
+        assert!(matches!(
+            &signal,
+            ClientSignal::IceCandidate { candidate } if candidate.sdp_mline_index.is_none()
+        ));
+        assert!(signal.validate().is_ok());
+
+        let publish: ClientSignal =
+            serde_json::from_value(json!({ "type": "publish", "track_id": "", "kind": "audio" }))
+                .unwrap();
+        assert!(publish.validate().is_err());
+
+        let participant_id = Uuid::new_v4();
+        let value = serde_json::to_value(ServerSignal::MuteChanged {
+            participant_id,
+            track_id: "mic".into(),
+            muted: true,
+        })
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "type": "mute_changed",
+                "participant_id": participant_id,
+                "track_id": "mic",
+                "muted": true
+            })
+        );
+    }
+
+    #[test]
+    fn media_keys_must_address_distinct_recipients() {
+        let recipient = Uuid::new_v4();
+        let sealed = |participant_id| SealedMediaKey {
+            participant_id,
+            ciphertext: "c2VhbGVk".into(),
+        };
+        let media_key = |keys| ClientSignal::MediaKey {
+            epoch: 1,
+            key_id: 7,
+            keys,
+        };
+
+        assert!(media_key(vec![sealed(recipient)]).validate().is_ok());
+        assert!(media_key(Vec::new()).validate().is_err());
+        assert!(media_key(vec![sealed(recipient), sealed(recipient)])
+            .validate()
+            .is_err());
+
+        let joined = serde_json::to_value(ServerSignal::Joined {
+            participant_id: recipient,
+            participants: Vec::new(),
+            media_encryption: None,
+        })
+        .unwrap();
+        assert!(joined.get("media_encryption").is_none());
+    }
+}
@@ -0,0 +1,128 @@
+//! Join tokens the homeserver issues for voice channels.
+//!
+//! A token is `base64(claims).base64(signature)`, signed with the homeserver's session key,
+//! so an SFU holding the matching verifying key can admit participants without calling back.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use openguild_crypto::{verify_signature, Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Value of `typ` in join token claims; keeps access tokens from passing as join tokens.
+pub const JOIN_TOKEN_TYPE: &str = "voice_join";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct JoinClaims {
+    pub typ: String,
+    pub channel_id: Uuid,
+    pub user_id: Uuid,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum JoinTokenError {
+    #[error("join token is malformed")]
+    Malformed,
+    #[error("join token signature is invalid")]
+    BadSignature,
+    #[error("token is not a voice join token")]
+    WrongType,
+    #[error("join token has expired")]
+    Expired,
+}
+
+/// Check a join token against any of `keys` (the active key first, then rotated-out ones)
+/// and return its claims if it has not expired at `now`.
+pub fn verify_join_token(
+    token: &str,
+    keys: &[VerifyingKey],
+    now: DateTime<Utc>,
+) -> Result<JoinClaims, JoinTokenError> {
+    let (payload_b64, signature_b64) = token.split_once('.').ok_or(JoinTokenError::Malformed)?;
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload_b64)
+        .map_err(|_| JoinTokenError::Malformed)?;
+    let signature: [u8; 64] = URL_SAFE_NO_PAD
+        .decode(signature_b64)
+        .map_err(|_| JoinTokenError::Malformed)?
+        .try_into()
+        .map_err(|_| JoinTokenError::Malformed)?;
+    let signature = Signature::from_bytes(&signature);
+    if !keys
+        .iter()
+        .any(|key| verify_signature(key, &payload, &signature).is_ok())
+    {
+        return Err(JoinTokenError::BadSignature);
+    }
+
+    let claims: JoinClaims =
+        serde_json::from_slice(&payload).map_err(|_| JoinTokenError::WrongType)?;
+    if claims.typ != JOIN_TOKEN_TYPE {
+        return Err(JoinTokenError::WrongType);
+    }
+    if claims.expires_at <= now {
+        return Err(JoinTokenError::Expired);
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use openguild_crypto::{generate_signing_key, sign_message, verifying_key_from};
+
+    use super::*;
+
+    fn encode(payload: &serde_json::Value, signing_key: &openguild_crypto::SigningKey) -> String {
+        let payload = serde_json::to_vec(payload).unwrap();
+        let signature = sign_message(signing_key, &payload);
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(&payload),
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+
+    #[test]
+    fn join_tokens_verify_offline_until_they_expire() {
+        let signing_key = generate_signing_key();
+        let rotated = verifying_key_from(&generate_signing_key());
+        let keys = [rotated, verifying_key_from(&signing_key)];
+        let now = Utc::now();
+        let claims = JoinClaims {
+            typ: JOIN_TOKEN_TYPE.into(),
+            channel_id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            issued_at: now,
+            expires_at: now + Duration::seconds(60),
+        };
+        let token = encode(&serde_json::to_value(&claims).unwrap(), &signing_key);
+
+        assert_eq!(verify_join_token(&token, &keys, now), Ok(claims));
+        assert_eq!(
+            verify_join_token(&token, &keys, now + Duration::seconds(61)),
+            Err(JoinTokenError::Expired)
+        );
+        assert_eq!(
+            verify_join_token(&token, &keys[..1], now),
+            Err(JoinTokenError::BadSignature)
+        );
+        assert_eq!(
+            verify_join_token("not-a-token", &keys, now),
+            Err(JoinTokenError::Malformed)
+        );
+
+        let access_token = encode(
+            &serde_json::json!({ "session_id": Uuid::new_v4(), "user_id": Uuid::new_v4() }),
+            &signing_key,
+        );
+        assert_eq!(
+            verify_join_token(&access_token, &keys, now),
+            Err(JoinTokenError::WrongType)
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// Device keys a client publishes for end-to-end encryption.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewDevice {
+    pub user_id: Uuid,
+    pub device_id: String,
+    pub display_name: Option<String>,
+    /// URL-safe base64 ed25519 public key.
+    pub identity_key: String,
+}
+
+/// Persisted device with its cross-signing state.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct DeviceRecord {
+    pub user_id: Uuid,
+    pub device_id: String,
+    pub display_name: Option<String>,
+    pub identity_key: String,
+    /// Signature by the user's current master key, if the device has been verified.
+    pub master_signature: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A user's master cross-signing key.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct MasterKeyRecord {
+    pub user_id: Uuid,
+    pub master_key: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Repository for the device registry and master cross-signing keys.
+#[derive(Clone)]
+pub struct DeviceRepository {
+    pool: Arc<PgPool>,
+}
+
+impl DeviceRepository {
+    /// Wrap a Postgres pool for device registry persistence.
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// Register a device or update its display name. Returns `None` when the device is
+    /// already registered with a different identity key.
+    pub async fn publish(&self, device: &NewDevice) -> Result<Option<DeviceRecord>> {
+        let record = sqlx::query_as::<_, DeviceRecord>(
+            r#"
+            INSERT INTO user_devices (user_id, device_id, display_name, identity_key)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, device_id) DO UPDATE
+                SET display_name = EXCLUDED.display_name, updated_at = NOW()
+                WHERE user_devices.identity_key = EXCLUDED.identity_key
+            RETURNING user_id, device_id, display_name, identity_key, master_signature,
+                      created_at, updated_at
+            "#,
+        )
+        .bind(device.user_id)
+        .bind(&device.device_id)
+        .bind(&device.display_name)
+        .bind(&device.identity_key)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(record)
+    }
+
+    pub async fn device(&self, user_id: Uuid, device_id: &str) -> Result<Option<DeviceRecord>> {
+        let record = sqlx::query_as::<_, DeviceRecord>(
+            r#"
+            SELECT user_id, device_id, display_name, identity_key, master_signature,
+                   created_at, updated_at
+            FROM user_devices
+            WHERE user_id = $1 AND device_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(record)
+    }
+
+    /// Every device of `user_id`, oldest first.
+    pub async fn devices(&self, user_id: Uuid) -> Result<Vec<DeviceRecord>> {
+        let records = sqlx::query_as::<_, DeviceRecord>(
+            r#"
+            SELECT user_id, device_id, display_name, identity_key, master_signature,
+                   created_at, updated_at
+            FROM user_devices
+            WHERE user_id = $1
+            ORDER BY created_at, device_id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(records)
+    }
+
+    /// Returns whether the device existed.
+    pub async fn remove(&self, user_id: Uuid, device_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM user_devices
+            WHERE user_id = $1 AND device_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .execute(self.pool())
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Attach a master key signature to a device. Returns `None` when the device is gone
+    /// or its identity key is no longer `identity_key`.
+    pub async fn sign_device(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+        identity_key: &str,
+        signature: &str,
+    ) -> Result<Option<DeviceRecord>> {
+        let record = sqlx::query_as::<_, DeviceRecord>(
+            r#"
+            UPDATE user_devices
+            SET master_signature = $4, updated_at = NOW()
+            WHERE user_id = $1 AND device_id = $2 AND identity_key = $3
+            RETURNING user_id, device_id, display_name, identity_key, master_signature,
+                      created_at, updated_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .bind(identity_key)
+        .bind(signature)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(record)
+    }
+
+    pub async fn master_key(&self, user_id: Uuid) -> Result<Option<MasterKeyRecord>> {
+        let record = sqlx::query_as::<_, MasterKeyRecord>(
+            r#"
+            SELECT user_id, master_key, updated_at
+            FROM cross_signing_keys
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(record)
+    }
+
+    /// Set the user's master key. Replacing it drops every device signature made with the
+    /// old one, so all devices have to be verified again.
+    pub async fn set_master_key(&self, user_id: Uuid, master_key: &str) -> Result<MasterKeyRecord> {
+        let mut tx = self.pool().begin().await?;
+        let previous: Option<String> = sqlx::query_scalar(
+            r#"
+            SELECT master_key FROM cross_signing_keys WHERE user_id = $1 FOR UPDATE
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if previous.as_deref() == Some(master_key) {
+            let record = sqlx::query_as::<_, MasterKeyRecord>(
+                r#"
+                SELECT user_id, master_key, updated_at
+                FROM cross_signing_keys
+                WHERE user_id = $1
+                "#,
+            )
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            return Ok(record);
+        }
+
+        let record = sqlx::query_as::<_, MasterKeyRecord>(
+            r#"
+            INSERT INTO cross_signing_keys (user_id, master_key)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+                SET master_key = EXCLUDED.master_key, updated_at = NOW()
+            RETURNING user_id, master_key, updated_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(master_key)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE user_devices
+            SET master_signature = NULL, updated_at = NOW()
+            WHERE user_id = $1 AND master_signature IS NOT NULL
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connect;
+    use sqlx::migrate::Migrator;
+    use std::env;
+
+    static MIGRATOR: Migrator = sqlx::migrate!("../../migrations");
+
+    async fn setup_repository() -> anyhow::Result<Option<DeviceRepository>> {
+        let database_url = match env::var("OPENGUILD_TEST_DATABASE_URL")
+            .or_else(|_| env::var("DATABASE_URL"))
+        {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!(
+                        "skipping device registry test: set OPENGUILD_TEST_DATABASE_URL or DATABASE_URL"
+                    );
+                return Ok(None);
+            }
+        };
+
+        let pool = connect(&database_url).await?;
+        MIGRATOR
+            .run(pool.pool())
+            .await
+            .expect("running migrations for the device registry");
+        Ok(Some(DeviceRepository::new(pool.cloned())))
+    }
+
+    #[tokio::test]
+    async fn identity_keys_are_fixed_and_master_rotation_clears_signatures() -> anyhow::Result<()> {
+        let Some(repo) = setup_repository().await? else {
+            return Ok(());
+        };
+        let alice = Uuid::new_v4();
+        let laptop = NewDevice {
+            user_id: alice,
+            device_id: "laptop".into(),
+            display_name: Some("Laptop".into()),
+            identity_key: "identity-a".into(),
+        };
+
+        assert!(repo.publish(&laptop).await?.is_some());
+        let renamed = NewDevice {
+            display_name: Some("Work laptop".into()),
+            ..laptop.clone()
+        };
+        let record = repo.publish(&renamed).await?.expect("rename keeps the key");
+        assert_eq!(record.display_name.as_deref(), Some("Work laptop"));
+        let rekeyed = NewDevice {
+            identity_key: "identity-b".into(),
+            ..laptop.clone()
+        };
+        assert!(repo.publish(&rekeyed).await?.is_none());
+
+        repo.set_master_key(alice, "master-1").await?;
+        assert!(repo
+            .sign_device(alice, "laptop", "identity-b", "sig")
+            .await?
+            .is_none());
+        let signed = repo
+            .sign_device(alice, "laptop", "identity-a", "sig")
+            .await?
+            .expect("device signed");
+        assert_eq!(signed.master_signature.as_deref(), Some("sig"));
+
+        // Re-uploading the same master key keeps signatures; a new one drops them.
+        repo.set_master_key(alice, "master-1").await?;
+        assert!(repo.devices(alice).await?[0].master_signature.is_some());
+        repo.set_master_key(alice, "master-2").await?;
+        assert!(repo.devices(alice).await?[0].master_signature.is_none());
+        assert_eq!(
+            repo.master_key(alice).await?.map(|key| key.master_key),
+            Some("master-2".to_string())
+        );
+
+        assert!(repo.remove(alice, "laptop").await?);
+        assert!(!repo.remove(alice, "laptop").await?);
+        assert!(repo.devices(alice).await?.is_empty());
+        Ok(())
+    }
+}
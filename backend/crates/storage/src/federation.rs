@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgPool};
+
+/// Event queued for delivery to a federation peer.
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct FederationOutboxEntry {
+    pub id: i64,
+    pub destination: String,
+    pub event_id: String,
+    pub body: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Repository backing the outbound federation queue.
+#[derive(Clone)]
+pub struct FederationOutboxStore {
+    pool: Arc<PgPool>,
+}
+
+impl FederationOutboxStore {
+    /// Wrap a Postgres pool for outbound federation persistence.
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// Queue an event for a destination; re-queueing the same event is a no-op.
+    pub async fn enqueue(
+        &self,
+        destination: &str,
+        event_id: &str,
+        body: &serde_json::Value,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO federation_outbox (destination, event_id, body)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (destination, event_id) DO NOTHING
+            "#,
+        )
+        .bind(destination)
+        .bind(event_id)
+        .bind(body.clone())
+        .execute(self.pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Oldest pending entries for a destination, in enqueue order.
+    pub async fn pending(
+        &self,
+        destination: &str,
+        limit: i64,
+    ) -> Result<Vec<FederationOutboxEntry>> {
+        let entries = sqlx::query_as::<_, FederationOutboxEntry>(
+            r#"
+            SELECT id, destination, event_id, body, created_at
+            FROM federation_outbox
+            WHERE destination = $1
+            ORDER BY id ASC
+            LIMIT $2
+            "#,
+        )
+        .bind(destination)
+        .bind(limit)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(entries)
+    }
+
+    /// Drop delivered (or permanently rejected) events from a destination's queue.
+    pub async fn remove(&self, destination: &str, event_ids: &[String]) -> Result<()> {
+        if event_ids.is_empty() {
+            return Ok(());
+        }
+        sqlx::query(
+            r#"
+            DELETE FROM federation_outbox
+            WHERE destination = $1 AND event_id = ANY($2)
+            "#,
+        )
+        .bind(destination)
+        .bind(event_ids)
+        .execute(self.pool())
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connect;
+    use serde_json::json;
+    use sqlx::migrate::Migrator;
+    use std::env;
+
+    static MIGRATOR: Migrator = sqlx::migrate!("../../migrations");
+
+    async fn setup_store() -> anyhow::Result<Option<FederationOutboxStore>> {
+        let database_url = match env::var("OPENGUILD_TEST_DATABASE_URL")
+            .or_else(|_| env::var("DATABASE_URL"))
+        {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!(
+                    "skipping federation outbox test: set OPENGUILD_TEST_DATABASE_URL or DATABASE_URL"
+                );
+                return Ok(None);
+            }
+        };
+
+        let pool = connect(&database_url).await?;
+        MIGRATOR
+            .run(pool.pool())
+            .await
+            .expect("running migrations for federation outbox");
+        sqlx::query("TRUNCATE federation_outbox RESTART IDENTITY")
+            .execute(pool.pool())
+            .await?;
+
+        Ok(Some(FederationOutboxStore::new(pool.cloned())))
+    }
+
+    #[tokio::test]
+    async fn enqueue_pending_and_remove() -> anyhow::Result<()> {
+        let Some(store) = setup_store().await? else {
+            return Ok(());
+        };
+
+        let body = json!({ "event_id": "$a" });
+        store.enqueue("remote.example.org", "$a", &body).await?;
+        store.enqueue("remote.example.org", "$a", &body).await?;
+        store.enqueue("remote.example.org", "$b", &body).await?;
+        store.enqueue("other.example.org", "$a", &body).await?;
+
+        let pending = store.pending("remote.example.org", 10).await?;
+        let ids: Vec<_> = pending.iter().map(|e| e.event_id.as_str()).collect();
+        assert_eq!(ids, vec!["$a", "$b"]);
+
+        store
+            .remove("remote.example.org", &["$a".to_string()])
+            .await?;
+        let pending = store.pending("remote.example.org", 10).await?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].event_id, "$b");
+        assert_eq!(store.pending("other.example.org", 10).await?.len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// Attempts at claiming the next version number before giving up on concurrent creators.
+const VERSION_ATTEMPTS: usize = 3;
+
+/// One version of a user's key backup. Only the newest live version accepts uploads.
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct KeyBackupVersion {
+    pub user_id: Uuid,
+    pub version: i64,
+    pub algorithm: String,
+    /// Public parameters clients use to check their recovery key.
+    pub auth_data: serde_json::Value,
+    pub key_count: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Client-encrypted key material for one MLS group epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewBackupKey {
+    /// URL-safe base64 of the MLS group id.
+    pub group_id: String,
+    pub epoch: i64,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Persisted backup entry.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct BackupKeyRecord {
+    pub group_id: String,
+    pub epoch: i64,
+    pub ciphertext: Vec<u8>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Repository for versioned, client-encrypted key backups.
+#[derive(Clone)]
+pub struct KeyBackupRepository {
+    pool: Arc<PgPool>,
+}
+
+impl KeyBackupRepository {
+    /// Wrap a Postgres pool for key backup persistence.
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// Start a new backup version numbered after every version the user has had, including
+    /// deleted ones.
+    pub async fn create_version(
+        &self,
+        user_id: Uuid,
+        algorithm: &str,
+        auth_data: &serde_json::Value,
+    ) -> Result<KeyBackupVersion> {
+        for _ in 0..VERSION_ATTEMPTS {
+            let created = sqlx::query_as::<_, KeyBackupVersion>(
+                r#"
+                INSERT INTO key_backup_versions (user_id, version, algorithm, auth_data)
+                SELECT $1, COALESCE(MAX(version), 0) + 1, $2, $3
+                FROM key_backup_versions
+                WHERE user_id = $1
+                ON CONFLICT (user_id, version) DO NOTHING
+                RETURNING user_id, version, algorithm, auth_data, 0::BIGINT AS key_count,
+                          created_at
+                "#,
+            )
+            .bind(user_id)
+            .bind(algorithm)
+            .bind(auth_data.clone())
+            .fetch_optional(self.pool())
+            .await?;
+            if let Some(created) = created {
+                return Ok(created);
+            }
+        }
+        Err(anyhow!(
+            "concurrent key backup version creation for {user_id}"
+        ))
+    }
+
+    /// The user's newest version that has not been deleted.
+    pub async fn latest_version(&self, user_id: Uuid) -> Result<Option<KeyBackupVersion>> {
+        let record = sqlx::query_as::<_, KeyBackupVersion>(
+            r#"
+            SELECT v.user_id, v.version, v.algorithm, v.auth_data,
+                   (SELECT COUNT(*) FROM key_backup_keys k
+                    WHERE k.user_id = v.user_id AND k.version = v.version) AS key_count,
+                   v.created_at
+            FROM key_backup_versions v
+            WHERE v.user_id = $1 AND v.deleted_at IS NULL
+            ORDER BY v.version DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(record)
+    }
+
+    pub async fn version(&self, user_id: Uuid, version: i64) -> Result<Option<KeyBackupVersion>> {
+        let record = sqlx::query_as::<_, KeyBackupVersion>(
+            r#"
+            SELECT v.user_id, v.version, v.algorithm, v.auth_data,
+                   (SELECT COUNT(*) FROM key_backup_keys k
+                    WHERE k.user_id = v.user_id AND k.version = v.version) AS key_count,
+                   v.created_at
+            FROM key_backup_versions v
+            WHERE v.user_id = $1 AND v.version = $2 AND v.deleted_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(version)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(record)
+    }
+
+    /// Delete a version and its keys. Returns whether the version was live.
+    pub async fn delete_version(&self, user_id: Uuid, version: i64) -> Result<bool> {
+        let mut tx = self.pool().begin().await?;
+        let deleted = sqlx::query(
+            r#"
+            UPDATE key_backup_versions
+            SET deleted_at = NOW()
+            WHERE user_id = $1 AND version = $2 AND deleted_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(version)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM key_backup_keys
+            WHERE user_id = $1 AND version = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(version)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(deleted.rows_affected() > 0)
+    }
+
+    /// Store keys in `version`, replacing entries for the same group epoch. Returns `None`
+    /// without storing anything when `version` is not the user's latest live version.
+    pub async fn put_keys(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        keys: &[NewBackupKey],
+    ) -> Result<Option<KeyBackupVersion>> {
+        let mut tx = self.pool().begin().await?;
+        let current: Option<i64> = sqlx::query_scalar(
+            r#"
+            SELECT version FROM key_backup_versions
+            WHERE user_id = $1 AND deleted_at IS NULL
+            ORDER BY version DESC
+            LIMIT 1
+            FOR UPDATE
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if current != Some(version) {
+            return Ok(None);
+        }
+
+        for key in keys {
+            sqlx::query(
+                r#"
+                INSERT INTO key_backup_keys (user_id, version, group_id, epoch, ciphertext)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (user_id, version, group_id, epoch) DO UPDATE
+                    SET ciphertext = EXCLUDED.ciphertext, updated_at = NOW()
+                "#,
+            )
+            .bind(user_id)
+            .bind(version)
+            .bind(&key.group_id)
+            .bind(key.epoch)
+            .bind(&key.ciphertext)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        self.version(user_id, version).await
+    }
+
+    /// Keys stored in `version`, optionally only those for one group, ordered by group and
+    /// epoch.
+    pub async fn keys(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        group_id: Option<&str>,
+    ) -> Result<Vec<BackupKeyRecord>> {
+        let records = sqlx::query_as::<_, BackupKeyRecord>(
+            r#"
+            SELECT group_id, epoch, ciphertext, updated_at
+            FROM key_backup_keys
+            WHERE user_id = $1 AND version = $2 AND ($3::TEXT IS NULL OR group_id = $3)
+            ORDER BY group_id, epoch
+            "#,
+        )
+        .bind(user_id)
+        .bind(version)
+        .bind(group_id)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connect;
+    use serde_json::json;
+    use sqlx::migrate::Migrator;
+    use std::env;
+
+    static MIGRATOR: Migrator = sqlx::migrate!("../../migrations");
+
+    async fn setup_repository() -> anyhow::Result<Option<KeyBackupRepository>> {
+        let database_url =
+            match env::var("OPENGUILD_TEST_DATABASE_URL").or_else(|_| env::var("DATABASE_URL")) {
+                Ok(url) => url,
+                Err(_) => {
+                    eprintln!(
+                        "skipping key backup test: set OPENGUILD_TEST_DATABASE_URL or DATABASE_URL"
+                    );
+                    return Ok(None);
+                }
+            };
+
+        let pool = connect(&database_url).await?;
+        MIGRATOR
+            .run(pool.pool())
+            .await
+            .expect("running migrations for key backups");
+        Ok(Some(KeyBackupRepository::new(pool.cloned())))
+    }
+
+    fn key(group_id: &str, epoch: i64, ciphertext: &[u8]) -> NewBackupKey {
+        NewBackupKey {
+            group_id: group_id.into(),
+            epoch,
+            ciphertext: ciphertext.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_version_accepts_keys_and_numbers_are_not_reused() -> anyhow::Result<()>
+    {
+        let Some(repo) = setup_repository().await? else {
+            return Ok(());
+        };
+        let alice = Uuid::new_v4();
+        let auth_data = json!({ "public_key": "cmVjb3Zlcnk" });
+
+        let first = repo.create_version(alice, "v1", &auth_data).await?;
+        assert_eq!(first.version, 1);
+        let stored = repo
+            .put_keys(
+                alice,
+                1,
+                &[key("Z3JvdXA", 0, b"a"), key("Z3JvdXA", 1, b"b")],
+            )
+            .await?
+            .expect("current version accepts keys");
+        assert_eq!(stored.key_count, 2);
+        let replaced = repo
+            .put_keys(alice, 1, &[key("Z3JvdXA", 1, b"c")])
+            .await?
+            .expect("current version accepts keys");
+        assert_eq!(replaced.key_count, 2);
+        let keys = repo.keys(alice, 1, Some("Z3JvdXA")).await?;
+        assert_eq!(keys[1].ciphertext, b"c");
+        assert!(repo.keys(alice, 1, Some("b3RoZXI")).await?.is_empty());
+
+        let second = repo.create_version(alice, "v1", &auth_data).await?;
+        assert_eq!(second.version, 2);
+        assert!(repo
+            .put_keys(alice, 1, &[key("Z3JvdXA", 2, b"d")])
+            .await?
+            .is_none());
+        assert_eq!(repo.version(alice, 1).await?.map(|v| v.key_count), Some(2));
+
+        assert!(repo.delete_version(alice, 2).await?);
+        assert!(!repo.delete_version(alice, 2).await?);
+        assert_eq!(
+            repo.latest_version(alice).await?.map(|v| v.version),
+            Some(1)
+        );
+        assert_eq!(
+            repo.create_version(alice, "v1", &auth_data).await?.version,
+            3
+        );
+        Ok(())
+    }
+}
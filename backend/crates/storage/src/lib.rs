@@ -5,6 +5,10 @@ use std::sync::Arc;
 use anyhow::Result;
 use sqlx::postgres::PgPoolOptions;
 
+pub mod devices;
+pub mod federation;
+pub mod key_backup;
+pub mod media;
 pub mod messaging;
 pub mod mls;
 pub mod refresh;
@@ -13,11 +17,19 @@ pub mod user;
 
 pub use sqlx::PgPool;
 
+pub use devices::{DeviceRecord, DeviceRepository, MasterKeyRecord, NewDevice};
+pub use federation::{FederationOutboxEntry, FederationOutboxStore};
+pub use key_backup::{BackupKeyRecord, KeyBackupRepository, KeyBackupVersion, NewBackupKey};
+pub use media::{MediaRecord, MediaRepository, MediaUsage, NewMedia};
 pub use messaging::{
-    Channel, ChannelEvent, ChannelMembership, ChannelMembershipSummary, ChannelUnreadState, Guild,
-    GuildMembership, GuildMembershipSummary, MessagingRepository,
+    Channel, ChannelEvent, ChannelMembership, ChannelMembershipSummary, ChannelUnreadState,
+    EventUsage, Guild, GuildMembership, GuildMembershipSummary, LatestEdit, MessagingRepository,
+    ReactionCount, RetentionPolicy, ThreadStats, ThreadUnreadState,
+};
+pub use mls::{
+    DeviceKeyPackageStock, MlsGroupRecord, MlsGroupRepository, MlsKeyPackageRecord,
+    MlsKeyPackageStore, MlsMessageRecord, NewMlsKeyPackage, NewMlsMessage,
 };
-pub use mls::{MlsKeyPackageRecord, MlsKeyPackageStore, NewMlsKeyPackage};
 pub use refresh::{DeviceMetadata, NewRefreshSession, RefreshSessionRecord, RefreshSessionStore};
 pub use session::{PersistedSession, SessionPersistence};
 pub use user::{CreateUserError, CredentialError, UserRecord, UserRepository};
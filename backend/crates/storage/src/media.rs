@@ -0,0 +1,228 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// Insertable media metadata for an object that has already been stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewMedia {
+    pub media_id: String,
+    pub content_hash: String,
+    pub content_type: String,
+    pub size: i64,
+    pub filename: Option<String>,
+    pub uploader_id: Uuid,
+}
+
+/// Persisted media metadata.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct MediaRecord {
+    pub media_id: String,
+    pub content_hash: String,
+    pub content_type: String,
+    pub size: i64,
+    pub filename: Option<String>,
+    pub uploader_id: Uuid,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Number of media records and the bytes they account for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromRow)]
+pub struct MediaUsage {
+    pub objects: i64,
+    pub bytes: i64,
+}
+
+/// Repository for media metadata.
+#[derive(Clone)]
+pub struct MediaRepository {
+    pool: Arc<PgPool>,
+}
+
+impl MediaRepository {
+    /// Wrap a Postgres pool for media metadata persistence.
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// Record an uploaded object and return the stored row.
+    pub async fn insert_media(&self, media: &NewMedia) -> Result<MediaRecord> {
+        let record = sqlx::query_as::<_, MediaRecord>(
+            r#"
+            INSERT INTO media (media_id, content_hash, content_type, size, filename, uploader_id)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING media_id, content_hash, content_type, size, filename, uploader_id, created_at
+            "#,
+        )
+        .bind(&media.media_id)
+        .bind(&media.content_hash)
+        .bind(&media.content_type)
+        .bind(media.size)
+        .bind(&media.filename)
+        .bind(media.uploader_id)
+        .fetch_one(self.pool())
+        .await?;
+
+        Ok(record)
+    }
+
+    /// Fetch the metadata for a media id.
+    pub async fn media_by_id(&self, media_id: &str) -> Result<Option<MediaRecord>> {
+        let record = sqlx::query_as::<_, MediaRecord>(
+            r#"
+            SELECT media_id, content_hash, content_type, size, filename, uploader_id, created_at
+            FROM media
+            WHERE media_id = $1
+            "#,
+        )
+        .bind(media_id)
+        .fetch_optional(self.pool())
+        .await?;
+
+        Ok(record)
+    }
+
+    /// Fetch the metadata for every known id in `media_ids`.
+    pub async fn media_by_ids(&self, media_ids: &[String]) -> Result<Vec<MediaRecord>> {
+        let records = sqlx::query_as::<_, MediaRecord>(
+            r#"
+            SELECT media_id, content_hash, content_type, size, filename, uploader_id, created_at
+            FROM media
+            WHERE media_id = ANY($1)
+            ORDER BY created_at ASC
+            "#,
+        )
+        .bind(media_ids)
+        .fetch_all(self.pool())
+        .await?;
+
+        Ok(records)
+    }
+
+    /// Remove a media record and return it. The stored object is left to the caller, since
+    /// other records may share it.
+    pub async fn delete_media(&self, media_id: &str) -> Result<Option<MediaRecord>> {
+        let record = sqlx::query_as::<_, MediaRecord>(
+            r#"
+            DELETE FROM media
+            WHERE media_id = $1
+            RETURNING media_id, content_hash, content_type, size, filename, uploader_id, created_at
+            "#,
+        )
+        .bind(media_id)
+        .fetch_optional(self.pool())
+        .await?;
+
+        Ok(record)
+    }
+
+    /// Whether any media record still points at the object with `content_hash`.
+    pub async fn content_hash_in_use(&self, content_hash: &str) -> Result<bool> {
+        let in_use = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (SELECT 1 FROM media WHERE content_hash = $1)",
+        )
+        .bind(content_hash)
+        .fetch_one(self.pool())
+        .await?;
+
+        Ok(in_use)
+    }
+
+    /// Media uploaded by `uploader_id`.
+    pub async fn uploader_usage(&self, uploader_id: Uuid) -> Result<MediaUsage> {
+        let usage = sqlx::query_as::<_, MediaUsage>(
+            r#"
+            SELECT COUNT(*) AS objects, COALESCE(SUM(size), 0)::BIGINT AS bytes
+            FROM media
+            WHERE uploader_id = $1
+            "#,
+        )
+        .bind(uploader_id)
+        .fetch_one(self.pool())
+        .await?;
+
+        Ok(usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connect;
+    use sqlx::migrate::Migrator;
+    use std::env;
+
+    static MIGRATOR: Migrator = sqlx::migrate!("../../migrations");
+
+    async fn setup_repo() -> anyhow::Result<Option<MediaRepository>> {
+        let database_url = match env::var("OPENGUILD_TEST_DATABASE_URL")
+            .or_else(|_| env::var("DATABASE_URL"))
+        {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!(
+                    "skipping media repository test: set OPENGUILD_TEST_DATABASE_URL or DATABASE_URL"
+                );
+                return Ok(None);
+            }
+        };
+
+        let pool = connect(&database_url).await?;
+        MIGRATOR
+            .run(pool.pool())
+            .await
+            .expect("running migrations for media repository");
+
+        Ok(Some(MediaRepository::new(pool.cloned())))
+    }
+
+    #[tokio::test]
+    async fn insert_and_fetch_media() -> anyhow::Result<()> {
+        let Some(repo) = setup_repo().await? else {
+            return Ok(());
+        };
+
+        let media_id = format!("test-{}", Uuid::new_v4());
+        let new = NewMedia {
+            media_id: media_id.clone(),
+            content_hash: Uuid::new_v4().simple().to_string().repeat(2),
+            content_type: "image/png".into(),
+            size: 42,
+            filename: Some("cat.png".into()),
+            uploader_id: Uuid::new_v4(),
+        };
+
+        let stored = repo.insert_media(&new).await?;
+        assert_eq!(stored.media_id, media_id);
+        assert_eq!(stored.size, 42);
+
+        let fetched = repo.media_by_id(&media_id).await?.expect("media exists");
+        assert_eq!(fetched, stored);
+        assert!(repo.media_by_id("missing").await?.is_none());
+        assert_eq!(
+            repo.media_by_ids(&[media_id.clone(), "missing".into()])
+                .await?,
+            vec![stored.clone()]
+        );
+        assert_eq!(
+            repo.uploader_usage(new.uploader_id).await?,
+            MediaUsage {
+                objects: 1,
+                bytes: 42
+            }
+        );
+
+        assert!(repo.content_hash_in_use(&new.content_hash).await?);
+        assert_eq!(repo.delete_media(&media_id).await?, Some(stored));
+        assert!(repo.delete_media(&media_id).await?.is_none());
+        assert!(!repo.content_hash_in_use(&new.content_hash).await?);
+
+        Ok(())
+    }
+}
@@ -24,6 +24,8 @@ pub struct Channel {
     pub channel_id: Uuid,
     pub guild_id: Uuid,
     pub name: String,
+    /// `text`, `voice`, `announcement` or `category`.
+    pub kind: String,
     pub created_at: DateTime<Utc>,
 }
 
@@ -35,6 +37,37 @@ pub struct ChannelEvent {
     pub event_type: String,
     pub body: serde_json::Value,
     pub created_at: DateTime<Utc>,
+    pub redacted: bool,
+    pub redacted_by: Option<String>,
+}
+
+/// Newest unredacted edit of a message, plus how many unredacted edits it has.
+#[derive(Debug, Clone, FromRow)]
+pub struct LatestEdit {
+    pub original_event_id: String,
+    pub edit_event_id: String,
+    pub body: serde_json::Value,
+    pub edit_count: i64,
+}
+
+/// Reply count and newest reply of a thread root.
+#[derive(Debug, Clone, FromRow)]
+pub struct ThreadStats {
+    pub root_event_id: String,
+    pub reply_count: i64,
+    pub last_reply_event_id: String,
+    pub last_reply_sequence: i64,
+    pub last_reply_body: serde_json::Value,
+}
+
+/// Reactions with one key on one event. Each sender counts once.
+#[derive(Debug, Clone, FromRow)]
+pub struct ReactionCount {
+    pub target_event_id: String,
+    pub reaction_key: String,
+    pub count: i64,
+    /// Whether the viewing user is among the senders.
+    pub me: bool,
 }
 
 #[derive(Debug, Clone, FromRow)]
@@ -79,6 +112,34 @@ pub struct ChannelUnreadState {
     pub latest_sequence: i64,
 }
 
+/// Read position inside one thread. `unread` counts replies after `last_read_sequence`, since
+/// channel sequences between replies belong to other messages.
+#[derive(Debug, Clone)]
+pub struct ThreadUnreadState {
+    pub channel_id: Uuid,
+    pub root_event_id: String,
+    pub last_read_sequence: i64,
+    pub latest_sequence: i64,
+    pub unread: i64,
+}
+
+/// How long a guild keeps channel events. Each limit is optional; `max_events` applies to
+/// every channel of the guild separately.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct RetentionPolicy {
+    pub guild_id: Uuid,
+    pub max_age_secs: Option<i64>,
+    pub max_events: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Number of stored events and the size of their JSON bodies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromRow)]
+pub struct EventUsage {
+    pub events: i64,
+    pub event_bytes: i64,
+}
+
 impl MessagingRepository {
     pub fn new(pool: StoragePool) -> Arc<Self> {
         Arc::new(Self { pool })
@@ -111,16 +172,17 @@ impl MessagingRepository {
         Ok(guilds)
     }
 
-    pub async fn create_channel(&self, guild_id: Uuid, name: &str) -> Result<Channel> {
+    pub async fn create_channel(&self, guild_id: Uuid, name: &str, kind: &str) -> Result<Channel> {
         let channel = sqlx::query_as::<_, Channel>(
             r#"
-            INSERT INTO channels (guild_id, name)
-            VALUES ($1, $2)
-            RETURNING channel_id, guild_id, name, created_at
+            INSERT INTO channels (guild_id, name, kind)
+            VALUES ($1, $2, $3)
+            RETURNING channel_id, guild_id, name, kind, created_at
             "#,
         )
         .bind(guild_id)
         .bind(name)
+        .bind(kind)
         .fetch_one(self.pool.pool())
         .await?;
         Ok(channel)
@@ -129,7 +191,7 @@ impl MessagingRepository {
     pub async fn list_channels_for_guild(&self, guild_id: Uuid) -> Result<Vec<Channel>> {
         let channels = sqlx::query_as::<_, Channel>(
             r#"
-            SELECT channel_id, guild_id, name, created_at
+            SELECT channel_id, guild_id, name, kind, created_at
             FROM channels
             WHERE guild_id = $1
             ORDER BY created_at ASC
@@ -144,95 +206,684 @@ impl MessagingRepository {
     pub async fn guild_exists(&self, guild_id: Uuid) -> Result<bool> {
         let exists = sqlx::query_scalar::<_, bool>(
             r#"
-            SELECT EXISTS (
-                SELECT 1 FROM guilds WHERE guild_id = $1
-            )
+            SELECT EXISTS (
+                SELECT 1 FROM guilds WHERE guild_id = $1
+            )
+            "#,
+        )
+        .bind(guild_id)
+        .fetch_one(self.pool.pool())
+        .await?;
+        Ok(exists)
+    }
+
+    pub async fn channel_exists(&self, channel_id: Uuid) -> Result<bool> {
+        let exists = sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM channels WHERE channel_id = $1
+            )
+            "#,
+        )
+        .bind(channel_id)
+        .fetch_one(self.pool.pool())
+        .await?;
+        Ok(exists)
+    }
+
+    pub async fn channel_by_id(&self, channel_id: Uuid) -> Result<Option<Channel>> {
+        let channel = sqlx::query_as::<_, Channel>(
+            r#"
+            SELECT channel_id, guild_id, name, kind, created_at
+            FROM channels
+            WHERE channel_id = $1
+            "#,
+        )
+        .bind(channel_id)
+        .fetch_optional(self.pool.pool())
+        .await?;
+        Ok(channel)
+    }
+
+    /// Append an event and record its DAG edges, updating the channel's forward extremities.
+    /// The event is queued in the federation outbox for each of `outbox` in the same
+    /// transaction, so a crash cannot store it without scheduling its delivery.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn append_event(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+        event_type: &str,
+        body: &serde_json::Value,
+        prev_events: &[String],
+        auth_events: &[String],
+        outbox: &[String],
+    ) -> Result<ChannelEvent> {
+        let mut tx = self.pool.pool().begin().await?;
+
+        let event = sqlx::query_as::<_, ChannelEvent>(
+            r#"
+            INSERT INTO channel_events (channel_id, event_id, event_type, body)
+            VALUES ($1, $2, $3, $4)
+            RETURNING sequence, channel_id, event_id, event_type, body, created_at, redacted, redacted_by
+            "#,
+        )
+        .bind(channel_id)
+        .bind(event_id)
+        .bind(event_type)
+        .bind(body.clone())
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for (kind, targets) in [("prev", prev_events), ("auth", auth_events)] {
+            if targets.is_empty() {
+                continue;
+            }
+            sqlx::query(
+                r#"
+                INSERT INTO channel_event_edges (channel_id, event_id, target_event_id, edge_kind)
+                SELECT $1, $2, target, $4
+                FROM UNNEST($3::TEXT[]) AS target
+                ON CONFLICT DO NOTHING
+                "#,
+            )
+            .bind(channel_id)
+            .bind(event_id)
+            .bind(targets)
+            .bind(kind)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        if !prev_events.is_empty() {
+            sqlx::query(
+                r#"
+                DELETE FROM channel_forward_extremities
+                WHERE channel_id = $1 AND event_id = ANY($2)
+                "#,
+            )
+            .bind(channel_id)
+            .bind(prev_events)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // Events ingested out of order may already be referenced by a descendant.
+        sqlx::query(
+            r#"
+            INSERT INTO channel_forward_extremities (channel_id, event_id)
+            SELECT $1, $2
+            WHERE NOT EXISTS (
+                SELECT 1 FROM channel_event_edges
+                WHERE channel_id = $1 AND target_event_id = $2 AND edge_kind = 'prev'
+            )
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(channel_id)
+        .bind(event_id)
+        .execute(&mut *tx)
+        .await?;
+
+        if !outbox.is_empty() {
+            sqlx::query(
+                r#"
+                INSERT INTO federation_outbox (destination, event_id, body)
+                SELECT destination, $2, $3
+                FROM UNNEST($1::TEXT[]) AS destination
+                ON CONFLICT (destination, event_id) DO NOTHING
+                "#,
+            )
+            .bind(outbox)
+            .bind(event_id)
+            .bind(body.clone())
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(event)
+    }
+
+    /// Events in the channel that no other event references as a predecessor.
+    pub async fn forward_extremities(&self, channel_id: Uuid) -> Result<Vec<String>> {
+        let ids = sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT fe.event_id
+            FROM channel_forward_extremities fe
+            JOIN channel_events ce ON ce.event_id = fe.event_id
+            WHERE fe.channel_id = $1
+            ORDER BY ce.sequence ASC
+            "#,
+        )
+        .bind(channel_id)
+        .fetch_all(self.pool.pool())
+        .await?;
+        Ok(ids)
+    }
+
+    /// Record the channel state after `event_id`, storing the snapshot under `state_group`
+    /// unless an identical group already exists.
+    pub async fn record_event_state(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+        state_group: &str,
+        state: &serde_json::Value,
+    ) -> Result<()> {
+        let mut tx = self.pool.pool().begin().await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO channel_state_groups (channel_id, state_group, state)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(channel_id)
+        .bind(state_group)
+        .bind(state.clone())
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO channel_event_state (channel_id, event_id, state_group)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (channel_id, event_id) DO UPDATE SET state_group = EXCLUDED.state_group
+            "#,
+        )
+        .bind(channel_id)
+        .bind(event_id)
+        .bind(state_group)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Channel state snapshot recorded after `event_id`, if any.
+    pub async fn event_state(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<Option<serde_json::Value>> {
+        let state = sqlx::query_scalar::<_, serde_json::Value>(
+            r#"
+            SELECT g.state
+            FROM channel_event_state es
+            JOIN channel_state_groups g
+              ON g.channel_id = es.channel_id AND g.state_group = es.state_group
+            WHERE es.channel_id = $1 AND es.event_id = $2
+            "#,
+        )
+        .bind(channel_id)
+        .bind(event_id)
+        .fetch_optional(self.pool.pool())
+        .await?;
+        Ok(state)
+    }
+
+    /// The channel's earliest stored state event, if any.
+    pub async fn first_state_event(&self, channel_id: Uuid) -> Result<Option<ChannelEvent>> {
+        let event = sqlx::query_as::<_, ChannelEvent>(
+            r#"
+            SELECT sequence, channel_id, event_id, event_type, body, created_at, redacted, redacted_by
+            FROM channel_events
+            WHERE channel_id = $1 AND body ? 'state_key'
+            ORDER BY sequence ASC
+            LIMIT 1
+            "#,
+        )
+        .bind(channel_id)
+        .fetch_optional(self.pool.pool())
+        .await?;
+        Ok(event)
+    }
+
+    pub async fn recent_events(
+        &self,
+        channel_id: Uuid,
+        since_sequence: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>> {
+        let events = if let Some(seq) = since_sequence {
+            sqlx::query_as::<_, ChannelEvent>(
+                r#"
+                SELECT sequence, channel_id, event_id, event_type, body, created_at, redacted, redacted_by
+                FROM channel_events
+                WHERE channel_id = $1 AND sequence > $2
+                ORDER BY sequence ASC
+                LIMIT $3
+                "#,
+            )
+            .bind(channel_id)
+            .bind(seq)
+            .bind(limit)
+            .fetch_all(self.pool.pool())
+            .await?
+        } else {
+            sqlx::query_as::<_, ChannelEvent>(
+                r#"
+                SELECT sequence, channel_id, event_id, event_type, body, created_at, redacted, redacted_by
+                FROM channel_events
+                WHERE channel_id = $1
+                ORDER BY sequence DESC
+                LIMIT $2
+                "#,
+            )
+            .bind(channel_id)
+            .bind(limit)
+            .fetch_all(self.pool.pool())
+            .await?
+            .into_iter()
+            .rev()
+            .collect()
+        };
+
+        Ok(events)
+    }
+
+    pub async fn event_by_id(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+    ) -> Result<Option<ChannelEvent>> {
+        let event = sqlx::query_as::<_, ChannelEvent>(
+            r#"
+            SELECT sequence, channel_id, event_id, event_type, body, created_at, redacted, redacted_by
+            FROM channel_events
+            WHERE channel_id = $1 AND event_id = $2
+            "#,
+        )
+        .bind(channel_id)
+        .bind(event_id)
+        .fetch_optional(self.pool.pool())
+        .await?;
+        Ok(event)
+    }
+
+    /// Replace an event's body with its redacted form and flag the row. Returns `false` when
+    /// the event is unknown or already redacted.
+    pub async fn redact_event(
+        &self,
+        channel_id: Uuid,
+        event_id: &str,
+        redacted_by: Option<&str>,
+        redacted_body: &serde_json::Value,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE channel_events
+            SET body = $4, redacted = TRUE, redacted_by = $3
+            WHERE channel_id = $1 AND event_id = $2 AND NOT redacted
+            "#,
+        )
+        .bind(channel_id)
+        .bind(event_id)
+        .bind(redacted_by)
+        .bind(redacted_body.clone())
+        .execute(self.pool.pool())
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Link an `edit` event to the message it replaces. Recording the same edit twice is a no-op.
+    pub async fn record_edit(
+        &self,
+        channel_id: Uuid,
+        original_event_id: &str,
+        edit_event_id: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO channel_message_edits (channel_id, original_event_id, edit_event_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (channel_id, edit_event_id) DO NOTHING
+            "#,
+        )
+        .bind(channel_id)
+        .bind(original_event_id)
+        .bind(edit_event_id)
+        .execute(self.pool.pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Every edit of `original_event_id`, oldest first, including redacted ones.
+    pub async fn edits_for_event(
+        &self,
+        channel_id: Uuid,
+        original_event_id: &str,
+    ) -> Result<Vec<ChannelEvent>> {
+        let events = sqlx::query_as::<_, ChannelEvent>(
+            r#"
+            SELECT e.sequence, e.channel_id, e.event_id, e.event_type, e.body, e.created_at, e.redacted, e.redacted_by
+            FROM channel_message_edits m
+            JOIN channel_events e
+                ON e.channel_id = m.channel_id AND e.event_id = m.edit_event_id
+            WHERE m.channel_id = $1 AND m.original_event_id = $2
+            ORDER BY (e.body->>'origin_ts')::BIGINT ASC, e.event_id ASC
+            "#,
+        )
+        .bind(channel_id)
+        .bind(original_event_id)
+        .fetch_all(self.pool.pool())
+        .await?;
+        Ok(events)
+    }
+
+    /// Newest unredacted edit for each of `original_event_ids` that has one. Edits are ordered
+    /// by `origin_ts` so every server picks the same one regardless of arrival order.
+    pub async fn latest_edits(
+        &self,
+        channel_id: Uuid,
+        original_event_ids: &[String],
+    ) -> Result<Vec<LatestEdit>> {
+        let edits = sqlx::query_as::<_, LatestEdit>(
+            r#"
+            SELECT DISTINCT ON (m.original_event_id)
+                m.original_event_id,
+                e.event_id AS edit_event_id,
+                e.body,
+                COUNT(*) OVER (PARTITION BY m.original_event_id) AS edit_count
+            FROM channel_message_edits m
+            JOIN channel_events e
+                ON e.channel_id = m.channel_id AND e.event_id = m.edit_event_id
+            WHERE m.channel_id = $1 AND m.original_event_id = ANY($2) AND NOT e.redacted
+            ORDER BY m.original_event_id, (e.body->>'origin_ts')::BIGINT DESC, e.event_id DESC
+            "#,
+        )
+        .bind(channel_id)
+        .bind(original_event_ids)
+        .fetch_all(self.pool.pool())
+        .await?;
+        Ok(edits)
+    }
+
+    /// Link a reply to its thread root. Recording the same reply twice is a no-op.
+    pub async fn record_thread_reply(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        event_id: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO channel_thread_replies (channel_id, root_event_id, event_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (channel_id, event_id) DO NOTHING
+            "#,
+        )
+        .bind(channel_id)
+        .bind(root_event_id)
+        .bind(event_id)
+        .execute(self.pool.pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Replies in the thread under `root_event_id`, paged like `recent_events`.
+    pub async fn thread_events(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        since_sequence: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>> {
+        let events = if let Some(seq) = since_sequence {
+            sqlx::query_as::<_, ChannelEvent>(
+                r#"
+                SELECT e.sequence, e.channel_id, e.event_id, e.event_type, e.body, e.created_at, e.redacted, e.redacted_by
+                FROM channel_thread_replies t
+                JOIN channel_events e
+                    ON e.channel_id = t.channel_id AND e.event_id = t.event_id
+                WHERE t.channel_id = $1 AND t.root_event_id = $2 AND e.sequence > $3
+                ORDER BY e.sequence ASC
+                LIMIT $4
+                "#,
+            )
+            .bind(channel_id)
+            .bind(root_event_id)
+            .bind(seq)
+            .bind(limit)
+            .fetch_all(self.pool.pool())
+            .await?
+        } else {
+            sqlx::query_as::<_, ChannelEvent>(
+                r#"
+                SELECT e.sequence, e.channel_id, e.event_id, e.event_type, e.body, e.created_at, e.redacted, e.redacted_by
+                FROM channel_thread_replies t
+                JOIN channel_events e
+                    ON e.channel_id = t.channel_id AND e.event_id = t.event_id
+                WHERE t.channel_id = $1 AND t.root_event_id = $2
+                ORDER BY e.sequence DESC
+                LIMIT $3
+                "#,
+            )
+            .bind(channel_id)
+            .bind(root_event_id)
+            .bind(limit)
+            .fetch_all(self.pool.pool())
+            .await?
+            .into_iter()
+            .rev()
+            .collect()
+        };
+
+        Ok(events)
+    }
+
+    /// Reply count and newest reply for each of `root_event_ids` that has replies.
+    pub async fn thread_stats(
+        &self,
+        channel_id: Uuid,
+        root_event_ids: &[String],
+    ) -> Result<Vec<ThreadStats>> {
+        let stats = sqlx::query_as::<_, ThreadStats>(
+            r#"
+            SELECT DISTINCT ON (t.root_event_id)
+                t.root_event_id,
+                COUNT(*) OVER (PARTITION BY t.root_event_id) AS reply_count,
+                e.event_id AS last_reply_event_id,
+                e.sequence AS last_reply_sequence,
+                e.body AS last_reply_body
+            FROM channel_thread_replies t
+            JOIN channel_events e
+                ON e.channel_id = t.channel_id AND e.event_id = t.event_id
+            WHERE t.channel_id = $1 AND t.root_event_id = ANY($2)
+            ORDER BY t.root_event_id, e.sequence DESC
+            "#,
+        )
+        .bind(channel_id)
+        .bind(root_event_ids)
+        .fetch_all(self.pool.pool())
+        .await?;
+        Ok(stats)
+    }
+
+    pub async fn update_thread_read_sequence(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        user_id: Uuid,
+        sequence: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO thread_read_state (channel_id, root_event_id, user_id, last_read_sequence)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (channel_id, root_event_id, user_id)
+            DO UPDATE
+            SET last_read_sequence = GREATEST(thread_read_state.last_read_sequence, EXCLUDED.last_read_sequence),
+                updated_at = NOW()
+            "#,
+        )
+        .bind(channel_id)
+        .bind(root_event_id)
+        .bind(user_id)
+        .bind(sequence)
+        .execute(self.pool.pool())
+        .await?;
+        Ok(())
+    }
+
+    pub async fn ensure_thread_read_state(
+        &self,
+        channel_id: Uuid,
+        root_event_id: &str,
+        user_id: Uuid,
+        sequence: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO thread_read_state (channel_id, root_event_id, user_id, last_read_sequence)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (channel_id, root_event_id, user_id) DO NOTHING
+            "#,
+        )
+        .bind(channel_id)
+        .bind(root_event_id)
+        .bind(user_id)
+        .bind(sequence)
+        .execute(self.pool.pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Unread state of every thread the user tracks in channels they are a member of.
+    pub async fn user_thread_unread(&self, user_id: Uuid) -> Result<Vec<ThreadUnreadState>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT
+                trs.channel_id,
+                trs.root_event_id,
+                trs.last_read_sequence,
+                COALESCE(MAX(e.sequence), 0) AS latest_sequence,
+                COUNT(e.sequence) FILTER (WHERE e.sequence > trs.last_read_sequence) AS unread
+            FROM thread_read_state trs
+            JOIN channel_memberships cm
+                ON cm.channel_id = trs.channel_id
+               AND cm.user_id = trs.user_id
+            LEFT JOIN channel_thread_replies t
+                ON t.channel_id = trs.channel_id
+               AND t.root_event_id = trs.root_event_id
+            LEFT JOIN channel_events e
+                ON e.channel_id = t.channel_id
+               AND e.event_id = t.event_id
+            WHERE trs.user_id = $1
+            GROUP BY trs.channel_id, trs.root_event_id, trs.last_read_sequence
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(self.pool.pool())
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ThreadUnreadState {
+                channel_id: row.get("channel_id"),
+                root_event_id: row.get("root_event_id"),
+                last_read_sequence: row.get::<i64, _>("last_read_sequence"),
+                latest_sequence: row.get::<i64, _>("latest_sequence"),
+                unread: row.get::<i64, _>("unread"),
+            })
+            .collect())
+    }
+
+    /// Record a reaction event. Recording the same event twice is a no-op.
+    pub async fn record_reaction(
+        &self,
+        channel_id: Uuid,
+        target_event_id: &str,
+        event_id: &str,
+        sender: &str,
+        reaction_key: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO channel_reactions (channel_id, target_event_id, event_id, sender, reaction_key)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (channel_id, event_id) DO NOTHING
             "#,
         )
-        .bind(guild_id)
-        .fetch_one(self.pool.pool())
+        .bind(channel_id)
+        .bind(target_event_id)
+        .bind(event_id)
+        .bind(sender)
+        .bind(reaction_key)
+        .execute(self.pool.pool())
         .await?;
-        Ok(exists)
+        Ok(())
     }
 
-    pub async fn channel_exists(&self, channel_id: Uuid) -> Result<bool> {
-        let exists = sqlx::query_scalar::<_, bool>(
+    /// Forget a reaction event. Returns `false` when it was not recorded.
+    pub async fn remove_reaction(&self, channel_id: Uuid, event_id: &str) -> Result<bool> {
+        let result = sqlx::query(
             r#"
-            SELECT EXISTS (
-                SELECT 1 FROM channels WHERE channel_id = $1
-            )
+            DELETE FROM channel_reactions
+            WHERE channel_id = $1 AND event_id = $2
             "#,
         )
         .bind(channel_id)
-        .fetch_one(self.pool.pool())
+        .bind(event_id)
+        .execute(self.pool.pool())
         .await?;
-        Ok(exists)
+        Ok(result.rows_affected() > 0)
     }
 
-    pub async fn append_event(
+    /// Ids of `sender`'s live reaction events with `reaction_key` on `target_event_id`.
+    pub async fn reactions_by_sender(
         &self,
         channel_id: Uuid,
-        event_id: &str,
-        event_type: &str,
-        body: &serde_json::Value,
-    ) -> Result<ChannelEvent> {
-        let event = sqlx::query_as::<_, ChannelEvent>(
+        target_event_id: &str,
+        sender: &str,
+        reaction_key: &str,
+    ) -> Result<Vec<String>> {
+        let ids = sqlx::query_scalar::<_, String>(
             r#"
-            INSERT INTO channel_events (channel_id, event_id, event_type, body)
-            VALUES ($1, $2, $3, $4)
-            RETURNING sequence, channel_id, event_id, event_type, body, created_at
+            SELECT event_id
+            FROM channel_reactions
+            WHERE channel_id = $1 AND target_event_id = $2 AND sender = $3 AND reaction_key = $4
+            ORDER BY event_id
             "#,
         )
         .bind(channel_id)
-        .bind(event_id)
-        .bind(event_type)
-        .bind(body.clone())
-        .fetch_one(self.pool.pool())
+        .bind(target_event_id)
+        .bind(sender)
+        .bind(reaction_key)
+        .fetch_all(self.pool.pool())
         .await?;
-        Ok(event)
+        Ok(ids)
     }
 
-    pub async fn recent_events(
+    /// Reaction counts per key for each of `target_event_ids`, most popular first.
+    pub async fn reaction_counts(
         &self,
         channel_id: Uuid,
-        since_sequence: Option<i64>,
-        limit: i64,
-    ) -> Result<Vec<ChannelEvent>> {
-        let events = if let Some(seq) = since_sequence {
-            sqlx::query_as::<_, ChannelEvent>(
-                r#"
-                SELECT sequence, channel_id, event_id, event_type, body, created_at
-                FROM channel_events
-                WHERE channel_id = $1 AND sequence > $2
-                ORDER BY sequence ASC
-                LIMIT $3
-                "#,
-            )
-            .bind(channel_id)
-            .bind(seq)
-            .bind(limit)
-            .fetch_all(self.pool.pool())
-            .await?
-        } else {
-            sqlx::query_as::<_, ChannelEvent>(
-                r#"
-                SELECT sequence, channel_id, event_id, event_type, body, created_at
-                FROM channel_events
-                WHERE channel_id = $1
-                ORDER BY sequence DESC
-                LIMIT $2
-                "#,
-            )
-            .bind(channel_id)
-            .bind(limit)
-            .fetch_all(self.pool.pool())
-            .await?
-            .into_iter()
-            .rev()
-            .collect()
-        };
-
-        Ok(events)
+        target_event_ids: &[String],
+        viewer: Option<&str>,
+    ) -> Result<Vec<ReactionCount>> {
+        let counts = sqlx::query_as::<_, ReactionCount>(
+            r#"
+            SELECT
+                target_event_id,
+                reaction_key,
+                COUNT(DISTINCT sender) AS count,
+                COALESCE(BOOL_OR(sender = $3), FALSE) AS me
+            FROM channel_reactions
+            WHERE channel_id = $1 AND target_event_id = ANY($2)
+            GROUP BY target_event_id, reaction_key
+            ORDER BY target_event_id, count DESC, reaction_key
+            "#,
+        )
+        .bind(channel_id)
+        .bind(target_event_ids)
+        .bind(viewer)
+        .fetch_all(self.pool.pool())
+        .await?;
+        Ok(counts)
     }
 
     pub async fn user_ids_for_channel(&self, channel_id: Uuid) -> Result<Vec<Uuid>> {
@@ -249,6 +900,21 @@ impl MessagingRepository {
         Ok(ids)
     }
 
+    pub async fn channel_members(&self, channel_id: Uuid) -> Result<Vec<ChannelMembership>> {
+        let members = sqlx::query_as::<_, ChannelMembership>(
+            r#"
+            SELECT channel_id, user_id, role, joined_at
+            FROM channel_memberships
+            WHERE channel_id = $1
+            ORDER BY joined_at ASC
+            "#,
+        )
+        .bind(channel_id)
+        .fetch_all(self.pool.pool())
+        .await?;
+        Ok(members)
+    }
+
     pub async fn user_ids_for_guild(&self, guild_id: Uuid) -> Result<Vec<Uuid>> {
         let ids = sqlx::query_scalar::<_, Uuid>(
             r#"
@@ -447,6 +1113,223 @@ impl MessagingRepository {
         .await?;
         Ok(memberships)
     }
+
+    /// Create or replace the retention policy of a guild.
+    pub async fn set_retention_policy(
+        &self,
+        guild_id: Uuid,
+        max_age_secs: Option<i64>,
+        max_events: Option<i64>,
+    ) -> Result<RetentionPolicy> {
+        let policy = sqlx::query_as::<_, RetentionPolicy>(
+            r#"
+            INSERT INTO guild_retention_policies (guild_id, max_age_secs, max_events)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (guild_id)
+            DO UPDATE SET max_age_secs = EXCLUDED.max_age_secs,
+                          max_events = EXCLUDED.max_events,
+                          updated_at = NOW()
+            RETURNING guild_id, max_age_secs, max_events, updated_at
+            "#,
+        )
+        .bind(guild_id)
+        .bind(max_age_secs)
+        .bind(max_events)
+        .fetch_one(self.pool.pool())
+        .await?;
+        Ok(policy)
+    }
+
+    /// Remove the retention policy of a guild; returns whether one existed.
+    pub async fn clear_retention_policy(&self, guild_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM guild_retention_policies WHERE guild_id = $1")
+            .bind(guild_id)
+            .execute(self.pool.pool())
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn retention_policy(&self, guild_id: Uuid) -> Result<Option<RetentionPolicy>> {
+        let policy = sqlx::query_as::<_, RetentionPolicy>(
+            r#"
+            SELECT guild_id, max_age_secs, max_events, updated_at
+            FROM guild_retention_policies
+            WHERE guild_id = $1
+            "#,
+        )
+        .bind(guild_id)
+        .fetch_optional(self.pool.pool())
+        .await?;
+        Ok(policy)
+    }
+
+    pub async fn retention_policies(&self) -> Result<Vec<RetentionPolicy>> {
+        let policies = sqlx::query_as::<_, RetentionPolicy>(
+            r#"
+            SELECT guild_id, max_age_secs, max_events, updated_at
+            FROM guild_retention_policies
+            ORDER BY guild_id
+            "#,
+        )
+        .fetch_all(self.pool.pool())
+        .await?;
+        Ok(policies)
+    }
+
+    /// Delete up to `limit` of the oldest events in the channel that were created before
+    /// `older_than` or fall outside its newest `keep_latest` events, and return them.
+    ///
+    /// State events and forward extremities are never pruned, so channel state and the
+    /// tips of the event graph survive. Edges, edit and thread links, reactions and state
+    /// snapshots of pruned events go with them.
+    pub async fn prune_channel_events(
+        &self,
+        channel_id: Uuid,
+        older_than: Option<DateTime<Utc>>,
+        keep_latest: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ChannelEvent>> {
+        let mut tx = self.pool.pool().begin().await?;
+
+        let event_ids = sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT e.event_id
+            FROM channel_events e
+            WHERE e.channel_id = $1
+              AND NOT (e.body ? 'state_key')
+              AND NOT EXISTS (
+                  SELECT 1 FROM channel_forward_extremities fe
+                  WHERE fe.channel_id = e.channel_id AND fe.event_id = e.event_id
+              )
+              AND (
+                  e.created_at < $2::TIMESTAMPTZ
+                  OR ($3::BIGINT IS NOT NULL AND e.sequence <= (
+                      SELECT sequence FROM channel_events
+                      WHERE channel_id = $1
+                      ORDER BY sequence DESC
+                      OFFSET $3
+                      LIMIT 1
+                  ))
+              )
+            ORDER BY e.sequence ASC
+            LIMIT $4
+            FOR UPDATE
+            "#,
+        )
+        .bind(channel_id)
+        .bind(older_than)
+        .bind(keep_latest)
+        .bind(limit)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        if event_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pruned = sqlx::query_as::<_, ChannelEvent>(
+            r#"
+            DELETE FROM channel_events
+            WHERE channel_id = $1 AND event_id = ANY($2)
+            RETURNING sequence, channel_id, event_id, event_type, body, created_at, redacted, redacted_by
+            "#,
+        )
+        .bind(channel_id)
+        .bind(&event_ids)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for statement in [
+            "DELETE FROM channel_event_edges WHERE channel_id = $1 AND event_id = ANY($2)",
+            "DELETE FROM channel_event_state WHERE channel_id = $1 AND event_id = ANY($2)",
+            "DELETE FROM channel_message_edits WHERE channel_id = $1 AND (edit_event_id = ANY($2) OR original_event_id = ANY($2))",
+            "DELETE FROM channel_thread_replies WHERE channel_id = $1 AND (event_id = ANY($2) OR root_event_id = ANY($2))",
+            "DELETE FROM thread_read_state WHERE channel_id = $1 AND root_event_id = ANY($2)",
+            "DELETE FROM channel_reactions WHERE channel_id = $1 AND (event_id = ANY($2) OR target_event_id = ANY($2))",
+        ] {
+            sqlx::query(statement)
+                .bind(channel_id)
+                .bind(&event_ids)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        pruned.sort_by_key(|event| event.sequence);
+        Ok(pruned)
+    }
+
+    /// Whether any stored event still lists `media_id` among its attachments.
+    pub async fn media_referenced(&self, media_id: &str) -> Result<bool> {
+        let referenced = sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM channel_events
+                WHERE body->'content'->'attachments'
+                    @> jsonb_build_array(jsonb_build_object('media_id', $1::TEXT))
+            )
+            "#,
+        )
+        .bind(media_id)
+        .fetch_one(self.pool.pool())
+        .await?;
+        Ok(referenced)
+    }
+
+    /// Events stored across every channel of the guild.
+    pub async fn guild_event_usage(&self, guild_id: Uuid) -> Result<EventUsage> {
+        let usage = sqlx::query_as::<_, EventUsage>(
+            r#"
+            SELECT COUNT(e.sequence) AS events,
+                   COALESCE(SUM(octet_length(e.body::TEXT)), 0)::BIGINT AS event_bytes
+            FROM channel_events e
+            JOIN channels c ON c.channel_id = e.channel_id
+            WHERE c.guild_id = $1
+            "#,
+        )
+        .bind(guild_id)
+        .fetch_one(self.pool.pool())
+        .await?;
+        Ok(usage)
+    }
+
+    /// Events sent by `sender` in any channel.
+    pub async fn user_event_usage(&self, sender: &str) -> Result<EventUsage> {
+        let usage = sqlx::query_as::<_, EventUsage>(
+            r#"
+            SELECT COUNT(sequence) AS events,
+                   COALESCE(SUM(octet_length(body::TEXT)), 0)::BIGINT AS event_bytes
+            FROM channel_events
+            WHERE body->>'sender' = $1
+            "#,
+        )
+        .bind(sender)
+        .fetch_one(self.pool.pool())
+        .await?;
+        Ok(usage)
+    }
+
+    /// Distinct media ids attached to events of the guild.
+    pub async fn guild_attachment_ids(&self, guild_id: Uuid) -> Result<Vec<String>> {
+        let ids = sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT DISTINCT attachment->>'media_id'
+            FROM channel_events e
+            JOIN channels c ON c.channel_id = e.channel_id
+            CROSS JOIN LATERAL jsonb_array_elements(
+                CASE WHEN jsonb_typeof(e.body->'content'->'attachments') = 'array'
+                     THEN e.body->'content'->'attachments'
+                     ELSE '[]'::JSONB
+                END
+            ) AS attachment
+            WHERE c.guild_id = $1 AND attachment->>'media_id' IS NOT NULL
+            "#,
+        )
+        .bind(guild_id)
+        .fetch_all(self.pool.pool())
+        .await?;
+        Ok(ids)
+    }
 }
 
 #[cfg(test)]
@@ -467,7 +1350,7 @@ mod tests {
     }
 
     async fn truncate_tables(pool: &StoragePool) -> anyhow::Result<()> {
-        sqlx::query("TRUNCATE channel_events, channel_memberships, guild_memberships, channels, guilds RESTART IDENTITY CASCADE")
+        sqlx::query("TRUNCATE guild_retention_policies, channel_reactions, thread_read_state, channel_thread_replies, channel_message_edits, channel_event_state, channel_state_groups, channel_event_edges, channel_forward_extremities, channel_events, channel_memberships, guild_memberships, channels, guilds RESTART IDENTITY CASCADE")
             .execute(pool.pool())
             .await
             .map(|_| ())
@@ -499,13 +1382,18 @@ mod tests {
         assert!(repo.guild_exists(alpha.guild_id).await?);
         assert!(!repo.guild_exists(Uuid::new_v4()).await?);
 
-        let general = repo.create_channel(alpha.guild_id, "general").await?;
-        let support = repo.create_channel(alpha.guild_id, "support").await?;
+        let general = repo
+            .create_channel(alpha.guild_id, "general", "text")
+            .await?;
+        let support = repo
+            .create_channel(alpha.guild_id, "support", "voice")
+            .await?;
 
         let channels = repo.list_channels_for_guild(alpha.guild_id).await?;
         let channel_ids: Vec<_> = channels.iter().map(|c| c.channel_id).collect();
         assert!(channel_ids.contains(&general.channel_id));
         assert!(channel_ids.contains(&support.channel_id));
+        assert_eq!(support.kind, "voice");
 
         assert!(repo.channel_exists(general.channel_id).await?);
         assert!(!repo.channel_exists(Uuid::new_v4()).await?);
@@ -515,10 +1403,64 @@ mod tests {
         let payload = json!({ "content": "hello world" });
 
         let first = repo
-            .append_event(general.channel_id, &first_event_id, "message", &payload)
+            .append_event(
+                general.channel_id,
+                &first_event_id,
+                "message",
+                &payload,
+                &[],
+                &[],
+                &[],
+            )
+            .await?;
+        assert_eq!(
+            repo.forward_extremities(general.channel_id).await?,
+            vec![first_event_id.clone()]
+        );
+        let destination = format!("{}.example.org", Uuid::new_v4());
+        repo.append_event(
+            general.channel_id,
+            &second_event_id,
+            "message",
+            &payload,
+            std::slice::from_ref(&first_event_id),
+            &[],
+            std::slice::from_ref(&destination),
+        )
+        .await?;
+        assert_eq!(
+            repo.forward_extremities(general.channel_id).await?,
+            vec![second_event_id.clone()]
+        );
+        let outbox = crate::FederationOutboxStore::new(pool.cloned())
+            .pending(&destination, 10)
+            .await?;
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].event_id, second_event_id);
+        assert!(repo
+            .event_by_id(general.channel_id, &first_event_id)
+            .await?
+            .is_some());
+        assert!(repo
+            .event_by_id(general.channel_id, "$unknown")
+            .await?
+            .is_none());
+
+        let snapshot =
+            json!([{ "event_type": "channel.name", "state_key": "", "event_id": "$name" }]);
+        repo.record_event_state(general.channel_id, &first_event_id, "group-a", &snapshot)
             .await?;
-        repo.append_event(general.channel_id, &second_event_id, "message", &payload)
+        repo.record_event_state(general.channel_id, &second_event_id, "group-a", &snapshot)
             .await?;
+        assert_eq!(
+            repo.event_state(general.channel_id, &second_event_id)
+                .await?,
+            Some(snapshot)
+        );
+        assert!(repo
+            .event_state(general.channel_id, "$unknown")
+            .await?
+            .is_none());
 
         let all_events = repo
             .recent_events(general.channel_id, None, 10)
@@ -538,6 +1480,107 @@ mod tests {
         assert_eq!(from_sequence.len(), 1);
         assert_eq!(from_sequence[0].event_id, second_event_id);
 
+        let edit_body = |content: &str, origin_ts: i64| json!({ "content": { "edits": first_event_id, "content": content }, "origin_ts": origin_ts });
+        for (edit_id, content, origin_ts) in [("$edit-b", "second", 20), ("$edit-a", "first", 10)] {
+            repo.append_event(
+                general.channel_id,
+                edit_id,
+                "edit",
+                &edit_body(content, origin_ts),
+                std::slice::from_ref(&second_event_id),
+                &[],
+                &[],
+            )
+            .await?;
+            repo.record_edit(general.channel_id, &first_event_id, edit_id)
+                .await?;
+        }
+        repo.record_edit(general.channel_id, &first_event_id, "$edit-a")
+            .await?;
+        let history = repo
+            .edits_for_event(general.channel_id, &first_event_id)
+            .await?;
+        let history_ids: Vec<_> = history.iter().map(|edit| edit.event_id.as_str()).collect();
+        assert_eq!(history_ids, vec!["$edit-a", "$edit-b"]);
+        let latest = repo
+            .latest_edits(
+                general.channel_id,
+                &[first_event_id.clone(), second_event_id.clone()],
+            )
+            .await?;
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].edit_event_id, "$edit-b");
+        assert_eq!(latest[0].edit_count, 2);
+
+        for (reaction_id, sender) in [
+            ("$react-a", "@alice"),
+            ("$react-b", "@alice"),
+            ("$react-c", "@bob"),
+        ] {
+            repo.record_reaction(
+                general.channel_id,
+                &first_event_id,
+                reaction_id,
+                sender,
+                "👍",
+            )
+            .await?;
+        }
+        assert_eq!(
+            repo.reactions_by_sender(general.channel_id, &first_event_id, "@alice", "👍")
+                .await?,
+            vec!["$react-a".to_string(), "$react-b".to_string()]
+        );
+        let counts = repo
+            .reaction_counts(
+                general.channel_id,
+                std::slice::from_ref(&first_event_id),
+                Some("@bob"),
+            )
+            .await?;
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].count, 2);
+        assert!(counts[0].me);
+        assert!(repo.remove_reaction(general.channel_id, "$react-c").await?);
+        assert!(!repo.remove_reaction(general.channel_id, "$react-c").await?);
+        let counts = repo
+            .reaction_counts(
+                general.channel_id,
+                std::slice::from_ref(&first_event_id),
+                Some("@bob"),
+            )
+            .await?;
+        assert_eq!(counts[0].count, 1);
+        assert!(!counts[0].me);
+
+        let redacted_body = json!({ "content": {} });
+        assert!(
+            repo.redact_event(
+                general.channel_id,
+                &first_event_id,
+                Some("$redaction"),
+                &redacted_body
+            )
+            .await?
+        );
+        assert!(
+            !repo
+                .redact_event(
+                    general.channel_id,
+                    &first_event_id,
+                    Some("$again"),
+                    &redacted_body
+                )
+                .await?
+        );
+        let redacted = repo
+            .event_by_id(general.channel_id, &first_event_id)
+            .await?
+            .expect("redacted event is kept");
+        assert!(redacted.redacted);
+        assert_eq!(redacted.redacted_by.as_deref(), Some("$redaction"));
+        assert_eq!(redacted.body, redacted_body);
+
         truncate_tables(&pool).await?;
         Ok(())
     }
@@ -559,7 +1602,9 @@ mod tests {
         truncate_tables(&pool).await?;
 
         let guild = repo.create_guild("Members").await?;
-        let channel = repo.create_channel(guild.guild_id, "general").await?;
+        let channel = repo
+            .create_channel(guild.guild_id, "general", "text")
+            .await?;
         let user_id = Uuid::new_v4();
 
         let guild_membership = repo
@@ -582,6 +1627,184 @@ mod tests {
         assert_eq!(channels[0].channel_name, "general");
         assert_eq!(channels[0].role, "moderator");
 
+        let members = repo.channel_members(channel.channel_id).await?;
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].user_id, user_id);
+        assert_eq!(members[0].role, "moderator");
+        let found = repo
+            .channel_by_id(channel.channel_id)
+            .await?
+            .expect("channel exists");
+        assert_eq!(found.guild_id, guild.guild_id);
+        assert!(repo.channel_by_id(Uuid::new_v4()).await?.is_none());
+
+        let root = repo
+            .append_event(
+                channel.channel_id,
+                "$root",
+                "message",
+                &json!({ "content": { "content": "root" } }),
+                &[],
+                &[],
+                &[],
+            )
+            .await?;
+        repo.ensure_thread_read_state(channel.channel_id, "$root", user_id, root.sequence)
+            .await?;
+        let mut prev = "$root".to_string();
+        for reply_id in ["$reply-1", "$reply-2"] {
+            repo.append_event(
+                channel.channel_id,
+                reply_id,
+                "message",
+                &json!({ "content": { "content": "reply", "thread_root": "$root" } }),
+                std::slice::from_ref(&prev),
+                &[],
+                &[],
+            )
+            .await?;
+            repo.record_thread_reply(channel.channel_id, "$root", reply_id)
+                .await?;
+            prev = reply_id.to_string();
+        }
+
+        let replies = repo
+            .thread_events(channel.channel_id, "$root", None, 10)
+            .await?;
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].event_id, "$reply-1");
+        let stats = repo
+            .thread_stats(channel.channel_id, &["$root".to_string()])
+            .await?;
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].reply_count, 2);
+        assert_eq!(stats[0].last_reply_event_id, "$reply-2");
+
+        let threads = repo.user_thread_unread(user_id).await?;
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0].unread, 2);
+        repo.update_thread_read_sequence(channel.channel_id, "$root", user_id, replies[0].sequence)
+            .await?;
+        let threads = repo.user_thread_unread(user_id).await?;
+        assert_eq!(threads[0].unread, 1);
+        assert_eq!(threads[0].latest_sequence, replies[1].sequence);
+
+        truncate_tables(&pool).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn messaging_repository_prunes_events_under_retention() -> anyhow::Result<()> {
+        let Some(database_url) = test_database_url() else {
+            eprintln!("skipping messaging repository test: set OPENGUILD_TEST_DATABASE_URL or DATABASE_URL");
+            return Ok(());
+        };
+
+        let pool = connect(&database_url).await?;
+        MIGRATOR
+            .run(pool.pool())
+            .await
+            .context("running migrations for retention repository tests failed")?;
+
+        let repo = MessagingRepository::new(pool.clone());
+        truncate_tables(&pool).await?;
+
+        let guild = repo.create_guild("Retention").await?;
+        let channel = repo
+            .create_channel(guild.guild_id, "general", "text")
+            .await?;
+
+        assert!(repo.retention_policy(guild.guild_id).await?.is_none());
+        let policy = repo
+            .set_retention_policy(guild.guild_id, None, Some(2))
+            .await?;
+        assert_eq!(policy.max_events, Some(2));
+        let policy = repo
+            .set_retention_policy(guild.guild_id, Some(3600), Some(2))
+            .await?;
+        assert_eq!(repo.retention_policies().await?, vec![policy]);
+
+        repo.append_event(
+            channel.channel_id,
+            "$topic",
+            "channel.topic",
+            &json!({ "sender": "alice", "state_key": "", "content": { "topic": "t" } }),
+            &[],
+            &[],
+            &[],
+        )
+        .await?;
+        let mut prev = "$topic".to_string();
+        for (index, event_id) in ["$m1", "$m2", "$m3", "$m4"].into_iter().enumerate() {
+            repo.append_event(
+                channel.channel_id,
+                event_id,
+                "message",
+                &json!({
+                    "sender": "alice",
+                    "content": {
+                        "content": "hi",
+                        "attachments": [{ "media_id": format!("media-{index}") }]
+                    }
+                }),
+                std::slice::from_ref(&prev),
+                &[],
+                &[],
+            )
+            .await?;
+            prev = event_id.to_string();
+        }
+        repo.record_reaction(channel.channel_id, "$m1", "$m4", "alice", "👍")
+            .await?;
+
+        let usage = repo.guild_event_usage(guild.guild_id).await?;
+        assert_eq!(usage.events, 5);
+        assert!(usage.event_bytes > 0);
+        assert_eq!(repo.user_event_usage("alice").await?, usage);
+        assert_eq!(repo.guild_attachment_ids(guild.guild_id).await?.len(), 4);
+        assert!(repo.media_referenced("media-0").await?);
+
+        // Nothing is old enough yet; keeping two events prunes the two oldest messages
+        // but not the state event.
+        let far_past = Utc::now() - chrono::Duration::days(1);
+        let pruned = repo
+            .prune_channel_events(channel.channel_id, Some(far_past), Some(2), 1)
+            .await?;
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].event_id, "$m1");
+        let pruned = repo
+            .prune_channel_events(channel.channel_id, Some(far_past), Some(2), 10)
+            .await?;
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].event_id, "$m2");
+        assert!(repo
+            .prune_channel_events(channel.channel_id, None, Some(2), 10)
+            .await?
+            .is_empty());
+        assert!(repo
+            .prune_channel_events(channel.channel_id, None, None, 10)
+            .await?
+            .is_empty());
+
+        assert!(!repo.media_referenced("media-0").await?);
+        assert!(repo
+            .reaction_counts(channel.channel_id, &["$m1".to_string()], None)
+            .await?
+            .is_empty());
+        // The forward extremity is kept even when everything is past the age limit.
+        let pruned = repo
+            .prune_channel_events(channel.channel_id, Some(Utc::now()), None, 10)
+            .await?;
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].event_id, "$m3");
+        assert_eq!(
+            repo.forward_extremities(channel.channel_id).await?,
+            vec!["$m4".to_string()]
+        );
+
+        assert!(repo.clear_retention_policy(guild.guild_id).await?);
+        assert!(!repo.clear_retention_policy(guild.guild_id).await?);
+
         truncate_tables(&pool).await?;
         Ok(())
     }
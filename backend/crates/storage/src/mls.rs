@@ -1,33 +1,51 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use sqlx::{FromRow, PgPool};
 use uuid::Uuid;
 
-/// Insertable MLS key package payload.
+/// Insertable MLS key package uploaded by a client device. Only public material is kept:
+/// `key_package` is the TLS-encoded `MLSMessage` carrying it; the other fields are read from it.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NewMlsKeyPackage {
+    pub user_id: Uuid,
+    pub device_id: String,
+    pub key_package_ref: String,
     pub identity: String,
     pub ciphersuite: String,
-    pub signing_key: String,
     pub signature_key: String,
     pub hpke_public_key: String,
+    pub key_package: Vec<u8>,
+    pub last_resort: bool,
 }
 
 /// Persisted MLS key package record.
 #[derive(Debug, Clone, PartialEq, Eq, FromRow)]
 pub struct MlsKeyPackageRecord {
     pub id: Uuid,
+    pub user_id: Uuid,
+    pub device_id: String,
+    pub key_package_ref: String,
     pub identity: String,
     pub ciphersuite: String,
-    pub signing_key: String,
     pub signature_key: String,
     pub hpke_public_key: String,
+    pub key_package: Vec<u8>,
+    pub last_resort: bool,
     pub created_at: DateTime<Utc>,
 }
 
-/// Repository for reading/writing MLS key packages.
+/// Unclaimed key packages held for one device.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct DeviceKeyPackageStock {
+    pub device_id: String,
+    /// Single-use packages left to hand out.
+    pub available: i64,
+    pub last_resort: bool,
+}
+
+/// Repository for client-uploaded MLS key packages.
 #[derive(Clone)]
 pub struct MlsKeyPackageStore {
     pool: Arc<PgPool>,
@@ -43,155 +61,618 @@ impl MlsKeyPackageStore {
         &self.pool
     }
 
-    /// Persist a key package rotation and return the stored record.
-    pub async fn record_package(&self, package: &NewMlsKeyPackage) -> Result<MlsKeyPackageRecord> {
-        let id = Uuid::new_v4();
-        let record = sqlx::query_as::<_, MlsKeyPackageRecord>(
+    /// Store a batch of key packages and return how many were new, or `None` without storing
+    /// anything when a device would hold more than `max_stock` single-use packages. A
+    /// last-resort package replaces the device's previous one; packages already on file are
+    /// skipped.
+    pub async fn upload(
+        &self,
+        packages: &[NewMlsKeyPackage],
+        max_stock: i64,
+    ) -> Result<Option<u64>> {
+        let mut uploaded: BTreeMap<(Uuid, &str), i64> = BTreeMap::new();
+        for package in packages {
+            *uploaded
+                .entry((package.user_id, package.device_id.as_str()))
+                .or_default() += i64::from(!package.last_resort);
+        }
+
+        let mut tx = self.pool().begin().await?;
+        for ((user_id, device_id), count) in uploaded {
+            // Serialise uploads per device so concurrent batches cannot both pass the limit.
+            sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+                .bind(format!("mls_key_packages:{user_id}:{device_id}"))
+                .execute(&mut *tx)
+                .await?;
+            let available: i64 = sqlx::query_scalar(
+                r#"
+                SELECT COUNT(*)
+                FROM mls_key_packages
+                WHERE user_id = $1 AND device_id = $2 AND NOT last_resort
+                "#,
+            )
+            .bind(user_id)
+            .bind(device_id)
+            .fetch_one(&mut *tx)
+            .await?;
+            if available + count > max_stock {
+                return Ok(None);
+            }
+        }
+
+        let mut stored = 0;
+        for package in packages {
+            if package.last_resort {
+                sqlx::query(
+                    r#"
+                    DELETE FROM mls_key_packages
+                    WHERE user_id = $1 AND device_id = $2 AND last_resort
+                    "#,
+                )
+                .bind(package.user_id)
+                .bind(&package.device_id)
+                .execute(&mut *tx)
+                .await?;
+            }
+            let inserted = sqlx::query(
+                r#"
+                INSERT INTO mls_key_packages (
+                    id, user_id, device_id, key_package_ref, identity, ciphersuite,
+                    signature_key, hpke_public_key, key_package, last_resort
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                ON CONFLICT (key_package_ref) DO NOTHING
+                "#,
+            )
+            .bind(Uuid::new_v4())
+            .bind(package.user_id)
+            .bind(&package.device_id)
+            .bind(&package.key_package_ref)
+            .bind(&package.identity)
+            .bind(&package.ciphersuite)
+            .bind(&package.signature_key)
+            .bind(&package.hpke_public_key)
+            .bind(&package.key_package)
+            .bind(package.last_resort)
+            .execute(&mut *tx)
+            .await?;
+            stored += inserted.rows_affected();
+        }
+        tx.commit().await?;
+        Ok(Some(stored))
+    }
+
+    /// Hand out the device's oldest single-use package, deleting it so nobody else gets it.
+    /// Falls back to the last-resort package, which is never consumed.
+    pub async fn claim(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<MlsKeyPackageRecord>> {
+        let claimed = sqlx::query_as::<_, MlsKeyPackageRecord>(
             r#"
-            INSERT INTO mls_key_packages (id, identity, ciphersuite, signing_key, signature_key, hpke_public_key)
-            VALUES ($1, $2, $3, $4, $5, $6)
-            RETURNING id, identity, ciphersuite, signing_key, signature_key, hpke_public_key, created_at
+            DELETE FROM mls_key_packages
+            WHERE id = (
+                SELECT id
+                FROM mls_key_packages
+                WHERE user_id = $1 AND device_id = $2 AND NOT last_resort
+                ORDER BY created_at, id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING
+                id, user_id, device_id, key_package_ref, identity, ciphersuite,
+                signature_key, hpke_public_key, key_package, last_resort, created_at
             "#,
         )
-        .bind(id)
-        .bind(&package.identity)
-        .bind(&package.ciphersuite)
-        .bind(&package.signing_key)
-        .bind(&package.signature_key)
-        .bind(&package.hpke_public_key)
-        .fetch_one(self.pool())
+        .bind(user_id)
+        .bind(device_id)
+        .fetch_optional(self.pool())
         .await?;
+        if claimed.is_some() {
+            return Ok(claimed);
+        }
+        self.last_resort_package(user_id, device_id).await
+    }
 
+    pub async fn last_resort_package(
+        &self,
+        user_id: Uuid,
+        device_id: &str,
+    ) -> Result<Option<MlsKeyPackageRecord>> {
+        let record = sqlx::query_as::<_, MlsKeyPackageRecord>(
+            r#"
+            SELECT
+                id, user_id, device_id, key_package_ref, identity, ciphersuite,
+                signature_key, hpke_public_key, key_package, last_resort, created_at
+            FROM mls_key_packages
+            WHERE user_id = $1 AND device_id = $2 AND last_resort
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .fetch_optional(self.pool())
+        .await?;
         Ok(record)
     }
 
-    /// Fetch the most recent key package for each identity.
-    pub async fn latest_packages(&self) -> Result<Vec<MlsKeyPackageRecord>> {
-        let mut records = sqlx::query_as::<_, MlsKeyPackageRecord>(
+    /// Unclaimed packages per device of `user_id`, ordered by device id.
+    pub async fn stock(&self, user_id: Uuid) -> Result<Vec<DeviceKeyPackageStock>> {
+        let stock = sqlx::query_as::<_, DeviceKeyPackageStock>(
             r#"
-            SELECT DISTINCT ON (identity)
-                id,
-                identity,
-                ciphersuite,
-                signing_key,
-                signature_key,
-                hpke_public_key,
-                created_at
+            SELECT
+                device_id,
+                COUNT(*) FILTER (WHERE NOT last_resort) AS available,
+                BOOL_OR(last_resort) AS last_resort
             FROM mls_key_packages
-            ORDER BY identity, created_at DESC
+            WHERE user_id = $1
+            GROUP BY device_id
+            ORDER BY device_id
             "#,
         )
+        .bind(user_id)
         .fetch_all(self.pool())
         .await?;
-
-        records.sort_by(|a, b| a.identity.cmp(&b.identity));
-        Ok(records)
+        Ok(stock)
     }
 
-    /// Fetch the most recent key package for a specific identity.
-    pub async fn latest_for_identity(&self, identity: &str) -> Result<Option<MlsKeyPackageRecord>> {
-        let record = sqlx::query_as::<_, MlsKeyPackageRecord>(
+    /// Every unclaimed package `user_id` has uploaded, oldest first per device.
+    pub async fn packages_for_user(&self, user_id: Uuid) -> Result<Vec<MlsKeyPackageRecord>> {
+        let records = sqlx::query_as::<_, MlsKeyPackageRecord>(
             r#"
             SELECT
-                id,
-                identity,
-                ciphersuite,
-                signing_key,
-                signature_key,
-                hpke_public_key,
-                created_at
+                id, user_id, device_id, key_package_ref, identity, ciphersuite,
+                signature_key, hpke_public_key, key_package, last_resort, created_at
             FROM mls_key_packages
-            WHERE identity = $1
-            ORDER BY created_at DESC
-            LIMIT 1
+            WHERE user_id = $1
+            ORDER BY device_id, last_resort, created_at, id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(records)
+    }
+}
+
+/// MLS group the delivery service routes messages for.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct MlsGroupRecord {
+    /// URL-safe base64 of the MLS group id.
+    pub group_id: String,
+    pub channel_id: Uuid,
+    pub creator_id: Uuid,
+    pub epoch: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Insertable MLS message; `message` is the TLS-encoded `MLSMessage`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewMlsMessage {
+    pub epoch: i64,
+    /// `commit`, `proposal`, `application` or `welcome`.
+    pub kind: String,
+    pub sender_id: Uuid,
+    pub recipient_id: Option<Uuid>,
+    pub message: Vec<u8>,
+}
+
+/// Persisted MLS message awaiting delivery to group members.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct MlsMessageRecord {
+    pub sequence: i64,
+    pub group_id: String,
+    pub epoch: i64,
+    pub kind: String,
+    pub sender_id: Uuid,
+    pub recipient_id: Option<Uuid>,
+    pub message: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Repository for MLS groups, their member rosters and queued messages.
+#[derive(Clone)]
+pub struct MlsGroupRepository {
+    pool: Arc<PgPool>,
+}
+
+impl MlsGroupRepository {
+    /// Wrap a Postgres pool for MLS group persistence.
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// Register a group at epoch 0 with its creator as the only member. Returns `None`
+    /// when the group id or the channel is already taken.
+    pub async fn create_group(
+        &self,
+        group_id: &str,
+        channel_id: Uuid,
+        creator_id: Uuid,
+    ) -> Result<Option<MlsGroupRecord>> {
+        let mut tx = self.pool().begin().await?;
+        let record = sqlx::query_as::<_, MlsGroupRecord>(
+            r#"
+            INSERT INTO mls_groups (group_id, channel_id, creator_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            RETURNING group_id, channel_id, creator_id, epoch, created_at
+            "#,
+        )
+        .bind(group_id)
+        .bind(channel_id)
+        .bind(creator_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(record) = record else {
+            return Ok(None);
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO mls_group_members (group_id, user_id, joined_epoch)
+            VALUES ($1, $2, 0)
+            "#,
+        )
+        .bind(group_id)
+        .bind(creator_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(record))
+    }
+
+    pub async fn group(&self, group_id: &str) -> Result<Option<MlsGroupRecord>> {
+        let record = sqlx::query_as::<_, MlsGroupRecord>(
+            r#"
+            SELECT group_id, channel_id, creator_id, epoch, created_at
+            FROM mls_groups
+            WHERE group_id = $1
+            "#,
+        )
+        .bind(group_id)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(record)
+    }
+
+    pub async fn group_for_channel(&self, channel_id: Uuid) -> Result<Option<MlsGroupRecord>> {
+        let record = sqlx::query_as::<_, MlsGroupRecord>(
+            r#"
+            SELECT group_id, channel_id, creator_id, epoch, created_at
+            FROM mls_groups
+            WHERE channel_id = $1
             "#,
         )
-        .bind(identity)
+        .bind(channel_id)
         .fetch_optional(self.pool())
         .await?;
+        Ok(record)
+    }
+
+    /// Current members of a group, ordered by user id.
+    pub async fn members(&self, group_id: &str) -> Result<Vec<Uuid>> {
+        let members = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            SELECT user_id
+            FROM mls_group_members
+            WHERE group_id = $1
+            ORDER BY user_id
+            "#,
+        )
+        .bind(group_id)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(members)
+    }
 
+    /// Queue a proposal or application message without touching the epoch.
+    pub async fn append_message(
+        &self,
+        group_id: &str,
+        message: &NewMlsMessage,
+    ) -> Result<MlsMessageRecord> {
+        let mut tx = self.pool().begin().await?;
+        let record = insert_message(&mut tx, group_id, message).await?;
+        tx.commit().await?;
         Ok(record)
     }
+
+    /// Accept the commit for `epoch`: advance the group to `epoch + 1`, apply roster changes
+    /// and queue `messages`. Returns `None` when another commit already moved the group past
+    /// `epoch`.
+    pub async fn commit(
+        &self,
+        group_id: &str,
+        epoch: i64,
+        added: &[Uuid],
+        removed: &[Uuid],
+        messages: &[NewMlsMessage],
+    ) -> Result<Option<Vec<MlsMessageRecord>>> {
+        let mut tx = self.pool().begin().await?;
+        let advanced = sqlx::query(
+            r#"
+            UPDATE mls_groups
+            SET epoch = epoch + 1
+            WHERE group_id = $1 AND epoch = $2
+            "#,
+        )
+        .bind(group_id)
+        .bind(epoch)
+        .execute(&mut *tx)
+        .await?;
+        if advanced.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        sqlx::query("DELETE FROM mls_group_members WHERE group_id = $1 AND user_id = ANY($2)")
+            .bind(group_id)
+            .bind(removed)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO mls_group_members (group_id, user_id, joined_epoch)
+            SELECT $1, user_id, $3 FROM UNNEST($2::uuid[]) AS added(user_id)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(group_id)
+        .bind(added)
+        .bind(epoch + 1)
+        .execute(&mut *tx)
+        .await?;
+
+        let mut records = Vec::with_capacity(messages.len());
+        for message in messages {
+            records.push(insert_message(&mut tx, group_id, message).await?);
+        }
+        tx.commit().await?;
+        Ok(Some(records))
+    }
+
+    /// Messages after `since` that member `user_id` may fetch: group messages from the epoch
+    /// they joined onwards plus welcomes addressed to them. Empty for non-members.
+    pub async fn messages_for(
+        &self,
+        group_id: &str,
+        user_id: Uuid,
+        since: i64,
+        limit: i64,
+    ) -> Result<Vec<MlsMessageRecord>> {
+        let records = sqlx::query_as::<_, MlsMessageRecord>(
+            r#"
+            SELECT
+                m.sequence,
+                m.group_id,
+                m.epoch,
+                m.kind,
+                m.sender_id,
+                m.recipient_id,
+                m.message,
+                m.created_at
+            FROM mls_messages m
+            JOIN mls_group_members g ON g.group_id = m.group_id AND g.user_id = $2
+            WHERE m.group_id = $1
+              AND m.sequence > $3
+              AND ((m.recipient_id IS NULL AND m.epoch >= g.joined_epoch) OR m.recipient_id = $2)
+            ORDER BY m.sequence ASC
+            LIMIT $4
+            "#,
+        )
+        .bind(group_id)
+        .bind(user_id)
+        .bind(since)
+        .bind(limit)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(records)
+    }
+}
+
+async fn insert_message(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    group_id: &str,
+    message: &NewMlsMessage,
+) -> Result<MlsMessageRecord> {
+    let record = sqlx::query_as::<_, MlsMessageRecord>(
+        r#"
+        INSERT INTO mls_messages (group_id, epoch, kind, sender_id, recipient_id, message)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING sequence, group_id, epoch, kind, sender_id, recipient_id, message, created_at
+        "#,
+    )
+    .bind(group_id)
+    .bind(message.epoch)
+    .bind(&message.kind)
+    .bind(message.sender_id)
+    .bind(message.recipient_id)
+    .bind(&message.message)
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(record)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::connect;
+    use crate::{connect, MessagingRepository, StoragePool};
     use sqlx::migrate::Migrator;
     use std::env;
 
     static MIGRATOR: Migrator = sqlx::migrate!("../../migrations");
 
-    async fn setup_store() -> anyhow::Result<Option<MlsKeyPackageStore>> {
-        let database_url = match env::var("OPENGUILD_TEST_DATABASE_URL")
-            .or_else(|_| env::var("DATABASE_URL"))
-        {
-            Ok(url) => url,
-            Err(_) => {
-                eprintln!(
-                    "skipping MLS key package store test: set OPENGUILD_TEST_DATABASE_URL or DATABASE_URL"
-                );
-                return Ok(None);
-            }
-        };
+    async fn setup_pool() -> anyhow::Result<Option<StoragePool>> {
+        let database_url =
+            match env::var("OPENGUILD_TEST_DATABASE_URL").or_else(|_| env::var("DATABASE_URL")) {
+                Ok(url) => url,
+                Err(_) => {
+                    eprintln!(
+                        "skipping MLS store test: set OPENGUILD_TEST_DATABASE_URL or DATABASE_URL"
+                    );
+                    return Ok(None);
+                }
+            };
 
         let pool = connect(&database_url).await?;
         MIGRATOR
             .run(pool.pool())
             .await
-            .expect("running migrations for MLS key package store");
+            .expect("running migrations for MLS stores");
 
-        Ok(Some(MlsKeyPackageStore::new(pool.cloned())))
+        Ok(Some(pool))
     }
 
-    fn sample_new(identity: &str) -> NewMlsKeyPackage {
+    async fn setup_store() -> anyhow::Result<Option<MlsKeyPackageStore>> {
+        Ok(setup_pool()
+            .await?
+            .map(|pool| MlsKeyPackageStore::new(pool.cloned())))
+    }
+
+    const STOCK: i64 = 500;
+
+    fn sample_new(user_id: Uuid, device_id: &str, last_resort: bool) -> NewMlsKeyPackage {
+        let key_package_ref = Uuid::new_v4().to_string();
         NewMlsKeyPackage {
-            identity: identity.into(),
+            user_id,
+            device_id: device_id.into(),
+            identity: "alice".into(),
             ciphersuite: "MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519".into(),
-            signing_key: "signing-key".into(),
             signature_key: "verifying-key".into(),
-            hpke_public_key: "hpke".into(),
+            hpke_public_key: format!("hpke-{key_package_ref}"),
+            key_package: key_package_ref.as_bytes().to_vec(),
+            key_package_ref,
+            last_resort,
         }
     }
 
     #[tokio::test]
-    async fn record_and_fetch_latest_packages() -> anyhow::Result<()> {
+    async fn packages_are_claimed_once_then_last_resort() -> anyhow::Result<()> {
         let Some(store) = setup_store().await? else {
             return Ok(());
         };
+        let alice = Uuid::new_v4();
 
-        // Record two identities with multiple entries to ensure ordering.
-        store
-            .record_package(&sample_new("alice"))
-            .await
-            .expect("alice stored");
+        let first = sample_new(alice, "laptop", false);
+        assert_eq!(
+            store.upload(std::slice::from_ref(&first), STOCK).await?,
+            Some(1)
+        );
+        let batch = vec![
+            sample_new(alice, "laptop", false),
+            sample_new(alice, "laptop", true),
+            sample_new(alice, "phone", false),
+        ];
+        assert_eq!(store.upload(&batch, 1).await?, None, "over the limit");
+        assert_eq!(store.upload(&batch, STOCK).await?, Some(3));
+        assert_eq!(
+            store.upload(std::slice::from_ref(&first), STOCK).await?,
+            Some(0),
+            "duplicates skipped"
+        );
+
+        let replacement = sample_new(alice, "laptop", true);
         store
-            .record_package(&sample_new("bob"))
-            .await
-            .expect("bob stored");
-
-        let mut newer = sample_new("alice");
-        newer.signing_key = "rotated".into();
-        store.record_package(&newer).await.expect("alice rotated");
-
-        let records = store.latest_packages().await?;
-        assert_eq!(records.len(), 2);
-        let alice = records
-            .iter()
-            .find(|pkg| pkg.identity == "alice")
-            .expect("alice present");
-        assert_eq!(alice.signing_key, "rotated");
-
-        let latest_alice = store
-            .latest_for_identity("alice")
+            .upload(std::slice::from_ref(&replacement), STOCK)
+            .await?;
+        let stock = store.stock(alice).await?;
+        assert_eq!(
+            stock,
+            vec![
+                DeviceKeyPackageStock {
+                    device_id: "laptop".into(),
+                    available: 2,
+                    last_resort: true,
+                },
+                DeviceKeyPackageStock {
+                    device_id: "phone".into(),
+                    available: 1,
+                    last_resort: false,
+                },
+            ]
+        );
+
+        let claimed = store.claim(alice, "laptop").await?.expect("package left");
+        assert_eq!(claimed.key_package_ref, first.key_package_ref);
+        assert!(!claimed.last_resort);
+        store.claim(alice, "laptop").await?.expect("second package");
+        for _ in 0..2 {
+            let fallback = store.claim(alice, "laptop").await?.expect("last resort");
+            assert_eq!(fallback.key_package_ref, replacement.key_package_ref);
+        }
+
+        store.claim(alice, "phone").await?.expect("phone package");
+        assert!(store.claim(alice, "phone").await?.is_none());
+        assert_eq!(store.packages_for_user(alice).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn commits_advance_the_epoch_once() -> anyhow::Result<()> {
+        let Some(pool) = setup_pool().await? else {
+            return Ok(());
+        };
+        let messaging = MessagingRepository::new(pool.clone());
+        let guild = messaging.create_guild("mls").await?;
+        let channel = messaging
+            .create_channel(guild.guild_id, "secret", "text")
+            .await?;
+        let groups = MlsGroupRepository::new(pool.cloned());
+
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let group_id = Uuid::new_v4().to_string();
+        let group = groups
+            .create_group(&group_id, channel.channel_id, alice)
             .await?
-            .expect("latest alice exists");
-        assert_eq!(latest_alice.signing_key, "rotated");
+            .expect("group created");
+        assert_eq!(group.epoch, 0);
+        assert!(groups
+            .create_group("other", channel.channel_id, bob)
+            .await?
+            .is_none());
 
-        let missing = store.latest_for_identity("carol").await?;
-        assert!(missing.is_none());
+        let commit = NewMlsMessage {
+            epoch: 0,
+            kind: "commit".into(),
+            sender_id: alice,
+            recipient_id: None,
+            message: vec![1, 2, 3],
+        };
+        let welcome = NewMlsMessage {
+            kind: "welcome".into(),
+            recipient_id: Some(bob),
+            ..commit.clone()
+        };
+        let stored = groups
+            .commit(&group_id, 0, &[bob], &[], &[commit.clone(), welcome])
+            .await?
+            .expect("first commit wins");
+        assert_eq!(stored.len(), 2);
+        assert!(groups
+            .commit(&group_id, 0, &[], &[bob], &[commit])
+            .await?
+            .is_none());
 
+        let mut members = vec![alice, bob];
+        members.sort();
+        assert_eq!(groups.members(&group_id).await?, members);
+        assert_eq!(groups.group(&group_id).await?.unwrap().epoch, 1);
+        let for_bob = groups.messages_for(&group_id, bob, 0, 10).await?;
+        assert_eq!(
+            for_bob.len(),
+            1,
+            "bob only sees his welcome, not the commit adding him"
+        );
+        assert_eq!(for_bob[0].kind, "welcome");
+        assert_eq!(groups.messages_for(&group_id, alice, 0, 10).await?.len(), 1);
+        assert!(groups
+            .messages_for(&group_id, Uuid::new_v4(), 0, 10)
+            .await?
+            .is_empty());
         Ok(())
     }
 }
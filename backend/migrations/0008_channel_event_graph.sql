@@ -0,0 +1,17 @@
+-- Persist the per-channel event DAG (prev/auth edges) and forward extremities.
+CREATE TABLE IF NOT EXISTS channel_event_edges (
+    channel_id UUID NOT NULL REFERENCES channels(channel_id) ON DELETE CASCADE,
+    event_id TEXT NOT NULL,
+    target_event_id TEXT NOT NULL,
+    edge_kind TEXT NOT NULL CHECK (edge_kind IN ('prev', 'auth')),
+    PRIMARY KEY (event_id, target_event_id, edge_kind)
+);
+
+CREATE INDEX IF NOT EXISTS channel_event_edges_target_idx
+    ON channel_event_edges (channel_id, target_event_id, edge_kind);
+
+CREATE TABLE IF NOT EXISTS channel_forward_extremities (
+    channel_id UUID NOT NULL REFERENCES channels(channel_id) ON DELETE CASCADE,
+    event_id TEXT NOT NULL,
+    PRIMARY KEY (channel_id, event_id)
+);
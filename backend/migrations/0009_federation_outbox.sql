@@ -0,0 +1,12 @@
+-- Durable per-destination queue of locally created events awaiting federation delivery.
+CREATE TABLE IF NOT EXISTS federation_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    destination TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    body JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (destination, event_id)
+);
+
+CREATE INDEX IF NOT EXISTS federation_outbox_destination_idx
+    ON federation_outbox (destination, id);
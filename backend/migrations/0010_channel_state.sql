@@ -0,0 +1,15 @@
+-- Channel state snapshots derived from the event DAG. Events that leave state unchanged share a group.
+CREATE TABLE IF NOT EXISTS channel_state_groups (
+    channel_id UUID NOT NULL REFERENCES channels(channel_id) ON DELETE CASCADE,
+    state_group TEXT NOT NULL,
+    state JSONB NOT NULL,
+    PRIMARY KEY (channel_id, state_group)
+);
+
+CREATE TABLE IF NOT EXISTS channel_event_state (
+    channel_id UUID NOT NULL REFERENCES channels(channel_id) ON DELETE CASCADE,
+    event_id TEXT NOT NULL,
+    state_group TEXT NOT NULL,
+    PRIMARY KEY (channel_id, event_id),
+    FOREIGN KEY (channel_id, state_group) REFERENCES channel_state_groups(channel_id, state_group)
+);
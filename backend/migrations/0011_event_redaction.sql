@@ -0,0 +1,4 @@
+-- Redacted events keep their row (and DAG position) but the body is replaced by its redacted form.
+ALTER TABLE channel_events
+    ADD COLUMN IF NOT EXISTS redacted BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN IF NOT EXISTS redacted_by TEXT;
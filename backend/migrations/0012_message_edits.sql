@@ -0,0 +1,10 @@
+-- Links `edit` events to the message they replace. The newest edit supplies the displayed body.
+CREATE TABLE IF NOT EXISTS channel_message_edits (
+    channel_id UUID NOT NULL REFERENCES channels(channel_id) ON DELETE CASCADE,
+    original_event_id TEXT NOT NULL,
+    edit_event_id TEXT NOT NULL,
+    PRIMARY KEY (channel_id, edit_event_id)
+);
+
+CREATE INDEX IF NOT EXISTS channel_message_edits_original_idx
+    ON channel_message_edits (channel_id, original_event_id);
@@ -0,0 +1,23 @@
+-- Links thread replies to their root message.
+CREATE TABLE IF NOT EXISTS channel_thread_replies (
+    channel_id UUID NOT NULL REFERENCES channels(channel_id) ON DELETE CASCADE,
+    root_event_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    PRIMARY KEY (channel_id, event_id)
+);
+
+CREATE INDEX IF NOT EXISTS channel_thread_replies_root_idx
+    ON channel_thread_replies (channel_id, root_event_id);
+
+-- Per-thread read position, tracked next to channel_read_state for thread participants.
+CREATE TABLE IF NOT EXISTS thread_read_state (
+    channel_id UUID NOT NULL REFERENCES channels(channel_id) ON DELETE CASCADE,
+    root_event_id TEXT NOT NULL,
+    user_id UUID NOT NULL,
+    last_read_sequence BIGINT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (channel_id, root_event_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_thread_read_state_user
+    ON thread_read_state (user_id);
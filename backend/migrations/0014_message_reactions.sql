@@ -0,0 +1,12 @@
+-- Live reactions. Rows are deleted when the reaction event is redacted.
+CREATE TABLE IF NOT EXISTS channel_reactions (
+    channel_id UUID NOT NULL REFERENCES channels(channel_id) ON DELETE CASCADE,
+    target_event_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    reaction_key TEXT NOT NULL,
+    PRIMARY KEY (channel_id, event_id)
+);
+
+CREATE INDEX IF NOT EXISTS channel_reactions_target_idx
+    ON channel_reactions (channel_id, target_event_id);
@@ -0,0 +1,13 @@
+-- Uploaded media. Objects are stored content-addressed under `content_hash`, so several
+-- media ids can share one stored object.
+CREATE TABLE IF NOT EXISTS media (
+    media_id TEXT PRIMARY KEY,
+    content_hash TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    filename TEXT,
+    uploader_id UUID NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS media_content_hash_idx ON media (content_hash);
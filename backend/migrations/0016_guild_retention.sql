@@ -0,0 +1,10 @@
+-- Per-guild retention. The reaper prunes channel events older than `max_age_secs` and
+-- beyond the newest `max_events` of each channel; NULL leaves that limit unset.
+CREATE TABLE IF NOT EXISTS guild_retention_policies (
+    guild_id UUID PRIMARY KEY REFERENCES guilds(guild_id) ON DELETE CASCADE,
+    max_age_secs BIGINT CHECK (max_age_secs > 0),
+    max_events BIGINT CHECK (max_events > 0),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS media_uploader_idx ON media (uploader_id);
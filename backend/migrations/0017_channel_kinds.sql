@@ -0,0 +1,4 @@
+-- Channel kinds. Existing channels were all text channels.
+ALTER TABLE channels
+    ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'text'
+    CHECK (kind IN ('text', 'voice', 'announcement', 'category'));
@@ -0,0 +1,30 @@
+-- MLS delivery service state. Groups, epochs and the member roster are routing metadata;
+-- handshake and application messages are stored as opaque TLS-encoded MLSMessages.
+CREATE TABLE IF NOT EXISTS mls_groups (
+    group_id TEXT PRIMARY KEY,
+    channel_id UUID NOT NULL UNIQUE REFERENCES channels(channel_id) ON DELETE CASCADE,
+    creator_id UUID NOT NULL,
+    epoch BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS mls_group_members (
+    group_id TEXT NOT NULL REFERENCES mls_groups(group_id) ON DELETE CASCADE,
+    user_id UUID NOT NULL,
+    joined_epoch BIGINT NOT NULL,
+    PRIMARY KEY (group_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS mls_messages (
+    sequence BIGSERIAL PRIMARY KEY,
+    group_id TEXT NOT NULL REFERENCES mls_groups(group_id) ON DELETE CASCADE,
+    epoch BIGINT NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('commit', 'proposal', 'application', 'welcome')),
+    sender_id UUID NOT NULL,
+    -- Set for welcomes, which only the added member may fetch.
+    recipient_id UUID,
+    message BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS mls_messages_group_idx ON mls_messages (group_id, sequence);
@@ -0,0 +1,24 @@
+-- Key packages are now generated and signed by client devices; the server only stores the
+-- public KeyPackage (as an MLSMessage) and hands each one out once. Server-generated rows held
+-- private signing keys and cannot be migrated, so they are discarded.
+DELETE FROM mls_key_packages;
+
+DROP INDEX IF EXISTS idx_mls_key_packages_identity_created_at;
+
+ALTER TABLE mls_key_packages
+    DROP COLUMN signing_key,
+    ADD COLUMN user_id UUID NOT NULL,
+    ADD COLUMN device_id TEXT NOT NULL,
+    -- URL-safe base64 of the KeyPackageRef (RFC 9420 §5.2).
+    ADD COLUMN key_package_ref TEXT NOT NULL UNIQUE,
+    ADD COLUMN key_package BYTEA NOT NULL,
+    ADD COLUMN last_resort BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX IF NOT EXISTS idx_mls_key_packages_device_created_at
+    ON mls_key_packages (user_id, device_id, created_at)
+    WHERE NOT last_resort;
+
+-- Each device keeps at most one last-resort package, handed out when its stock runs dry.
+CREATE UNIQUE INDEX IF NOT EXISTS idx_mls_key_packages_last_resort
+    ON mls_key_packages (user_id, device_id)
+    WHERE last_resort;
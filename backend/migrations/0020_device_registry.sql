@@ -0,0 +1,21 @@
+-- End-to-end encryption device registry. Devices publish their ed25519 identity key; a
+-- user's master cross-signing key signs the devices they have verified.
+CREATE TABLE IF NOT EXISTS user_devices (
+    user_id UUID NOT NULL,
+    device_id TEXT NOT NULL,
+    display_name TEXT,
+    -- URL-safe base64 ed25519 public key. Fixed for the lifetime of the device.
+    identity_key TEXT NOT NULL,
+    -- Master key signature over the device's keys; cleared when the master key changes.
+    master_signature TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS cross_signing_keys (
+    user_id UUID PRIMARY KEY,
+    -- URL-safe base64 ed25519 public key.
+    master_key TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,28 @@
+-- Server-side key backups. Clients encrypt MLS epoch secrets and message keys with a recovery
+-- key the server never sees; the server only stores the ciphertext so a new device can restore
+-- history. Each new backup version supersedes the previous one.
+CREATE TABLE IF NOT EXISTS key_backup_versions (
+    user_id UUID NOT NULL,
+    version BIGINT NOT NULL,
+    -- Client-chosen name of the backup encryption scheme.
+    algorithm TEXT NOT NULL,
+    -- Public parameters clients use to check their recovery key, e.g. its public half.
+    auth_data JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- Deleted versions keep their row so version numbers are never reused.
+    deleted_at TIMESTAMPTZ,
+    PRIMARY KEY (user_id, version)
+);
+
+CREATE TABLE IF NOT EXISTS key_backup_keys (
+    user_id UUID NOT NULL,
+    version BIGINT NOT NULL,
+    -- URL-safe base64 of the MLS group id.
+    group_id TEXT NOT NULL,
+    epoch BIGINT NOT NULL,
+    ciphertext BYTEA NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, version, group_id, epoch),
+    FOREIGN KEY (user_id, version)
+        REFERENCES key_backup_versions (user_id, version) ON DELETE CASCADE
+);